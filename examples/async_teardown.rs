@@ -0,0 +1,110 @@
+//! Drive the idle loop from tokio, instead of with a blocking loop.
+//!
+//! This starts a long-running child process, standing in for the
+//! namespaces tunnel-ns would set up, and then waits, on a tokio
+//! runtime, for the usual reasons to tear it down: stdin closed, a
+//! termination signal, or the child exiting on its own.  Once every
+//! few seconds it reports that it is still waiting.  Build with
+//! `cargo run --features async --example async_teardown`, and then
+//! press ^C or ^D.
+
+use std::io;
+use std::process;
+
+use std::future::poll_fn;
+use std::io::Write;
+use std::pin::Pin;
+use std::process::{Child, Command};
+use std::time::Duration;
+
+extern crate libc;
+extern crate futures_core;
+extern crate tokio;
+
+extern crate openvpn_netns_tools;
+use openvpn_netns_tools::*;
+
+use futures_core::Stream;
+
+/// Undo setup: kill the child, if it's still there, and wait for it.
+fn teardown(mut child: Child) {
+    writeln!(io::stderr(), "# tearing down").unwrap();
+    let _ = child.kill();
+    match child.wait() {
+        Ok(status) => writeln!(io::stderr(), "# child {}", status).unwrap(),
+        Err(e) => writeln!(io::stderr(), "wait: {}", e).unwrap()
+    }
+}
+
+fn inner_main() -> Result<i32, HLError> {
+    // As always, this must come before anything that might start a
+    // thread, including the tokio runtime.
+    let (sigfd, child_mask) = try!(prepare_signals());
+
+    let child = try!(Command::new("sleep").arg("1000").spawn()
+                     .map_err(|e| map_io_err(e, String::from("sleep"))));
+    let child_pid = child.id() as libc::pid_t;
+
+    let runtime = try!(tokio::runtime::Builder::new_current_thread()
+                       .enable_all().build()
+                       .map_err(|e| map_io_err(e, String::from("tokio"))));
+
+    let mut events = AsyncIdleLoop::new(IdleLoop::new(sigfd));
+    events.get_mut().add_timer(Duration::from_secs(5), true);
+
+    let mut child = Some(child);
+    let mut exit_code = 0;
+    loop {
+        // An async fn would say `events.next().await` here.
+        let ev = runtime.block_on(poll_fn(|cx| {
+            Pin::new(&mut events).poll_next(cx)
+        }));
+        match ev {
+            Some(Event::StdinClosed) => {
+                writeln!(io::stderr(), "# stdin closed").unwrap();
+                break;
+            },
+            Some(Event::TermSignal(sig)) => {
+                writeln!(io::stderr(), "# {:?}", sig).unwrap();
+                break;
+            },
+            Some(Event::ChildExit(pid, status)) if pid == child_pid => {
+                // The idle loop has reaped it; don't wait for it again.
+                let status = try!(reap_child(child.take().unwrap(), status));
+                writeln!(io::stderr(), "# child {}, unexpectedly",
+                         status).unwrap();
+                exit_code = 1;
+                break;
+            },
+            Some(Event::Timeout(_)) => {
+                writeln!(io::stderr(), "# still waiting").unwrap();
+            },
+            Some(Event::Error(e)) => {
+                writeln!(io::stderr(), "idle loop: {}", e).unwrap();
+                exit_code = 1;
+                break;
+            },
+            Some(_) => (),
+            None => {
+                exit_code = 1;
+                break;
+            }
+        }
+    }
+
+    if let Some(child) = child {
+        teardown(child);
+    }
+    try!(events.into_inner().shutdown(child_mask));
+    Ok(exit_code)
+}
+
+fn main() {
+    process::exit(match inner_main() {
+        Ok(code) => code,
+        Err(e) => {
+            writeln!(io::stderr(), "async_teardown: {}", e).unwrap();
+            1
+        }
+    });
+}
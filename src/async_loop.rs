@@ -0,0 +1,225 @@
+//! Driving the idle loop from tokio (only with the "async" feature).
+//! The IdleLoop does all the real work, exactly as it does when used
+//! on its own; this just arranges for tokio to wake up the task when
+//! one of the loop's file descriptors is ready or its next timer is
+//! due, so no helper thread is needed.
+
+use std::future::Future;
+use std::io;
+use std::io::Write;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_core::Stream;
+use tokio::io::unix::AsyncFd;
+use tokio::time::{sleep_until, Sleep};
+
+use idle_loop::{IdleLoop, Event, Interest};
+
+/// Internal: a descriptor that belongs to the idle loop (or its
+/// caller), registered with tokio.  Dropping this deregisters it, but
+/// doesn't close it.
+struct WatchedFd(RawFd);
+impl AsRawFd for WatchedFd {
+    fn as_raw_fd(&self) -> RawFd { self.0 }
+}
+
+/// Internal: check whether AFD is ready for INTEREST, and if not,
+/// arrange for the current task to be woken when it is.
+fn poll_watched(afd: &AsyncFd<WatchedFd>, interest: Interest,
+                cx: &mut Context) -> bool {
+    let mut ready = false;
+    if interest != Interest::Writable {
+        match afd.poll_read_ready(cx) {
+            Poll::Ready(Ok(mut guard)) => {
+                guard.clear_ready();
+                ready = true;
+            },
+            // tokio itself is in trouble; try again later, rather
+            // than spinning.
+            Poll::Ready(Err(_)) => cx.waker().wake_by_ref(),
+            Poll::Pending => ()
+        }
+    }
+    if interest != Interest::Readable {
+        match afd.poll_write_ready(cx) {
+            Poll::Ready(Ok(mut guard)) => {
+                guard.clear_ready();
+                ready = true;
+            },
+            Poll::Ready(Err(_)) => cx.waker().wake_by_ref(),
+            Poll::Pending => ()
+        }
+    }
+    ready
+}
+
+/// An IdleLoop as a Stream of Events.  It must be polled from within
+/// a tokio runtime with I/O and time drivers enabled.  Like the
+/// Iterator impl for IdleLoop, the stream ends if the loop gives up
+/// after repeated errors; otherwise it never ends.
+///
+/// The EOF grace period for stdin is not supported, because it would
+/// mean blocking the runtime; new sets it to zero, and it should not
+/// be changed via get_mut.
+pub struct AsyncIdleLoop {
+    idle: IdleLoop,
+    watched: Vec<AsyncFd<WatchedFd>>,
+    timer: Option<Pin<Box<Sleep>>>,
+}
+impl AsyncIdleLoop {
+    pub fn new (mut idle: IdleLoop) -> AsyncIdleLoop {
+        idle.set_stdin_eof_grace(Duration::from_secs(0));
+        AsyncIdleLoop { idle: idle, watched: Vec::new(), timer: None }
+    }
+
+    /// The underlying IdleLoop, e.g. for registering file descriptors
+    /// or setting timers.
+    pub fn get_mut (&mut self) -> &mut IdleLoop {
+        &mut self.idle
+    }
+
+    /// Take back the underlying IdleLoop.
+    pub fn into_inner (self) -> IdleLoop {
+        self.idle
+    }
+
+    /// Internal: register everything the loop is watching with tokio,
+    /// so this task is woken when there is something to do.  Returns
+    /// true if there already is.
+    ///
+    /// The registrations are made afresh every time, because the set
+    /// of descriptors changes as the loop runs, and a descriptor
+    /// number may have been closed and reused since last time.  A
+    /// descriptor that is already ready is reported by tokio as soon
+    /// as it is registered, so nothing is missed.
+    fn arm (&mut self, cx: &mut Context) -> bool {
+        use tokio::io::Interest as TokioInterest;
+
+        // tokio won't register the same descriptor twice.
+        let mut fds: Vec<(RawFd, Interest)> = Vec::new();
+        for (fd, interest) in self.idle.watched_fds() {
+            match fds.iter().position(|&(f, _)| f == fd) {
+                Some(i) => if fds[i].1 != interest {
+                    fds[i].1 = Interest::Both;
+                },
+                None => fds.push((fd, interest))
+            }
+        }
+
+        self.watched.clear();
+        let mut ready = false;
+        for (fd, interest) in fds {
+            let tokio_interest = match interest {
+                Interest::Readable => TokioInterest::READABLE,
+                Interest::Writable => TokioInterest::WRITABLE,
+                Interest::Both =>
+                    TokioInterest::READABLE.add(TokioInterest::WRITABLE)
+            };
+            match AsyncFd::with_interest(WatchedFd(fd), tokio_interest) {
+                Ok(afd) => {
+                    if poll_watched(&afd, interest, cx) {
+                        ready = true;
+                    }
+                    self.watched.push(afd);
+                },
+                // Regular files (e.g. stdin redirected from one) can't
+                // be watched, but they are always ready anyway.  Come
+                // back soon, but give other tasks a turn first.
+                Err(ref e) if e.raw_os_error() == Some(::libc::EPERM) => {
+                    cx.waker().wake_by_ref();
+                },
+                Err(e) => {
+                    writeln!(io::stderr(), "idle loop: can't watch fd {} \
+                                            with tokio: {}", fd, e).unwrap();
+                }
+            }
+        }
+
+        self.timer = self.idle.next_deadline().map(|deadline| {
+            Box::pin(sleep_until(::tokio::time::Instant::from_std(deadline)))
+        });
+        if let Some(ref mut timer) = self.timer {
+            if timer.as_mut().poll(cx).is_ready() {
+                ready = true;
+            }
+        }
+        ready
+    }
+}
+impl Stream for AsyncIdleLoop {
+    type Item = Event;
+    fn poll_next (self: Pin<&mut Self>, cx: &mut Context)
+                  -> Poll<Option<Event>> {
+        let this = self.get_mut();
+        loop {
+            if this.idle.gave_up() {
+                return Poll::Ready(None);
+            }
+            if let Some(ev) = this.idle.next_event_timeout(
+                Duration::from_secs(0)) {
+                return Poll::Ready(Some(ev));
+            }
+            if !this.arm(cx) {
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::poll_fn;
+    use std::thread;
+    use std::time::Instant;
+    use nix::unistd::{close, pipe, write};
+    use tokio::runtime::{Builder, Runtime};
+    use super::*;
+
+    /// Internal: the next event from EVENTS, waiting for it on RUNTIME.
+    fn next(runtime: &Runtime, events: &mut AsyncIdleLoop) -> Option<Event> {
+        runtime.block_on(poll_fn(|cx| Pin::new(&mut *events).poll_next(cx)))
+    }
+
+    #[test]
+    fn timers_and_descriptors_wake_the_task() {
+        let runtime = Builder::new_current_thread().enable_all().build()
+            .unwrap();
+        let (quiet, quiet_wr) = pipe().unwrap();
+        let mut idle = IdleLoop::new(quiet);
+        idle.ignore_stdin();
+        let mut events = AsyncIdleLoop::new(idle);
+
+        let id = events.get_mut().add_timer(Duration::from_millis(100),
+                                            false);
+        let start = Instant::now();
+        match next(&runtime, &mut events) {
+            Some(Event::Timeout(t)) => assert_eq!(t, id),
+            _ => panic!("expected Timeout")
+        }
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        // Nothing is ready when the task goes to sleep; tokio has to
+        // wake it up.
+        let (rd, wr) = pipe().unwrap();
+        let token = events.get_mut().register_fd(rd, Interest::Readable);
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            write(wr, b"x").unwrap();
+            wr
+        });
+        match next(&runtime, &mut events) {
+            Some(Event::FdReady(t, true, false)) => assert_eq!(t, token),
+            _ => panic!("expected FdReady")
+        }
+        let wr = writer.join().unwrap();
+
+        let mut idle = events.into_inner();
+        assert!(idle.deregister_fd(token));
+        for &fd in &[rd, wr, quiet, quiet_wr] {
+            close(fd).unwrap();
+        }
+    }
+}
@@ -26,6 +26,20 @@
  * is taken as 'program', and all subsequent arguments are passed to
  * 'program' verbatim.
  *
+ * If ISOL_ENV_FILE is set, it names a file of additional environment
+ * variable settings for 'program', one KEY=VALUE per line.  Blank
+ * lines and lines beginning with '#' are ignored; a value may be
+ * enclosed in quotes, which are removed, but is otherwise taken
+ * literally.  Any other kind of line is a fatal error.  'VAR=val'
+ * arguments on the command line take precedence over settings in the
+ * file, and the same variables that may not be set on the command
+ * line may not be set in the file either.
+ *
+ * If ISOL_PID_FILE is set, this program writes its process ID to
+ * the file it names, and removes the file again when it exits.  If
+ * the file already names a running process, this program refuses to
+ * start.  The directory must be writable by whoever invoked it.
+ *
  * VARs with names starting ISOL_*, on the command line, may be used
  * to adjust the behavior of this program, and will not be passed
  * down.  These are *not* honored if set in this program's own
@@ -62,6 +76,30 @@
  * RLIMIT_AS, RLIMIT_DATA, and RLIMIT_RSS; those three cannot be set
  * individually.
  *
+ * The value of any ISOL_RL_<limit> may be "unlimited".  Otherwise it
+ * is a decimal number.  For the limits measured in bytes (CORE,
+ * FSIZE, MEM, MEMLOCK, MSGQUEUE, and STACK), the number may be
+ * followed by k, M, G, or T, meaning kibi-, mebi-, gibi-, or
+ * tebibytes respectively; e.g. ISOL_RL_MEM=512M.
+ *
+ * If ISOL_SECCOMP is set, a seccomp-bpf system call filter is applied
+ * to the isolated program, immediately before it is executed (and
+ * therefore after all credential changes).  ISOL_SECCOMP=strict allows
+ * only read, write, and exit; ISOL_SECCOMP=profile:/some/file allows
+ * the system calls listed in that file, one per line.  Any other
+ * system call kills the program.  If the kernel does not support
+ * seccomp filtering, the program is not run at all.  The profile is
+ * read with the permissions of whoever invoked this program.
+ *
+ * When this program receives SIGTERM or SIGINT, it passes the signal
+ * on to the isolated program's process group, and keeps waiting for
+ * the program to exit.  ISOL_FORWARD_SIGNALS changes which signals
+ * are passed on; its value is a comma-separated list of signal names,
+ * e.g. "TERM,INT,HUP" (the "SIG" prefix is optional), and may be
+ * empty.  The signals that may be listed are HUP, INT, QUIT, TERM,
+ * ALRM, USR1, and USR2; any of these that are not listed are ignored.
+ * SIGTSTP and SIGCONT are always passed on.
+ *
  * This program is not intended as a replacement for full-fledged
  * containers!  The subsidiary program can still access the entire
  * filesystem and all other shared resources.  It can spawn children
@@ -71,6 +109,13 @@
  * anything like that.  But on the up side, you don't have to
  * construct a chroot environment.
  *
+ * Not all of the above is implemented yet.  At present, the program
+ * runs as the user who invoked this program, in the current working
+ * directory; no home directory is created, and HOME, USER, PWD,
+ * LOGNAME, SHELL, and TMPDIR are not set.  ISOL_HOME, ISOL_LOW_UID,
+ * ISOL_HIGH_UID, and ISOL_NETNS are rejected.  Resource
+ * limits that are not set explicitly are inherited unchanged.
+ *
  * This program has only been tested on Linux.  C99 and POSIX.1-2001
  * features are used throughout.  It also requires static_assert, from
  * C11; dirfd, lchown, and strdup, from POSIX.1-2008; and execvpe,
@@ -80,6 +125,528 @@
  * but it may well be impractical to port it to anything older.
  */
 
+use std::env;
+use std::io;
+use std::process;
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+use std::process::Child;
+use std::time::{Duration, Instant};
+
+extern crate libc;
+extern crate nix;
+
+extern crate openvpn_netns_tools;
+use openvpn_netns_tools::*;
+
+use libc::rlim_t;
+use nix::sys::signal::Signal;
+use nix::sys::wait::WaitStatus;
+
+/// Settings parsed from the command line.
+struct Settings {
+    /// Environment variables set with VAR=val, in order.
+    env: Vec<(String, String)>,
+    /// ISOL_ENV_FILE.
+    env_file: Option<String>,
+    /// ISOL_PID_FILE.
+    pid_file: Option<String>,
+    /// ISOL_RL_* settings, other than ISOL_RL_WALL.
+    limits: Vec<(Limit, rlim_t)>,
+    /// ISOL_RL_WALL.
+    wall: Option<Duration>,
+    /// ISOL_FORWARD_SIGNALS.
+    forward_signals: Vec<Signal>,
+    /// ISOL_SECCOMP, already compiled.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    seccomp: Option<SeccompFilter>,
+    /// The program to run, and its arguments.
+    argv: Vec<String>,
+}
+
+/// Environment variables passed down from our own environment: these,
+/// and any whose names begin with one of PRESERVED_PREFIXES.
+const PRESERVED_VARS: &'static [&'static str] = &["PATH", "TZ", "TERM",
+                                                   "LANG"];
+const PRESERVED_PREFIXES: &'static [&'static str] = &["LC_"];
+
+/// True if the environment variable NAME is passed down from our own
+/// environment.
+fn preserved_env_var(name: &str) -> bool {
+    PRESERVED_VARS.contains(&name)
+        || PRESERVED_PREFIXES.iter().any(|p| name.starts_with(p))
+}
+
+/// True if the environment variable NAME may not be set with VAR=val
+/// (or in ISOL_ENV_FILE), either because we set it ourselves or
+/// because it should have been set in our own environment instead.
+fn forbidden_env_var(name: &str) -> bool {
+    preserved_env_var(name) || name == "HOME" || name == "PWD"
+        || name == "TMPDIR" || name == "USER" || name == "LOGNAME"
+        || name == "SHELL"
+}
+
+/// Termination signals that may be passed on to the isolated program,
+/// by name (without the "SIG" prefix).
+const FORWARDABLE_SIGNALS: &'static [(&'static str, Signal)] = &[
+    ("HUP", Signal::SIGHUP),
+    ("INT", Signal::SIGINT),
+    ("QUIT", Signal::SIGQUIT),
+    ("TERM", Signal::SIGTERM),
+    ("ALRM", Signal::SIGALRM),
+    ("USR1", Signal::SIGUSR1),
+    ("USR2", Signal::SIGUSR2),
+];
+
+/// Parse VALUE, a comma-separated list of signal names, for
+/// ISOL_FORWARD_SIGNALS.
+fn parse_signal_list(value: &str) -> Result<Vec<Signal>, HLError> {
+    let mut sigs = Vec::new();
+    for name in value.split(',').filter(|n| !n.is_empty()) {
+        let bare = if name.starts_with("SIG") { &name[3..] } else { name };
+        match FORWARDABLE_SIGNALS.iter().find(|&&(n, _)| n == bare) {
+            Some(&(_, sig)) => if !sigs.contains(&sig) { sigs.push(sig) },
+            None => return Err(map_config_err(format!(
+                "ISOL_FORWARD_SIGNALS: {:?} is not a signal that can be \
+                 forwarded", name)))
+        }
+    }
+    Ok(sigs)
+}
+
+/// Apply one ISOL_NAME=VALUE setting.
+fn apply_isol_setting(settings: &mut Settings, name: &str, value: &str)
+                      -> Result<(), HLError> {
+    if name.starts_with("RL_") {
+        let limit = match limit_by_name(&name[3..]) {
+            Some(limit) => limit,
+            None => return Err(map_config_err(format!(
+                "unrecognized variable ISOL_{}", name)))
+        };
+        let value = try!(parse_rlimit(&name[3..], value));
+        if limit == Limit::Wall {
+            settings.wall = if value == libc::RLIM_INFINITY {
+                None
+            } else {
+                Some(Duration::from_secs(value as u64))
+            };
+        } else {
+            settings.limits.retain(|&(l, _)| l != limit);
+            settings.limits.push((limit, value));
+        }
+        return Ok(());
+    }
+    match name {
+        "ENV_FILE" => {
+            settings.env_file = Some(String::from(value));
+            Ok(())
+        },
+        "PID_FILE" => {
+            settings.pid_file = Some(String::from(value));
+            Ok(())
+        },
+        "FORWARD_SIGNALS" => {
+            settings.forward_signals = try!(parse_signal_list(value));
+            Ok(())
+        },
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        "SECCOMP" => {
+            settings.seccomp = Some(try!(SeccompFilter::from_spec(value)));
+            Ok(())
+        },
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        "SECCOMP" => Err(map_config_err(String::from(
+            "ISOL_SECCOMP is only supported on Linux"))),
+        "HOME" | "LOW_UID" | "HIGH_UID" | "NETNS" =>
+            Err(map_config_err(format!(
+                "ISOL_{} is not supported yet", name))),
+        _ => Err(map_config_err(format!(
+            "unrecognized variable ISOL_{}", name)))
+    }
+}
+
+/// Parse the command line, ARGS (not including the program name).
+fn parse_args(args: &[String]) -> Result<Settings, HLError> {
+    let mut settings = Settings {
+        env: Vec::new(),
+        env_file: None,
+        pid_file: None,
+        limits: Vec::new(),
+        wall: None,
+        forward_signals: vec![Signal::SIGTERM, Signal::SIGINT],
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        seccomp: None,
+        argv: Vec::new(),
+    };
+
+    let mut i = 0;
+    while i < args.len() {
+        let mut parts = args[i].splitn(2, '=');
+        let name = parts.next().unwrap_or("");
+        let value = match parts.next() {
+            Some(v) if is_valid_env_name(name) => v,
+            _ => break
+        };
+        if name.starts_with("ISOL_") {
+            try!(apply_isol_setting(&mut settings, &name[5..], value));
+        } else if forbidden_env_var(name) {
+            return Err(map_config_err(format!(
+                "{} may not be set on the command line", name)));
+        } else {
+            merge_env(&mut settings.env,
+                      &[(String::from(name), String::from(value))]);
+        }
+        i += 1;
+    }
+
+    if i == args.len() {
+        return Err(map_config_err(String::from("no program specified")));
+    }
+    settings.argv = args[i..].to_vec();
+    Ok(settings)
+}
+
+/// Construct the environment for the isolated program: the preserved
+/// variables from our own environment, then ISOL_ENV_FILE, then
+/// VAR=val settings from the command line.
+fn child_environment(settings: &Settings)
+                     -> Result<Vec<(String, String)>, HLError> {
+    let mut child_env = allowlisted_env(PRESERVED_VARS, PRESERVED_PREFIXES,
+                                        &[]);
+
+    if let Some(ref path) = settings.env_file {
+        let vars = try!(read_env_file(Path::new(path)));
+        for &(ref k, _) in &vars {
+            if k.starts_with("ISOL_") || forbidden_env_var(k) {
+                return Err(map_config_err(format!(
+                    "{:?}: {} may not be set", path, k)));
+            }
+        }
+        merge_env(&mut child_env, &vars);
+    }
+    merge_env(&mut child_env, &settings.env);
+    Ok(child_env)
+}
+
+/// Internal: install the ISOL_SECCOMP filter, if any, in the calling
+/// process.  Called from a pre_exec hook, so must not allocate.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn install_seccomp(filter: &Option<SeccompFilter>) -> io::Result<()> {
+    match *filter {
+        Some(ref filter) => filter.install(),
+        None => Ok(())
+    }
+}
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn install_seccomp(_: &Option<()>) -> io::Result<()> {
+    Ok(())
+}
+
+/// Start the isolated program, in its own process group, with the
+/// requested resource limits and system call filter, and with the
+/// real user and group IDs of whoever invoked us.
+fn start_child(settings: &Settings, env: &ChildEnv)
+               -> Result<Child, HLError> {
+    use std::os::unix::process::CommandExt;
+    use libc::{getgid, getuid, setgid, setpgid, setuid};
+
+    let argv: Vec<&str> = settings.argv.iter().map(|s| s.as_str()).collect();
+    let mut cmd = command(&argv, env);
+    let limits = settings.limits.clone();
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let seccomp = settings.seccomp.clone();
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let seccomp: Option<()> = None;
+
+    // The hook must not allocate.  Limits are set while we still have
+    // the privilege to raise them; setgid must come before setuid for
+    // the same reason.  The system call filter goes on last, so that
+    // it needn't allow any of that.
+    unsafe {
+        cmd.pre_exec(move || {
+            if setpgid(0, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            for &(limit, value) in &limits {
+                try!(apply_limit(limit, value));
+            }
+            if setgid(getgid()) != 0 || setuid(getuid()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            install_seccomp(&seccomp)
+        });
+    }
+    cmd.spawn().map_err(|e| map_io_err(e, format!("spawn {}", argv[0])))
+}
+
+/// The signal handling configuration for SETTINGS.  The program runs
+/// in its own process group, so job-control signals aimed at us must
+/// be passed on to it.  SIGHUP, unless it is to be passed on, is
+/// treated as a request to reload, which we have nothing to do for.
+fn signal_config(settings: &Settings) -> SignalConfig {
+    let signals = SignalConfig::new()
+        .notify(Signal::SIGTSTP)
+        .notify(Signal::SIGCONT);
+    if settings.forward_signals.contains(&Signal::SIGHUP) {
+        signals
+    } else {
+        signals.reload(Signal::SIGHUP)
+    }
+}
+
+/// The signal, if any, that should be passed on to the isolated
+/// program's process group when the idle loop reports EV.
+fn signal_to_forward(settings: &Settings, ev: &Event) -> Option<Signal> {
+    match *ev {
+        Event::NotifySignal(sig) => Some(sig),
+        Event::TermSignal(sig) if settings.forward_signals.contains(&sig) =>
+            Some(sig),
+        _ => None
+    }
+}
+
+/// Send SIG to every process in the process group led by PGRP.  It is
+/// not an error if they have all exited already.
+fn kill_group(pgrp: libc::pid_t, sig: Signal) {
+    if let Err(e) = kill_process_group(pgrp, sig) {
+        writeln!(io::stderr(), "isolate: {}", e).unwrap();
+    }
+}
+
+fn inner_main(settings: Settings) -> Result<i32, HLError> {
+    let signals = signal_config(&settings);
+    let (sigfd, child_mask) = try!(signals.prepare());
+    let _pid_file = match settings.pid_file {
+        Some(ref path) => Some(try!(PidFile::create(Path::new(path)))),
+        None => None
+    };
+    let child_env = ChildEnv {
+        env: try!(child_environment(&settings)),
+        mask: child_mask,
+        verbose: false,
+        dryrun: false,
+        unexpected_child: UnexpectedChildPolicy::Ignore,
+        umask: 0o077,
+        recorder: None,
+        tools: HashMap::new(),
+        child_fd_cap: None
+    };
+
+    let child = try!(start_child(&settings, &child_env));
+    let pgrp = child.id() as libc::pid_t;
+    let argv: Vec<&str> = settings.argv.iter().map(|s| s.as_str()).collect();
+
+    let mut idle = match settings.wall {
+        Some(wall) => IdleLoop::with_deadline(sigfd, Instant::now() + wall),
+        None => IdleLoop::new(sigfd)
+    };
+    idle.set_signal_config(&signals);
+    // stdin belongs to the child.
+    idle.ignore_stdin();
+
+    for ev in idle {
+        match ev {
+            Event::Timeout(_) => {
+                writeln!(io::stderr(), "isolate: {}: wall-clock time limit \
+                                        exceeded", argv[0]).unwrap();
+                kill_group(pgrp, Signal::SIGKILL);
+            },
+            Event::TermSignal(_) | Event::NotifySignal(_) | Event::Reload => {
+                if let Some(sig) = signal_to_forward(&settings, &ev) {
+                    kill_group(pgrp, sig);
+                }
+            },
+            Event::ChildExit(pid, status) if pid == pgrp => {
+                // Anything the program left behind in its process
+                // group goes with it.
+                kill_group(pgrp, Signal::SIGKILL);
+                // The idle loop has reaped it; don't wait for it again.
+                drop(child);
+                return match status {
+                    WaitStatus::Exited(_, 0) => Ok(0),
+                    status => Err(HLError::UnsuccessfulChild {
+                        status: describe_wait_status(&status),
+                        cmdline: shell_quote_argv(&argv),
+                        stderr: String::new()
+                    })
+                };
+            },
+            Event::Error(e) => {
+                // Don't leave the program running unsupervised.
+                kill_group(pgrp, Signal::SIGKILL);
+                return Err(e);
+            },
+            // We shouldn't have any other children, but whatever;
+            // the idle loop has already reaped them.
+            Event::ChildExit(_, _) => (),
+            // Never reported, because of ignore_stdin.
+            Event::StdinClosed | Event::StdinLine(_) => (),
+            // No fds were registered, and no status signals configured.
+            Event::FdReady(..) | Event::FdHangup(_) | Event::Status(_) =>
+                unreachable!(),
+        }
+    }
+    unreachable!()
+}
+
 fn main() {
-    unimplemented!()
+    let args: Vec<String> = env::args().skip(1).collect();
+    process::exit(match parse_args(&args).and_then(inner_main) {
+        Ok(code) => code,
+        Err(e) => {
+            writeln!(io::stderr(), "isolate: {}", e).unwrap();
+            1
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|&s| String::from(s)).collect()
+    }
+
+    /// Run the isolate command line ARGS, without any supervision, and
+    /// wait for the program to exit.
+    fn run_isolated(args: &[String]) -> process::ExitStatus {
+        use nix::sys::signal::SigSet;
+
+        let settings = parse_args(args).unwrap();
+        let env = ChildEnv {
+            env: child_environment(&settings).unwrap(),
+            mask: SigSet::empty(),
+            verbose: false,
+            dryrun: false,
+            unexpected_child: UnexpectedChildPolicy::Ignore,
+            umask: 0o077,
+            recorder: None,
+            tools: HashMap::new(),
+            child_fd_cap: None
+        };
+        start_child(&settings, &env).unwrap().wait().unwrap()
+    }
+
+    /// Run the shell command SCRIPT under isolate, with the settings
+    /// SETTINGS placed before it on the command line, and return what
+    /// it wrote to stdout.  NAME distinguishes the output file from
+    /// those of other tests running at the same time.
+    fn output_of(name: &str, settings: &[&str], script: &str) -> String {
+        use std::fs;
+
+        let out = env::temp_dir().join(format!(
+            "isolate-{}-{}", name, nix::unistd::getpid()));
+        let script = format!("exec >\"$1\"; {}", script);
+        let mut args = strings(settings);
+        args.extend(strings(&["sh", "-c", &script, "sh",
+                              out.to_str().unwrap()]));
+        let status = run_isolated(&args);
+        let output = fs::read_to_string(&out).unwrap();
+        fs::remove_file(&out).unwrap();
+        assert!(status.success(), "{}: {}", script, status);
+        output
+    }
+
+    #[test]
+    fn only_chosen_signals_are_forwarded() {
+        use nix::sys::signal::Signal::*;
+
+        let settings = parse_args(&strings(&["true"])).unwrap();
+        assert!(signal_config(&settings).is_reload(SIGHUP));
+        assert_eq!(signal_to_forward(&settings, &Event::TermSignal(SIGTERM)),
+                   Some(SIGTERM));
+        assert_eq!(signal_to_forward(&settings, &Event::TermSignal(SIGINT)),
+                   Some(SIGINT));
+        assert_eq!(signal_to_forward(&settings, &Event::Reload), None);
+        assert_eq!(signal_to_forward(&settings, &Event::TermSignal(SIGUSR1)),
+                   None);
+        assert_eq!(signal_to_forward(&settings,
+                                     &Event::NotifySignal(SIGTSTP)),
+                   Some(SIGTSTP));
+
+        let settings = parse_args(&strings(&["ISOL_FORWARD_SIGNALS=HUP,\
+                                              SIGUSR1", "true"])).unwrap();
+        assert!(!signal_config(&settings).is_reload(SIGHUP));
+        assert_eq!(signal_to_forward(&settings, &Event::TermSignal(SIGHUP)),
+                   Some(SIGHUP));
+        assert_eq!(signal_to_forward(&settings, &Event::TermSignal(SIGTERM)),
+                   None);
+
+        assert!(parse_args(&strings(&["ISOL_FORWARD_SIGNALS=KILL", "true"]))
+                .is_err());
+    }
+
+    #[test]
+    fn seccomp_setting_is_parsed() {
+        assert!(parse_args(&strings(&["ISOL_SECCOMP=bogus", "true"]))
+                .is_err());
+        let settings = parse_args(&strings(&["ISOL_SECCOMP=strict", "true"]))
+            .unwrap();
+        assert!(settings.seccomp.is_some());
+        assert_eq!(settings.argv, strings(&["true"]));
+    }
+
+    /// The strict filter doesn't even allow mmap, so any dynamically
+    /// linked program is killed with SIGSYS as soon as it starts.
+    #[cfg(all(target_os = "linux",
+              any(target_arch = "x86_64", target_arch = "aarch64")))]
+    #[test]
+    fn strict_seccomp_filter_kills_the_child() {
+        use std::os::unix::process::ExitStatusExt;
+        use nix::sys::signal::SigSet;
+
+        let settings = parse_args(&strings(&["ISOL_SECCOMP=strict", "true"]))
+            .unwrap();
+        let env = ChildEnv {
+            env: vec![(String::from("PATH"), String::from(SAFE_PATH))],
+            mask: SigSet::empty(),
+            verbose: false,
+            dryrun: false,
+            unexpected_child: UnexpectedChildPolicy::Ignore,
+            umask: 0o077,
+            recorder: None,
+            tools: HashMap::new(),
+            child_fd_cap: None
+        };
+        let status = start_child(&settings, &env).unwrap().wait().unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGSYS));
+
+        // Without the filter, it runs normally.
+        let settings = parse_args(&strings(&["true"])).unwrap();
+        let status = start_child(&settings, &env).unwrap().wait().unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn command_line_settings_override_the_env_file() {
+        use std::fs;
+
+        let file = env::temp_dir().join(format!(
+            "isolate-env-file-{}", nix::unistd::getpid()));
+        fs::write(&file, "ISOLATE_TEST_A=from file\n\
+                          ISOLATE_TEST_B=from file\n\
+                          ISOLATE_TEST_C=from file\n").unwrap();
+        // Our own environment isn't passed down at all, so it can't
+        // override the file either.
+        env::set_var("ISOLATE_TEST_C", "from environment");
+        let setting = format!("ISOL_ENV_FILE={}", file.to_str().unwrap());
+        let output = output_of(
+            "env-file", &[&setting, "ISOLATE_TEST_B=from command line"],
+            "echo \"$ISOLATE_TEST_A|$ISOLATE_TEST_B|$ISOLATE_TEST_C\"");
+        fs::remove_file(&file).unwrap();
+        assert_eq!(output, "from file|from command line|from file\n");
+    }
+
+    #[test]
+    fn resource_limits_apply_to_the_isolated_program() {
+        // This goes through apply_limit in the pre_exec hook.  With so
+        // few descriptors, some shells can't redirect output, so the
+        // limit comes back as the exit status.
+        let status = run_isolated(&strings(&[
+            "ISOL_RL_NOFILE=8", "sh", "-c", "exit \"$(ulimit -n)\""]));
+        assert_eq!(status.code(), Some(8));
+    }
 }
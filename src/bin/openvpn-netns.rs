@@ -13,7 +13,8 @@
  * use for communication.  NAMESPACE must already exist.  (The program
  * 'tunnel-ns' sets up namespaces appropriately.)  CONFIG-FILE is an
  * OpenVPN configuration file, and any ARGS will be appended to the
- * OpenVPN command line.
+ * OpenVPN command line.  (If the first of ARGS begins with a dash,
+ * put "--" in front of it.)
  *
  * This program expects to be run with both stdin and stdout connected
  * to pipes.  When it detects that the namespace is ready for use, it
@@ -23,21 +24,1104 @@
  * it will terminate the OpenVPN client, tear down the network
  * namespace (and terminate all processes still in there), and exit.
  *
+ * CONFIG-FILE is read with the permissions of whoever invoked this
+ * program, and OpenVPN is given a copy of what was read, not the file
+ * itself.  Because OpenVPN runs as root, options that would run
+ * programs, load plugins, open a management interface, change
+ * directory, read further configuration files, or write files
+ * (--up, --plugin, --management, --config, --cd, --log, --writepid,
+ * --setenv and the like) are refused, whether they appear in
+ * CONFIG-FILE or in ARGS.
+ *
  * Error messages, and any output from the OpenVPN client, will be
  * written to stderr.  One may wish to include "--verb 0" in ARGS to
  * make the client less chatty.
  *
+ * By default, the OpenVPN client is found by looking for a program
+ * named "openvpn" in a standard set of "bin" directories.  The
+ * --openvpn-binary option overrides this with the absolute path of a
+ * specific executable (which may be a wrapper script).  When this
+ * program is running setuid, that executable, and every directory
+ * leading to it, must belong to root and not be writable by anyone
+ * else, since OpenVPN is run as root.
+ *
+ * OpenVPN is told to run this program as its "up" script (with the
+ * internal-only option --as-up-script).  That moves the tunnel device
+ * into NAMESPACE and configures its addresses and routes there, using
+ * the parameters OpenVPN passes down.  The namespace is considered
+ * ready once it has a default route.  If OpenVPN exits before then,
+ * or the default route has not appeared after --ready-timeout seconds
+ * (default 60), the tunnel is shut down and this program exits
+ * unsuccessfully.  When OpenVPN exits, the kernel removes the tunnel
+ * device, and everything configured on it, automatically.
+ *
+ * So that nobody else can use --as-up-script to reconfigure network
+ * devices, each run makes up a random token, which it stores where
+ * only root can read it (in /run/openvpn-netns) and also passes to
+ * the up script through OpenVPN (with --setenv, in the copy of the
+ * configuration).  The up script does nothing unless the two match.
+ *
+ * With --ready-probe CMD, the namespace is additionally not considered
+ * ready until CMD (for instance, a curl invocation that fetches
+ * something through the tunnel) succeeds.  CMD is split on whitespace;
+ * it is not passed to a shell.  It is run inside NAMESPACE, with the
+ * real user and group IDs of whoever invoked this program, every
+ * --ready-interval milliseconds (default 1000) until it exits
+ * successfully, subject to the same --ready-timeout.
+ *
+ * With --pid-file PATH, this program's process ID is written to PATH,
+ * which is removed again when it exits.  If PATH already names a
+ * running process, this program refuses to start.
+ *
+ * With --reload-on-hup, SIGHUP (once the tunnel is ready) does not
+ * shut everything down; instead, the OpenVPN client is sent SIGUSR1,
+ * which makes it reconnect without exiting (a "soft restart").
+ *
  * This program must be installed setuid root.
  *
  * This program makes extensive use of Linux-specific network stack
  * features.  A port to a different OS might well entail a complete
- * rewrite.  Apart from that, C99 and POSIX.1-2001 features are used
- * throughout.  It also requires dirfd, strdup, and strsignal, from
- * POSIX.1-2008; execvpe, pipe2, and vasprintf, from the shared
- * BSD/GNU extension set; and the currently Linux-specific signalfd
- * and getauxval.
+ * rewrite.
  */
 
+use std::env;
+use std::fs;
+use std::io;
+use std::process;
+
+use std::ascii::AsciiExt;
+use std::ffi::OsString;
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::process::Child;
+use std::time::{Duration, Instant};
+
+extern crate libc;
+extern crate nix;
+#[macro_use] extern crate clap;
+
+extern crate openvpn_netns_tools;
+use openvpn_netns_tools::*;
+
+use nix::sys::signal::{kill, Signal};
+
+/// Data parsed from the command line.
+struct Args {
+    namespace: String,
+    config_text: Vec<u8>,
+    openvpn_args: Vec<String>,
+    openvpn_binary: String,
+    ready_probe: Option<Vec<String>>,
+    ready_interval: u64,
+    ready_timeout: u64,
+    reload_on_hup: bool,
+    pid_file: Option<String>,
+    verbose: bool,
+}
+
+/// Internal: true if PATH names a regular file that someone is
+/// allowed to execute.
+fn is_executable_file(path: &Path) -> bool {
+    match fs::metadata(path) {
+        Ok(m) => m.is_file() && m.permissions().mode() & 0o111 != 0,
+        Err(_) => false
+    }
+}
+
+/// Internal: true if PATH, and every directory above it, belongs to
+/// root and is not writable by group or others, so that only root
+/// can have put it there or changed it.  Symbolic links are resolved
+/// first.
+fn is_root_controlled(path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    let path = match fs::canonicalize(path) {
+        Ok(p) => p,
+        Err(_) => return false
+    };
+    path.ancestors().all(|p| match fs::symlink_metadata(p) {
+        Ok(m) => m.uid() == 0 && m.mode() & 0o022 == 0,
+        Err(_) => false
+    })
+}
+
+/// True if NAME is acceptable as a namespace name: nonempty, and
+/// consisting only of ASCII letters, numbers, and underscores.
+fn is_valid_namespace_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(
+        |c| c.is_ascii() && (c.is_alphanumeric() || c == '_'))
+}
+
+/// OpenVPN options that would let whoever invokes us run programs,
+/// load code, talk to OpenVPN while it runs, or read or write files,
+/// all as root, or that would undo settings we depend on.  Any option
+/// beginning with "management" is also forbidden.
+const FORBIDDEN_OPENVPN_OPTIONS: &'static [&'static str] = &[
+    "auth-user-pass-verify", "cd", "chroot", "client-connect",
+    "client-disconnect", "config", "daemon", "down", "ipchange",
+    "iproute", "learn-address", "log", "log-append", "plugin",
+    "route-pre-down", "route-up", "script-security", "setenv",
+    "setenv-safe", "status", "tls-crypt-v2-verify", "tls-export-cert",
+    "tls-verify", "up", "writepid",
+];
+
+/// The file descriptor on which OpenVPN reads its configuration.
+const CONFIG_FD: RawFd = 3;
+
+/// Internal: fail if OPTION (without its leading dashes) is one of
+/// the forbidden ones.  LOCATION says where it was found.
+fn check_openvpn_option(option: &str, location: &str) -> Result<(), String> {
+    if option.starts_with("management")
+        || FORBIDDEN_OPENVPN_OPTIONS.contains(&option) {
+        Err(format!("{}: OpenVPN option --{} is not allowed",
+                    location, option))
+    } else {
+        Ok(())
+    }
+}
+
+/// Check that ARGS, the additional arguments for OpenVPN, don't
+/// include any forbidden options.
+fn check_openvpn_args(args: &[String]) -> Result<(), String> {
+    for arg in args {
+        if arg.starts_with("--") {
+            try!(check_openvpn_option(&arg[2..], "additional arguments"));
+        }
+    }
+    Ok(())
+}
+
+/// Check that TEXT, the contents of the OpenVPN configuration file
+/// PATH, doesn't use any forbidden options.  Inline files (<ca> and so
+/// on) are skipped, but <connection> blocks hold ordinary options, so
+/// they are checked.  OpenVPN lets options be quoted or contain
+/// backslash escapes, so those are removed before the check.
+fn check_openvpn_config(text: &str, path: &Path) -> Result<(), String> {
+    let mut inline_end: Option<String> = None;
+    for (lineno, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if let Some(end) = inline_end.take() {
+            if line != end {
+                inline_end = Some(end);
+            }
+            continue;
+        }
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if line.starts_with('<') && line.ends_with('>') {
+            let tag = &line[1..line.len() - 1];
+            if !tag.starts_with('/') && tag != "connection" {
+                inline_end = Some(format!("</{}>", tag));
+            }
+            continue;
+        }
+        let option: String = line.split_whitespace().next().unwrap_or("")
+            .chars().filter(|&c| c != '"' && c != '\'' && c != '\\')
+            .collect();
+        let option = if option.starts_with("--") {
+            &option[2..]
+        } else {
+            &option[..]
+        };
+        try!(check_openvpn_option(option, &format!("{}:{}", path.display(),
+                                                   lineno + 1)));
+    }
+    Ok(())
+}
+
+/// Construct the command line for the OpenVPN client.  UP_SCRIPT is
+/// the command OpenVPN should run once the tunnel device exists.
+/// The configuration is read from CONFIG_FD.
+fn openvpn_argv(args: &Args, up_script: &str) -> Vec<OsString> {
+    let config = format!("/dev/fd/{}", CONFIG_FD);
+    let mut argv: Vec<OsString> =
+        [args.openvpn_binary.as_str(), "--config", config.as_str()].iter()
+        .map(OsString::from).collect();
+    argv.extend(["--ifconfig-noexec", "--route-noexec",
+                 "--script-security", "2", "--up", up_script].iter()
+                .map(OsString::from));
+    argv.extend(args.openvpn_args.iter().map(OsString::from));
+    argv
+}
+
+/// Parse the command line.
+fn parse_cmdline() -> Args {
+    use clap::{App,Arg,Error};
+    use clap::ErrorKind::ValueValidation;
+
+    let matches = App::new("openvpn-netns")
+        .arg(Arg::with_name("namespace")
+             .help("Namespace that will use the tunnel.")
+             .index(1)
+             .required(true)
+             .empty_values(false))
+        .arg(Arg::with_name("config")
+             .help("OpenVPN configuration file.")
+             .index(2)
+             .required(true)
+             .empty_values(false))
+        .arg(Arg::with_name("openvpn_args")
+             .help("Additional arguments for OpenVPN.")
+             .index(3)
+             .multiple(true))
+        .arg(Arg::with_name("openvpn_binary")
+             .help("Absolute path of the OpenVPN client to use.")
+             .long("openvpn-binary")
+             .value_name("PATH")
+             .takes_value(true)
+             .empty_values(false))
+        .arg(Arg::with_name("ready_probe")
+             .help("Command whose success means the tunnel is ready.")
+             .long("ready-probe")
+             .value_name("CMD")
+             .takes_value(true)
+             .empty_values(false))
+        .arg(Arg::with_name("ready_interval")
+             .help("Milliseconds between runs of the readiness probe.")
+             .long("ready-interval")
+             .value_name("MILLIS")
+             .takes_value(true)
+             .requires("ready_probe"))
+        .arg(Arg::with_name("ready_timeout")
+             .help("Seconds to wait for the tunnel to become ready.")
+             .long("ready-timeout")
+             .value_name("SECONDS")
+             .takes_value(true))
+        .arg(Arg::with_name("reload_on_hup")
+             .help("On SIGHUP, soft-restart OpenVPN instead of exiting.")
+             .long("reload-on-hup"))
+        .arg(Arg::with_name("pid_file")
+             .help("Write this program's process ID to PATH.")
+             .long("pid-file")
+             .value_name("PATH")
+             .takes_value(true)
+             .empty_values(false))
+        .arg(Arg::with_name("verbose")
+             .help("Report all actions as they are executed.")
+             .short("v")
+             .long("verbose"))
+        .get_matches();
+
+    // These unwraps are safe because the values are marked 'required'.
+    let namespace = matches.value_of("namespace").unwrap();
+    let config = matches.value_of_os("config").unwrap();
+
+    if !is_valid_namespace_name(namespace) {
+        Error::with_description(
+            &format!("invalid namespace name: {:?}", namespace),
+            ValueValidation).exit();
+    }
+
+    let mut config_text = Vec::new();
+    if let Err(e) = open_as_real_user(Path::new(config))
+        .and_then(|mut f| f.read_to_end(&mut config_text)) {
+        Error::with_description(&format!("{:?}: {}", config, e),
+                                ValueValidation).exit();
+    }
+    if let Err(e) = check_openvpn_config(
+        &String::from_utf8_lossy(&config_text), Path::new(config)) {
+        Error::with_description(&e, ValueValidation).exit();
+    }
+
+    let openvpn_args: Vec<String> = matches.values_of("openvpn_args")
+        .map(|v| v.map(String::from).collect())
+        .unwrap_or_else(Vec::new);
+    if let Err(e) = check_openvpn_args(&openvpn_args) {
+        Error::with_description(&e, ValueValidation).exit();
+    }
+
+    let openvpn_binary = match matches.value_of("openvpn_binary") {
+        None => "openvpn",
+        Some(path) => {
+            if !Path::new(path).is_absolute() || !is_executable_file(
+                Path::new(path)) {
+                Error::with_description(
+                    &format!("--openvpn-binary: {:?} is not an absolute \
+                              path to an executable file", path),
+                    ValueValidation).exit();
+            }
+            if nix::unistd::getuid() != nix::unistd::geteuid()
+                && !is_root_controlled(Path::new(path)) {
+                Error::with_description(
+                    &format!("--openvpn-binary: {:?} must belong to root, \
+                              in directories that belong to root, and \
+                              not be writable by anyone else, when \
+                              running setuid", path),
+                    ValueValidation).exit();
+            }
+            path
+        }
+    };
+
+    let ready_probe: Option<Vec<String>> = matches.value_of("ready_probe")
+        .map(|cmd| cmd.split_whitespace().map(String::from).collect());
+    if let Some(ref probe) = ready_probe {
+        if probe.is_empty() {
+            Error::with_description("--ready-probe: empty command",
+                                    ValueValidation).exit();
+        }
+    }
+
+    let ready_interval = if matches.is_present("ready_interval") {
+        value_t!(matches, "ready_interval", u64)
+            .unwrap_or_else(|e| e.exit())
+    } else {
+        1000
+    };
+    let ready_timeout = if matches.is_present("ready_timeout") {
+        value_t!(matches, "ready_timeout", u64)
+            .unwrap_or_else(|e| e.exit())
+    } else {
+        60
+    };
+    if ready_interval == 0 {
+        Error::with_description("--ready-interval must be positive",
+                                ValueValidation).exit();
+    }
+
+    Args {
+        namespace: String::from(namespace),
+        config_text: config_text,
+        openvpn_args: openvpn_args,
+        openvpn_binary: String::from(openvpn_binary),
+        ready_probe: ready_probe,
+        ready_interval: ready_interval,
+        ready_timeout: ready_timeout,
+        reload_on_hup: matches.is_present("reload_on_hup"),
+        pid_file: matches.value_of("pid_file").map(String::from),
+        verbose: matches.is_present("verbose"),
+    }
+}
+
+/// The programs to look up in the standard "bin" directories.  With
+/// --openvpn-binary, there is no need to look for "openvpn", and not
+/// finding it mustn't be an error.
+fn tools_needed(args: &Args) -> &'static [&'static str] {
+    if args.openvpn_binary == "openvpn" {
+        &["ip", "openvpn"]
+    } else {
+        &["ip"]
+    }
+}
+
+/// Environment variables passed down to child processes; as in
+/// tunnel-ns, everything else is discarded, and PATH is fixed.
+fn prepare_child_env() -> Vec<(String, String)> {
+    allowlisted_env(&["TERM", "TZ", "LANG"], &["LC_"], &[("PATH", SAFE_PATH)])
+}
+
+/// Internal: the value of the environment variable VAR, which OpenVPN
+/// should have set for its up script.
+fn must_getenv(var: &str) -> Result<String, HLError> {
+    env::var(var).map_err(|_| map_config_err(format!(
+        "{} not set in the environment (this mode is for use only as \
+         an OpenVPN up script)", var)))
+}
+
+/// Where each run keeps the token that authenticates its up script,
+/// in a file named after the namespace.
+const TOKEN_DIR: &'static str = "/run/openvpn-netns";
+
+/// The variable in which OpenVPN passes the token to the up script.
+const TOKEN_VAR: &'static str = "openvpn_netns_token";
+
+/// RAII class for the token that authenticates our up script.  The
+/// file holding it is removed when this is dropped.
+struct UpScriptToken {
+    path: PathBuf,
+    token: String
+}
+impl UpScriptToken {
+    /// Make up a new token for NAMESPACE and store it in TOKEN_DIR,
+    /// replacing any left over from a previous run.
+    fn create(namespace: &str) -> Result<UpScriptToken, HLError> {
+        use std::fs::{DirBuilder, OpenOptions};
+        use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
+        use libc::{geteuid, O_NOFOLLOW};
+
+        let mut raw = [0u8; 16];
+        try!(fs::File::open("/dev/urandom")
+             .and_then(|mut f| f.read_exact(&mut raw))
+             .map_err(|e| map_io_err(e, String::from("/dev/urandom"))));
+        let token: String = raw.iter().map(|b| format!("{:02x}", b))
+            .collect();
+
+        let dir = Path::new(TOKEN_DIR);
+        if let Err(e) = DirBuilder::new().mode(0o700).create(dir) {
+            if e.kind() != io::ErrorKind::AlreadyExists {
+                return Err(map_io_err(e, format!("{:?}", dir)));
+            }
+        }
+        // If it was already there, it had better still be private.
+        let meta = try!(fs::symlink_metadata(dir)
+                        .map_err(|e| map_io_err(e, format!("{:?}", dir))));
+        if !meta.is_dir() || meta.uid() != unsafe { geteuid() }
+            || meta.mode() & 0o077 != 0 {
+            return Err(map_config_err(format!(
+                "{:?} is not a directory that only root can use", dir)));
+        }
+
+        let path = dir.join(namespace);
+        if let Err(e) = fs::remove_file(&path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(map_io_err(e, format!("{:?}", path)));
+            }
+        }
+        try!(OpenOptions::new().write(true).create_new(true).mode(0o600)
+             .custom_flags(O_NOFOLLOW).open(&path)
+             .and_then(|mut f| f.write_all(token.as_bytes()))
+             .map_err(|e| map_io_err(e, format!("{:?}", path))));
+        Ok(UpScriptToken { path: path, token: token })
+    }
+}
+impl Drop for UpScriptToken {
+    fn drop (&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            writeln!(io::stderr(), "warning: could not delete {:?}: {}",
+                     &self.path, e).unwrap();
+        }
+    }
+}
+
+/// Internal: fail unless OpenVPN passed the up script the token that
+/// is stored for NAMESPACE.
+fn check_up_script_token(namespace: &str) -> Result<(), HLError> {
+    let given = try!(must_getenv(TOKEN_VAR));
+    let mut stored = String::new();
+    let _ = fs::File::open(Path::new(TOKEN_DIR).join(namespace))
+        .and_then(|mut f| f.read_to_string(&mut stored));
+    // Compare every byte, so the time taken says nothing about how
+    // much of the token was right.
+    if stored.is_empty() || stored.len() != given.len()
+        || stored.bytes().zip(given.bytes())
+           .fold(0, |acc, (a, b)| acc | (a ^ b)) != 0 {
+        return Err(map_config_err(format!(
+            "{} does not match (this mode is for use only as the up \
+             script of an OpenVPN started by openvpn-netns)", TOKEN_VAR)));
+    }
+    Ok(())
+}
+
+/// Internal: convert a dotted-quad netmask to a prefix length.
+fn mask2cidr(netmask: &str) -> Result<u32, HLError> {
+    use std::net::Ipv4Addr;
+
+    let bad = || map_config_err(format!("invalid netmask {:?}", netmask));
+    let mask = u32::from(try!(netmask.parse::<Ipv4Addr>()
+                              .map_err(|_| bad())));
+    // A valid netmask is some number of 1 bits followed by all 0 bits.
+    let host = !mask;
+    if host & host.wrapping_add(1) != 0 {
+        return Err(bad());
+    }
+    Ok(mask.count_ones())
+}
+
+/// Internal-only mode: OpenVPN runs this program as its up script, as
+/// "openvpn-netns --as-up-script NAMESPACE [openvpn's own args...]",
+/// with the tunnel parameters in environment variables.  Move the
+/// tunnel device into NAMESPACE and configure it there.  The default
+/// route is added last, because the controller takes its appearance
+/// to mean that the namespace is ready.
+fn do_up_script(namespace: &str) -> Result<(), HLError> {
+    use nix::sys::signal::SigSet;
+
+    if !is_valid_namespace_name(namespace) {
+        return Err(map_config_err(format!("invalid namespace name: {:?}",
+                                          namespace)));
+    }
+    try!(check_up_script_token(namespace));
+    // Check everything we are going to need before doing anything.
+    let dev = try!(must_getenv("dev"));
+    let mtu = try!(must_getenv("tun_mtu"));
+    let local = try!(must_getenv("ifconfig_local"));
+    let gateway = try!(must_getenv("route_vpn_gateway"));
+
+    let vars = prepare_child_env();
+    let env = ChildEnv {
+        tools: try!(resolve_tools(&vars, &["ip"])),
+        env: vars,
+        mask: SigSet::empty(),
+        verbose: false,
+        dryrun: false,
+        unexpected_child: UnexpectedChildPolicy::Ignore,
+        umask: DEFAULT_UMASK,
+        recorder: None,
+        child_fd_cap: None
+    };
+
+    try!(run(&["ip", "link", "set", "dev", &dev, "netns", namespace], &env));
+
+    match env::var("ifconfig_netmask") {
+        Ok(netmask) => {
+            let local = format!("{}/{}", local, try!(mask2cidr(&netmask)));
+            match env::var("ifconfig_broadcast") {
+                Ok(bcast) => try!(run_in_netns(
+                    namespace, &["ip", "addr", "add", "dev", &dev,
+                                 "local", &local, "broadcast", &bcast],
+                    &env)),
+                Err(_) => try!(run_in_netns(
+                    namespace, &["ip", "addr", "add", "dev", &dev,
+                                 "local", &local],
+                    &env)),
+            }
+        },
+        Err(_) => {
+            // Without a netmask, this is a point-to-point link, and
+            // there had better be a remote address.
+            let remote = try!(must_getenv("ifconfig_remote"));
+            try!(run_in_netns(namespace,
+                              &["ip", "addr", "add", "dev", &dev,
+                                "local", &local, "peer", &remote],
+                              &env));
+        }
+    }
+    try!(run_in_netns(namespace,
+                      &["ip", "link", "set", "dev", &dev, "mtu", &mtu, "up"],
+                      &env));
+
+    if let (Ok(local6), Ok(bits6)) = (env::var("ifconfig_ipv6_local"),
+                                      env::var("ifconfig_ipv6_netbits")) {
+        let local6 = format!("{}/{}", local6, bits6);
+        try!(run_in_netns(namespace,
+                          &["ip", "addr", "add", "dev", &dev,
+                            "local", &local6],
+                          &env));
+        for i in 0.. {
+            let (network, gateway) =
+                match (env::var(format!("route_ipv6_network_{}", i)),
+                       env::var(format!("route_ipv6_gateway_{}", i))) {
+                    (Ok(n), Ok(g)) => (n, g),
+                    _ => break
+                };
+            try!(run_in_netns(namespace,
+                              &["ip", "route", "add", &network,
+                                "via", &gateway, "dev", &dev],
+                              &env));
+        }
+    }
+
+    for i in 0.. {
+        let (network, netmask, gateway) =
+            match (env::var(format!("route_network_{}", i)),
+                   env::var(format!("route_netmask_{}", i)),
+                   env::var(format!("route_gateway_{}", i))) {
+                (Ok(n), Ok(m), Ok(g)) => (n, m, g),
+                _ => break
+            };
+        let network = format!("{}/{}", network, try!(mask2cidr(&netmask)));
+        try!(run_in_netns(namespace,
+                          &["ip", "route", "add", &network,
+                            "via", &gateway, "dev", &dev],
+                          &env));
+    }
+    run_in_netns(namespace,
+                 &["ip", "route", "add", "default",
+                   "via", &gateway, "dev", &dev],
+                 &env)
+}
+
+/// Start the OpenVPN client for ARGS.namespace.  Its stdout is copied
+/// to our stderr, since our stdout is reserved for "READY".  It reads
+/// its configuration from a pipe, which a helper thread fills with
+/// ARGS.config_text, so that it sees exactly what was checked, plus a
+/// setting that passes TOKEN on to the up script.
+fn start_tunnel(args: &Args, token: &UpScriptToken, env: &ChildEnv)
+                -> Result<Child, HLError> {
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::thread;
+    use nix::fcntl::O_CLOEXEC;
+    use nix::unistd::pipe2;
+
+    let self_exe = try!(env::current_exe()
+                        .map_err(|e| map_io_err(e, String::from(
+                            "locating this program"))));
+    let self_exe = try!(self_exe.to_str().ok_or_else(|| map_config_err(
+        format!("{:?}: program path is not valid UTF-8", self_exe))))
+        .to_owned();
+    let up_script = format!("{} --as-up-script {}",
+                            shell_quote(&self_exe), args.namespace);
+
+    let (rd, wr) = try!(pipe2(O_CLOEXEC)
+                        .map_err(|e| map_nix_err(e, String::from("pipe"))));
+    let config_rd = unsafe { fs::File::from_raw_fd(rd) };
+    let mut config_wr = unsafe { fs::File::from_raw_fd(wr) };
+    let mut config_text = args.config_text.clone();
+    config_text.extend_from_slice(format!("\nsetenv {} {}\n", TOKEN_VAR,
+                                          token.token).as_bytes());
+    // If OpenVPN never reads it all, the write fails once our end of
+    // the pipe is closed, below, and the thread exits.
+    thread::spawn(move || { let _ = config_wr.write_all(&config_text); });
+
+    let spec = CommandSpec::from_argv(&openvpn_argv(args, &up_script))
+        .pass_fd(config_rd.as_raw_fd(), CONFIG_FD);
+    let (openvpn, _pump) = try!(spawn_spec_stdout_to_stderr(
+        &spec, env, |line| format!("openvpn: {}", line)));
+    Ok(openvpn)
+}
+
+/// Internal: if OPENVPN has exited, return an error saying so.
+fn check_still_running(openvpn: &mut Child) -> Result<(), HLError> {
+    match openvpn.try_wait() {
+        Ok(None) => Ok(()),
+        Ok(Some(status)) => Err(HLError::UnsuccessfulChild {
+            status: match status.code() {
+                Some(n) => format!("exited before the tunnel was ready \
+                                    (code {})", n),
+                None => String::from("was killed before the tunnel was \
+                                      ready")
+            },
+            cmdline: String::from("openvpn"),
+            stderr: String::new()
+        }),
+        Err(e) => Err(map_io_err(e, String::from("wait for openvpn")))
+    }
+}
+
+/// Wait for the default route to appear in the namespace, which
+/// do_up_script adds last.  Returns true when it does, false if a
+/// termination signal arrives on SIGFD first, or an error if OPENVPN
+/// exits or DEADLINE passes.
+fn wait_for_tunnel(args: &Args, openvpn: &mut Child, deadline: Instant,
+                   sigfd: RawFd, env: &ChildEnv) -> Result<bool, HLError> {
+    loop {
+        try!(check_still_running(openvpn));
+        // Failure here probably means the namespace is missing, which
+        // won't fix itself, so give up.
+        let routes = try!(run_in_netns_get_json(
+            &args.namespace, &["ip", "route", "show", "default"], env));
+        if routes.as_array().map_or(false, |r| !r.is_empty()) {
+            return Ok(true);
+        }
+        if Instant::now() >= deadline {
+            return Err(map_config_err(format!(
+                "tunnel for {} not ready after {} seconds",
+                args.namespace, args.ready_timeout)));
+        }
+        if let SleepOutcome::Interrupted(sig) =
+            interruptible_sleep(Duration::from_millis(250), sigfd) {
+            if args.verbose {
+                writeln!(io::stderr(), "# {:?} while waiting for the tunnel",
+                         sig).unwrap();
+            }
+            return Ok(false);
+        }
+    }
+}
+
+/// Call RUN_PROBE, which runs the readiness probe PROBE, every
+/// ARGS.ready_interval milliseconds until it succeeds (returns true),
+/// a termination signal arrives on SIGFD (returns false), or DEADLINE
+/// passes (returns an error).
+fn wait_for_probe<F>(args: &Args, probe: &[&str], deadline: Instant,
+                     sigfd: RawFd, mut run_probe: F)
+                     -> Result<bool, HLError>
+    where F: FnMut() -> Result<bool, HLError>
+{
+    let interval = Duration::from_millis(args.ready_interval);
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        if try!(run_probe()) {
+            return Ok(true);
+        }
+        if args.verbose {
+            writeln!(io::stderr(), "# readiness probe failed (attempt {})",
+                     attempts).unwrap();
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(HLError::UnsuccessfulChild {
+                status: format!("did not succeed within {} seconds \
+                                 ({} attempts)", args.ready_timeout,
+                                attempts),
+                cmdline: shell_quote_argv(probe),
+                stderr: String::new()
+            });
+        }
+        let nap = if deadline - now < interval {
+            deadline - now
+        } else {
+            interval
+        };
+        if let SleepOutcome::Interrupted(sig) =
+            interruptible_sleep(nap, sigfd) {
+            if args.verbose {
+                writeln!(io::stderr(), "# {:?} while waiting for readiness",
+                         sig).unwrap();
+            }
+            return Ok(false);
+        }
+    }
+}
+
+/// Run PROBE inside the namespace (whose handle is in RUN_DIR,
+/// normally NETNS_RUN_DIR) until it succeeds, as wait_for_probe does.
+fn probe_in_netns(args: &Args, probe: &[String], run_dir: &Path,
+                  deadline: Instant, sigfd: RawFd, env: &ChildEnv)
+                  -> Result<bool, HLError> {
+    use nix::unistd::close;
+
+    let probe: Vec<&str> = probe.iter().map(|s| s.as_str()).collect();
+    let netns_fd = try!(open_netns(&args.namespace, run_dir));
+    let result = wait_for_probe(args, &probe, deadline, sigfd, || {
+        run_status_in_netns_as_invoker(netns_fd, &probe, env)
+    });
+    let _ = close(netns_fd);
+    result
+}
+
+/// Ask the OpenVPN client to shut down, and wait for it to do so.
+/// The kernel removes the tunnel device when it exits.
+fn stop_tunnel(mut openvpn: Child, verbose: bool) {
+    use nix::sys::signal::SIGTERM;
+
+    // If it has already been reaped, its process ID may belong to
+    // something else by now.
+    if let Ok(Some(_)) = openvpn.try_wait() {
+        return;
+    }
+    if verbose {
+        writeln!(io::stderr(), "# stopping openvpn (pid {})",
+                 openvpn.id()).unwrap();
+    }
+    if let Err(e) = kill(openvpn.id() as libc::pid_t, SIGTERM) {
+        writeln!(io::stderr(), "kill openvpn: {}", e).unwrap();
+    }
+    if let Err(e) = openvpn.wait() {
+        writeln!(io::stderr(), "wait for openvpn: {}", e).unwrap();
+    }
+}
+
+fn inner_main(args: Args) -> Result<i32, HLError> {
+    let signals = if args.reload_on_hup {
+        SignalConfig::new().reload(Signal::SIGHUP)
+    } else {
+        SignalConfig::new()
+    };
+    let (sigfd, child_mask) = try!(signals.prepare());
+    let _pid_file = match args.pid_file {
+        Some(ref path) => Some(try!(PidFile::create(Path::new(path)))),
+        None => None
+    };
+    let vars = prepare_child_env();
+    let child_env = ChildEnv {
+        tools: try!(resolve_tools(&vars, tools_needed(&args))),
+        env: vars,
+        mask: child_mask,
+        verbose: args.verbose,
+        dryrun: false,
+        unexpected_child: UnexpectedChildPolicy::Warn,
+        umask: DEFAULT_UMASK,
+        recorder: None,
+        child_fd_cap: None
+    };
+
+    let token = try!(UpScriptToken::create(&args.namespace));
+    let mut openvpn = try!(start_tunnel(&args, &token, &child_env));
+    let deadline = Instant::now() + Duration::from_secs(args.ready_timeout);
+    let ready = wait_for_tunnel(&args, &mut openvpn, deadline, sigfd,
+                                &child_env)
+        .and_then(|up| match args.ready_probe {
+            Some(ref probe) if up =>
+                probe_in_netns(&args, probe, Path::new(NETNS_RUN_DIR),
+                               deadline, sigfd, &child_env),
+            _ => Ok(up)
+        })
+        // The probe may have slept through openvpn's SIGCHLD.
+        .and_then(|up| check_still_running(&mut openvpn).map(|_| up));
+    match ready {
+        Ok(true) => (),
+        Ok(false) => {
+            stop_tunnel(openvpn, args.verbose);
+            return Ok(0);
+        },
+        Err(e) => {
+            stop_tunnel(openvpn, args.verbose);
+            return Err(e);
+        }
+    }
+    println!("READY");
+    close_stdout();
+
+    let mut idle = IdleLoop::new(sigfd);
+    idle.set_signal_config(&signals);
+    for ev in idle {
+        match ev {
+            Event::StdinClosed => {
+                if args.verbose {
+                    writeln!(io::stderr(), "# stdin closed, exiting").unwrap();
+                }
+                break;
+            },
+            Event::TermSignal(sig) => {
+                if args.verbose {
+                    writeln!(io::stderr(), "# {:?}, exiting", sig).unwrap();
+                }
+                break;
+            },
+            Event::Reload => {
+                if args.verbose {
+                    writeln!(io::stderr(), "# SIGHUP, restarting openvpn \
+                                            (pid {})", openvpn.id()).unwrap();
+                }
+                if let Err(e) = kill(openvpn.id() as libc::pid_t,
+                                     Signal::SIGUSR1) {
+                    writeln!(io::stderr(), "kill openvpn: {}", e).unwrap();
+                }
+            },
+            Event::ChildExit(pid, status) if pid as u32 == openvpn.id() => {
+                let status = try!(reap_child(openvpn, status));
+                return Err(HLError::UnsuccessfulChild {
+                    status: format!("exited unexpectedly ({})", status),
+                    cmdline: String::from("openvpn"),
+                    stderr: String::new()
+                });
+            },
+            Event::ChildExit(pid, status) => {
+                writeln!(io::stderr(), "# unexpected child process {} {}",
+                         pid, describe_wait_status(&status)).unwrap();
+            },
+            Event::Error(e) => {
+                stop_tunnel(openvpn, args.verbose);
+                return Err(e);
+            },
+            // No timers were set, no fds registered, no notify or
+            // status signals configured, and stdin is discarded.
+            Event::Timeout(_) | Event::FdReady(..) | Event::FdHangup(_)
+                | Event::NotifySignal(_) | Event::Status(_)
+                | Event::StdinLine(_) => unreachable!(),
+        }
+    }
+
+    stop_tunnel(openvpn, args.verbose);
+    Ok(0)
+}
+
 fn main() {
-    unimplemented!()
+    let argv: Vec<String> = env::args().collect();
+    if argv.len() >= 3 && argv[1] == "--as-up-script" {
+        if let Err(e) = do_up_script(&argv[2]) {
+            writeln!(io::stderr(), "openvpn-netns --as-up-script: {}",
+                     e).unwrap();
+            process::exit(1);
+        }
+        process::exit(0);
+    }
+
+    process::exit(match inner_main(parse_cmdline()) {
+        Ok(code) => code,
+        Err(e) => {
+            writeln!(io::stderr(), "{}", e).unwrap();
+            1
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use super::*;
+
+    #[test]
+    fn config_with_ordinary_options_is_accepted() {
+        let text = "client\n\
+                    dev tun\n\
+                    ; a comment\n\
+                    # up /tmp/evil.sh\n\
+                    <connection>\n\
+                    remote vpn.example.com 1194\n\
+                    </connection>\n\
+                    <ca>\n\
+                    up\n\
+                    </ca>\n\
+                    verb 3\n";
+        assert!(check_openvpn_config(text, Path::new("x.conf")).is_ok());
+    }
+
+    #[test]
+    fn config_with_scripts_is_refused() {
+        for text in &["up /tmp/evil.sh\n",
+                      "  --plugin /tmp/evil.so\n",
+                      "\"route-up\" /tmp/evil.sh\n",
+                      "s\\etenv LD_PRELOAD /tmp/evil.so\n",
+                      "management 127.0.0.1 7505\n",
+                      "<connection>\nconfig /root/secret\n</connection>\n"] {
+            let err = check_openvpn_config(text, Path::new("x.conf"))
+                .unwrap_err();
+            assert!(err.starts_with("x.conf:"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn extra_args_with_scripts_are_refused() {
+        let ok: Vec<String> = vec!["--verb".into(), "0".into()];
+        assert!(check_openvpn_args(&ok).is_ok());
+        for bad in &["--down", "--management-query-passwords", "--log"] {
+            let args: Vec<String> = vec![String::from(*bad), "x".into()];
+            assert!(check_openvpn_args(&args).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn only_root_controlled_binaries_are_trusted() {
+        use std::env;
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+
+        assert!(!is_root_controlled(Path::new("/nonexistent/openvpn")));
+        // A world-writable file is never acceptable, whoever owns it.
+        let path = env::temp_dir().join(format!("openvpn-netns-test-{}",
+                                                process::id()));
+        fs::write(&path, "#! /bin/sh\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o777))
+            .unwrap();
+        assert!(!is_root_controlled(&path));
+        fs::remove_file(&path).unwrap();
+    }
+
+    fn test_args(openvpn_binary: &str) -> Args {
+        Args {
+            namespace: String::from("ns"),
+            config_text: Vec::new(),
+            openvpn_args: Vec::new(),
+            openvpn_binary: String::from(openvpn_binary),
+            ready_probe: None,
+            ready_interval: 1000,
+            ready_timeout: 60,
+            reload_on_hup: false,
+            pid_file: None,
+            verbose: false,
+        }
+    }
+
+    #[test]
+    fn openvpn_is_looked_up_only_without_override() {
+        assert_eq!(tools_needed(&test_args("openvpn")), &["ip", "openvpn"]);
+        assert_eq!(tools_needed(&test_args("/opt/vpn/bin/openvpn")),
+                   &["ip"]);
+    }
+
+    #[test]
+    fn the_openvpn_command_line_uses_the_chosen_binary() {
+        let argv = openvpn_argv(&test_args("/opt/vpn/bin/openvpn"), "up");
+        assert_eq!(argv[0], OsString::from("/opt/vpn/bin/openvpn"));
+        assert_eq!(argv[1], OsString::from("--config"));
+
+        // Without the option, it is plain "openvpn", and the rest of
+        // the command line is the same.
+        let mut args = test_args("openvpn");
+        args.openvpn_args = vec![String::from("--verb"), String::from("0")];
+        let default = openvpn_argv(&args, "up");
+        assert_eq!(default[0], OsString::from("openvpn"));
+        assert_eq!(&default[1..argv.len()], &argv[1..]);
+        assert_eq!(&default[argv.len()..],
+                   &[OsString::from("--verb"), OsString::from("0")]);
+    }
+
+    #[test]
+    fn a_failing_probe_is_retried_until_it_succeeds() {
+        use nix::unistd::{close, pipe};
+
+        let (quiet, quiet_wr) = pipe().unwrap();
+        let mut args = test_args("openvpn");
+        args.ready_interval = 10;
+
+        let mut calls = 0;
+        let deadline = Instant::now() + Duration::from_secs(5);
+        assert!(wait_for_probe(&args, &["probe"], deadline, quiet, || {
+            calls += 1;
+            Ok(calls == 3)
+        }).unwrap());
+        assert_eq!(calls, 3);
+
+        let mut calls = 0;
+        let deadline = Instant::now() + Duration::from_millis(100);
+        let result = wait_for_probe(&args, &["probe"], deadline, quiet, || {
+            calls += 1;
+            Ok(false)
+        });
+        match result {
+            Err(HLError::UnsuccessfulChild { ref status, ref cmdline, .. })
+                => {
+                assert!(status.contains(&format!("({} attempts)", calls)),
+                        "{}", status);
+                assert_eq!(cmdline, "probe");
+            },
+            other => panic!("expected a timeout, got {:?}", other)
+        }
+        assert!(calls > 1);
+
+        // A probe that can't be run at all isn't retried.
+        let mut calls = 0;
+        let deadline = Instant::now() + Duration::from_secs(5);
+        assert!(wait_for_probe(&args, &["probe"], deadline, quiet, || {
+            calls += 1;
+            Err(map_config_err(String::from("no such program")))
+        }).is_err());
+        assert_eq!(calls, 1);
+
+        close(quiet).unwrap();
+        close(quiet_wr).unwrap();
+    }
+
+    #[test]
+    fn readiness_probes_are_retried_until_the_deadline() {
+        use std::collections::HashMap;
+        use std::thread;
+        use nix::sys::signal::SigSet;
+        use nix::unistd::{close, geteuid, getpid, pipe};
+
+        let child_env = ChildEnv {
+            env: vec![(String::from("PATH"), String::from(SAFE_PATH))],
+            mask: SigSet::empty(),
+            verbose: false,
+            dryrun: false,
+            unexpected_child: UnexpectedChildPolicy::Ignore,
+            umask: DEFAULT_UMASK,
+            recorder: None,
+            tools: HashMap::new(),
+            child_fd_cap: None
+        };
+        let (quiet, quiet_wr) = pipe().unwrap();
+        // The probe runs in the namespace we are already in, which
+        // still takes privileges to enter.
+        let mut args = test_args("openvpn");
+        args.namespace = String::from("net");
+        args.ready_interval = 50;
+        let run_dir = Path::new("/proc/self/ns");
+        let probe = |argv: &[&str], patience: u64| {
+            let argv: Vec<String> = argv.iter().map(|&a| a.into()).collect();
+            probe_in_netns(&args, &argv, run_dir,
+                           Instant::now() + Duration::from_millis(patience),
+                           quiet, &child_env)
+        };
+        if geteuid() != 0 {
+            assert!(probe(&["true"], 1000).is_err());
+            close(quiet).unwrap();
+            close(quiet_wr).unwrap();
+            return;
+        }
+
+        assert!(probe(&["true"], 1000).unwrap());
+        let err = probe(&["false"], 300).unwrap_err();
+        assert!(err.to_string().contains("attempts"), "{}", err);
+
+        let marker = env::temp_dir().join(format!(
+            "openvpn-netns-probe-{}", getpid()));
+        let marker_again = marker.clone();
+        let creator = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            fs::write(&marker_again, "").unwrap();
+        });
+        assert!(probe(&["test", "-e", marker.to_str().unwrap()], 5000)
+                .unwrap());
+        creator.join().unwrap();
+        fs::remove_file(&marker).unwrap();
+        close(quiet).unwrap();
+        close(quiet_wr).unwrap();
+    }
 }
@@ -9,6 +9,8 @@
 //! There is NO WARRANTY.
 //!
 //!     tunnel-ns PREFIX N
+//!     tunnel-ns --batch FILE
+//!     tunnel-ns --reap-idle PREFIX
 //!
 //! creates N network namespaces, imaginatively named PREFIX_ns0,
 //! PREFIX_ns1, ... The loopback device in each namespace is brought
@@ -28,18 +30,149 @@
 //!
 //! After all namespaces have been created, stdout is closed.
 //!
+//! With --batch FILE, several pools of namespaces are created at once,
+//! instead of just one.  Each non-blank line of FILE is a pool
+//! specification, "PREFIX N", which has the same meaning as the
+//! command-line arguments in the first form; '#' begins a comment.
+//! One line is written to stdout for every namespace in every pool,
+//! and they are all torn down together.  If FILE is "-", the
+//! specifications are read from stdin, up to the first blank line;
+//! after that, stdin behaves as described below.
+//!
 //! Anything written to stdin is read and discarded.  When stdin is
 //! *closed*, however, all of the network namespaces are torn down
 //! (killing any processes still in there, if necessary) and the
-//! program exits.  This also happens on receipt of any catchable
-//! signal whose default action is to terminate the process without
-//! a core dump (e.g. SIGTERM, SIGHUP).
+//! program exits.  (With --stdin-eof-grace, the program waits that
+//! many milliseconds after stdin is closed, and only tears down if
+//! nothing has reopened it in that time.)  This also happens on
+//! receipt of any catchable signal whose default action is to
+//! terminate the process without a core dump (e.g. SIGTERM, SIGHUP).
+//!
+//! SIGUSR2 is an exception: it triggers a "detach" teardown.  The
+//! namespaces are deleted with "ip netns del" and their /etc/netns
+//! directories are removed, but processes still inside them are *not*
+//! killed.  "ip netns del" only removes the name; a namespace that
+//! still has processes in it continues to exist, with all its
+//! interfaces, until the last of them exits.  However, it can no
+//! longer be entered or inspected by name, and nothing will clean it
+//! up for you.  This is meant for rolling reconfiguration, where
+//! something else takes responsibility for those processes.
+//!
+//! Errors, if any, will be written to stderr.  At startup, a warning
+//! is printed if any namespaces named PREFIX_* already exist, since
+//! that probably means a previous run crashed; --quiet suppresses
+//! this.  If teardown runs into more than a fixed number of errors
+//! (32, or as set with --failure-budget), it gives up, reports the
+//! namespaces it could not delete, and the program exits with code 2
+//! instead of the usual 1.
+//!
+//! With --alias NAME=PREFIX_nsX (which may be repeated), a symbolic
+//! link /run/netns/NAME pointing to PREFIX_nsX is created after all
+//! the namespaces, and removed again on teardown.  "ip netns exec"
+//! follows these links, so NAME can be used as a stable name for the
+//! namespace.
+//!
+//! With --ttl SECONDS, everything is torn down automatically once the
+//! program has been running for that long, as if stdin had been
+//! closed.  In that case the program exits with code 3.
+//!
+//! With --reload-on-hup, SIGHUP does not cause teardown.  Instead, any
+//! of the namespaces that have been deleted out from under this
+//! program (e.g. by a careless "ip netns del") are created again, with
+//! their loopback interfaces set up as before.  Files in their
+//! /etc/netns directories are not touched.
+//!
+//! If an /etc/netns directory already exists when a namespace is
+//! created (perhaps because an operator put files there by hand), it
+//! is not removed on teardown.  Only the files this program added to
+//! it are removed, and any files they replaced are restored.
+//!
+//! With --hosts-file PATH, the contents of PATH are copied into each
+//! namespace's /etc/netns directory as "hosts", so that processes in
+//! the namespace see it as /etc/hosts.  PATH is read once, at startup,
+//! and must look like a hosts file: each line that isn't blank or a
+//! comment must be an IP address followed by one or more host names.
+//!
+//! The /etc/netns directories, the files installed in them, and the
+//! manifest are created with umask 022 (so they are not group- or
+//! world-writable, whatever umask the program was started with), or
+//! with the octal umask given by --umask MASK.
+//!
+//! With --resolv-conf PATH, the contents of PATH are likewise copied
+//! into each namespace's /etc/netns directory as "resolv.conf", so
+//! that processes in the namespace use the nameservers it lists
+//! rather than the host's, which may not be reachable from inside.
+//! Alternatively, --nameserver ADDR (which may be repeated) generates
+//! a resolv.conf listing just those nameservers.  PATH must be
+//! readable by the user running this program, not merely by root.
+//!
+//! With --lo-addr CIDR, the loopback interface in each namespace is
+//! given the address CIDR *instead of* the usual 127.0.0.1/8 and ::1.
+//! This is occasionally useful for testing, but very few programs
+//! expect it, so a warning is printed if CIDR is not within
+//! 127.0.0.0/8 (or ::1, for IPv6).
+//!
+//! With --manifest PATH, once everything has been set up, a JSON
+//! description of it is written to PATH: the process ID of this
+//! program, and for each namespace, its name, pool prefix, index,
+//! /etc/netns directory, and loopback address (null if the default),
+//! plus the list of aliases.  PATH is removed again on teardown.  It
+//! is written, and removed, with the permissions of the user running
+//! this program, not root's, so it belongs to them.
+//!
+//! SIGUSR1 is also an exception: it makes the program exit *without*
+//! tearing anything down.  The namespaces, their /etc/netns
+//! directories, and the processes inside them are all left intact.
+//! (Aliases and the manifest, if any, are still removed.)  This is
+//! the old supervisor's half of a handoff; see --handoff-from.
+//!
+//! With --handoff-from PIDFILE, the program takes over from another
+//! instance of itself that was started with --pid-file PIDFILE and is
+//! managing the same namespaces.  It sends that instance SIGUSR1,
+//! waits for it to exit (which it signals by removing PIDFILE), and
+//! then proceeds as usual, except that namespaces which already exist
+//! are adopted rather than created.  The new instance may use the
+//! same --pid-file as the old one.  If the old instance does not exit
+//! within 30 seconds, nothing is done and the program fails.
+//!
+//! With --status-signals, SIGUSR1 and SIGUSR2 lose their special
+//! meanings described above.  Instead, either one makes the program
+//! print, on stderr, each namespace it is managing, whether its
+//! /etc/netns directory exists, and how many processes are in it.
+//! SIGUSR2 also prints some internal state, for debugging.  A program
+//! started this way can't be handed off from or detached by signal.
+//!
+//! With --setsid, the program makes itself the leader of a new
+//! session, detached from any controlling terminal, before doing
+//! anything else.  It will then no longer receive SIGHUP when the
+//! terminal is closed, nor SIGINT or SIGQUIT from the keyboard.  Its
+//! stdin and stdout are unaffected.  This only works if the program
+//! is not already a process group leader, as it would be if started
+//! directly from an interactive shell; start it via setsid(1) or a
+//! script instead (in which case --setsid is unnecessary anyway).
 //!
-//! Errors, if any, will be written to stderr.
+//! With --pid-file PATH, the program's process ID is written to PATH
+//! at startup, and PATH is removed on exit.  If PATH already names a
+//! running process, the program refuses to start.
+//!
+//! The second form is a maintenance mode.  It does not create anything;
+//! instead, it looks for existing namespaces named PREFIX_*, and deletes
+//! each one that has no processes in it (along with its /etc/netns
+//! directory).  Namespaces that are still in use are left alone, and
+//! so are namespaces managed by a running instance of this program,
+//! even if they are empty; each instance holds a lock on the
+//! namespaces it manages for this purpose.  One line is written to
+//! stdout for each namespace considered, either "reaped NAME" or "kept
+//! NAME".  If anything goes wrong with one namespace, the error is
+//! reported, the rest are still considered, and the exit status is 1.
+//! Since this mode never kills anything, it is safe to run while other
+//! instances of this program are active.
 //!
 //! This program must be installed setuid root.  It expects the "ip"
 //! utility to be available in a standard "bin" directory (see
-//! prepare_child_env for the PATH setting used).  It makes
+//! SAFE_PATH for the PATH setting used).  For development
+//! use, --inherit-path passes down the invoker's PATH instead, but
+//! only when the program is *not* actually running setuid.  It makes
 //! extensive use of Linux-specific network stack features.
 //! A port to a different OS might well entail a complete rewrite.
 
@@ -49,47 +182,154 @@ use std::io;
 use std::fs;
 
 use std::ascii::AsciiExt;
+use std::collections::HashMap;
 use std::convert::From;
 use std::io::Write;
-use std::path::PathBuf;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::time::{Duration, Instant};
 
+extern crate libc;
 extern crate nix;
 #[macro_use] extern crate clap;
+#[macro_use] extern crate serde_json;
 
 // The internal shared-code crate has this awkward name because
 // I haven't figured out how to make it less awkward.
 extern crate openvpn_netns_tools;
 use openvpn_netns_tools::*;
 
+use nix::sys::signal::Signal;
+use nix::sys::wait::WaitStatus;
+
 /// RAII class which creates and removes an /etc/netns directory
-/// for a namespace.
+/// for a namespace.  If the directory already existed, it is not
+/// removed; only the files that were installed in it by this program
+/// are, and any files they replaced are put back.
 struct NsConfDir<'a> {
     path: PathBuf,
+    owned: bool,
+    installed: Vec<(PathBuf, Option<Vec<u8>>)>,
+    keep: bool,
     env: &'a ChildEnv
 }
 impl<'a> NsConfDir<'a> {
-    fn new(name: &str, env: &'a ChildEnv) -> Result<NsConfDir<'a>, HLError> {
+    fn path_for(name: &str) -> PathBuf {
         let mut path = PathBuf::new();
         path.push("/etc/netns");
         path.push(name);
+        path
+    }
+
+    fn new(name: &str, env: &'a ChildEnv) -> Result<NsConfDir<'a>, HLError> {
+        let path = NsConfDir::path_for(name);
+        let owned = !path.exists();
         if env.verbose {
-            writeln!(io::stderr(), "mkdir {:?}", &path).unwrap();
+            if owned {
+                writeln!(io::stderr(), "mkdir {:?}", &path).unwrap();
+            } else {
+                writeln!(io::stderr(), "# {:?} already exists", &path)
+                    .unwrap();
+            }
         }
         if !env.dryrun {
-            try!(fs::create_dir_all(&path)
+            try!(with_umask(env.umask, || fs::create_dir_all(&path))
                  .map_err(|e| map_io_err(e, format!(
                      "mkdir {:?}", &path))));
         }
 
-        Ok(NsConfDir { path: path, env: env })
+        Ok(NsConfDir { path: path, owned: owned, installed: Vec::new(),
+                       keep: false, env: env })
+    }
+
+    /// Take responsibility for the /etc/netns directory of a namespace
+    /// that was created by someone else.  It is not an error if the
+    /// directory doesn't exist.
+    fn adopt(name: &str, env: &'a ChildEnv) -> NsConfDir<'a> {
+        NsConfDir { path: NsConfDir::path_for(name), owned: true,
+                    installed: Vec::new(), keep: false, env: env }
+    }
+
+    /// Treat this directory as ours to remove, even though it already
+    /// existed; for when its creator has handed it over to us.
+    fn claim(&mut self) {
+        self.owned = true;
+    }
+
+    /// Don't remove the directory after all.
+    fn keep(&mut self) {
+        self.keep = true;
+    }
+
+    /// Create a file named NAME in this directory, with CONTENTS.
+    /// It will be removed again on drop; if it replaced an existing
+    /// file, that file's contents will be restored instead.
+    fn install_file(&mut self, name: &str, contents: &[u8])
+                    -> Result<(), HLError> {
+        use std::io::Read;
+
+        let path = self.path.join(name);
+        if self.env.verbose {
+            writeln!(io::stderr(), "install {:?}", &path).unwrap();
+        }
+        if !self.env.dryrun {
+            let mut previous = None;
+            if !self.owned && path.exists() {
+                let mut old = Vec::new();
+                try!(fs::File::open(&path)
+                     .and_then(|mut f| f.read_to_end(&mut old))
+                     .map_err(|e| map_io_err(e, format!(
+                         "saving {:?}", &path))));
+                previous = Some(old);
+            }
+            try!(with_umask(self.env.umask, || fs::File::create(&path))
+                 .and_then(|mut f| f.write_all(contents))
+                 .map_err(|e| map_io_err(e, format!(
+                     "install {:?}", &path))));
+            self.installed.push((path, previous));
+        }
+        Ok(())
     }
 }
 impl<'a> Drop for NsConfDir<'a> {
     fn drop (&mut self) {
+        if self.keep {
+            return;
+        }
+        if !self.owned {
+            // Undo our changes, most recent first, so that installing
+            // the same file twice still restores the original.
+            while let Some((path, previous)) = self.installed.pop() {
+                let result = match previous {
+                    Some(ref old) => {
+                        if self.env.verbose {
+                            writeln!(io::stderr(), "restore {:?}", &path)
+                                .unwrap();
+                        }
+                        fs::File::create(&path)
+                            .and_then(|mut f| f.write_all(old))
+                    },
+                    None => {
+                        if self.env.verbose {
+                            writeln!(io::stderr(), "rm {:?}", &path)
+                                .unwrap();
+                        }
+                        fs::remove_file(&path)
+                    }
+                };
+                if let Err(e) = result {
+                    writeln!(io::stderr(),
+                             "warning: could not restore {:?}: {:?}",
+                             &path, e).unwrap();
+                }
+            }
+            return;
+        }
         if self.env.verbose {
             writeln!(io::stderr(), "rm -rf {:?}", &self.path).unwrap();
         }
-        if !self.env.dryrun {
+        if !self.env.dryrun && self.path.exists() {
             if let Err(e) = fs::remove_dir_all(&self.path) {
                 writeln!(io::stderr(),
                          "warning: could not delete {:?}: {:?}",
@@ -99,112 +339,945 @@ impl<'a> Drop for NsConfDir<'a> {
     }
 }
 
-/// RAII class which creates and destroys a network namespace and its
-/// /etc/netns directory.
+/// RAII class which creates and removes a symbolic link in RUN_DIR
+/// (normally /run/netns), giving an existing namespace an alternative
+/// name.
+struct NsAlias<'a> {
+    path: PathBuf,
+    env: &'a ChildEnv
+}
+impl<'a> NsAlias<'a> {
+    fn new(alias: &str, target: &str, run_dir: &Path, env: &'a ChildEnv)
+           -> Result<NsAlias<'a>, HLError> {
+        use std::os::unix::fs::symlink;
+
+        let path = run_dir.join(alias);
+        if env.verbose {
+            writeln!(io::stderr(), "ln -s {} {:?}", target, &path).unwrap();
+        }
+        if !env.dryrun {
+            try!(symlink(target, &path)
+                 .map_err(|e| map_io_err(e, format!(
+                     "ln -s {} {:?}", target, &path))));
+        }
+
+        Ok(NsAlias { path: path, env: env })
+    }
+}
+impl<'a> Drop for NsAlias<'a> {
+    fn drop (&mut self) {
+        if self.env.verbose {
+            writeln!(io::stderr(), "rm {:?}", &self.path).unwrap();
+        }
+        if !self.env.dryrun {
+            if let Err(e) = fs::remove_file(&self.path) {
+                writeln!(io::stderr(),
+                         "warning: could not delete {:?}: {:?}",
+                         &self.path, e).unwrap();
+            }
+        }
+    }
+}
+
+/// RAII class which writes a manifest file describing the namespaces,
+/// and removes it on drop.
+struct Manifest<'a> {
+    path: PathBuf,
+    env: &'a ChildEnv
+}
+impl<'a> Manifest<'a> {
+    /// Write CONTENTS to PATH.  The file is written under a temporary
+    /// name and then renamed into place, so that readers never see a
+    /// partial manifest.  Both steps are done as the real user, and
+    /// the temporary file must not already exist (it's removed first,
+    /// if it does) and can't be a symbolic link, so this can't be used
+    /// to overwrite anything they couldn't have overwritten themselves.
+    fn write(path: &Path, contents: &str, env: &'a ChildEnv)
+             -> Result<Manifest<'a>, HLError> {
+        use std::os::unix::fs::OpenOptionsExt;
+        use libc::O_NOFOLLOW;
+
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+
+        if env.verbose {
+            writeln!(io::stderr(), "write manifest {:?}", path).unwrap();
+        }
+        if !env.dryrun {
+            try!(as_real_user(|| {
+                if let Err(e) = fs::remove_file(&tmp) {
+                    if e.kind() != io::ErrorKind::NotFound {
+                        return Err(e);
+                    }
+                }
+                try!(with_umask(env.umask, || {
+                    fs::OpenOptions::new()
+                        .write(true).create_new(true)
+                        .custom_flags(O_NOFOLLOW).open(&tmp)
+                }).and_then(|mut f| f.write_all(contents.as_bytes())));
+                fs::rename(&tmp, path)
+            }).map_err(|e| map_io_err(e, format!("write manifest {:?}",
+                                                 path))));
+        }
+        Ok(Manifest { path: path.to_path_buf(), env: env })
+    }
+}
+impl<'a> Drop for Manifest<'a> {
+    fn drop (&mut self) {
+        if self.env.verbose {
+            writeln!(io::stderr(), "rm {:?}", &self.path).unwrap();
+        }
+        if !self.env.dryrun {
+            if let Err(e) = as_real_user(|| fs::remove_file(&self.path)) {
+                writeln!(io::stderr(),
+                         "warning: could not delete {:?}: {:?}",
+                         &self.path, e).unwrap();
+            }
+        }
+    }
+}
+
+/// Construct the manifest describing the namespaces that ARGS asked
+/// for, which must all have been created.
+fn manifest_json(args: &Args) -> String {
+    let mut namespaces = Vec::new();
+    for &(ref prefix, nnsp) in &args.pools {
+        for i in 0..nnsp {
+            let name = format!("{}_ns{}", prefix, i);
+            namespaces.push(json!({
+                "name": name,
+                "prefix": prefix,
+                "index": i,
+                "confdir": NsConfDir::path_for(&name).to_string_lossy(),
+                "lo_addr": args.lo_addr
+            }));
+        }
+    }
+    let aliases: Vec<serde_json::Value> = args.aliases.iter().map(
+        |&(ref alias, ref target)| json!({
+            "alias": alias,
+            "target": target
+        })).collect();
+
+    format!("{:#}\n", json!({
+        "pid": nix::unistd::getpid(),
+        "namespaces": namespaces,
+        "aliases": aliases
+    }))
+}
+
+/// Set when the operator sends a termination signal while we are
+/// already tearing down.  After that, there is no grace period
+/// between SIGTERM and SIGKILL for processes in the namespaces.
+static IMPATIENT: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// A network namespace and its /etc/netns directory.  The namespace
+/// is created by NetNs::new, but it is only destroyed by a
+/// NamespaceSet, which see.
 struct NetNs<'a> {
-    name:     String,
-    _confdir: NsConfDir<'a>,
-    env:      &'a ChildEnv
+    name:      String,
+    detach:    bool,
+    keep:      bool,
+    torn_down: bool,
+    sigfd:     RawFd,
+    confdir:   NsConfDir<'a>,
+    unkilled:  Vec<pid_t>,
+    lock:      Option<NetnsLock>,
+    runner:    &'a Runner
 }
 impl<'a> NetNs<'a> {
-    fn new(name: String, env: &'a ChildEnv) -> Result<NetNs<'a>, HLError> {
-        let confdir = try!(NsConfDir::new(&name, env));
-        try!(run(&["ip", "netns", "add", &name], env));
+    fn new(name: String, lo_addr: Option<&str>, sigfd: RawFd,
+           runner: &'a Runner) -> Result<NetNs<'a>, HLError> {
+        let confdir = try!(NsConfDir::new(&name, runner.child_env()));
+        try!(NetNs::create(&name, lo_addr, runner));
+        let lock = try!(NetNs::lock(&name, runner));
+        Ok(NetNs { name: name, detach: false, keep: false, torn_down: false,
+                   sigfd: sigfd, confdir: confdir, unkilled: Vec::new(),
+                   lock: lock, runner: runner })
+    }
+
+    /// Create the namespace itself again, after it has been deleted
+    /// by someone else.
+    fn recreate(&mut self, lo_addr: Option<&str>) -> Result<(), HLError> {
+        self.lock = None;
+        try!(NetNs::create(&self.name, lo_addr, self.runner));
+        self.lock = try!(NetNs::lock(&self.name, self.runner));
+        Ok(())
+    }
+
+    /// Internal: take a shared lock on the namespace NAME, marking it
+    /// as managed by us, so that --reap-idle leaves it alone even
+    /// while it is empty.  In a dry run, the namespace doesn't really
+    /// exist, so there is nothing to lock.
+    fn lock(name: &str, runner: &Runner)
+            -> Result<Option<NetnsLock>, HLError> {
+        if runner.child_env().dryrun {
+            return Ok(None);
+        }
+        match try!(lock_netns(name, Path::new(NETNS_RUN_DIR), false)) {
+            Some(lock) => Ok(Some(lock)),
+            None => Err(map_config_err(format!(
+                "namespace {} is being reaped by someone else", name)))
+        }
+    }
+
+    /// Internal: create the namespace NAME and set up its loopback
+    /// interface.
+    fn create(name: &str, lo_addr: Option<&str>, runner: &Runner)
+              -> Result<(), HLError> {
+        try!(runner.run(&["ip", "netns", "add", name]));
 
         // The loopback interface automatically exists in the namespace,
         // with the usual address and an appropriate routing table entry,
-        // but it is not brought up automatically.  If this fails, we must
-        // tear down the namespace manually; RAII is not yet in effect.
-        if let Err(e) = run(&["ip", "netns", "exec", &name,
-                              "ip", "link", "set", "dev", "lo", "up"],
-                            env) {
-            run_ignore_failure(&["ip", "netns", "del", &name], env);
+        // but it is not brought up automatically.  If anything here
+        // fails, we must tear down the namespace manually; RAII is not
+        // yet in effect.  None of this cares about /etc, so the
+        // namespace can be entered directly, saving a fork and exec of
+        // "ip netns exec" for each command.
+        let setup_lo = || -> Result<(), HLError> {
+            if let Some(addr) = lo_addr {
+                try!(runner.run_in_netns_direct(name, &["ip", "addr",
+                                                        "flush", "dev",
+                                                        "lo"]));
+                try!(runner.run_in_netns_direct(name, &["ip", "addr", "add",
+                                                        addr, "dev", "lo"]));
+            }
+            runner.run_in_netns_direct(name, &["ip", "link", "set", "dev",
+                                               "lo", "up"])
+        };
+        if let Err(e) = setup_lo() {
+            runner.run_ignore_failure(&["ip", "netns", "del", name]);
             return Err(e);
         }
+        Ok(())
+    }
 
+    /// Take over a namespace that already exists, e.g. one that was
+    /// created by a previous supervisor which has handed it off to us.
+    /// It is assumed to be set up already.
+    fn adopt(name: String, sigfd: RawFd, runner: &'a Runner)
+             -> Result<NetNs<'a>, HLError> {
+        let mut confdir = try!(NsConfDir::new(&name, runner.child_env()));
+        confdir.claim();
+        let lock = try!(NetNs::lock(&name, runner));
+        Ok(NetNs { name: name, detach: false, keep: false, torn_down: false,
+                   sigfd: sigfd, confdir: confdir, unkilled: Vec::new(),
+                   lock: lock, runner: runner })
+    }
 
-        Ok(NetNs { name: name, _confdir: confdir, env: env })
+    /// When this namespace is torn down, don't do anything to it at
+    /// all; leave it, its /etc/netns directory, and its processes for
+    /// someone else to manage.
+    fn set_keep(&mut self) {
+        self.keep = true;
+        self.confdir.keep();
     }
 
-    fn kill_processes_in_namespace(&self) -> Result<(), HLError> {
-        use nix::sys::signal::kill;
+    /// When this namespace is torn down, leave the processes inside it
+    /// alone; just delete its name and /etc/netns directory.
+    fn set_detach(&mut self) {
+        self.detach = true;
+    }
+
+    /// Kill every process in this namespace: SIGTERM first, then,
+    /// after a grace period, SIGKILL for any that are left.  Processes
+    /// that can't be sent SIGKILL are remembered in self.unkilled, and
+    /// the error lists them.
+    fn kill_processes_in_namespace(&mut self) -> Result<(), HLError> {
         use nix::sys::signal::Signal::{SIGTERM, SIGKILL};
-        use std::thread::sleep;
-        use std::time::Duration;
 
-        let to_kill = try!(run_get_output_pids(
-            &["ip", "netns", "pids", &self.name], self.env));
+        let to_kill = try!(self.runner.run_query_get_output_pids(
+            &["ip", "netns", "pids", &self.name]));
         if to_kill.len() == 0 { return Ok(()); }
 
         for pid in to_kill {
-            if let Err(_) = kill(pid, SIGTERM) {
+            if let Err(_) = self.runner.kill(pid, SIGTERM) {
                 // errors deliberately ignored
             }
         }
 
-        sleep(Duration::from_secs(5));
-        let to_kill = try!(run_get_output_pids(
-            &["ip", "netns", "pids", &self.name], self.env));
+        // Give the processes a chance to exit gracefully, unless the
+        // operator sends another termination signal in the meantime.
+        // In a dry run, nothing was really signaled, so there is
+        // nothing to wait for.
+        if !IMPATIENT.load(Ordering::SeqCst)
+            && !self.runner.child_env().dryrun {
+            if let SleepOutcome::Interrupted(sig) =
+                interruptible_sleep(Duration::from_secs(5), self.sigfd) {
+                if self.runner.child_env().verbose {
+                    writeln!(io::stderr(),
+                             "# {:?} during teardown, not waiting", sig)
+                        .unwrap();
+                }
+                IMPATIENT.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let to_kill = try!(self.runner.run_query_get_output_pids(
+            &["ip", "netns", "pids", &self.name]));
 
         if to_kill.len() == 0 { return Ok(()); }
+        let mut last_err = None;
         for pid in to_kill {
-            if let Err(_) = kill(pid, SIGKILL) {
-                // errors deliberately ignored
+            if let Err(e) = self.runner.kill(pid, SIGKILL) {
+                self.unkilled.push(pid);
+                last_err = Some(e);
             }
         }
-        Ok(())
+        match last_err {
+            None => Ok(()),
+            Some(e) => Err(map_io_err(
+                io::Error::new(io::ErrorKind::Other, e.to_string()),
+                format!("could not kill process{} {} in namespace {}",
+                        if self.unkilled.len() == 1 { "" } else { "es" },
+                        self.unkilled.iter().map(|p| p.to_string())
+                            .collect::<Vec<_>>().join(", "),
+                        self.name)))
+        }
+    }
+
+    /// Describe this namespace for the list of those that are still
+    /// present after an incomplete cleanup.
+    fn describe_remaining(&self) -> String {
+        if self.unkilled.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{} (processes {})", self.name,
+                    self.unkilled.iter().map(|p| p.to_string())
+                    .collect::<Vec<_>>().join(", "))
+        }
+    }
+
+    /// Try to find out why "ip netns del" said this namespace was busy,
+    /// and return a description suitable for a diagnostic.
+    fn diagnose_busy(&self) -> String {
+        let pids = match self.runner.run_query_get_output_pids(
+            &["ip", "netns", "pids", &self.name]) {
+            Ok(pids) => pids,
+            Err(e) => return format!("could not list processes ({})", e)
+        };
+        if pids.is_empty() {
+            return String::from("no processes remain; it may be mounted \
+                                 somewhere other than /run/netns");
+        }
+        let procs: Vec<String> = pids.iter().map(|pid| {
+            let mut comm = String::new();
+            let _ = fs::File::open(format!("/proc/{}/comm", pid))
+                .and_then(|mut f| {
+                    use std::io::Read;
+                    f.read_to_string(&mut comm)
+                });
+            format!("{} ({})", pid, comm.trim())
+        }).collect();
+        format!("held by process{} {}",
+                if procs.len() == 1 { "" } else { "es" }, procs.join(", "))
     }
+
+    /// Tear down this namespace.  Each failure is reported, and uses
+    /// up one unit of BUDGET.  If BUDGET runs out, stop immediately.
+    /// Returns true if the namespace was successfully deleted.
+    fn teardown(&mut self, budget: &mut u32, log: &mut TeardownLog)
+                -> bool {
+        let name = self.name.clone();
+        let mut spend = |budget: &mut u32, e: HLError| {
+            log.record(&name, &e);
+            *budget = budget.saturating_sub(1);
+            *budget > 0
+        };
+
+        // Whatever happens, don't try again.
+        self.torn_down = true;
+
+        if self.keep {
+            return true;
+        }
+
+        if !self.detach {
+            // In a dry run, the runner only reports the kills.
+            if let Err(e) = self.kill_processes_in_namespace() {
+                if !spend(budget, e) { return false; }
+            }
+            if let Err(e) = self.runner.run_in_netns(
+                &self.name, &["ip", "link", "set", "dev", "lo", "down"]) {
+                if !spend(budget, e) { return false; }
+            }
+        }
+        // Deletion can fail with EBUSY for a little while after the
+        // last process in the namespace has been killed.
+        self.lock = None;
+        match self.runner.run_with_retry(&["ip", "netns", "del", &self.name],
+                                         4, Duration::from_millis(100)) {
+            Ok(_) => true,
+            Err(e) => {
+                let busy = match e {
+                    HLError::UnsuccessfulChild { ref stderr, .. } =>
+                        stderr.contains("Device or resource busy"),
+                    _ => false
+                };
+                if busy {
+                    writeln!(io::stderr(), "warning: namespace {} is still \
+                                            in use: {}",
+                             &self.name, self.diagnose_busy()).unwrap();
+                }
+                spend(budget, e);
+                false
+            }
+        }
+    }
+}
+
+/// Replace each occurrence of the namespace name NAME in TEXT with
+/// REPLACEMENT, except where it is only part of a longer name (as
+/// t_ns1 is part of t_ns10).
+fn replace_name(text: &str, name: &str, replacement: &str) -> String {
+    let name_char = |c: char| c.is_alphanumeric() || c == '_' || c == '-';
+    let mut out = String::with_capacity(text.len());
+    let mut copied = 0;
+    for (start, _) in text.match_indices(name) {
+        let end = start + name.len();
+        if text[..start].chars().next_back().map_or(false, &name_char)
+            || text[end..].chars().next().map_or(false, &name_char) {
+            continue;
+        }
+        out.push_str(&text[copied..start]);
+        out.push_str(replacement);
+        copied = end;
+    }
+    out.push_str(&text[copied..]);
+    out
 }
-impl<'a> Drop for NetNs<'a> {
+
+/// Collects the errors that occur while tearing down a NamespaceSet,
+/// so that a failure which happens to many namespaces at once (e.g.
+/// because "ip" itself is broken) is reported once, with a count,
+/// rather than flooding the log.  Errors are considered identical if
+/// their text is the same apart from the namespace name.
+struct TeardownLog {
+    counts: HashMap<String, (u32, String)>,
+    order:  Vec<String>,
+}
+impl TeardownLog {
+    fn new() -> TeardownLog {
+        TeardownLog { counts: HashMap::new(), order: Vec::new() }
+    }
+
+    /// Note that tearing down NAMESPACE failed with error E.
+    fn record(&mut self, namespace: &str, e: &HLError) {
+        let msg = e.to_string();
+        let key = replace_name(&msg, namespace, "<namespace>");
+        if !self.counts.contains_key(&key) {
+            self.order.push(key.clone());
+        }
+        self.counts.entry(key).or_insert((0, msg)).0 += 1;
+    }
+
+    /// One message for each distinct error, in the order they first
+    /// occurred.
+    fn messages(&self) -> Vec<String> {
+        self.order.iter().map(|key| {
+            let &(n, ref first) = &self.counts[key];
+            if n == 1 {
+                first.clone()
+            } else {
+                format!("teardown failed for {} namespaces: {}", n, key)
+            }
+        }).collect()
+    }
+
+    /// Write each of the messages to stderr.
+    fn report(&self) {
+        for msg in self.messages() {
+            writeln!(io::stderr(), "{}", msg).unwrap();
+        }
+    }
+}
+
+/// RAII class which owns a collection of NetNs objects, and destroys
+/// them all (in reverse order of creation) when dropped, or when
+/// teardown() is called.  Teardown stops early if more than a fixed
+/// number of failures occur across all the namespaces, so that a
+/// badly broken environment can't make shutdown take forever.
+///
+/// Because teardown happens on drop, a panic anywhere after the
+/// namespaces are created still removes them, as long as panics
+/// unwind; see the profile settings in Cargo.toml.
+struct NamespaceSet<'a> {
+    nsps:           Vec<NetNs<'a>>,
+    failure_budget: u32,
+}
+impl<'a> NamespaceSet<'a> {
+    fn new(failure_budget: u32) -> NamespaceSet<'a> {
+        NamespaceSet { nsps: Vec::new(), failure_budget: failure_budget }
+    }
+
+    fn push(&mut self, ns: NetNs<'a>) {
+        self.nsps.push(ns);
+    }
+
+    fn last_mut(&mut self) -> &mut NetNs<'a> {
+        // Only called immediately after push().
+        self.nsps.last_mut().unwrap()
+    }
+
+    fn iter_mut(&mut self) -> std::slice::IterMut<NetNs<'a>> {
+        self.nsps.iter_mut()
+    }
+
+    /// Describe the current state of each namespace on stderr.
+    fn report_status(&self) {
+        for ns in self.nsps.iter().filter(|ns| !ns.torn_down) {
+            let confdir = if NsConfDir::path_for(&ns.name).is_dir() {
+                "present"
+            } else {
+                "missing"
+            };
+            let pids = match ns.runner.run_query_get_output_pids(
+                &["ip", "netns", "pids", &ns.name]) {
+                Ok(pids) => format!("{} processes", pids.len()),
+                Err(e) => format!("processes unknown ({})", e)
+            };
+            writeln!(io::stderr(), "# {}: /etc/netns {}, {}",
+                     ns.name, confdir, pids).unwrap();
+        }
+    }
+
+    /// Create again any of the namespaces that no longer exist, other
+    /// than those that have been torn down on purpose.  Returns how
+    /// many there were.  Gives up at the first failure.
+    fn recreate_missing(&mut self, lo_addr: Option<&str>, runner: &Runner)
+                        -> Result<usize, HLError> {
+        let existing = try!(list_netns(runner));
+        let mut count = 0;
+        for ns in self.nsps.iter_mut()
+            .filter(|ns| !ns.torn_down && !existing.contains(&ns.name)) {
+            try!(ns.recreate(lo_addr));
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Tear down all of the namespaces.  If the failure budget runs
+    /// out, returns an IncompleteCleanup error listing the namespaces
+    /// that could not be deleted.  If there is nothing left to tear
+    /// down (e.g. because creating the very first namespace failed),
+    /// this does nothing at all, and in particular runs no commands.
+    fn teardown(&mut self) -> Result<(), HLError> {
+        if self.nsps.iter().all(|ns| ns.torn_down) {
+            return Ok(());
+        }
+
+        let mut budget = self.failure_budget;
+        let mut failures = 0;
+        let mut remaining = Vec::new();
+        let mut log = TeardownLog::new();
+
+        for ns in self.nsps.iter_mut().rev().filter(|ns| !ns.torn_down) {
+            if budget == 0 {
+                ns.torn_down = true;
+                remaining.push(ns.describe_remaining());
+                continue;
+            }
+            let before = budget;
+            if !ns.teardown(&mut budget, &mut log) {
+                remaining.push(ns.describe_remaining());
+            }
+            failures += before - budget;
+        }
+        log.report();
+
+        if budget == 0 {
+            Err(map_incomplete_cleanup(failures, &remaining))
+        } else {
+            Ok(())
+        }
+    }
+}
+impl<'a> Drop for NamespaceSet<'a> {
     fn drop (&mut self) {
-        if let Err(e) = self.kill_processes_in_namespace() {
-            writeln!(io::stderr(), "{:?}", e).unwrap();
+        if let Err(e) = self.teardown() {
+            writeln!(io::stderr(), "{}", e).unwrap();
         }
-        run_ignore_failure(&["ip", "netns", "exec", &self.name,
-                             "ip", "link", "set", "dev", "lo", "down"],
-                           self.env);
-        run_ignore_failure(&["ip", "netns", "del", &self.name],
-                           self.env);
     }
 }
 
-/// Create NNSP namespaces, named {PREFIX}_ns{N} where N is a number
-/// from 0 to N-1.  Return their NetNs objects.
-fn create_namespaces<'a>(prefix: &str, nnsp: u32, env: &'a ChildEnv)
-                         -> Result<Vec<NetNs<'a>>, HLError> {
-    let nnsp = nnsp as usize;
-    let mut nsps: Vec<NetNs> = Vec::with_capacity(nnsp);
-    for i in 0..nnsp {
-        nsps.push(try!(NetNs::new(format!("{}_ns{}", prefix, i), env)));
-        println!("{}", &nsps[i].name);
+/// For each (PREFIX, NNSP) pair in ARGS.pools, create NNSP namespaces,
+/// named {PREFIX}_ns{N} where N is a number from 0 to NNSP-1, and set
+/// them up as requested by the rest of ARGS.  Return all of them as a
+/// single NamespaceSet.  Timings are recorded in METRICS.
+fn create_namespaces<'a>(args: &Args, sigfd: RawFd,
+                         metrics: &mut Metrics, runner: &'a Runner)
+                         -> Result<NamespaceSet<'a>, HLError> {
+    // validate() has already checked the hosts file, but it could
+    // have changed since.
+    let hosts = match args.hosts_file {
+        Some(ref path) => Some(try!(read_hosts_file(path)
+                                    .map_err(map_config_err))),
+        None => None
+    };
+    let resolv_conf = try!(resolv_conf_contents(args));
+
+    // When taking over from another supervisor, namespaces it left
+    // behind are adopted instead of created.
+    let existing = if args.handoff_from.is_some() {
+        try!(list_netns(runner))
+    } else {
+        Vec::new()
+    };
+
+    let start = Instant::now();
+    let mut nsps = NamespaceSet::new(args.failure_budget);
+    for &(ref prefix, nnsp) in &args.pools {
+        for i in 0..nnsp {
+            let ns_start = Instant::now();
+            let name = format!("{}_ns{}", prefix, i);
+            let ns = if existing.contains(&name) {
+                try!(NetNs::adopt(name, sigfd, runner))
+            } else {
+                try!(NetNs::new(name,
+                                args.lo_addr.as_ref().map(|a| a.as_str()),
+                                sigfd, runner))
+            };
+            // Once pushed, the namespace will be torn down on failure.
+            let name = ns.name.clone();
+            nsps.push(ns);
+            if let Some(ref hosts) = hosts {
+                try!(nsps.last_mut().confdir.install_file("hosts",
+                                                          hosts.as_bytes()));
+            }
+            if let Some(ref resolv_conf) = resolv_conf {
+                try!(nsps.last_mut().confdir.install_file(
+                    "resolv.conf", resolv_conf.as_bytes()));
+            }
+            metrics.record_create(ns_start.elapsed());
+            println!("{}", name);
+        }
     }
-    close_stdout();
+    metrics.set_create_total(start.elapsed());
     Ok(nsps)
 }
 
+/// List all of the network namespaces that currently exist.
+fn list_netns(runner: &Runner) -> Result<Vec<String>, HLError> {
+    // The output is an array of objects, each with a "name" and
+    // possibly an "id".
+    let list = try!(runner.run_get_json(&["ip", "netns", "list"]));
+    Ok(list.as_array().map(|a| a.as_slice()).unwrap_or(&[]).iter()
+       .filter_map(|ns| ns["name"].as_str())
+       .map(String::from)
+       .collect())
+}
+
+/// The namespaces named {PREFIX}_* that already exist.  These are
+/// most likely left over from a previous run that crashed.
+fn leftover_namespaces(prefix: &str, runner: &Runner)
+                       -> Result<Vec<String>, HLError> {
+    let leader = format!("{}_", prefix);
+    Ok(try!(list_netns(runner)).into_iter()
+       .filter(|n| n.starts_with(&leader))
+       .collect())
+}
+
+/// Warn about any leftover namespaces (see leftover_namespaces).
+fn warn_about_leftovers(prefix: &str, runner: &Runner) {
+    match leftover_namespaces(prefix, runner) {
+        Ok(leftovers) => {
+            if leftovers.len() > 0 {
+                writeln!(io::stderr(),
+                         "warning: namespaces left over from a previous \
+                          run: {}", leftovers.join(", ")).unwrap();
+            }
+        },
+        Err(e) => {
+            writeln!(io::stderr(),
+                     "warning: could not check for leftover namespaces: {}",
+                     e).unwrap();
+        }
+    }
+}
+
+/// Delete every namespace named {PREFIX}_* (whose handle is in
+/// RUN_DIR) that has no processes in it and is not managed by a
+/// running supervisor, along with its /etc/netns directory.  Other
+/// namespaces are left alone.  A failure to deal with one namespace
+/// is reported, and then the rest are considered anyway.  Returns the
+/// exit code: 0 if everything went well, 1 if there were failures.
+fn reap_idle_namespaces(prefix: &str, runner: &Runner, run_dir: &Path)
+                        -> Result<i32, HLError> {
+    let leader = format!("{}_", prefix);
+    let candidates = try!(list_netns(runner));
+    let mut exit_code = 0;
+
+    for name in candidates.iter().filter(|n| n.starts_with(&leader)) {
+        // Supervisors hold a shared lock on each namespace they manage,
+        // and they can't take it while we hold this one, so nothing can
+        // start managing the namespace while we are deleting it.
+        let reap = || -> Result<bool, HLError> {
+            let _lock = match try!(lock_netns(name, run_dir, true)) {
+                Some(lock) => lock,
+                None => return Ok(false)
+            };
+            let pids = try!(runner.run_query_get_output_pids(
+                &["ip", "netns", "pids", name]));
+            if pids.len() > 0 {
+                return Ok(false);
+            }
+            try!(runner.run(&["ip", "netns", "del", name]));
+            // Dropping the adopted confdir removes it.
+            NsConfDir::adopt(name, runner.child_env());
+            Ok(true)
+        };
+        match reap() {
+            Ok(true) => println!("reaped {}", name),
+            Ok(false) => println!("kept {}", name),
+            Err(e) => {
+                writeln!(io::stderr(), "{}", e).unwrap();
+                println!("kept {}", name);
+                exit_code = 1;
+            }
+        }
+    }
+    Ok(exit_code)
+}
+
+/// Create all of the requested namespace aliases, in RUN_DIR.
+fn create_aliases<'a>(aliases: &[(String, String)], run_dir: &Path,
+                      env: &'a ChildEnv)
+                      -> Result<Vec<NsAlias<'a>>, HLError> {
+    let mut links: Vec<NsAlias> = Vec::with_capacity(aliases.len());
+    for &(ref alias, ref target) in aliases {
+        links.push(try!(NsAlias::new(alias, target, run_dir, env)));
+    }
+    Ok(links)
+}
+
 /// Establish a safe set of environment variables for running child
 /// processes.  TERM, TZ, LANG, and LC_* are passed down.  PATH is
-/// forced to a known-good standard value.  All other environment
+/// forced to a known-good standard value, unless INHERIT_PATH is true,
+/// in which case it is passed down too.  All other environment
 /// variables are discarded.  (The only subprogram run by this program
 /// is "ip", which does not require HOME, USER, TMPDIR, etc.)
-fn prepare_child_env() -> Vec<(String, String)> {
-    let mut child_env: Vec<(String, String)> =
-        env::vars().filter(|&(ref k, _)|
-            k == "TERM" || k == "TZ" || k == "LANG" || k.starts_with("LC_")
-        ).collect();
+fn prepare_child_env(inherit_path: bool) -> Vec<(String, String)> {
+    if inherit_path && env::var("PATH").is_ok() {
+        allowlisted_env(&["TERM", "TZ", "LANG", "PATH"], &["LC_"], &[])
+    } else {
+        allowlisted_env(&["TERM", "TZ", "LANG"], &["LC_"],
+                        &[("PATH", SAFE_PATH)])
+    }
+}
+
+/// True if NAME is acceptable as a namespace prefix or alias:
+/// nonempty, and consisting only of ASCII letters, numbers, and
+/// underscores.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(
+        |c| c.is_ascii() && (c.is_alphanumeric() || c == '_'))
+}
+
+/// Namespace names and aliases become file names in /run/netns and
+/// /etc/netns, so they can't be longer than this.
+const MAX_NAME_LEN: usize = 255;
+
+/// Parse the count in one "PREFIX N" pool specification.  The prefix
+/// and the range of the count are checked later, by validate().
+fn parse_pool_spec(prefix: &str, count: &str)
+                   -> Result<(String, u32), String> {
+    match count.parse::<u32>() {
+        Ok(n) => Ok((String::from(prefix), n)),
+        Err(_) => Err(format!("invalid number of namespaces: {:?}", count))
+    }
+}
+
+/// Internal: read stdin up to and including the first blank line (or
+/// EOF), and return what was read, less the blank line.  This reads
+/// one byte at a time, directly from the file descriptor, so that
+/// nothing after the blank line is consumed; the idle loop needs to
+/// see the rest.
+fn read_stdin_to_blank_line() -> io::Result<String> {
+    use libc::{c_void, read};
+
+    let mut text = Vec::new();
+    let mut line_start = 0;
+    loop {
+        let mut byte = 0u8;
+        let n = unsafe { read(0, &mut byte as *mut u8 as *mut c_void, 1) };
+        if n < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        if n == 0 {
+            break;
+        }
+        if byte == b'\n' {
+            if text[line_start..].iter()
+                .all(|&b| b == b' ' || b == b'\t' || b == b'\r') {
+                text.truncate(line_start);
+                break;
+            }
+            text.push(byte);
+            line_start = text.len();
+        } else {
+            text.push(byte);
+        }
+    }
+    String::from_utf8(text).map_err(
+        |_| io::Error::new(io::ErrorKind::InvalidData,
+                           "stream did not contain valid UTF-8"))
+}
 
-    child_env.push((String::from("PATH"),
-                    String::from("/usr/local/bin:/usr/bin:/bin:\
-                                  /usr/local/sbin:/usr/sbin:/sbin")));
+/// Read pool specifications from PATH, or from stdin if PATH is "-".
+/// When reading from stdin, stop at the first blank line, so that
+/// stdin can go on to serve its usual purpose.  PATH is opened as the
+/// real user.
+fn read_batch_file(path: &str) -> Result<Vec<(String, u32)>, String> {
+    use std::io::Read;
 
-    child_env.sort();
-    child_env
+    let mut contents = String::new();
+    try!(if path == "-" {
+        read_stdin_to_blank_line().map(|text| contents = text)
+    } else {
+        open_as_real_user(Path::new(path))
+            .and_then(|mut f| f.read_to_string(&mut contents))
+            .map(|_| ())
+    }.map_err(|e| format!("{}: {}", path, e)));
+    parse_batch_file(path, &contents)
+}
+
+/// Internal: parse CONTENTS, read from PATH, as pool specifications.
+fn parse_batch_file(path: &str, contents: &str)
+                    -> Result<Vec<(String, u32)>, String> {
+    let mut pools: Vec<(String, u32)> = Vec::new();
+    for (lineno, line) in contents.lines().enumerate() {
+        let fields: Vec<&str> = line.splitn(2, '#').next().unwrap_or("")
+            .split_whitespace().collect();
+        match fields.len() {
+            0 => continue,
+            2 => pools.push(try!(parse_pool_spec(fields[0], fields[1])
+                                 .map_err(|e| format!("{}:{}: {}",
+                                                      path, lineno + 1, e)))),
+            _ => return Err(format!("{}:{}: expected \"PREFIX N\"",
+                                    path, lineno + 1))
+        }
+    }
+    if pools.is_empty() {
+        return Err(format!("{}: no pool specifications", path));
+    }
+    Ok(pools)
+}
+
+/// Work out what should go in each namespace's resolv.conf, if
+/// anything: either a copy of the file named by --resolv-conf, or one
+/// "nameserver" line for each --nameserver.
+fn resolv_conf_contents(args: &Args) -> Result<Option<String>, HLError> {
+    use std::io::Read;
+
+    if let Some(ref path) = args.resolv_conf {
+        let mut contents = String::new();
+        try!(open_as_real_user(Path::new(path))
+             .and_then(|mut f| f.read_to_string(&mut contents))
+             .map_err(|e| map_io_err(e, path.clone())));
+        Ok(Some(contents))
+    } else if !args.nameservers.is_empty() {
+        Ok(Some(args.nameservers.iter()
+                .map(|ns| format!("nameserver {}\n", ns))
+                .collect()))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Read PATH and check that it looks like a hosts file: every line
+/// that isn't blank or a comment must be an IP address followed by at
+/// least one plausible host name.  Returns the contents.  The file is
+/// opened as the real user, and error messages give only the line
+/// number, so this can't be used to read files they otherwise
+/// couldn't.
+fn read_hosts_file(path: &str) -> Result<String, String> {
+    use std::io::Read;
+    use std::net::IpAddr;
+
+    let mut contents = String::new();
+    try!(open_as_real_user(Path::new(path))
+         .and_then(|mut f| f.read_to_string(&mut contents))
+         .map_err(|e| format!("{}: {}", path, e)));
+
+    for (lineno, line) in contents.lines().enumerate() {
+        let mut fields = line.splitn(2, '#').next().unwrap_or("")
+            .split_whitespace();
+        let addr = match fields.next() {
+            None => continue,
+            Some(a) => a
+        };
+        if addr.parse::<IpAddr>().is_err() {
+            return Err(format!("{}:{}: invalid address",
+                               path, lineno + 1));
+        }
+        let mut nhosts = 0;
+        for host in fields {
+            if !host.chars().all(|c| c.is_ascii() &&
+                                 (c.is_alphanumeric() ||
+                                  c == '-' || c == '.' || c == '_')) {
+                return Err(format!("{}:{}: invalid host name",
+                                   path, lineno + 1));
+            }
+            nhosts += 1;
+        }
+        if nhosts == 0 {
+            return Err(format!("{}:{}: no host names",
+                               path, lineno + 1));
+        }
+    }
+    Ok(contents)
+}
+
+/// Check that CIDR is an IP address with a prefix length, and warn if
+/// it isn't a conventional loopback address.
+fn check_lo_addr(cidr: &str) -> Result<(), String> {
+    use std::net::IpAddr;
+
+    let mut parts = cidr.splitn(2, '/');
+    let addr = parts.next().unwrap_or("").parse::<IpAddr>();
+    let plen = parts.next().and_then(|p| p.parse::<u8>().ok());
+    let conventional = match (addr, plen) {
+        (Ok(IpAddr::V4(a)), Some(p)) if p <= 32 => a.octets()[0] == 127,
+        (Ok(IpAddr::V6(a)), Some(p)) if p <= 128 => a.is_loopback(),
+        _ => return Err(format!("invalid loopback address: {:?} \
+                                 (expected ADDRESS/LENGTH)", cidr))
+    };
+    if !conventional {
+        writeln!(io::stderr(),
+                 "warning: {} is not a conventional loopback address; \
+                  many programs will not expect it", cidr).unwrap();
+    }
+    Ok(())
 }
 
 /// Data parsed from the command line.
 struct Args {
     prefix: String,
-    n_namespaces: u32,
+    pools: Vec<(String, u32)>,
+    reap_idle: bool,
+    stdin_eof_grace: u64,
+    pid_file: Option<String>,
+    handoff_from: Option<String>,
+    manifest: Option<String>,
+    aliases: Vec<(String, String)>,
+    hosts_file: Option<String>,
+    resolv_conf: Option<String>,
+    nameservers: Vec<String>,
+    lo_addr: Option<String>,
+    failure_budget: u32,
+    inherit_path: bool,
+    setsid: bool,
+    quiet: bool,
+    ttl: u64,
+    reload_on_hup: bool,
+    status_signals: bool,
+    unexpected_child: UnexpectedChildPolicy,
+    umask: u32,
     dryrun: bool,
     verbose: bool
 }
@@ -219,13 +1292,142 @@ fn parse_cmdline() -> Args {
              .help("Prefix to use for the namespaces.  Must consist of \
                     ASCII letters, numbers, and underscores.")
              .index(1)
-             .required(true)
+             .required_unless("batch")
              .empty_values(false))
         .arg(Arg::with_name("n_namespaces")
              .help("Number of namespaces to create (1-1024).")
              .index(2)
-             .required(true)
+             .required_unless_one(&["reap_idle", "batch"])
+             .empty_values(false))
+        .arg(Arg::with_name("batch")
+             .help("Create every pool of namespaces listed in FILE \
+                    (one \"PREFIX N\" per line; \"-\" for stdin).")
+             .long("batch")
+             .value_name("FILE")
+             .takes_value(true)
+             .empty_values(false)
+             .conflicts_with_all(&["prefix", "n_namespaces", "reap_idle"]))
+        .arg(Arg::with_name("reap_idle")
+             .help("Instead of creating namespaces, delete all existing \
+                    namespaces named PREFIX_* that have no processes \
+                    in them.")
+             .long("reap-idle")
+             .conflicts_with("n_namespaces"))
+        .arg(Arg::with_name("stdin_eof_grace")
+             .help("After stdin is closed, wait this many milliseconds \
+                    for it to be reopened before tearing down.")
+             .long("stdin-eof-grace")
+             .value_name("MILLIS")
+             .takes_value(true))
+        .arg(Arg::with_name("alias")
+             .help("Create an alias NAME for the namespace PREFIX_nsX.  \
+                    May be repeated.")
+             .long("alias")
+             .value_name("NAME=PREFIX_nsX")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1))
+        .arg(Arg::with_name("hosts_file")
+             .help("Install a copy of PATH as /etc/hosts in each \
+                    namespace.")
+             .long("hosts-file")
+             .value_name("PATH")
+             .takes_value(true)
+             .empty_values(false)
+             .conflicts_with("reap_idle"))
+        .arg(Arg::with_name("resolv_conf")
+             .help("Install a copy of PATH as /etc/resolv.conf in each \
+                    namespace.")
+             .long("resolv-conf")
+             .value_name("PATH")
+             .takes_value(true)
+             .empty_values(false)
+             .conflicts_with_all(&["reap_idle", "nameserver"]))
+        .arg(Arg::with_name("nameserver")
+             .help("Give each namespace a resolv.conf that uses this \
+                    nameserver.  May be repeated.")
+             .long("nameserver")
+             .value_name("ADDR")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .conflicts_with("reap_idle"))
+        .arg(Arg::with_name("lo_addr")
+             .help("Give the loopback interface in each namespace this \
+                    address, instead of the usual ones.")
+             .long("lo-addr")
+             .value_name("CIDR")
+             .takes_value(true)
+             .empty_values(false)
+             .conflicts_with("reap_idle"))
+        .arg(Arg::with_name("manifest")
+             .help("Write a JSON description of the namespaces to PATH.")
+             .long("manifest")
+             .value_name("PATH")
+             .takes_value(true)
+             .empty_values(false)
+             .conflicts_with("reap_idle"))
+        .arg(Arg::with_name("pid_file")
+             .help("Write the process ID of this program to PATH.")
+             .long("pid-file")
+             .value_name("PATH")
+             .takes_value(true)
              .empty_values(false))
+        .arg(Arg::with_name("handoff_from")
+             .help("Take over the namespaces of the instance of this \
+                    program whose pid file is PIDFILE.")
+             .long("handoff-from")
+             .value_name("PIDFILE")
+             .takes_value(true)
+             .empty_values(false)
+             .conflicts_with("reap_idle"))
+        .arg(Arg::with_name("failure_budget")
+             .help("During teardown, give up after this many failures \
+                    (default 32).")
+             .long("failure-budget")
+             .value_name("N")
+             .takes_value(true))
+        .arg(Arg::with_name("inherit_path")
+             .help("Run subprograms with the caller's PATH rather than \
+                    a known-good one.  Insecure; refused when running \
+                    setuid.")
+             .long("inherit-path"))
+        .arg(Arg::with_name("setsid")
+             .help("Run in a new session, detached from the controlling \
+                    terminal.")
+             .long("setsid"))
+        .arg(Arg::with_name("quiet")
+             .help("Do not warn about namespaces left over from \
+                    previous runs.")
+             .short("q")
+             .long("quiet"))
+        .arg(Arg::with_name("ttl")
+             .help("Tear down and exit after this many seconds, \
+                    regardless of stdin.")
+             .long("ttl")
+             .value_name("SECONDS")
+             .takes_value(true))
+        .arg(Arg::with_name("reload_on_hup")
+             .help("On SIGHUP, re-create any namespaces that have been \
+                    deleted, instead of tearing down and exiting.")
+             .long("reload-on-hup"))
+        .arg(Arg::with_name("status_signals")
+             .help("On SIGUSR1 or SIGUSR2, report status instead of \
+                    handing off or detaching.")
+             .long("status-signals"))
+        .arg(Arg::with_name("unexpected_child")
+             .help("What to do when an unexpected child process exits \
+                    (default: warn).")
+             .long("unexpected-child")
+             .value_name("POLICY")
+             .takes_value(true)
+             .possible_values(&["ignore", "warn", "fatal"]))
+        .arg(Arg::with_name("umask")
+             .help("Umask (in octal) for files and directories created \
+                    under /etc/netns (default: 022).")
+             .long("umask")
+             .value_name("MASK")
+             .takes_value(true))
         .arg(Arg::with_name("dryrun")
              .help("Do not perform any actions, just report \
                     what would have been done.")
@@ -237,28 +1439,80 @@ fn parse_cmdline() -> Args {
              .long("verbose"))
         .get_matches();
 
-    // This unwrap is safe because the value is marked 'required' above.
-    let prefix = matches.value_of("prefix").unwrap();
-    let nnsp   = value_t!(matches, "n_namespaces", u32)
-        .unwrap_or_else(|e| e.exit());
-
-    for c in prefix.chars() {
-        if !(c.is_ascii() && (c.is_alphanumeric() || c == '_')) {
-            Error::with_description(
-                &format!("invalid prefix: {:?}", prefix),
-                ValueValidation).exit();
+    // clap guarantees that one of these is present.
+    let prefix = matches.value_of("prefix").unwrap_or("");
+    let batch = matches.value_of("batch");
+    let reap_idle = matches.is_present("reap_idle");
+    let stdin_eof_grace = if matches.is_present("stdin_eof_grace") {
+        value_t!(matches, "stdin_eof_grace", u64)
+            .unwrap_or_else(|e| e.exit())
+    } else { 0 };
+    let ttl = if matches.is_present("ttl") {
+        value_t!(matches, "ttl", u64).unwrap_or_else(|e| e.exit())
+    } else { 0 };
+    let failure_budget = if matches.is_present("failure_budget") {
+        value_t!(matches, "failure_budget", u32)
+            .unwrap_or_else(|e| e.exit())
+    } else { 32 };
+    let umask = match matches.value_of("umask") {
+        None => DEFAULT_UMASK,
+        Some(mask) => match u32::from_str_radix(mask, 8) {
+            Ok(m) if m <= 0o777 => m,
+            _ => Error::with_description(
+                &format!("invalid umask: {:?}", mask),
+                ValueValidation).exit()
         }
-    }
+    };
 
-    if nnsp < 1 || nnsp > 1024 {
-        Error::with_description(
-            &format!("n_namespaces must be from 1 to 1024, not {}", nnsp),
-            ValueValidation).exit()
-    }
+    let pools = if let Some(path) = batch {
+        read_batch_file(path).unwrap_or_else(
+            |e| Error::with_description(&e, ValueValidation).exit())
+    } else if reap_idle {
+        Vec::new()
+    } else {
+        // This unwrap is safe because the value is required when
+        // neither --batch nor --reap-idle is given.
+        vec![parse_pool_spec(prefix, matches.value_of("n_namespaces")
+                             .unwrap())
+             .unwrap_or_else(
+                 |e| Error::with_description(&e, ValueValidation).exit())]
+    };
+
+    // Aliases are checked by validate(), along with everything else.
+    let aliases = matches.values_of("alias").into_iter().flat_map(|v| v)
+        .map(|spec| {
+            let mut parts = spec.splitn(2, '=');
+            (String::from(parts.next().unwrap_or("")),
+             String::from(parts.next().unwrap_or("")))
+        })
+        .collect();
 
     Args {
         prefix: String::from(prefix),
-        n_namespaces: nnsp,
+        pools: pools,
+        reap_idle: reap_idle,
+        stdin_eof_grace: stdin_eof_grace,
+        pid_file: matches.value_of("pid_file").map(String::from),
+        handoff_from: matches.value_of("handoff_from").map(String::from),
+        manifest: matches.value_of("manifest").map(String::from),
+        aliases: aliases,
+        hosts_file: matches.value_of("hosts_file").map(String::from),
+        resolv_conf: matches.value_of("resolv_conf").map(String::from),
+        nameservers: matches.values_of("nameserver")
+            .map(|v| v.map(String::from).collect())
+            .unwrap_or_else(Vec::new),
+        lo_addr: matches.value_of("lo_addr").map(String::from),
+        failure_budget: failure_budget,
+        inherit_path: matches.is_present("inherit_path"),
+        setsid: matches.is_present("setsid"),
+        quiet: matches.is_present("quiet"),
+        ttl: ttl,
+        reload_on_hup: matches.is_present("reload_on_hup"),
+        status_signals: matches.is_present("status_signals"),
+        // This unwrap is safe because clap has already checked the value.
+        unexpected_child: UnexpectedChildPolicy::from_name(
+            matches.value_of("unexpected_child").unwrap_or("warn")).unwrap(),
+        umask: umask,
         verbose: (matches.is_present("verbose") ||
                   matches.is_present("dryrun")),
         dryrun: matches.is_present("dryrun")
@@ -266,55 +1520,1206 @@ fn parse_cmdline() -> Args {
 }
 
 
-fn inner_main(args: Args) -> Result<(), HLError> {
+/// Check the configuration as a whole, before anything is done.
+/// Everything that can be checked without making changes to the
+/// system is checked here, so that a mistake in the configuration
+/// doesn't leave some namespaces half set up.
+fn validate(args: &Args) -> Result<(), HLError> {
+    let invalid = |msg: String| Err(map_config_err(msg));
 
-    let (sigfd, child_mask) = try!(prepare_signals());
+    if args.reap_idle {
+        if !is_valid_name(&args.prefix) {
+            return invalid(format!("invalid prefix: {:?}", args.prefix));
+        }
+    }
 
-    let child_env = ChildEnv {
-        env: prepare_child_env(),
-        mask: child_mask,
-        verbose: args.verbose,
-        dryrun: args.dryrun
+    for (i, &(ref prefix, nnsp)) in args.pools.iter().enumerate() {
+        if !is_valid_name(prefix) {
+            return invalid(format!("invalid prefix: {:?}", prefix));
+        }
+        if nnsp < 1 || nnsp > 1024 {
+            return invalid(format!("number of namespaces for {} must be \
+                                    from 1 to 1024, not {}", prefix, nnsp));
+        }
+        if format!("{}_ns{}", prefix, nnsp - 1).len() > MAX_NAME_LEN {
+            return invalid(format!("prefix too long: {:?}", prefix));
+        }
+        if args.pools[..i].iter().any(|&(ref p, _)| p == prefix) {
+            return invalid(format!("duplicate prefix: {:?}", prefix));
+        }
+    }
+
+    for &(ref alias, ref target) in &args.aliases {
+        let spec = format!("{}={}", alias, target);
+        let is_ns_name = |name: &str| args.pools.iter().any(
+            |&(ref prefix, nnsp)|
+            (0..nnsp).any(|i| name == format!("{}_ns{}", prefix, i)));
+        if !is_valid_name(alias) || alias.len() > MAX_NAME_LEN {
+            return invalid(format!("invalid alias: {:?}", spec));
+        }
+        if !is_ns_name(target) {
+            return invalid(format!("alias target is not one of the \
+                                    namespaces: {:?}", spec));
+        }
+        if is_ns_name(alias) {
+            return invalid(format!("alias would shadow a namespace: {:?}",
+                                   spec));
+        }
+    }
+
+    if args.failure_budget < 1 {
+        return invalid(String::from("failure budget must be at least 1"));
+    }
+
+    if args.inherit_path {
+        use nix::unistd::{getuid, geteuid};
+        if getuid() != geteuid() {
+            return invalid(String::from(
+                "--inherit-path may not be used when running setuid"));
+        }
+        writeln!(io::stderr(),
+                 "warning: --inherit-path in effect; subprograms will be \
+                  found using the caller's PATH").unwrap();
+    }
+
+    if let Some(ref path) = args.hosts_file {
+        try!(read_hosts_file(path).map_err(map_config_err));
+    }
+    for ns in &args.nameservers {
+        use std::net::IpAddr;
+        if ns.parse::<IpAddr>().is_err() {
+            return invalid(format!("invalid nameserver address: {:?}", ns));
+        }
+    }
+    try!(resolv_conf_contents(args));
+    if let Some(ref cidr) = args.lo_addr {
+        try!(check_lo_addr(cidr).map_err(map_config_err));
+    }
+
+    Ok(())
+}
+
+/// Ask the supervisor whose pid file is PATH to hand its namespaces
+/// over to us, and wait for it to exit.  The pid file must be one
+/// that a copy of this program wrote (see read_pid_file), and the
+/// process it names must be running this program, or we won't signal
+/// it.
+fn take_over_from(path: &Path, sigfd: RawFd, verbose: bool, dryrun: bool)
+                  -> Result<(), HLError> {
+    use nix::sys::signal::kill;
+
+    let pid = try!(read_pid_file(path));
+    try!(check_same_program(pid));
+    if verbose {
+        writeln!(io::stderr(), "kill -USR1 {}", pid).unwrap();
+    }
+    if dryrun {
+        return Ok(());
+    }
+    try!(kill(pid, Signal::SIGUSR1)
+         .map_err(|e| map_nix_err(e, format!("signaling process {}", pid))));
+    wait_for_pid_file_removal(path, Duration::from_secs(30), sigfd)
+}
+
+/// Respond as POLICY says to the exit of child process PID, with
+/// STATUS, which nothing was expecting.  (The idle loop has already
+/// reaped it.)  Returns true if the program should shut down.
+fn unexpected_child_exit(policy: UnexpectedChildPolicy, pid: pid_t,
+                         status: &WaitStatus) -> bool {
+    if policy == UnexpectedChildPolicy::Ignore {
+        return false;
+    }
+    writeln!(io::stderr(), "# unexpected child process {} {}",
+             pid, describe_wait_status(status)).unwrap();
+    policy == UnexpectedChildPolicy::Fatal
+}
+
+/// Create everything ARGS asks for: the namespaces, then their aliases
+/// in RUN_DIR, then the manifest.  Once all of that is done, the time
+/// since STARTED is recorded in METRICS as the ready time.
+fn set_up<'a>(args: &Args, started: Instant, sigfd: RawFd, run_dir: &Path,
+              metrics: &mut Metrics, runner: &'a Runner)
+              -> Result<(NamespaceSet<'a>, Vec<NsAlias<'a>>,
+                         Option<Manifest<'a>>), HLError> {
+    let nsps = try!(create_namespaces(args, sigfd, metrics, runner));
+    let env = runner.child_env();
+    let aliases = try!(create_aliases(&args.aliases, run_dir, env));
+    let manifest = match args.manifest {
+        Some(ref path) => Some(try!(Manifest::write(Path::new(path),
+                                                    &manifest_json(args),
+                                                    env))),
+        None => None
     };
+    metrics.set_ready(started.elapsed());
+    Ok((nsps, aliases, manifest))
+}
 
-    // _nsps exists solely so that the namespaces will be torn down
-    // *after* the idle loop.
-    let _nsps = try!(create_namespaces(&args.prefix,
-                                       args.n_namespaces,
-                                       &child_env));
+/// Exit code used when the program shuts down because --ttl expired.
+const EXIT_TTL_EXPIRED: i32 = 3;
+
+/// Wait in IDLE until it is time to shut down, then tear everything
+/// down: ALIASES and MANIFEST first, and then NSPS.  The TTL, if ARGS
+/// sets one, counts from STARTED.  Teardown timing is recorded in
+/// METRICS.  Returns the exit code.
+fn supervise<'a>(args: &Args, started: Instant, mut idle: IdleLoop,
+                 mut nsps: NamespaceSet<'a>, aliases: Vec<NsAlias<'a>>,
+                 manifest: Option<Manifest<'a>>, metrics: &mut Metrics,
+                 runner: &'a Runner) -> Result<i32, HLError> {
+    let mut exit_code = 0;
+    // The TTL counts from startup, not from when setup finished.
+    let ttl_timer = if args.ttl > 0 {
+        Some(idle.set_deadline(started + Duration::from_secs(args.ttl)))
+    } else {
+        None
+    };
 
-    for ev in IdleLoop::new(sigfd) {
-        match ev {
+    loop {
+        match idle.next_event() {
             Event::StdinClosed => {
                 if args.verbose {
                     writeln!(io::stderr(), "# stdin closed, exiting").unwrap();
                 }
                 break;
             },
+            Event::Status(sig) => {
+                writeln!(io::stderr(), "# {:?}, status:", sig).unwrap();
+                nsps.report_status();
+                if sig == Signal::SIGUSR2 {
+                    writeln!(io::stderr(), "# idle loop: {}",
+                             idle.describe_state()).unwrap();
+                }
+            },
+            Event::TermSignal(Signal::SIGUSR2) => {
+                if args.verbose {
+                    writeln!(io::stderr(),
+                             "# SIGUSR2, detaching and exiting").unwrap();
+                }
+                for ns in nsps.iter_mut() {
+                    ns.set_detach();
+                }
+                break;
+            },
+            Event::TermSignal(Signal::SIGUSR1) => {
+                if args.verbose {
+                    writeln!(io::stderr(),
+                             "# SIGUSR1, handing off and exiting").unwrap();
+                }
+                for ns in nsps.iter_mut() {
+                    ns.set_keep();
+                }
+                break;
+            },
+            Event::Timeout(id) if Some(id) == ttl_timer => {
+                if args.verbose {
+                    writeln!(io::stderr(), "# TTL expired, exiting").unwrap();
+                }
+                exit_code = EXIT_TTL_EXPIRED;
+                break;
+            },
             Event::TermSignal(sig) => {
                 if args.verbose {
                     writeln!(io::stderr(), "# {:?}, exiting", sig).unwrap();
                 }
                 break;
             },
-            Event::ChildExit(pid) => {
-                use nix::sys::wait::waitpid;
-                let status = waitpid(pid, None).unwrap();
-                writeln!(io::stderr(),
-                         "# unexpected SIGCHLD(pid={}; status={:?})",
-                         pid, status).unwrap();
+            Event::Reload => {
+                match nsps.recreate_missing(
+                    args.lo_addr.as_ref().map(|a| a.as_str()), runner) {
+                    Ok(n) if args.verbose => {
+                        writeln!(io::stderr(), "# SIGHUP, re-created {} \
+                                                namespaces", n).unwrap();
+                    },
+                    Ok(_) => (),
+                    Err(e) => {
+                        writeln!(io::stderr(), "reload: {}", e).unwrap();
+                    }
+                }
+            },
+            Event::ChildExit(pid, status) => {
+                if unexpected_child_exit(runner.child_env().unexpected_child,
+                                         pid, &status) {
+                    exit_code = 1;
+                    break;
+                }
+            },
+            Event::Error(e) => {
+                writeln!(io::stderr(), "idle loop: {}; tearing down",
+                         e).unwrap();
+                exit_code = 1;
+                break;
             },
+            // No other timers were set, no fds registered, no notify
+            // signals configured, and stdin is discarded.
+            Event::Timeout(_) | Event::FdReady(..) | Event::FdHangup(_)
+                | Event::NotifySignal(_) | Event::StdinLine(_) =>
+                unreachable!(),
         }
     }
-    Ok(())
+
+    drop(manifest);
+    drop(aliases);
+    let start = Instant::now();
+    let result = nsps.teardown();
+    metrics.set_teardown_total(start.elapsed());
+    if args.verbose {
+        write!(io::stderr(), "{}", metrics.timing_report()).unwrap();
+    }
+    result.map(|_| exit_code)
+}
+
+fn inner_main(args: Args) -> Result<i32, HLError> {
+    let started = Instant::now();
+    try!(validate(&args));
+
+    // This must happen before prepare_signals starts its thread.
+    if args.setsid && !args.dryrun {
+        try!(become_session_leader());
+    }
+
+    let mut signals = SignalConfig::new();
+    if args.reload_on_hup {
+        signals = signals.reload(Signal::SIGHUP);
+    }
+    if args.status_signals {
+        signals = signals.status(Signal::SIGUSR1).status(Signal::SIGUSR2);
+    }
+    let (sigfd, child_mask) = try!(signals.prepare());
+
+    // The old instance's pid file may be the same as ours, so it must
+    // be gone before we create ours.
+    if let Some(ref path) = args.handoff_from {
+        try!(take_over_from(Path::new(path), sigfd, args.verbose,
+                            args.dryrun));
+    }
+
+    let _pid_file = match args.pid_file {
+        Some(ref path) if !args.dryrun =>
+            Some(try!(PidFile::create(Path::new(path)))),
+        _ => None
+    };
+
+    let vars = prepare_child_env(args.inherit_path);
+    let child_env = ChildEnv {
+        tools: try!(resolve_tools(&vars, &["ip"])),
+        env: vars,
+        mask: child_mask,
+        verbose: args.verbose,
+        dryrun: args.dryrun,
+        unexpected_child: args.unexpected_child,
+        umask: args.umask,
+        recorder: None,
+        child_fd_cap: None
+    };
+
+    if args.reap_idle {
+        return reap_idle_namespaces(&args.prefix, &child_env,
+                                    Path::new(NETNS_RUN_DIR));
+    }
+
+    // After a handoff, existing namespaces are expected, not leftovers.
+    if !args.quiet && args.handoff_from.is_none() {
+        for &(ref prefix, _) in &args.pools {
+            warn_about_leftovers(prefix, &child_env);
+        }
+    }
+
+    let mut metrics = Metrics::new();
+    let (nsps, aliases, manifest) =
+        try!(set_up(&args, started, sigfd, Path::new(NETNS_RUN_DIR),
+                    &mut metrics, &child_env));
+    close_stdout();
+    if let (true, Some(ready)) = (args.verbose, metrics.ready()) {
+        writeln!(io::stderr(), "# {}", Metrics::ready_message(ready)).unwrap();
+    }
+
+    let mut idle = IdleLoop::new(sigfd);
+    idle.set_signal_config(&signals);
+    idle.set_stdin_eof_grace(Duration::from_millis(args.stdin_eof_grace));
+    supervise(&args, started, idle, nsps, aliases, manifest, &mut metrics,
+              &child_env)
 }
 
 fn main() {
     process::exit(match inner_main(parse_cmdline()) {
-        Ok(_) => 0,
+        Ok(code) => code,
         Err(ref e) => {
             writeln!(io::stderr(), "{}", e).unwrap();
-            1
+            match e {
+                &HLError::IncompleteCleanup { .. } => 2,
+                _ => 1
+            }
         }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::{HashMap, VecDeque};
+    use std::process::Child;
+
+    use nix::sys::signal::{SigSet, Signal};
+    use super::*;
+
+    /// What a MockRunner does when asked to run a particular command:
+    /// print OUTPUT and succeed, or fail with STDERR.
+    #[derive(Clone)]
+    enum Outcome {
+        Output(&'static str),
+        Fail(&'static str),
+    }
+
+    /// A Runner that runs nothing.  It records each command it is
+    /// asked to run (as the words of the command joined with spaces,
+    /// preceded by "netns NAME: " if it was to be run inside namespace
+    /// NAME) and each signal it is asked to send, and answers with
+    /// the outcomes scripted for that command, in order; the last one
+    /// is repeated as often as necessary.  Commands with no script
+    /// succeed and print nothing.
+    ///
+    /// Its ChildEnv is set for a dry run, so nothing that consults that
+    /// instead of the runner (such as NsConfDir) touches the system.
+    struct MockRunner {
+        env: ChildEnv,
+        script: RefCell<HashMap<String, VecDeque<Outcome>>>,
+        calls: RefCell<Vec<String>>,
+        kills: RefCell<Vec<(pid_t, Signal)>>,
+        unkillable: Vec<pid_t>,
+    }
+    impl MockRunner {
+        fn new() -> MockRunner {
+            MockRunner {
+                env: ChildEnv {
+                    env: Vec::new(),
+                    mask: SigSet::empty(),
+                    verbose: false,
+                    dryrun: true,
+                    unexpected_child: UnexpectedChildPolicy::Ignore,
+                    umask: DEFAULT_UMASK,
+                    recorder: None,
+                    tools: HashMap::new(),
+                    child_fd_cap: None
+                },
+                script: RefCell::new(HashMap::new()),
+                calls: RefCell::new(Vec::new()),
+                kills: RefCell::new(Vec::new()),
+                unkillable: Vec::new(),
+            }
+        }
+
+        /// Script the outcomes of COMMAND.
+        fn on(self, command: &str, outcomes: &[Outcome]) -> MockRunner {
+            self.script.borrow_mut().insert(String::from(command),
+                                            outcomes.iter().cloned()
+                                            .collect());
+            self
+        }
+
+        /// Make every attempt to signal PID fail.
+        fn unkillable(mut self, pid: pid_t) -> MockRunner {
+            self.unkillable.push(pid);
+            self
+        }
+
+        fn calls(&self) -> Vec<String> {
+            self.calls.borrow().clone()
+        }
+
+        fn kills(&self) -> Vec<(pid_t, Signal)> {
+            self.kills.borrow().clone()
+        }
+
+        /// Internal: record COMMAND and produce its next outcome.
+        fn answer(&self, command: String) -> Result<Vec<u8>, HLError> {
+            let outcome = match self.script.borrow_mut().get_mut(&command) {
+                Some(queue) if queue.len() > 1 => queue.pop_front(),
+                Some(queue) => queue.front().cloned(),
+                None => None
+            };
+            self.calls.borrow_mut().push(command.clone());
+            match outcome {
+                None => Ok(Vec::new()),
+                Some(Outcome::Output(text)) => Ok(text.as_bytes().to_vec()),
+                Some(Outcome::Fail(stderr)) => Err(HLError::UnsuccessfulChild {
+                    status: String::from("exited with code 1"),
+                    cmdline: command,
+                    stderr: String::from(stderr)
+                })
+            }
+        }
+    }
+    impl Runner for MockRunner {
+        fn child_env(&self) -> &ChildEnv {
+            &self.env
+        }
+        fn run(&self, argv: &[&str]) -> Result<(), HLError> {
+            self.answer(argv.join(" ")).map(|_| ())
+        }
+        fn run_get_output(&self, argv: &[&str]) -> Result<Vec<u8>, HLError> {
+            self.answer(argv.join(" "))
+        }
+        fn run_query_get_output(&self, argv: &[&str])
+                                -> Result<Vec<u8>, HLError> {
+            self.answer(argv.join(" "))
+        }
+        fn spawn(&self, argv: &[&str]) -> Result<Child, HLError> {
+            panic!("MockRunner can't spawn {}", argv.join(" "));
+        }
+        fn run_in_netns(&self, namespace: &str, argv: &[&str])
+                        -> Result<(), HLError> {
+            self.answer(format!("netns {}: {}", namespace, argv.join(" ")))
+                .map(|_| ())
+        }
+        #[cfg(target_os = "linux")]
+        fn run_in_netns_direct(&self, namespace: &str, argv: &[&str])
+                               -> Result<(), HLError> {
+            self.run_in_netns(namespace, argv)
+        }
+        fn kill(&self, pid: pid_t, sig: Signal) -> Result<(), HLError> {
+            self.kills.borrow_mut().push((pid, sig));
+            if self.unkillable.contains(&pid) {
+                Err(map_nix_err(nix::Error::Sys(nix::Errno::EPERM),
+                                format!("kill process {}", pid)))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn strings(words: &[&str]) -> Vec<String> {
+        words.iter().map(|&w| String::from(w)).collect()
+    }
+
+    #[test]
+    fn namespace_is_created_and_torn_down() {
+        let runner = MockRunner::new();
+        {
+            let mut nsps = NamespaceSet::new(3);
+            nsps.push(NetNs::new(String::from("t_ns0"), None, -1, &runner)
+                      .unwrap());
+            assert!(nsps.teardown().is_ok());
+        }
+        assert_eq!(runner.calls(), strings(&[
+            "ip netns add t_ns0",
+            "netns t_ns0: ip link set dev lo up",
+            "ip netns pids t_ns0",
+            "netns t_ns0: ip link set dev lo down",
+            "ip netns del t_ns0",
+        ]));
+        assert!(runner.kills().is_empty());
+    }
+
+    #[test]
+    fn failed_loopback_setup_deletes_the_namespace() {
+        let runner = MockRunner::new()
+            .on("netns t_ns0: ip link set dev lo up",
+                &[Outcome::Fail("RTNETLINK answers: Permission denied")]);
+        assert!(NetNs::new(String::from("t_ns0"), None, -1, &runner)
+                .is_err());
+        assert_eq!(runner.calls().last().unwrap(), "ip netns del t_ns0");
+    }
+
+    #[test]
+    fn processes_in_the_namespace_are_killed() {
+        use nix::sys::signal::Signal::{SIGKILL, SIGTERM};
+
+        let runner = MockRunner::new()
+            .on("ip netns pids t_ns0",
+                &[Outcome::Output("123\n456\n"), Outcome::Output("456\n")]);
+        {
+            let mut nsps = NamespaceSet::new(3);
+            nsps.push(NetNs::new(String::from("t_ns0"), None, -1, &runner)
+                      .unwrap());
+            assert!(nsps.teardown().is_ok());
+        }
+        assert_eq!(runner.kills(),
+                   vec![(123, SIGTERM), (456, SIGTERM), (456, SIGKILL)]);
+    }
+
+    #[test]
+    fn unkillable_processes_are_reported() {
+        use nix::sys::signal::Signal::SIGKILL;
+
+        let runner = MockRunner::new()
+            .on("ip netns pids t_ns0", &[Outcome::Output("77\n")])
+            .on("ip netns pids t_ns1", &[Outcome::Output("88\n")])
+            .on("ip netns del t_ns1",
+                &[Outcome::Fail("Cannot remove namespace file \
+                                 \"/run/netns/t_ns1\": \
+                                 Device or resource busy")])
+            .unkillable(77)
+            .unkillable(88);
+        let result = {
+            let mut nsps = NamespaceSet::new(2);
+            nsps.push(NetNs::new(String::from("t_ns0"), None, -1, &runner)
+                      .unwrap());
+            nsps.push(NetNs::new(String::from("t_ns1"), None, -1, &runner)
+                      .unwrap());
+            nsps.teardown()
+        };
+        match result {
+            Err(HLError::IncompleteCleanup { failures, remaining }) => {
+                assert_eq!(failures, 2);
+                assert_eq!(remaining, "t_ns1 (processes 88), t_ns0");
+            },
+            other => panic!("unexpected teardown result: {:?}", other)
+        }
+        // Once the budget was spent, t_ns0 was left alone.
+        assert!(runner.kills().contains(&(88, SIGKILL)));
+        assert!(!runner.kills().iter().any(|&(pid, _)| pid == 77));
+    }
+
+    #[test]
+    fn only_idle_unmanaged_namespaces_are_reaped() {
+        let run_dir = env::temp_dir().join(format!(
+            "openvpn-netns-tools-reap-{}", nix::unistd::getpid()));
+        fs::create_dir_all(&run_dir).unwrap();
+        for name in &["t_ns0", "t_ns1", "t_ns2", "t_ns3"] {
+            fs::File::create(run_dir.join(name)).unwrap();
+        }
+        let runner = MockRunner::new()
+            .on("ip -j netns list",
+                &[Outcome::Output("[{\"name\":\"t_ns0\"},\
+                                   {\"name\":\"t_ns1\"},\
+                                   {\"name\":\"t_ns2\"},\
+                                   {\"name\":\"t_ns3\"},\
+                                   {\"name\":\"u_ns0\"}]")])
+            .on("ip netns pids t_ns1", &[Outcome::Output("123\n")])
+            .on("ip netns pids t_ns3",
+                &[Outcome::Fail("Cannot open network namespace")]);
+
+        // t_ns2 is managed by a supervisor.
+        let supervisor_lock = lock_netns("t_ns2", &run_dir, false).unwrap();
+        assert!(supervisor_lock.is_some());
+        let exit_code = reap_idle_namespaces("t", &runner, &run_dir)
+            .unwrap();
+        drop(supervisor_lock);
+        fs::remove_dir_all(&run_dir).unwrap();
+
+        // Failing to check t_ns3 doesn't stop the others from being
+        // considered, but it does make the exit status unsuccessful.
+        assert_eq!(exit_code, 1);
+        assert_eq!(runner.calls(), strings(&[
+            "ip -j netns list",
+            "ip netns pids t_ns0",
+            "ip netns del t_ns0",
+            "ip netns pids t_ns1",
+            "ip netns pids t_ns3",
+        ]));
+    }
+
+    #[test]
+    fn namespaces_are_listed_through_the_runner() {
+        let runner = MockRunner::new()
+            .on("ip -j netns list",
+                &[Outcome::Output("[{\"name\":\"a_ns0\"},\
+                                   {\"name\":\"b_ns1\",\"id\":3}]")]);
+        assert_eq!(list_netns(&runner).unwrap(), strings(&["a_ns0", "b_ns1"]));
+    }
+
+    #[test]
+    fn detached_namespaces_keep_their_processes() {
+        let runner = MockRunner::new()
+            .on("ip netns pids t_ns0", &[Outcome::Output("123\n")]);
+        {
+            let mut nsps = NamespaceSet::new(3);
+            nsps.push(NetNs::new(String::from("t_ns0"), None, -1, &runner)
+                      .unwrap());
+            for ns in nsps.iter_mut() {
+                ns.set_detach();
+            }
+            assert!(nsps.teardown().is_ok());
+        }
+        // Only the name goes away.
+        assert_eq!(runner.calls(), strings(&[
+            "ip netns add t_ns0",
+            "netns t_ns0: ip link set dev lo up",
+            "ip netns del t_ns0",
+        ]));
+        assert!(runner.kills().is_empty());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn a_second_signal_cuts_the_grace_period_short() {
+        use libc::{c_int, pthread_kill, pthread_self};
+        use nix::sys::signal::Signal::{SIGKILL, SIGTERM, SIGUSR1};
+
+        let mut runner = MockRunner::new()
+            .on("ip netns pids t_ns0", &[Outcome::Output("123\n")]);
+        // There is no grace period at all in a dry run.
+        runner.env.dryrun = false;
+        let (sigfd, mask) = SignalConfig::with_set(SigSet::empty())
+            .terminate(SIGUSR1).prepare().unwrap();
+        // Built by hand, so that nothing touches /etc/netns.
+        let mut confdir = NsConfDir::adopt("t_ns0", runner.child_env());
+        confdir.keep();
+        let mut ns = NetNs { name: String::from("t_ns0"), detach: false,
+                             keep: false, torn_down: false, sigfd: sigfd,
+                             confdir: confdir, unkilled: Vec::new(),
+                             lock: None, runner: &runner };
+
+        assert_eq!(unsafe { pthread_kill(pthread_self(), SIGUSR1 as c_int) },
+                   0);
+        let start = Instant::now();
+        ns.kill_processes_in_namespace().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(4));
+        assert!(IMPATIENT.load(Ordering::SeqCst));
+        IMPATIENT.store(false, Ordering::SeqCst);
+        assert_eq!(runner.kills(), vec![(123, SIGTERM), (123, SIGKILL)]);
+        shutdown_signals(sigfd, mask).unwrap();
+    }
+
+    /// Internal: the Args for creating POOLS, with every option at its
+    /// default.
+    fn args(pools: &[(&str, u32)]) -> Args {
+        Args {
+            prefix: String::new(),
+            pools: pools.iter().map(|&(p, n)| (String::from(p), n))
+                .collect(),
+            reap_idle: false,
+            stdin_eof_grace: 0,
+            pid_file: None,
+            handoff_from: None,
+            manifest: None,
+            aliases: Vec::new(),
+            hosts_file: None,
+            resolv_conf: None,
+            nameservers: Vec::new(),
+            lo_addr: None,
+            failure_budget: 32,
+            inherit_path: false,
+            setsid: false,
+            quiet: false,
+            ttl: 0,
+            reload_on_hup: false,
+            status_signals: false,
+            unexpected_child: UnexpectedChildPolicy::Warn,
+            umask: DEFAULT_UMASK,
+            dryrun: true,
+            verbose: false
+        }
+    }
+
+    #[test]
+    fn aliases_must_name_one_of_the_namespaces() {
+        let mut a = args(&[("t", 2)]);
+        a.aliases = vec![(String::from("stable"), String::from("t_ns1"))];
+        assert!(validate(&a).is_ok());
+
+        for &(alias, target) in &[("stable", "t_ns2"), ("stable", "u_ns0"),
+                                  ("t_ns0", "t_ns1"), ("no-dashes", "t_ns0"),
+                                  ("", "t_ns0")] {
+            a.aliases = vec![(String::from(alias), String::from(target))];
+            assert!(validate(&a).is_err(), "{}={}", alias, target);
+        }
+
+        let run_dir = env::temp_dir().join(format!(
+            "openvpn-netns-tools-alias-{}", nix::unistd::getpid()));
+        fs::create_dir_all(&run_dir).unwrap();
+        let stable = run_dir.join("stable");
+        let aliases = [(String::from("stable"), String::from("t_ns1"))];
+
+        // In a dry run, the links are only reported.
+        let mut runner = MockRunner::new();
+        drop(create_aliases(&aliases, &run_dir, runner.child_env())
+             .unwrap());
+        assert!(fs::symlink_metadata(&stable).is_err());
+
+        runner.env.dryrun = false;
+        {
+            let links = create_aliases(&aliases, &run_dir,
+                                       runner.child_env()).unwrap();
+            assert_eq!(links.len(), 1);
+            assert_eq!(fs::read_link(&stable).unwrap(), Path::new("t_ns1"));
+            // An alias that is already taken is an error.
+            assert!(create_aliases(&aliases, &run_dir, runner.child_env())
+                    .is_err());
+        }
+        assert!(fs::symlink_metadata(&stable).is_err());
+        fs::remove_dir(&run_dir).unwrap();
+    }
+
+    #[test]
+    fn the_callers_path_is_only_inherited_on_request() {
+        let path = |vars: &[(String, String)]| {
+            vars.iter().find(|&&(ref k, _)| k == "PATH")
+                .map(|&(_, ref v)| v.clone())
+        };
+        assert_eq!(path(&prepare_child_env(false)),
+                   Some(String::from(SAFE_PATH)));
+        assert_eq!(path(&prepare_child_env(true)), env::var("PATH").ok());
+
+        // Allowed, with a warning, as long as we aren't setuid.
+        let mut a = args(&[("t", 1)]);
+        a.inherit_path = true;
+        assert!(validate(&a).is_ok());
+    }
+
+    #[test]
+    fn leftover_namespaces_are_found_by_prefix() {
+        let runner = MockRunner::new()
+            .on("ip -j netns list",
+                &[Outcome::Output("[{\"name\":\"t_ns0\"},\
+                                   {\"name\":\"tt_ns0\"},\
+                                   {\"name\":\"u_ns3\"},\
+                                   {\"name\":\"t_ns7\"}]"),
+                  Outcome::Fail("Permission denied")]);
+        assert_eq!(leftover_namespaces("t", &runner).unwrap(),
+                   strings(&["t_ns0", "t_ns7"]));
+        // Failing to check is only worth a warning.
+        assert!(leftover_namespaces("t", &runner).is_err());
+        warn_about_leftovers("t", &runner);
+    }
+
+    #[test]
+    fn unexpected_children_are_handled_as_configured() {
+        let status = WaitStatus::Exited(4321, 1);
+        assert!(!unexpected_child_exit(UnexpectedChildPolicy::Ignore, 4321,
+                                       &status));
+        assert!(!unexpected_child_exit(UnexpectedChildPolicy::Warn, 4321,
+                                       &status));
+        assert!(unexpected_child_exit(UnexpectedChildPolicy::Fatal, 4321,
+                                      &status));
+
+        assert_eq!(UnexpectedChildPolicy::from_name("fatal"),
+                   Some(UnexpectedChildPolicy::Fatal));
+        assert_eq!(UnexpectedChildPolicy::from_name("Fatal"), None);
+    }
+
+    #[test]
+    fn batch_files_list_pools() {
+        let pools = parse_batch_file("pools", "# two pools\n\
+                                               a 3\n\
+                                               \n\
+                                               \tb   1  # the other\n")
+            .unwrap();
+        assert_eq!(pools, vec![(String::from("a"), 3),
+                               (String::from("b"), 1)]);
+
+        assert_eq!(parse_batch_file("pools", "a 3\nb\n").unwrap_err(),
+                   "pools:2: expected \"PREFIX N\"");
+        assert_eq!(parse_batch_file("pools", "a three\n").unwrap_err(),
+                   "pools:1: invalid number of namespaces: \"three\"");
+        assert_eq!(parse_batch_file("pools", "# nothing\n").unwrap_err(),
+                   "pools: no pool specifications");
+
+        let path = env::temp_dir().join(format!(
+            "openvpn-netns-tools-batch-{}", nix::unistd::getpid()));
+        fs::write(&path, "c 2\n").unwrap();
+        let path_str = path.to_str().unwrap();
+        assert_eq!(read_batch_file(path_str).unwrap(),
+                   vec![(String::from("c"), 2)]);
+        fs::remove_file(&path).unwrap();
+        assert!(read_batch_file(path_str).unwrap_err()
+                .starts_with(path_str));
+    }
+
+    #[test]
+    fn hosts_files_are_checked() {
+        let path = env::temp_dir().join(format!(
+            "openvpn-netns-tools-hosts-{}", nix::unistd::getpid()));
+        let path_str = path.to_str().unwrap();
+        let check = |contents: &str| {
+            fs::write(&path, contents).unwrap();
+            read_hosts_file(path_str)
+        };
+
+        let good = "# local\n127.0.0.1 localhost\n\n\
+                    ::1 localhost ip6-localhost # v6\n\
+                    10.0.0.2\tgateway.example gateway\n";
+        assert_eq!(check(good).unwrap(), good);
+        // Errors give the line number, but nothing from the file.
+        assert_eq!(check("localhost 127.0.0.1\n").unwrap_err(),
+                   format!("{}:1: invalid address", path_str));
+        assert_eq!(check("# ok\n10.0.0.1\n").unwrap_err(),
+                   format!("{}:2: no host names", path_str));
+        assert_eq!(check("10.0.0.1 bad/name\n").unwrap_err(),
+                   format!("{}:1: invalid host name", path_str));
+
+        let mut a = args(&[("t", 1)]);
+        a.hosts_file = Some(String::from(path_str));
+        assert!(validate(&a).is_err());
+        fs::remove_file(&path).unwrap();
+        assert!(validate(&a).is_err());
+    }
+
+    #[test]
+    fn nothing_is_run_when_there_is_nothing_to_tear_down() {
+        let runner = MockRunner::new()
+            .on("ip netns add t_ns0",
+                &[Outcome::Fail("mount --make-shared /run/netns failed: \
+                                 Operation not permitted")]);
+        {
+            let mut nsps = NamespaceSet::new(3);
+            assert!(NetNs::new(String::from("t_ns0"), None, -1, &runner)
+                    .is_err());
+            assert!(nsps.teardown().is_ok());
+        }
+        assert_eq!(runner.calls(), strings(&["ip netns add t_ns0"]));
+
+        // Nor is anything run twice.
+        let runner = MockRunner::new();
+        {
+            let mut nsps = NamespaceSet::new(3);
+            nsps.push(NetNs::new(String::from("t_ns0"), None, -1, &runner)
+                      .unwrap());
+            assert!(nsps.teardown().is_ok());
+        }
+        assert_eq!(runner.calls().iter()
+                   .filter(|c| *c == "ip netns del t_ns0").count(), 1);
+    }
+
+    #[test]
+    fn the_loopback_address_can_be_replaced() {
+        let runner = MockRunner::new();
+        {
+            let mut nsps = NamespaceSet::new(3);
+            nsps.push(NetNs::new(String::from("t_ns0"), Some("127.0.0.2/8"),
+                                 -1, &runner).unwrap());
+            assert!(nsps.teardown().is_ok());
+        }
+        assert_eq!(&runner.calls()[..4], &strings(&[
+            "ip netns add t_ns0",
+            "netns t_ns0: ip addr flush dev lo",
+            "netns t_ns0: ip addr add 127.0.0.2/8 dev lo",
+            "netns t_ns0: ip link set dev lo up",
+        ])[..]);
+
+        assert!(check_lo_addr("127.0.0.2/8").is_ok());
+        assert!(check_lo_addr("::1/128").is_ok());
+        // Unconventional, but allowed.
+        assert!(check_lo_addr("10.1.2.3/24").is_ok());
+        for &bad in &["127.0.0.2", "127.0.0.2/33", "::1/129", "lo/8", ""] {
+            assert!(check_lo_addr(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn manifests_describe_the_namespaces() {
+        let mut a = args(&[("a", 2), ("b", 1)]);
+        a.aliases = vec![(String::from("stable"), String::from("a_ns1"))];
+        a.lo_addr = Some(String::from("127.0.0.2/8"));
+        let manifest: serde_json::Value =
+            serde_json::from_str(&manifest_json(&a)).unwrap();
+        assert_eq!(manifest["pid"], json!(nix::unistd::getpid()));
+        assert_eq!(manifest["namespaces"][1], json!({
+            "name": "a_ns1",
+            "prefix": "a",
+            "index": 1,
+            "confdir": "/etc/netns/a_ns1",
+            "lo_addr": "127.0.0.2/8"
+        }));
+        assert_eq!(manifest["namespaces"][2]["name"], json!("b_ns0"));
+        assert_eq!(manifest["aliases"],
+                   json!([{ "alias": "stable", "target": "a_ns1" }]));
+
+        // It is written in one go, and removed on drop.
+        let mut runner = MockRunner::new();
+        runner.env.dryrun = false;
+        let path = env::temp_dir().join(format!(
+            "openvpn-netns-tools-manifest-{}", nix::unistd::getpid()));
+        {
+            let _manifest = Manifest::write(&path, "{}\n",
+                                            runner.child_env()).unwrap();
+            assert_eq!(fs::read_to_string(&path).unwrap(), "{}\n");
+            let mut tmp = path.as_os_str().to_owned();
+            tmp.push(".tmp");
+            assert!(!Path::new(&tmp).exists());
+        }
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn the_whole_configuration_is_validated_up_front() {
+        assert!(validate(&args(&[("t", 1), ("u", 1024)])).is_ok());
+
+        let long = "x".repeat(MAX_NAME_LEN);
+        for pools in &[vec![("t", 0)], vec![("t", 1025)], vec![("t-1", 1)],
+                       vec![("", 1)], vec![("t", 1), ("t", 2)],
+                       vec![(long.as_str(), 1)]] {
+            assert!(validate(&args(pools)).is_err(), "{:?}", pools);
+        }
+
+        let mut a = args(&[("t", 1)]);
+        a.failure_budget = 0;
+        assert!(validate(&a).is_err());
+
+        let mut a = args(&[("t", 1)]);
+        a.nameservers = vec![String::from("10.0.0.1"),
+                             String::from("dns.example")];
+        assert!(validate(&a).is_err());
+
+        let mut a = args(&[("t", 1)]);
+        a.lo_addr = Some(String::from("127.0.0.1"));
+        assert!(validate(&a).is_err());
+
+        let mut a = args(&[("t", 1)]);
+        a.resolv_conf = Some(String::from("/nonexistent/resolv.conf"));
+        assert!(validate(&a).is_err());
+
+        // Only the prefix matters for --reap-idle.
+        let mut a = args(&[]);
+        a.reap_idle = true;
+        a.prefix = String::from("t");
+        assert!(validate(&a).is_ok());
+        a.prefix = String::from("t/..");
+        assert!(validate(&a).is_err());
+    }
+
+    #[test]
+    fn existing_confdirs_are_restored_not_removed() {
+        let mut runner = MockRunner::new();
+        runner.env.dryrun = false;
+        let dir = env::temp_dir().join(format!(
+            "openvpn-netns-tools-confdir-{}", nix::unistd::getpid()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("hosts"), "operator's\n").unwrap();
+        fs::write(dir.join("notes"), "keep me\n").unwrap();
+
+        // Built by hand, since path_for always points into /etc/netns.
+        {
+            let mut confdir = NsConfDir { path: dir.clone(), owned: false,
+                                          installed: Vec::new(),
+                                          keep: false,
+                                          env: runner.child_env() };
+            confdir.install_file("hosts", b"ours\n").unwrap();
+            confdir.install_file("hosts", b"ours again\n").unwrap();
+            confdir.install_file("resolv.conf", b"nameserver ::1\n")
+                .unwrap();
+            assert_eq!(fs::read_to_string(dir.join("hosts")).unwrap(),
+                       "ours again\n");
+        }
+        assert_eq!(fs::read_to_string(dir.join("hosts")).unwrap(),
+                   "operator's\n");
+        assert_eq!(fs::read_to_string(dir.join("notes")).unwrap(),
+                   "keep me\n");
+        assert!(!dir.join("resolv.conf").exists());
+
+        // A directory we created is ours to remove, contents and all.
+        {
+            let mut confdir = NsConfDir { path: dir.clone(), owned: true,
+                                          installed: Vec::new(),
+                                          keep: false,
+                                          env: runner.child_env() };
+            confdir.install_file("hosts", b"ours\n").unwrap();
+        }
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn busy_namespaces_are_diagnosed() {
+        let busy = "Cannot remove namespace file \"/run/netns/t_ns0\": \
+                    Device or resource busy";
+        let runner = MockRunner::new()
+            .on("ip netns pids t_ns0",
+                &[Outcome::Output(""), Outcome::Output(""),
+                  Outcome::Output(""), Outcome::Output("999999999\n"),
+                  Outcome::Fail("Permission denied")])
+            .on("ip netns del t_ns0", &[Outcome::Fail(busy)]);
+        let mut ns = NetNs::new(String::from("t_ns0"), None, -1, &runner)
+            .unwrap();
+        let mut budget = 5;
+        let mut log = TeardownLog::new();
+        assert!(!ns.teardown(&mut budget, &mut log));
+        assert_eq!(budget, 4);
+        // Deletion was retried before giving up.
+        assert_eq!(runner.calls().iter()
+                   .filter(|c| *c == "ip netns del t_ns0").count(), 4);
+
+        // Teardown used up two of the scripted answers (once to find
+        // processes to kill, once to diagnose); these get the rest.
+        assert_eq!(ns.diagnose_busy(),
+                   "no processes remain; it may be mounted somewhere \
+                    other than /run/netns");
+        assert_eq!(ns.diagnose_busy(),
+                   "held by process 999999999 ()");
+        assert!(ns.diagnose_busy().starts_with("could not list processes"));
+    }
+
+    #[test]
+    fn namespaces_are_torn_down_when_a_panic_unwinds() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let runner = MockRunner::new();
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            let mut nsps = NamespaceSet::new(3);
+            nsps.push(NetNs::new(String::from("t_ns0"), None, -1, &runner)
+                      .unwrap());
+            panic!("something went wrong in the idle loop");
+        }));
+        assert!(result.is_err());
+        assert_eq!(runner.calls().last().unwrap(), "ip netns del t_ns0");
+    }
+
+    #[test]
+    fn identical_teardown_errors_are_collapsed() {
+        let mut log = TeardownLog::new();
+        for name in &["t_ns0", "t_ns1", "t_ns2"] {
+            log.record(name, &map_config_err(format!(
+                "ip netns del {}: broken", name)));
+        }
+        log.record("t_ns1", &map_config_err(String::from("different")));
+        assert_eq!(log.messages(), strings(&[
+            "teardown failed for 3 namespaces: Invalid configuration: \
+             ip netns del <namespace>: broken.",
+            "Invalid configuration: different.",
+        ]));
+
+        // With a broken "ip", every namespace fails the same way.
+        let runner = MockRunner::new()
+            .on("netns t_ns0: ip link set dev lo down",
+                &[Outcome::Fail("ip: broken")])
+            .on("netns t_ns1: ip link set dev lo down",
+                &[Outcome::Fail("ip: broken")]);
+        let mut log = TeardownLog::new();
+        let mut budget = 10;
+        for name in &["t_ns0", "t_ns1"] {
+            let mut ns = NetNs::new(String::from(*name), None, -1, &runner)
+                .unwrap();
+            assert!(ns.teardown(&mut budget, &mut log));
+        }
+        assert_eq!(budget, 8);
+        assert_eq!(log.messages().len(), 1);
+        assert!(log.messages()[0].starts_with(
+            "teardown failed for 2 namespaces: "), "{:?}", log.messages());
+    }
+
+    #[test]
+    fn each_namespace_created_is_timed_once() {
+        let runner = MockRunner::new();
+        let mut metrics = Metrics::new();
+        {
+            let _nsps = create_namespaces(&args(&[("a", 2), ("b", 3)]), -1,
+                                          &mut metrics, &runner).unwrap();
+        }
+        assert_eq!(metrics.create_count(), 5);
+        assert!(metrics.timing_report().starts_with("create: "));
+        assert!(metrics.timing_report().contains("create: 5 namespaces"));
+
+        // A namespace that fails to come up isn't counted.
+        let runner = MockRunner::new()
+            .on("ip netns add a_ns1", &[Outcome::Fail("File exists")]);
+        let mut metrics = Metrics::new();
+        assert!(create_namespaces(&args(&[("a", 2)]), -1, &mut metrics,
+                                  &runner).is_err());
+        assert_eq!(metrics.create_count(), 1);
+    }
+
+    #[test]
+    fn namespaces_are_torn_down_when_the_ttl_expires() {
+        use nix::unistd::{close, pipe};
+
+        let runner = MockRunner::new();
+        let mut a = args(&[("t", 1)]);
+        a.ttl = 1;
+        let started = Instant::now();
+        let mut metrics = Metrics::new();
+        let nsps = create_namespaces(&a, -1, &mut metrics, &runner).unwrap();
+        let (quiet, quiet_wr) = pipe().unwrap();
+        let mut idle = IdleLoop::new(quiet);
+        // Nothing ever arrives on stdin, so only the TTL can end this.
+        idle.ignore_stdin();
+        assert_eq!(supervise(&a, started, idle, nsps, Vec::new(), None,
+                             &mut metrics, &runner).unwrap(),
+                   EXIT_TTL_EXPIRED);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert_eq!(runner.calls().last().unwrap(), "ip netns del t_ns0");
+        assert!(metrics.timing_report().contains("teardown: "));
+        close(quiet).unwrap();
+        close(quiet_wr).unwrap();
+    }
+
+    #[test]
+    fn every_pool_in_a_batch_is_created_and_torn_down() {
+        let mut a = args(&[]);
+        a.pools = parse_batch_file("pools", "a 2\nb 1\n").unwrap();
+        let runner = MockRunner::new();
+        let mut metrics = Metrics::new();
+        {
+            let mut nsps = create_namespaces(&a, -1, &mut metrics, &runner)
+                .unwrap();
+            assert_eq!(runner.calls().iter()
+                       .filter(|c| c.starts_with("ip netns add "))
+                       .collect::<Vec<_>>(),
+                       vec!["ip netns add a_ns0", "ip netns add a_ns1",
+                            "ip netns add b_ns0"]);
+            assert!(nsps.teardown().is_ok());
+        }
+        // One teardown covers both pools, newest first.
+        assert_eq!(runner.calls().iter()
+                   .filter(|c| c.starts_with("ip netns del "))
+                   .collect::<Vec<_>>(),
+                   vec!["ip netns del b_ns0", "ip netns del a_ns1",
+                        "ip netns del a_ns0"]);
+    }
+
+    #[test]
+    fn ready_time_is_recorded_once_setup_is_complete() {
+        let runner = MockRunner::new();
+        let started = Instant::now();
+        let mut metrics = Metrics::new();
+        {
+            let _setup = set_up(&args(&[("t", 2)]), started, -1,
+                                Path::new("/nonexistent"), &mut metrics,
+                                &runner).unwrap();
+        }
+        let ready = metrics.ready().unwrap();
+        assert!(ready >= metrics.create_total().unwrap());
+        assert!(ready <= started.elapsed());
+
+        // If setup fails, the program never becomes ready.
+        let runner = MockRunner::new()
+            .on("ip netns add t_ns1", &[Outcome::Fail("File exists")]);
+        let mut metrics = Metrics::new();
+        assert!(set_up(&args(&[("t", 2)]), Instant::now(), -1,
+                       Path::new("/nonexistent"), &mut metrics,
+                       &runner).is_err());
+        assert_eq!(metrics.ready(), None);
+    }
+
+    #[test]
+    fn adopted_namespaces_that_are_kept_are_left_alone() {
+        let runner = MockRunner::new()
+            .on("ip -j netns list",
+                &[Outcome::Output("[{\"name\":\"t_ns0\"},\
+                                   {\"name\":\"t_ns1\"}]")]);
+        let mut a = args(&[("t", 2)]);
+        a.handoff_from = Some(String::from("/nonexistent/old.pid"));
+        let mut metrics = Metrics::new();
+        let mut nsps = create_namespaces(&a, -1, &mut metrics, &runner)
+            .unwrap();
+        // Both already existed, so neither was created.
+        assert!(!runner.calls().iter().any(|c| c.contains("netns add")));
+
+        // Hand them off again, as SIGUSR1 does.
+        for ns in nsps.iter_mut() {
+            ns.set_keep();
+        }
+        let before = runner.calls().len();
+        nsps.teardown().unwrap();
+        drop(nsps);
+        let after = runner.calls();
+        assert!(!after[before..].iter().any(|c| c.contains(" del")),
+                "{:?}", &after[before..]);
+        assert!(runner.kills().is_empty());
+    }
+
+    #[test]
+    fn teardown_errors_collapse_only_whole_namespace_names() {
+        assert_eq!(replace_name("t_ns1 t_ns10 xt_ns1 t_ns1.", "t_ns1", "N"),
+                   "N t_ns10 xt_ns1 N.");
+
+        // Every error mentions t_ns10 as well, which must survive.
+        let mut log = TeardownLog::new();
+        for name in &["t_ns1", "t_ns2"] {
+            log.record(name, &map_config_err(format!(
+                "ip netns del {}: conflicts with t_ns10", name)));
+        }
+        assert_eq!(log.messages(), strings(&[
+            "teardown failed for 2 namespaces: Invalid configuration: \
+             ip netns del <namespace>: conflicts with t_ns10.",
+        ]));
+    }
+}
@@ -0,0 +1,552 @@
+//! Helpers for running as a long-lived supervisor process.
+
+use std::ffi::CString;
+use std::fs;
+use std::io;
+
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use libc::{gid_t, mode_t, pid_t, uid_t, umask};
+use nix::unistd::getpid;
+
+use err::*;
+use idle_loop::*;
+
+/// Internal: true if a process with id PID currently exists.
+/// (It might belong to another user, in which case kill() fails
+/// with EPERM, but it still exists.)
+fn process_exists(pid: pid_t) -> bool {
+    use libc::kill;
+    use nix::Errno;
+
+    if pid <= 0 { return false; }
+    let rv = unsafe { kill(pid, 0) };
+    rv == 0 || Errno::last() == Errno::EPERM
+}
+
+/// Internal: true if the *real* user ID may write to the directory
+/// open as DIR.  Because our programs are setuid root, we must not
+/// let the invoking user write a pid file somewhere they couldn't
+/// have written themselves.  This checks the directory itself, not
+/// a name for it, so it can't be swapped for another one afterward.
+fn dir_writable_by_real_user(dir: RawFd) -> bool {
+    use libc::{faccessat, W_OK};
+
+    unsafe { faccessat(dir, b".\0".as_ptr() as *const _, W_OK, 0) == 0 }
+}
+
+/// Internal: RAII class which puts back the saved filesystem (or, off
+/// Linux, effective) user and group IDs when dropped, so that
+/// as_real_user restores them even if its closure panics.
+struct RealUserGuard {
+    uid: uid_t,
+    gid: gid_t
+}
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl Drop for RealUserGuard {
+    fn drop (&mut self) {
+        use libc::{setfsgid, setfsuid};
+
+        // The user ID must go back first, or we might not be allowed
+        // to change the group ID.
+        unsafe {
+            setfsuid(self.uid);
+            setfsgid(self.gid);
+        }
+    }
+}
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+impl Drop for RealUserGuard {
+    fn drop (&mut self) {
+        use libc::{setegid, seteuid};
+
+        unsafe {
+            seteuid(self.uid);
+            setegid(self.gid);
+        }
+    }
+}
+
+/// Call F with file system permission checks done as the *real* user
+/// and group IDs, then switch back.  Our programs run setuid root, so
+/// any file named by the invoking user must be opened (or created)
+/// inside one of these calls; otherwise they could use us to read or
+/// write files they couldn't have themselves.  Unlike checking first
+/// with access(), this leaves no window in which the file can be
+/// swapped for another one.  Files created by F belong to the real
+/// user.
+///
+/// On Linux this changes only the calling thread's filesystem IDs.
+/// Elsewhere it has to change the effective IDs of the whole process,
+/// so it should not be used while other threads might be creating
+/// files.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn as_real_user<T, F>(f: F) -> io::Result<T>
+    where F: FnOnce() -> io::Result<T>
+{
+    use libc::{getgid, getuid, setfsgid, setfsuid};
+
+    let (uid, gid) = unsafe { (getuid(), getgid()) };
+    // These calls can't report failure, except by not changing
+    // anything, so each is checked by making it again.
+    let _guard = RealUserGuard {
+        gid: unsafe { setfsgid(gid) } as gid_t,
+        uid: unsafe { setfsuid(uid) } as uid_t
+    };
+    if unsafe { setfsgid(gid) } as gid_t != gid
+        || unsafe { setfsuid(uid) } as uid_t != uid {
+        return Err(io::Error::new(ErrorKind::PermissionDenied,
+                                  "could not switch to the real user ID"));
+    }
+    f()
+}
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn as_real_user<T, F>(f: F) -> io::Result<T>
+    where F: FnOnce() -> io::Result<T>
+{
+    use libc::{getegid, geteuid, getgid, getuid, setegid, seteuid};
+
+    let (uid, gid) = unsafe { (getuid(), getgid()) };
+    let _guard = unsafe { RealUserGuard { uid: geteuid(), gid: getegid() } };
+    if unsafe { setegid(gid) } != 0 || unsafe { seteuid(uid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    f()
+}
+
+/// Open PATH for reading with the permissions of the real user; see
+/// as_real_user.
+pub fn open_as_real_user(path: &Path) -> io::Result<fs::File> {
+    as_real_user(|| fs::File::open(path))
+}
+
+/// RAII class which writes the current process's ID to a file, and
+/// removes the file again when dropped.
+pub struct PidFile {
+    path: PathBuf,
+    /// The directory containing the file, opened when the file was
+    /// created; the file's name is looked up relative to this from
+    /// then on.
+    dir: fs::File,
+    name: CString
+}
+impl PidFile {
+    /// Create a pid file at PATH.  If PATH already exists and names
+    /// a process that is still running, this is presumed to be a
+    /// duplicate instance of this program, and an error is returned;
+    /// if the process no longer exists, the stale file is replaced.
+    ///
+    /// The directory is opened with the permissions of the real user,
+    /// and must be writable by them.  Whatever is at PATH already is
+    /// removed with their permissions, and the new file is created
+    /// exclusively, within the directory that was opened, never
+    /// through a symbolic link, so this can't be used to create or
+    /// overwrite files anywhere they couldn't have themselves.  The
+    /// file belongs to our effective user, which read_pid_file checks
+    /// for.
+    pub fn create(path: &Path) -> Result<PidFile, HLError> {
+        use std::os::unix::fs::OpenOptionsExt;
+        use std::os::unix::io::{AsRawFd, FromRawFd};
+        use libc::{openat, unlinkat, O_CLOEXEC, O_CREAT, O_DIRECTORY,
+                   O_EXCL, O_NOFOLLOW, O_WRONLY};
+
+        let what = || format!("pid file {:?}", path);
+        let dir = match path.parent() {
+            Some(d) if d.as_os_str().len() > 0 => d,
+            _ => Path::new(".")
+        };
+        let name = match path.file_name() {
+            Some(name) => try!(CString::new(name.as_bytes())
+                               .map_err(|e| map_io_err(io::Error::from(e),
+                                                       what()))),
+            None => return Err(map_io_err(
+                io::Error::new(ErrorKind::InvalidInput,
+                               "does not name a file"), what()))
+        };
+        let dir = try!(as_real_user(|| {
+            fs::OpenOptions::new().read(true).custom_flags(O_DIRECTORY)
+                .open(dir)
+        }).map_err(|e| map_io_err(e, what())));
+        if !dir_writable_by_real_user(dir.as_raw_fd()) {
+            return Err(map_io_err(
+                io::Error::new(ErrorKind::PermissionDenied,
+                               "directory is not writable"), what()));
+        }
+
+        if let Ok(pid) = pid_from_file(open_in_dir(&dir, &name), path) {
+            if process_exists(pid) {
+                return Err(map_io_err(
+                    io::Error::new(ErrorKind::AlreadyExists,
+                                   format!("process {} is still running",
+                                           pid)),
+                    what()));
+            }
+        }
+
+        try!(as_real_user(|| {
+            if unsafe { unlinkat(dir.as_raw_fd(), name.as_ptr(), 0) } == 0 {
+                return Ok(());
+            }
+            match io::Error::last_os_error() {
+                ref e if e.kind() == ErrorKind::NotFound => Ok(()),
+                e => Err(e)
+            }
+        }).map_err(|e| map_io_err(e, what())));
+        let fd = unsafe {
+            openat(dir.as_raw_fd(), name.as_ptr(),
+                   O_WRONLY | O_CREAT | O_EXCL | O_NOFOLLOW | O_CLOEXEC,
+                   0o644)
+        };
+        if fd < 0 {
+            return Err(map_io_err(io::Error::last_os_error(), what()));
+        }
+        let mut f = unsafe { fs::File::from_raw_fd(fd) };
+        try!(writeln!(f, "{}", getpid())
+             .map_err(|e| map_io_err(e, what())));
+
+        Ok(PidFile { path: path.to_path_buf(), dir: dir, name: name })
+    }
+}
+impl Drop for PidFile {
+    fn drop (&mut self) {
+        use std::os::unix::io::AsRawFd;
+        use libc::unlinkat;
+
+        // If it isn't ours any more, someone else must have replaced
+        // it, and it isn't ours to remove.
+        match pid_from_file(open_in_dir(&self.dir, &self.name),
+                            &self.path) {
+            Ok(pid) if pid == getpid() => (),
+            _ => return
+        }
+        if unsafe { unlinkat(self.dir.as_raw_fd(), self.name.as_ptr(), 0) }
+            != 0 {
+            writeln!(io::stderr(),
+                     "warning: could not delete {:?}: {:?}",
+                     &self.path, io::Error::last_os_error()).unwrap();
+        }
+    }
+}
+
+/// Make this process the leader of a new session, with no controlling
+/// terminal, so that nothing that happens to the terminal it was
+/// started from (such as the terminal being closed, which sends
+/// SIGHUP) can affect it.  This must be called before any threads
+/// or child processes are created.
+///
+/// If the process is already a session leader, this does nothing.
+/// setsid() refuses to work in a process that is a process group
+/// leader (as a command run directly from an interactive shell
+/// usually is); the usual workaround is to fork, but that would
+/// change our process ID out from under whoever started us, so in
+/// that case an error is returned instead.
+pub fn become_session_leader() -> Result<(), HLError> {
+    use libc::{getpgrp, getsid};
+    use nix::unistd::setsid;
+
+    let pid = getpid();
+    if unsafe { getsid(0) } == pid {
+        return Ok(());
+    }
+    if unsafe { getpgrp() } == pid {
+        return Err(map_io_err(
+            io::Error::new(ErrorKind::Other,
+                           "already a process group leader; run this \
+                            program via setsid(1) or from a script"),
+            String::from("setsid")));
+    }
+    setsid()
+        .map(|_| ())
+        .map_err(|e| map_nix_err(e, String::from("setsid")))
+}
+
+/// The umask used, unless overridden, while creating configuration
+/// files and directories: files come out mode 0644 and directories
+/// mode 0755, whatever umask we were started with.
+pub const DEFAULT_UMASK: u32 = 0o022;
+
+/// Internal: RAII class which puts back a saved umask when dropped,
+/// so that with_umask restores it even if its closure panics.
+struct UmaskGuard {
+    saved: mode_t
+}
+impl Drop for UmaskGuard {
+    fn drop (&mut self) {
+        unsafe { umask(self.saved); }
+    }
+}
+
+/// Call F with the process umask set to MASK, then restore the umask
+/// that was in effect before.  The umask is process-wide, so this
+/// affects files created by all threads (and child processes spawned
+/// by F) in the meantime.
+pub fn with_umask<T, F>(mask: u32, f: F) -> T where F: FnOnce() -> T {
+    let _guard = UmaskGuard { saved: unsafe { umask(mask as mode_t) } };
+    f()
+}
+
+/// Read the process ID recorded in the pid file at PATH.  The file
+/// must belong to our effective user (root, when running setuid), as
+/// files written by PidFile do, and not be a symbolic link; otherwise
+/// anyone could point us at a process of their choosing.
+pub fn read_pid_file(path: &Path) -> Result<pid_t, HLError> {
+    use std::os::unix::fs::OpenOptionsExt;
+    use libc::O_NOFOLLOW;
+
+    pid_from_file(fs::OpenOptions::new().read(true)
+                  .custom_flags(O_NOFOLLOW).open(path), path)
+}
+
+/// Internal: open the file called NAME in the directory DIR for
+/// reading, not following a symbolic link.
+fn open_in_dir(dir: &fs::File, name: &CString) -> io::Result<fs::File> {
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use libc::{openat, O_CLOEXEC, O_NOFOLLOW, O_RDONLY};
+
+    let fd = unsafe {
+        openat(dir.as_raw_fd(), name.as_ptr(),
+               O_RDONLY | O_NOFOLLOW | O_CLOEXEC)
+    };
+    if fd < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(unsafe { fs::File::from_raw_fd(fd) })
+    }
+}
+
+/// Internal: the rest of read_pid_file, once the file at PATH has been
+/// opened (or not) as FILE.
+fn pid_from_file(file: io::Result<fs::File>, path: &Path)
+                 -> Result<pid_t, HLError> {
+    use std::os::unix::fs::MetadataExt;
+    use libc::geteuid;
+
+    let mut contents = String::new();
+    try!(file
+         .and_then(|f| {
+             let meta = try!(f.metadata());
+             if meta.uid() != unsafe { geteuid() } {
+                 return Err(io::Error::new(
+                     ErrorKind::PermissionDenied,
+                     format!("owned by uid {}, not by uid {}",
+                             meta.uid(), unsafe { geteuid() })));
+             }
+             Ok(f)
+         })
+         .and_then(|mut f| f.read_to_string(&mut contents))
+         .map_err(|e| map_io_err(e, format!("pid file {:?}", path))));
+    contents.trim().parse::<pid_t>()
+        .map_err(|e| map_pi_err(e, format!("in pid file {:?}", path)))
+}
+
+/// Fail unless process PID is running the same program as we are, as
+/// judged by /proc/PID/exe.  If the program file has been replaced
+/// since that process started, as it would be by an upgrade, it still
+/// counts as the same program.
+pub fn check_same_program(pid: pid_t) -> Result<(), HLError> {
+    use std::env;
+
+    let ours = try!(env::current_exe()
+                    .map_err(|e| map_io_err(e, String::from(
+                        "locating this program"))));
+    let proc_exe = format!("/proc/{}/exe", pid);
+    let theirs = try!(fs::read_link(&proc_exe)
+                      .map_err(|e| map_io_err(e, proc_exe.clone())));
+    let theirs = theirs.as_os_str().as_bytes();
+    let theirs = if theirs.ends_with(b" (deleted)") {
+        &theirs[..theirs.len() - b" (deleted)".len()]
+    } else {
+        theirs
+    };
+    if theirs != ours.as_os_str().as_bytes() {
+        return Err(map_config_err(format!(
+            "process {} is running {:?}, not {:?}", pid,
+            String::from_utf8_lossy(theirs), ours)));
+    }
+    Ok(())
+}
+
+/// Wait up to TIMEOUT for the pid file at PATH to be removed, which
+/// its owner does as the very last thing before exiting.  (Watching
+/// the process itself is not reliable, because it may linger as a
+/// zombie until its parent gets around to reaping it.)  Gives up
+/// early if a signal arrives on SIGNAL_PIPE.
+pub fn wait_for_pid_file_removal(path: &Path, timeout: Duration,
+                                 signal_pipe: RawFd)
+                                 -> Result<(), HLError> {
+    let deadline = Instant::now() + timeout;
+    while path.exists() {
+        if Instant::now() >= deadline {
+            return Err(map_io_err(
+                io::Error::new(ErrorKind::TimedOut,
+                               "still present after handoff request"),
+                format!("pid file {:?}", path)));
+        }
+        if let SleepOutcome::Interrupted(sig) =
+            interruptible_sleep(Duration::from_millis(50), signal_pipe) {
+            return Err(map_io_err(
+                io::Error::new(ErrorKind::Interrupted,
+                               format!("{:?} while waiting for handoff", sig)),
+                format!("pid file {:?}", path)));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use nix::unistd::getpid;
+    use super::*;
+
+    /// A name for a scratch file in the temporary directory.
+    fn scratch_path(tag: &str) -> PathBuf {
+        env::temp_dir().join(format!("openvpn-netns-tools-{}-{}",
+                                     tag, getpid()))
+    }
+
+    #[test]
+    fn pid_file_records_our_pid_and_is_removed() {
+        let path = scratch_path("pidfile");
+        {
+            let _pid_file = PidFile::create(&path).unwrap();
+            assert_eq!(read_pid_file(&path).unwrap(), getpid());
+            // We are still running, so a second instance is refused.
+            assert!(PidFile::create(&path).is_err());
+        }
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn pid_file_must_not_be_a_symlink() {
+        use std::os::unix::fs::symlink;
+
+        let target = scratch_path("pid-target");
+        let link = scratch_path("pid-link");
+        fs::write(&target, format!("{}\n", getpid())).unwrap();
+        symlink(&target, &link).unwrap();
+        assert!(read_pid_file(&target).is_ok());
+        assert!(read_pid_file(&link).is_err());
+        fs::remove_file(&link).unwrap();
+        fs::remove_file(&target).unwrap();
+    }
+
+    #[test]
+    fn same_program_check() {
+        use libc::getppid;
+
+        assert!(check_same_program(getpid()).is_ok());
+        // Our parent is cargo, or a shell, not this test program.
+        assert!(check_same_program(unsafe { getppid() }).is_err());
+    }
+
+    #[test]
+    fn stale_pid_file_is_replaced() {
+        let path = scratch_path("stale");
+        // Larger than any process ID the kernel hands out.
+        fs::write(&path, "999999999\n").unwrap();
+        {
+            let _pid_file = PidFile::create(&path).unwrap();
+            assert_eq!(read_pid_file(&path).unwrap(), getpid());
+        }
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn pid_file_is_not_written_through_a_symlink() {
+        use std::os::unix::fs::symlink;
+
+        let target = scratch_path("target");
+        let link = scratch_path("link");
+        fs::write(&target, "precious\n").unwrap();
+        symlink(&target, &link).unwrap();
+        {
+            let _pid_file = PidFile::create(&link).unwrap();
+            assert!(!fs::symlink_metadata(&link).unwrap()
+                    .file_type().is_symlink());
+        }
+        assert_eq!(fs::read_to_string(&target).unwrap(), "precious\n");
+        fs::remove_file(&target).unwrap();
+    }
+
+    #[test]
+    fn pid_file_stays_in_the_directory_that_was_checked() {
+        // What is checked, written to and cleaned up is the directory
+        // that was opened, whatever its name comes to refer to later.
+        let dir = scratch_path("piddir");
+        let moved = scratch_path("piddir-moved");
+        fs::create_dir(&dir).unwrap();
+        {
+            let _pid_file = PidFile::create(&dir.join("pid")).unwrap();
+            fs::rename(&dir, &moved).unwrap();
+            fs::create_dir(&dir).unwrap();
+            fs::write(dir.join("pid"), format!("{}\n", getpid())).unwrap();
+        }
+        assert!(!moved.join("pid").exists());
+        assert!(dir.join("pid").exists());
+        fs::remove_dir(&moved).unwrap();
+        fs::remove_file(dir.join("pid")).unwrap();
+        fs::remove_dir(&dir).unwrap();
+
+        assert!(PidFile::create(Path::new("/")).is_err());
+    }
+
+    #[test]
+    fn session_leadership_is_taken_in_a_child() {
+        use libc::{_exit, fork, getsid, setpgid};
+        use nix::sys::wait::{waitpid, WaitStatus};
+
+        // Run CHECK in a child process, since it changes the session,
+        // and return its exit code.
+        fn in_child<F: FnOnce() -> bool>(check: F) -> i8 {
+            let pid = unsafe { fork() };
+            assert!(pid >= 0);
+            if pid == 0 {
+                unsafe { _exit(if check() { 0 } else { 1 }) };
+            }
+            match waitpid(pid, None).unwrap() {
+                WaitStatus::Exited(_, code) => code,
+                _ => -1
+            }
+        }
+
+        assert_eq!(in_child(|| {
+            become_session_leader().is_ok()
+                && unsafe { getsid(0) } == getpid()
+                // Doing it again is harmless.
+                && become_session_leader().is_ok()
+        }), 0);
+        // A process group leader can't, and is told why.
+        assert_eq!(in_child(|| {
+            unsafe { setpgid(0, 0) };
+            become_session_leader().is_err()
+                && unsafe { getsid(0) } != getpid()
+        }), 0);
+    }
+
+    #[test]
+    fn umask_is_set_temporarily() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::panic;
+
+        let path = scratch_path("umask");
+        let before = unsafe { umask(0o027) };
+        with_umask(DEFAULT_UMASK, || fs::File::create(&path)).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode()
+                   & 0o777, 0o644);
+        fs::remove_file(&path).unwrap();
+
+        // The original comes back, even after a panic.
+        assert!(panic::catch_unwind(|| {
+            with_umask(0o077, || panic!("oops"))
+        }).is_err());
+        assert_eq!(unsafe { umask(before) }, 0o027);
+    }
+}
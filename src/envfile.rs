@@ -0,0 +1,127 @@
+//! Reading environment variable settings from files.
+//!
+//! The format is a deliberately small subset of what "dotenv" tools
+//! accept: each line is either blank, a comment beginning with '#',
+//! or KEY=VALUE.  KEY must be a valid shell variable name.  VALUE is
+//! taken literally, except that if it is entirely enclosed in a
+//! matching pair of single or double quotes, they are removed.  There
+//! is no variable expansion, no escape processing, and no way to
+//! continue a value onto the next line.
+
+use std::ascii::AsciiExt;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use daemon::open_as_real_user;
+use err::*;
+
+/// True if NAME is acceptable as the name of an environment variable:
+/// /^[A-Za-z_][A-Za-z0-9_]*$/.
+pub fn is_valid_env_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii() && (c.is_alphabetic() || c == '_') => (),
+        _ => return false
+    }
+    chars.all(|c| c.is_ascii() && (c.is_alphanumeric() || c == '_'))
+}
+
+/// Internal: remove one level of matching quotes from VALUE, if present.
+fn unquote(value: &str) -> &str {
+    let b = value.as_bytes();
+    if b.len() >= 2 && (b[0] == b'"' || b[0] == b'\'')
+        && b[b.len() - 1] == b[0] {
+        &value[1..value.len() - 1]
+    } else {
+        value
+    }
+}
+
+/// Read the environment file at PATH and return its settings, in
+/// order.  Any line that is not blank, a comment, or a valid KEY=VALUE
+/// setting is a fatal error.  The file is opened with the permissions
+/// of the real user, since it is named by whoever invoked us.
+pub fn read_env_file(path: &Path) -> Result<Vec<(String, String)>, HLError> {
+    let f = try!(open_as_real_user(path)
+                 .map_err(|e| map_io_err(e, format!("{:?}", path))));
+
+    let mut vars = Vec::new();
+    for (lineno, line) in BufReader::new(f).lines().enumerate() {
+        let line = try!(line.map_err(|e| map_io_err(e, format!("{:?}",
+                                                               path))));
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let mut parts = trimmed.splitn(2, '=');
+        let key = parts.next().unwrap_or("");
+        let value = match parts.next() {
+            Some(v) if is_valid_env_name(key) => v,
+            _ => return Err(map_config_err(format!(
+                "{:?}, line {}: expected KEY=VALUE", path, lineno + 1)))
+        };
+        vars.push((String::from(key), String::from(unquote(value))));
+    }
+    Ok(vars)
+}
+
+/// Merge the settings in EXTRA into ENV.  A variable that is already
+/// set in ENV has its value replaced; otherwise it is added at the end.
+pub fn merge_env(env: &mut Vec<(String, String)>,
+                 extra: &[(String, String)]) {
+    for &(ref k, ref v) in extra {
+        match env.iter().position(|&(ref ek, _)| ek == k) {
+            Some(i) => env[i].1 = v.clone(),
+            None => env.push((k.clone(), v.clone()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use nix::unistd::getpid;
+    use super::*;
+
+    fn pairs(items: &[(&str, &str)]) -> Vec<(String, String)> {
+        items.iter().map(|&(k, v)| (String::from(k), String::from(v)))
+            .collect()
+    }
+
+    #[test]
+    fn env_files_are_parsed_literally() {
+        let path = env::temp_dir().join(format!(
+            "openvpn-netns-tools-envfile-{}", getpid()));
+        fs::write(&path, "# settings\n\
+                          \n\
+                          A=1\n\
+                          \x20 B=two \n\
+                          C=\"quoted # not a comment\"\n\
+                          D='$HOME'\n\
+                          E=\"mismatched'\n\
+                          F=\n\
+                          G=x=y\n").unwrap();
+        assert_eq!(read_env_file(&path).unwrap(), pairs(&[
+            ("A", "1"), ("B", "two"), ("C", "quoted # not a comment"),
+            ("D", "$HOME"), ("E", "\"mismatched'"), ("F", ""), ("G", "x=y")
+        ]));
+
+        for bad in &["no_equals\n", "1A=x\n", "A-B=x\n", "A = x\n", "=x\n"] {
+            fs::write(&path, bad).unwrap();
+            let err = read_env_file(&path).unwrap_err();
+            assert!(err.to_string().contains("line 1"), "{}", err);
+        }
+        fs::remove_file(&path).unwrap();
+        assert!(read_env_file(&path).is_err());
+    }
+
+    #[test]
+    fn merged_settings_replace_or_add() {
+        let mut env = pairs(&[("PATH", "/bin"), ("LANG", "C")]);
+        merge_env(&mut env, &pairs(&[("LANG", "en_US.UTF-8"), ("X", "1")]));
+        assert_eq!(env, pairs(&[("PATH", "/bin"), ("LANG", "en_US.UTF-8"),
+                                ("X", "1")]));
+    }
+}
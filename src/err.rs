@@ -1,31 +1,49 @@
 //! Error type and helper functions.
 
+use std::cmp;
 use std::io;
 use std::num;
 use std::fmt;
 use std::str;
 
+use std::ascii::AsciiExt;
 use std::error::Error;
 use std::process::ExitStatus;
+use std::time::Duration;
 use std::os::unix::process::ExitStatusExt;
 
 use nix;
 use nix::sys::signal::Signal;
+use nix::sys::wait::WaitStatus;
+use serde_json;
 
 #[derive(Debug)]
 pub enum HLError {
-    UnsuccessfulChild { status: String, cmdline: String },
+    UnsuccessfulChild { status: String, cmdline: String, stderr: String },
     IOError           { cause: io::Error, detail: String },
     NixError          { cause: nix::Error, detail: String },
     PIError           { cause: num::ParseIntError, detail: String },
     UTF8Error         { cause: str::Utf8Error, detail: String },
+    IncompleteCleanup { failures: u32, remaining: String },
+    NetnsExecFailed   { namespace: String, command: String, reason: String },
+    ConfigError       { detail: String },
+    CommandFailures   { total: usize, failures: Vec<(usize, String, HLError)> },
+    ChildTimedOut     { cmdline: String, timeout: Duration, killed: bool },
+    OutputTooLong     { cmdline: String, limit: usize, read: u64 },
+    JSONError         { cause: serde_json::Error, cmdline: String,
+                        text: String },
 }
 
 impl fmt::Display for HLError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            &HLError::UnsuccessfulChild { ref status, ref cmdline } => {
-                write!(f, "Child process '{}' {}.", cmdline, status)
+            &HLError::UnsuccessfulChild { ref status, ref cmdline,
+                                          ref stderr } => {
+                try!(write!(f, "Child process '{}' {}.", cmdline, status));
+                for line in stderr.lines() {
+                    try!(write!(f, "\n  {}", line));
+                }
+                Ok(())
             },
             &HLError::IOError { ref cause, ref detail } => {
                 write!(f, "{}: {}.", detail, cause)
@@ -38,6 +56,45 @@ impl fmt::Display for HLError {
             },
             &HLError::UTF8Error { ref cause, ref detail } => {
                 write!(f, "Invalid UTF-8 in {}: {}.", detail, cause)
+            },
+            &HLError::IncompleteCleanup { failures, ref remaining } => {
+                write!(f, "Cleanup abandoned after {} failures; \
+                           still present: {}.", failures, remaining)
+            },
+            &HLError::NetnsExecFailed { ref namespace, ref command,
+                                        ref reason } => {
+                write!(f, "Could not execute '{}' inside namespace {}: {}.",
+                       command, namespace, reason)
+            },
+            &HLError::ConfigError { ref detail } => {
+                write!(f, "Invalid configuration: {}.", detail)
+            },
+            &HLError::CommandFailures { total, ref failures } => {
+                try!(write!(f, "{} of {} commands failed:",
+                            failures.len(), total));
+                for &(index, ref cmdline, ref err) in failures {
+                    try!(write!(f, "\n  [{}] {}: {}", index, cmdline, err));
+                }
+                Ok(())
+            },
+            &HLError::ChildTimedOut { ref cmdline, timeout, killed } => {
+                let ms = timeout.as_secs() * 1000
+                    + (timeout.subsec_nanos() / 1_000_000) as u64;
+                write!(f, "Child process '{}' did not finish within {} ms; \
+                           {}.", cmdline, ms,
+                       if killed { "killed" } else { "terminated" })
+            },
+            &HLError::OutputTooLong { ref cmdline, limit, read } => {
+                write!(f, "Child process '{}' produced more than {} bytes \
+                           of output ({} in all); killed.",
+                       cmdline, limit, read)
+            },
+            &HLError::JSONError { ref cause, ref cmdline, ref text } => {
+                try!(write!(f, "Invalid JSON from '{}': {}.", cmdline, cause));
+                for line in text.lines() {
+                    try!(write!(f, "\n  {}", line));
+                }
+                Ok(())
             }
         }
     }
@@ -51,6 +108,13 @@ impl Error for HLError {
             &HLError::NixError          { .. } => "System error",
             &HLError::PIError           { .. } => "Invalid integer",
             &HLError::UTF8Error         { .. } => "Invalid UTF-8 text",
+            &HLError::IncompleteCleanup { .. } => "Cleanup incomplete",
+            &HLError::NetnsExecFailed   { .. } => "Exec in namespace failed",
+            &HLError::ConfigError       { .. } => "Invalid configuration",
+            &HLError::CommandFailures   { .. } => "Commands failed",
+            &HLError::ChildTimedOut     { .. } => "Child process timed out",
+            &HLError::OutputTooLong     { .. } => "Too much output",
+            &HLError::JSONError         { .. } => "Invalid JSON",
         }
     }
     fn cause(&self) -> Option<&Error> {
@@ -60,11 +124,48 @@ impl Error for HLError {
             &HLError::NixError          { ref cause, .. } => Some(cause),
             &HLError::PIError           { ref cause, .. } => Some(cause),
             &HLError::UTF8Error         { ref cause, .. } => Some(cause),
+            &HLError::IncompleteCleanup { .. } => None,
+            &HLError::NetnsExecFailed   { .. } => None,
+            &HLError::ConfigError       { .. } => None,
+            &HLError::CommandFailures   { .. } => None,
+            &HLError::ChildTimedOut     { .. } => None,
+            &HLError::OutputTooLong     { .. } => None,
+            &HLError::JSONError         { ref cause, .. } => Some(cause),
         }
     }
 }
 
-pub fn map_unsuc_child (status: &ExitStatus, cmdline: &[&str]) -> HLError {
+/// Quote ARG, if necessary, so that a POSIX shell would read it back
+/// as a single word with the same value.  Arguments consisting only of
+/// characters that are never special to the shell are left alone;
+/// anything else is wrapped in single quotes, with embedded single
+/// quotes written as '\''.
+pub fn shell_quote(arg: &str) -> String {
+    let plain = !arg.is_empty() && arg.chars().all(|c| {
+        c.is_ascii() && (c.is_alphanumeric() || "-_./,:=@%+".contains(c))
+    });
+    if plain {
+        String::from(arg)
+    } else {
+        format!("'{}'", arg.replace("'", "'\\''"))
+    }
+}
+
+/// Quote each element of ARGV as necessary and join them with spaces,
+/// producing a command line that could be pasted into a shell.
+pub fn shell_quote_argv<S: AsRef<str>>(argv: &[S]) -> String {
+    argv.iter().map(|a| shell_quote(a.as_ref())).collect::<Vec<_>>().join(" ")
+}
+
+pub fn map_unsuc_child<S: AsRef<str>> (status: &ExitStatus, cmdline: &[S])
+                                       -> HLError {
+    map_unsuc_child_with_stderr(status, cmdline, "")
+}
+/// Like map_unsuc_child, but STDERR (typically the last part of what
+/// the child wrote to its stderr) is included in the error message.
+pub fn map_unsuc_child_with_stderr<S: AsRef<str>> (status: &ExitStatus,
+                                                   cmdline: &[S],
+                                                   stderr: &str) -> HLError {
     let status = match status.code() {
         Some(n) => format!("exited unsuccessfully (code {})", n),
         None => match status.signal() {
@@ -80,9 +181,24 @@ pub fn map_unsuc_child (status: &ExitStatus, cmdline: &[&str]) -> HLError {
             None => unreachable!(),
         }
     };
-    // FIXME: shell-quote as necessary.
-    let cmd = cmdline.join(" ");
-    HLError::UnsuccessfulChild { status: status, cmdline: cmd }
+    HLError::UnsuccessfulChild { status: status,
+                                 cmdline: shell_quote_argv(cmdline),
+                                 stderr: String::from(stderr) }
+}
+
+/// Describe STATUS, as reported by waitpid(), for a message such as
+/// "process 1234 {}".
+pub fn describe_wait_status(status: &WaitStatus) -> String {
+    match *status {
+        WaitStatus::Exited(_, 0) => String::from("exited successfully"),
+        WaitStatus::Exited(_, code) => format!("exited with code {}", code),
+        WaitStatus::Signaled(_, sig, true) =>
+            format!("killed by {:?} (core dumped)", sig),
+        WaitStatus::Signaled(_, sig, false) => format!("killed by {:?}", sig),
+        WaitStatus::Stopped(_, sig) => format!("stopped by {:?}", sig),
+        WaitStatus::Continued(_) => String::from("continued"),
+        WaitStatus::StillAlive => String::from("still running"),
+    }
 }
 
 pub fn map_io_err (cause: io::Error, detail: String) -> HLError {
@@ -97,3 +213,74 @@ pub fn map_pi_err (cause: num::ParseIntError, detail: String) -> HLError {
 pub fn map_utf8_err (cause: str::Utf8Error, detail: String) -> HLError {
     HLError::UTF8Error { cause: cause, detail: detail }
 }
+pub fn map_incomplete_cleanup (failures: u32, remaining: &[String])
+                               -> HLError {
+    HLError::IncompleteCleanup { failures: failures,
+                                 remaining: remaining.join(", ") }
+}
+pub fn map_netns_exec_failed (namespace: &str, command: &str, reason: &str)
+                              -> HLError {
+    HLError::NetnsExecFailed { namespace: String::from(namespace),
+                               command: String::from(command),
+                               reason: String::from(reason) }
+}
+pub fn map_config_err (detail: String) -> HLError {
+    HLError::ConfigError { detail: detail }
+}
+pub fn map_child_timed_out (cmdline: &[&str], timeout: Duration,
+                            killed: bool) -> HLError {
+    HLError::ChildTimedOut { cmdline: shell_quote_argv(cmdline),
+                             timeout: timeout, killed: killed }
+}
+pub fn map_output_too_long<S: AsRef<str>> (cmdline: &[S], limit: usize,
+                                           read: u64) -> HLError {
+    HLError::OutputTooLong { cmdline: shell_quote_argv(cmdline),
+                             limit: limit, read: read }
+}
+/// TEXT is what CMDLINE printed that couldn't be parsed; only the
+/// beginning of it is kept.
+pub fn map_json_err<S: AsRef<str>> (cause: serde_json::Error, cmdline: &[S],
+                                    text: &[u8]) -> HLError {
+    let text = &text[..cmp::min(text.len(), 1024)];
+    HLError::JSONError { cause: cause, cmdline: shell_quote_argv(cmdline),
+                         text: String::from(String::from_utf8_lossy(text)) }
+}
+/// Combine the errors from a batch of TOTAL commands into one.  Each
+/// entry of FAILURES is the index of a failed command within the
+/// batch, its command line, and its error.
+pub fn map_command_failures (total: usize,
+                             failures: Vec<(usize, String, HLError)>)
+                             -> HLError {
+    HLError::CommandFailures { total: total, failures: failures }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::ExitStatus;
+    use std::os::unix::process::ExitStatusExt;
+
+    use super::*;
+
+    #[test]
+    fn failed_command_lines_are_shell_quoted() {
+        let status = ExitStatus::from_raw(3 << 8);
+        let err = map_unsuc_child_with_stderr(
+            &status, &["sh", "-c", "echo 'hi'; exit 3"], "hi\n");
+        assert_eq!(format!("{}", err),
+                   "Child process 'sh -c 'echo '\\''hi'\\''; exit 3'' \
+                    exited unsuccessfully (code 3).\n  hi");
+    }
+
+    #[test]
+    fn arguments_are_quoted_only_when_necessary() {
+        assert_eq!(shell_quote("ip"), "ip");
+        assert_eq!(shell_quote("10.0.0.1/8"), "10.0.0.1/8");
+        assert_eq!(shell_quote("a=b,c:d@e%f+g"), "a=b,c:d@e%f+g");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("two words"), "'two words'");
+        assert_eq!(shell_quote("$HOME"), "'$HOME'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote_argv(&["ip", "netns", "exec", "a b"]),
+                   "ip netns exec 'a b'");
+    }
+}
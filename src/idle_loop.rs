@@ -4,16 +4,24 @@
 
 use std::io;
 use std::mem;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
 use nix;
 
-use std::io::{ErrorKind, Read, Write};
-use std::os::unix::io::RawFd;
+use std::io::Write;
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
 use nix::sys::signal::{Signal, SigSet, SIG_BLOCK};
+use nix::poll::{PollFd, EventFlags};
+use nix::sys::wait::WaitStatus;
 use libc::{pid_t, c_int};
 
 use err::*;
+use subprocess::PidFd;
 
 /// Internal: put a file descriptor into non-blocking mode.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
 fn make_nonblocking(fd: RawFd) -> Result<(), HLError> {
     use nix::fcntl::{fcntl, O_NONBLOCK};
     use nix::fcntl::FcntlArg::F_SETFL;
@@ -23,93 +31,100 @@ fn make_nonblocking(fd: RawFd) -> Result<(), HLError> {
         .map_err(|e| map_nix_err(e, format!("make_nonblocking({})", fd)))
 }
 
-/// Internal: Consume and discard data from standard input until
-/// either EOF or EAGAIN.  Returns true for EOF, false for EAGAIN, or
-/// an error.
-fn consume_stdin() -> Result<bool, HLError> {
-    let mut scratch: [u8; 4096] = unsafe { mem::uninitialized() };
-    let mut stdin = io::stdin();
-    loop {
-        match stdin.read(&mut scratch) {
-            Ok(0) => { return Ok(true); },
-            Ok(_) => { continue; },
-            Err(e) => {
-                if e.kind() == ErrorKind::WouldBlock {
-                    return Ok(false);
-                } else {
-                    return Err(map_io_err(e, String::from("stdin")));
-                }
-            }
-        }
-    }
-}
+/// The most reads consume_stdin will do in one call.  A writer that
+/// keeps stdin constantly supplied with data must not be able to keep
+/// the idle loop from getting around to anything else.
+const STDIN_READS_PER_CALL: usize = 16;
 
-// WNOWAIT isn't specified to work with waitpid.
-// Neither nix nor libc exposes waitid.
-// Feh.  Feh, I say.  Feh.
-#[cfg(target_os = "macos")]
-mod ffi {
-    use libc::{c_int, siginfo_t};
+/// The longest line IdleLoop::read_stdin_lines will deliver, not
+/// counting the newline.  Anything longer is discarded.
+const STDIN_LINE_MAX: usize = 4096;
 
-    #[repr(C)]
-    #[allow(dead_code)]
-    #[allow(non_camel_case_types)]
-    pub enum idtype_t { P_ALL, P_PID, P_PGID }
-    #[allow(non_camel_case_types)]
-    pub type id_t = u32;
-
-    pub const WNOHANG : c_int = 0x00000001;
-    pub const WEXITED : c_int = 0x00000004;
-    pub const WNOWAIT : c_int = 0x00000020;
+/// Internal: Consume data from standard input until either EOF, there
+/// is nothing more to read right now, or STDIN_READS_PER_CALL reads
+/// have been done.  If KEEP is provided, the data is appended to it;
+/// otherwise it is discarded.  Returns true for EOF, false otherwise,
+/// or an error.
+///
+/// Stdin is not put into non-blocking mode, because O_NONBLOCK
+/// belongs to the open file description, which we share with our
+/// parent (and whoever else has the same stdin), and they wouldn't
+/// expect it to change underneath them.  Instead, each read is only
+/// done after poll() says it won't block.  The reads bypass
+/// io::stdin(), whose buffer would hide data from poll().
+fn consume_stdin(mut keep: Option<&mut Vec<u8>>) -> Result<bool, HLError> {
+    use nix::poll::{poll, PollFd, POLLIN, POLLHUP, POLLERR, POLLNVAL,
+                    EventFlags};
+    use nix::unistd::read;
+    use nix::Errno::{EAGAIN, EINTR};
 
-    extern {
-        pub fn waitid(idtype: idtype_t, id: id_t,
-                      infop: *mut siginfo_t, options: c_int) -> c_int;
+    let mut scratch = [0u8; 4096];
+    for _ in 0..STDIN_READS_PER_CALL {
+        let mut pfds = [PollFd::new(0 /* stdin */, POLLIN,
+                                    EventFlags::empty())];
+        match poll(&mut pfds, 0) {
+            Ok(0) => return Ok(false),
+            Ok(_) => (),
+            Err(nix::Error::Sys(EINTR)) => continue,
+            Err(e) => return Err(map_nix_err(e, String::from("stdin")))
+        }
+        let revents = pfds[0].revents().unwrap_or(POLLNVAL);
+        if revents.contains(POLLNVAL) {
+            return Ok(true);
+        }
+        if !revents.intersects(POLLIN | POLLHUP | POLLERR) {
+            return Ok(false);
+        }
+        match read(0, &mut scratch) {
+            Ok(0) => return Ok(true),
+            Ok(n) => if let Some(ref mut keep) = keep {
+                keep.extend_from_slice(&scratch[..n]);
+            },
+            Err(nix::Error::Sys(EINTR)) => (),
+            Err(nix::Error::Sys(EAGAIN)) => return Ok(false),
+            Err(e) => return Err(map_nix_err(e, String::from("stdin")))
+        }
     }
+    Ok(false)
 }
 
-#[cfg(target_os = "linux")]
-mod ffi {
-    use libc::{c_int, siginfo_t};
+/// Convert a Duration to a timeout value for poll(), rounding up to
+/// the next millisecond and saturating at the largest value poll() can
+/// accept.
+pub fn duration_to_poll_timeout(d: Duration) -> c_int {
+    use std::cmp::min;
 
-    #[repr(C)]
-    #[allow(dead_code)]
-    #[allow(non_camel_case_types)]
-    pub enum idtype_t { P_ALL, P_PID, P_PGID }
-    #[allow(non_camel_case_types)]
-    pub type id_t = u32;
-
-    pub const WNOHANG : c_int = 1;
-    pub const WEXITED : c_int = 4;
-    pub const WNOWAIT : c_int = 0x01000000;
+    let ms = d.as_secs().saturating_mul(1000)
+        .saturating_add(((d.subsec_nanos() + 999_999) / 1_000_000) as u64);
+    min(ms, c_int::max_value() as u64) as c_int
+}
 
-    extern {
-        pub fn waitid(idtype: idtype_t, id: id_t,
-                      infop: *mut siginfo_t, options: c_int) -> c_int;
+/// Internal: the process ID in STATUS, as returned by waitpid().
+fn wait_status_pid(status: &WaitStatus) -> pid_t {
+    match *status {
+        WaitStatus::Exited(pid, _) => pid,
+        WaitStatus::Signaled(pid, _, _) => pid,
+        WaitStatus::Stopped(pid, _) => pid,
+        WaitStatus::Continued(pid) => pid,
+        WaitStatus::StillAlive => 0,
     }
 }
 
-/// Internal: Poll for reapable child processes, if any.
-/// Does not actually reap; caller is expected to do that.
-fn poll_next_child() -> Option<pid_t> {
-    use libc::siginfo_t;
+/// Internal: reap the child process PID, if it has exited, or any
+/// child that has exited, if PID is -1; return its process ID and
+/// status.
+fn reap_exited_child(pid: pid_t) -> Option<(pid_t, WaitStatus)> {
     use nix::Errno;
-    use self::ffi::*;
-
-    let mut stat: siginfo_t = unsafe { mem::uninitialized() };
-    let rv = unsafe { waitid(idtype_t::P_ALL,
-                             0 as id_t,
-                             &mut stat as *mut siginfo_t,
-                             WEXITED|WNOHANG|WNOWAIT) };
+    use nix::sys::wait::{waitpid, WNOHANG};
 
-    if rv == 0 {
-        return if stat.si_pid == 0 { None } else { Some(stat.si_pid) };
-    } else {
-        let err = Errno::last();
-        if err != Errno::ECHILD {
-            writeln!(io::stderr(), "waitid: {}", err.desc()).unwrap();
+    match waitpid(pid, Some(WNOHANG)) {
+        Ok(WaitStatus::StillAlive) => None,
+        Ok(status) => Some((wait_status_pid(&status), status)),
+        Err(nix::Error::Sys(Errno::ECHILD)) => None,
+        Err(e) => {
+            writeln!(io::stderr(), "waitpid: {}", e).unwrap();
+            None
         }
-        return None;
     }
 }
 
@@ -148,28 +163,52 @@ fn sigset_normal_termination () -> SigSet {
     ss
 }
 
+/// Size of one serialized signal in the self-pipe.
+#[cfg(any(test, not(any(target_os = "linux", target_os = "android"))))]
+const SIGNAL_RECORD_SIZE: usize = 4;
+
 /// Convert a Signal into a value that can be written to a pipe.
-/// We know a priori that signal numbers are small, so we just take the
-/// low 8 bits (checking for overflow).  Succeeds or crashes.
-#[cfg(not(any(target_os = "linux", target_os = "android")))]
-fn serialize_signal (sig: Signal) -> u8 {
-    let rv = sig as u32;
-    assert!(rv < 256);
-    rv as u8
+/// The full c_int is written, so that no signal number, however
+/// large, can be truncated.  Writes of this size to a pipe are
+/// atomic, so the reader always sees whole records.
+#[cfg(any(test, not(any(target_os = "linux", target_os = "android"))))]
+fn serialize_signal (sig: Signal) -> [u8; SIGNAL_RECORD_SIZE] {
+    unsafe { mem::transmute::<c_int, [u8; SIGNAL_RECORD_SIZE]>(
+        sig as c_int) }
 }
 
-/// The inverse operation.  Succeeds or crashes.
-#[cfg(not(any(target_os = "linux", target_os = "android")))]
-fn deserialize_signal (sig: u8) -> Signal {
-    Signal::from_c_int(sig as c_int).unwrap()
+/// The inverse operation.  Returns None if the number read from the
+/// pipe does not correspond to any Signal we know about.
+#[cfg(any(test, not(any(target_os = "linux", target_os = "android"))))]
+fn deserialize_signal (buf: [u8; SIGNAL_RECORD_SIZE]) -> Option<Signal> {
+    let signo = unsafe {
+        mem::transmute::<[u8; SIGNAL_RECORD_SIZE], c_int>(buf)
+    };
+    Signal::from_c_int(signo).ok()
 }
 
+/// True while a SIGCHLD record is sitting in the self-pipe, unread.
+/// Any number of SIGCHLDs are equivalent to one, since the idle loop
+/// reaps every child that has exited whenever it sees SIGCHLD, so
+/// further ones are not written to the pipe while this is set.  This
+/// means a flood of exiting children cannot fill up the pipe and
+/// crowd out termination signals.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+static SIGCHLD_QUEUED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Set by shutdown_signals to ask the signal worker thread to exit.
+/// The thread only looks at this when sigwait() hands it a SIGCHLD,
+/// so shutdown_signals sends one after setting it.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+static SIGNAL_WORKER_STOP: AtomicBool = ATOMIC_BOOL_INIT;
+
 /// This function implements the "self-pipe trick" for plumbing signals
 /// into a select() operation.  It is used on systems that do not support
 /// signalfd().
 #[cfg(not(any(target_os = "linux", target_os = "android")))]
 fn start_signal_worker (sigs: SigSet) -> Result<RawFd, HLError> {
-    use nix::unistd::{pipe, write};
+    use nix::unistd::{pipe, write, close};
+    use nix::Errno::{EINTR, EPIPE};
     use std::thread::spawn;
 
     let (rd, wr) = try!(pipe()
@@ -177,34 +216,463 @@ fn start_signal_worker (sigs: SigSet) -> Result<RawFd, HLError> {
 
     try!(make_nonblocking(rd));
 
-    // There is no good way to tell this thread to exit (because it'll
-    // be blocked in sigwait() all the time), so we just drop the
-    // handle.  Failures in the thread should be impossible.
+    // The thread is blocked in sigwait() nearly all the time, so the
+    // only way to tell it to exit is with a signal; see
+    // shutdown_signals.  When it does exit, it closes the write end
+    // of the pipe, which is how shutdown_signals knows it is gone.
+    // Failures in the thread should be impossible, but if sigwait()
+    // hands us something nix can't represent (e.g. a realtime
+    // signal), or writing to the pipe fails, report it and keep going
+    // rather than crash.  If the read end has been closed, though,
+    // there is nobody left to tell about signals.
+    SIGNAL_WORKER_STOP.store(false, Ordering::SeqCst);
     spawn(move || {
-        loop {
-            let sig = [serialize_signal(sigs.wait().unwrap())];
-            write(wr, &sig).unwrap();
+        'worker: loop {
+            match sigs.wait() {
+                Ok(Signal::SIGCHLD)
+                    if SIGNAL_WORKER_STOP.load(Ordering::SeqCst) => break,
+                Ok(Signal::SIGCHLD)
+                    if SIGCHLD_QUEUED.swap(true, Ordering::SeqCst) => (),
+                Ok(sig) => loop {
+                    match write(wr, &serialize_signal(sig)) {
+                        Ok(_) => break,
+                        Err(nix::Error::Sys(EINTR)) => (),
+                        Err(nix::Error::Sys(EPIPE)) => break 'worker,
+                        Err(e) => {
+                            writeln!(io::stderr(), "signal pipe: {}", e)
+                                .unwrap();
+                            break;
+                        }
+                    }
+                },
+                Err(e) => {
+                    writeln!(io::stderr(), "sigwait: {}", e).unwrap();
+                }
+            }
         }
+        let _ = close(wr);
     });
 
     Ok(rd)
 }
 
 /// This function reads from the self-pipe and regenerates Signal objects.
-/// When the pipe is drained it returns None.
+/// When the pipe is drained it returns None.  Unrecognized signal
+/// numbers are reported and skipped; read errors are returned.
 #[cfg(not(any(target_os = "linux", target_os = "android")))]
-fn next_signal(fd: RawFd) -> Option<Signal> {
+fn try_next_signal(fd: RawFd) -> Result<Option<Signal>, HLError> {
     use nix::unistd::read;
-    use nix::Errno::EAGAIN;
+    use nix::Errno::{EAGAIN, EINTR, EIO};
+
+    loop {
+        let mut buf = [0u8; SIGNAL_RECORD_SIZE];
+        match read(fd, &mut buf) {
+            Err(nix::Error::Sys(EAGAIN)) => return Ok(None),
+            Err(nix::Error::Sys(EINTR)) => (),
+            Ok(0) => return Ok(None),
+            Ok(SIGNAL_RECORD_SIZE) => match deserialize_signal(buf) {
+                Some(Signal::SIGCHLD) => {
+                    // Clear this before the caller starts reaping, so
+                    // that children exiting from now on are noticed.
+                    SIGCHLD_QUEUED.store(false, Ordering::SeqCst);
+                    return Ok(Some(Signal::SIGCHLD));
+                },
+                Some(sig) => return Ok(Some(sig)),
+                None => {
+                    writeln!(io::stderr(), "next_signal: unknown signal \
+                                            {:?}", buf).unwrap();
+                }
+            },
+
+            Err(e) => return Err(map_nix_err(e, format!(
+                "reading signal pipe (fd {})", fd))),
+            Ok(n) => return Err(map_nix_err(nix::Error::Sys(EIO), format!(
+                "reading signal pipe (fd {}): short read ({} bytes)", fd, n)))
+        }
+    }
+}
+
+/// On Linux, signals are plumbed into poll() with signalfd(), which
+/// needs no helper thread.  The signalfd is non-blocking, so that
+/// next_signal can drain it.  SIGS must be the same set of signals
+/// that prepare_signals blocked, or some of them would be neither
+/// delivered normally nor reported here.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn start_signal_worker (sigs: SigSet) -> Result<RawFd, HLError> {
+    use nix::sys::signalfd::{signalfd, SIGNALFD_NEW,
+                             SFD_CLOEXEC, SFD_NONBLOCK};
+
+    signalfd(SIGNALFD_NEW, &sigs, SFD_NONBLOCK | SFD_CLOEXEC)
+        .map_err(|e| map_nix_err(e, String::from("signalfd")))
+}
+
+/// This function reads signalfd_siginfo records from the signalfd
+/// and converts them to Signal objects.  When no more signals are
+/// pending it returns None.  Unrecognized signal numbers are reported
+/// and skipped; read errors are returned.  (The kernel coalesces
+/// multiple pending instances of the same signal, so unlike the
+/// self-pipe, SIGCHLD needs no special handling here.)
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn try_next_signal(fd: RawFd) -> Result<Option<Signal>, HLError> {
+    use nix::sys::signalfd::{siginfo, SIGNALFD_SIGINFO_SIZE};
+    use nix::unistd::read;
+    use nix::Errno::{EAGAIN, EINTR, EIO};
+
+    loop {
+        let mut buf = [0u8; SIGNALFD_SIGINFO_SIZE];
+        match read(fd, &mut buf) {
+            Err(nix::Error::Sys(EAGAIN)) => return Ok(None),
+            Err(nix::Error::Sys(EINTR)) => (),
+            Ok(SIGNALFD_SIGINFO_SIZE) => {
+                let info = unsafe {
+                    mem::transmute::<[u8; SIGNALFD_SIGINFO_SIZE], siginfo>(
+                        buf)
+                };
+                match Signal::from_c_int(info.ssi_signo as c_int) {
+                    Ok(sig) => return Ok(Some(sig)),
+                    Err(_) => {
+                        writeln!(io::stderr(), "next_signal: unknown \
+                                                signal {}",
+                                 info.ssi_signo).unwrap();
+                    }
+                }
+            },
+
+            Err(e) => return Err(map_nix_err(e, format!(
+                "reading signalfd (fd {})", fd))),
+            Ok(n) => return Err(map_nix_err(nix::Error::Sys(EIO), format!(
+                "reading signalfd (fd {}): short read ({} bytes)", fd, n)))
+        }
+    }
+}
+
+/// Like try_next_signal, but an error is reported and treated as
+/// though the pipe had been drained.  This is for places outside the
+/// idle loop, which have no way to pass the error on.
+fn next_signal(fd: RawFd) -> Option<Signal> {
+    match try_next_signal(fd) {
+        Ok(sig) => sig,
+        Err(e) => {
+            writeln!(io::stderr(), "next_signal: {}", e).unwrap();
+            None
+        }
+    }
+}
+
+/// Internal: the events poll() reported for PFD, which is WHAT.  An
+/// error if poll() says PFD is not an open file descriptor, or set
+/// bits nix doesn't know about.
+fn checked_revents(pfd: &PollFd, what: &str)
+                   -> Result<EventFlags, HLError> {
+    use nix::poll::POLLNVAL;
+    use nix::Errno::{EBADF, EINVAL};
+
+    match pfd.revents() {
+        Some(revents) if revents.contains(POLLNVAL) =>
+            Err(map_nix_err(nix::Error::Sys(EBADF),
+                            format!("poll: {}", what))),
+        Some(revents) => Ok(revents),
+        None => Err(map_nix_err(nix::Error::Sys(EINVAL), format!(
+            "poll: {}: unrecognized events", what)))
+    }
+}
+
+/// How long shutdown_signals waits for the signal worker thread to
+/// notice it has been asked to exit.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const SIGNAL_WORKER_EXIT_WAIT_MS: u64 = 1000;
+
+/// Internal: stop the signal worker thread, and close SIGNAL_PIPE.
+/// Signals the thread picked up that nobody has read from the pipe
+/// yet are returned, so that the caller can deliver them again once
+/// they are no longer blocked.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn stop_signal_worker (signal_pipe: RawFd) -> Result<Vec<Signal>, HLError> {
+    use nix::poll::{poll, PollFd, POLLIN, POLLHUP, EventFlags};
+    use nix::sys::signal::kill;
+    use nix::unistd::{close, getpid};
+
+    SIGNAL_WORKER_STOP.store(true, Ordering::SeqCst);
+    try!(kill(getpid(), Signal::SIGCHLD)
+         .map_err(|e| map_nix_err(e, String::from("kill(SIGCHLD)"))));
+
+    let mut leftover = Vec::new();
+    let deadline = Instant::now()
+        + Duration::from_millis(SIGNAL_WORKER_EXIT_WAIT_MS);
+    let mut hangup = false;
+    loop {
+        while let Some(sig) = next_signal(signal_pipe) {
+            if sig != Signal::SIGCHLD {
+                leftover.push(sig);
+            }
+        }
+        if hangup {
+            break;
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            writeln!(io::stderr(), "signal worker thread did not exit; \
+                                    abandoning it").unwrap();
+            break;
+        }
+        let mut pfds = [PollFd::new(signal_pipe, POLLIN,
+                                    EventFlags::empty())];
+        match poll(&mut pfds, duration_to_poll_timeout(deadline - now)) {
+            Ok(0) => (),
+            Ok(_) => {
+                // The write end is only closed once the thread has
+                // written its last record, so drain once more and stop.
+                let revents = pfds[0].revents().unwrap_or(POLLHUP);
+                hangup = revents.contains(POLLHUP);
+            },
+            Err(nix::Error::Sys(nix::Errno::EINTR)) => (),
+            Err(e) => {
+                let _ = close(signal_pipe);
+                return Err(map_nix_err(e, String::from("poll")));
+            }
+        }
+    }
+
+    SIGCHLD_QUEUED.store(false, Ordering::SeqCst);
+    try!(close(signal_pipe)
+         .map_err(|e| map_nix_err(e, String::from("close(signal pipe)"))));
+    Ok(leftover)
+}
 
-    let mut buf : [u8;1] = unsafe { mem::uninitialized() };
-    match read(fd, &mut buf) {
-        Err(nix::Error::Sys(EAGAIN)) => None,
-        Ok(0) => None,
-        Ok(1) => Some(deserialize_signal(buf[0])),
+/// On Linux, there is no thread; closing the signalfd is enough.
+/// Signals not yet read from it are still pending, and will be
+/// delivered in the usual way once they are unblocked.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn stop_signal_worker (signal_pipe: RawFd) -> Result<Vec<Signal>, HLError> {
+    use nix::unistd::close;
 
-        Err(e) => panic!("next_signal: {:?}", e),
-        Ok(n) => panic!("next_signal: read too many bytes ({})", n)
+    try!(close(signal_pipe)
+         .map_err(|e| map_nix_err(e, String::from("close(signalfd)"))));
+    Ok(Vec::new())
+}
+
+/// Undo prepare_signals.  SIGNAL_PIPE and ORIGINAL_MASK must be the
+/// values it returned.  The signal pipe or signalfd is closed, the
+/// worker thread (if any) is told to exit, and the signal mask is set
+/// back to ORIGINAL_MASK, so that signals behave as they did before
+/// prepare_signals was called.  Any signal that arrived but was never
+/// reported is then delivered in the usual way, which may well
+/// terminate the process.
+///
+/// Like prepare_signals, this must be called from the main thread;
+/// it only restores the mask of the calling thread.  SIGNAL_PIPE must
+/// not be used again afterward.
+pub fn shutdown_signals(signal_pipe: RawFd, original_mask: SigSet)
+                        -> Result<(), HLError> {
+    use nix::sys::signal::kill;
+    use nix::unistd::getpid;
+
+    let leftover = stop_signal_worker(signal_pipe);
+    try!(original_mask.thread_set_mask()
+         .map_err(|e| map_nix_err(e, String::from("sigprocmask"))));
+    for sig in try!(leftover) {
+        try!(kill(getpid(), sig)
+             .map_err(|e| map_nix_err(e, format!("kill({:?})", sig))));
+    }
+    Ok(())
+}
+
+/// How many times prepare_signals will try to set up the signal
+/// pipe / signalfd when the process or system is out of file
+/// descriptors, before giving up.
+const SIGNAL_SETUP_ATTEMPTS: u32 = 3;
+
+/// Test hook: this many of the upcoming attempts to set up signal
+/// handling will fail with EMFILE, as if we were out of file
+/// descriptors.
+#[cfg(test)]
+static FORCED_SETUP_FAILURES: ::std::sync::atomic::AtomicUsize =
+    ::std::sync::atomic::ATOMIC_USIZE_INIT;
+
+/// Internal: if a test has asked for it (see FORCED_SETUP_FAILURES),
+/// use up one forced failure and return it.
+#[cfg(test)]
+fn forced_setup_failure() -> Option<HLError> {
+    use std::sync::atomic::Ordering;
+
+    loop {
+        let n = FORCED_SETUP_FAILURES.load(Ordering::SeqCst);
+        if n == 0 {
+            return None;
+        }
+        if FORCED_SETUP_FAILURES.compare_and_swap(n, n - 1, Ordering::SeqCst)
+            == n {
+            return Some(map_nix_err(nix::Error::Sys(nix::Errno::EMFILE),
+                                    String::from("forced failure")));
+        }
+    }
+}
+#[cfg(not(test))]
+#[inline]
+fn forced_setup_failure() -> Option<HLError> {
+    None
+}
+
+/// Internal: if ERR means we ran out of file descriptors (EMFILE or
+/// ENFILE), return the underlying nix error.
+fn fd_shortage(err: &HLError) -> Option<nix::Error> {
+    use nix::Errno::{EMFILE, ENFILE};
+
+    match err {
+        &HLError::NixError { cause: nix::Error::Sys(errno), .. }
+        if errno == EMFILE || errno == ENFILE => {
+            Some(nix::Error::Sys(errno))
+        },
+        _ => None
+    }
+}
+
+/// The result of an interruptible_sleep.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SleepOutcome {
+    /// The full duration elapsed.
+    Completed,
+    /// This signal arrived before the duration was up.
+    Interrupted(Signal),
+}
+
+/// Sleep for DUR, unless a signal arrives on SIGNAL_PIPE (as returned
+/// by prepare_signals) first.  A signal that cuts the sleep short is
+/// consumed from the pipe and returned.  SIGCHLD is not interesting
+/// to callers of this function, so it is consumed and ignored.  This
+/// is for use outside the idle loop, e.g. for grace periods during
+/// teardown, when a further signal means the operator wants us to
+/// hurry up.
+pub fn interruptible_sleep(dur: Duration, signal_pipe: RawFd)
+                           -> SleepOutcome {
+    use nix::poll::{poll, PollFd, POLLIN, EventFlags};
+    use std::time::Instant;
+
+    let deadline = Instant::now() + dur;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return SleepOutcome::Completed;
+        }
+        let mut pfds = [PollFd::new(signal_pipe, POLLIN,
+                                    EventFlags::empty())];
+        match poll(&mut pfds, duration_to_poll_timeout(deadline - now)) {
+            Ok(0) => return SleepOutcome::Completed,
+            Ok(_) => {
+                while let Some(sig) = next_signal(signal_pipe) {
+                    if sig != Signal::SIGCHLD {
+                        return SleepOutcome::Interrupted(sig);
+                    }
+                }
+            },
+            Err(nix::Error::Sys(nix::Errno::EINTR)) => (),
+            Err(e) => {
+                // Without the pipe, fall back to an ordinary sleep.
+                writeln!(io::stderr(), "poll: {}", e).unwrap();
+                ::std::thread::sleep(deadline - now);
+                return SleepOutcome::Completed;
+            }
+        }
+    }
+}
+
+/// Which signals the program picks up through the idle loop, and what
+/// they mean to it.  By default, every signal whose default action is
+/// to terminate the process without a core dump is picked up, and
+/// reported as TermSignal.  Signals added with `notify` are reported
+/// as NotifySignal instead, signals added with `reload` as Reload,
+/// signals added with `status` as Status, and signals removed with
+/// `pass_through` are left alone, so they have their usual effect on
+/// this process.
+/// SIGCHLD is always picked up, since the idle loop depends on it.
+#[derive(Clone)]
+pub struct SignalConfig {
+    blocked: SigSet,
+    notify: Vec<Signal>,
+    reload: Vec<Signal>,
+    status: Vec<Signal>,
+}
+impl SignalConfig {
+    /// The default configuration, described above.
+    pub fn new () -> SignalConfig {
+        SignalConfig::with_set(sigset_normal_termination())
+    }
+
+    /// Pick up exactly the signals in SIGS (plus SIGCHLD), all of
+    /// them reported as TermSignal.
+    pub fn with_set (sigs: SigSet) -> SignalConfig {
+        let mut blocked = sigs;
+        blocked.add(Signal::SIGCHLD);
+        SignalConfig { blocked: blocked, notify: Vec::new(),
+                       reload: Vec::new(), status: Vec::new() }
+    }
+
+    /// Pick up SIG, and report it as TermSignal.
+    pub fn terminate (mut self, sig: Signal) -> SignalConfig {
+        self.blocked.add(sig);
+        self.notify.retain(|&s| s != sig);
+        self.reload.retain(|&s| s != sig);
+        self.status.retain(|&s| s != sig);
+        self
+    }
+
+    /// Pick up SIG, and report it as NotifySignal.
+    pub fn notify (mut self, sig: Signal) -> SignalConfig {
+        self = self.terminate(sig);
+        self.notify.push(sig);
+        self
+    }
+
+    /// Pick up SIG, and report it as Reload.  (Conventionally, this is
+    /// done for SIGHUP.)
+    pub fn reload (mut self, sig: Signal) -> SignalConfig {
+        self = self.terminate(sig);
+        self.reload.push(sig);
+        self
+    }
+
+    /// Pick up SIG, and report it as Status.  (Conventionally, this is
+    /// done for SIGUSR1 and/or SIGUSR2.)
+    pub fn status (mut self, sig: Signal) -> SignalConfig {
+        self = self.terminate(sig);
+        self.status.push(sig);
+        self
+    }
+
+    /// Don't pick up SIG; it will be delivered to this process in the
+    /// usual way.  SIGCHLD cannot be passed through.
+    pub fn pass_through (mut self, sig: Signal) -> SignalConfig {
+        if sig != Signal::SIGCHLD {
+            self = self.terminate(sig);
+            self.blocked.remove(sig);
+        }
+        self
+    }
+
+    /// The signals that will be picked up.
+    pub fn blocked_set (&self) -> SigSet {
+        self.blocked
+    }
+
+    /// True if SIG should be reported as NotifySignal.
+    pub fn is_notify (&self, sig: Signal) -> bool {
+        self.notify.contains(&sig)
+    }
+
+    /// True if SIG should be reported as Reload.
+    pub fn is_reload (&self, sig: Signal) -> bool {
+        self.reload.contains(&sig)
+    }
+
+    /// True if SIG should be reported as Status.
+    pub fn is_status (&self, sig: Signal) -> bool {
+        self.status.contains(&sig)
+    }
+
+    /// Prepare signal handling according to this configuration.
+    /// See prepare_signals.
+    pub fn prepare (&self) -> Result<(RawFd, SigSet), HLError> {
+        start_signal_handling(self.blocked)
     }
 }
 
@@ -214,13 +682,52 @@ fn next_signal(fd: RawFd) -> Option<Signal> {
 /// or signalfd(), and starts the thread / signalfd going.
 /// Must be called before creating any threads, so that the
 /// signal mask is established globally.
+///
+/// A momentary shortage of file descriptors is tolerated: setup is
+/// retried a few times, with a short delay, before failing.
+///
+/// This uses the default SignalConfig; for anything else, use
+/// SignalConfig::prepare.  shutdown_signals undoes all of this.
 pub fn prepare_signals() -> Result<(RawFd, SigSet), HLError> {
-    let parent_mask = sigset_normal_termination();
+    SignalConfig::new().prepare()
+}
+
+/// Internal: the guts of prepare_signals, picking up PARENT_MASK.
+fn start_signal_handling(parent_mask: SigSet)
+                         -> Result<(RawFd, SigSet), HLError> {
+    use std::thread::sleep;
+
     let child_mask = try!(
         parent_mask.thread_swap_mask(SIG_BLOCK)
             .map_err(|e| map_nix_err(e, String::from("sigprocmask"))));
 
-    let sigpipe = try!(start_signal_worker(parent_mask));
+    // If setup fails, the mask is put back the way it was.
+    let mut attempt = 1;
+    let sigpipe = loop {
+        let result = match forced_setup_failure() {
+            Some(e) => Err(e),
+            None => start_signal_worker(parent_mask)
+        };
+        match result {
+            Ok(fd) => break fd,
+            Err(e) => match fd_shortage(&e) {
+                Some(cause) => {
+                    if attempt >= SIGNAL_SETUP_ATTEMPTS {
+                        let _ = child_mask.thread_set_mask();
+                        return Err(map_nix_err(cause, format!(
+                            "setting up signal handling \
+                             (gave up after {} attempts)", attempt)));
+                    }
+                    sleep(Duration::from_millis(50 * attempt as u64));
+                    attempt += 1;
+                },
+                None => {
+                    let _ = child_mask.thread_set_mask();
+                    return Err(e);
+                }
+            }
+        }
+    };
 
     Ok((sigpipe, child_mask))
 }
@@ -248,99 +755,751 @@ pub fn close_stdout() {
 /// An "event" is anything that the main program might need to take
 /// notice of.  Currently these are:
 ///  - stdin has been closed
+///  - a line of text has been read from stdin (only if asked for, with
+///    IdleLoop::read_stdin_lines; otherwise stdin is discarded)
 ///  - the program received a signal that should trigger a graceful exit
-///  - an asynchronous child process has exited
+///  - the program received a signal that the caller asked, via
+///    SignalConfig::notify and IdleLoop::set_signal_config, to hear
+///    about without exiting
+///  - the program received a signal asking it to reload (see
+///    SignalConfig::reload).  Any number of these arriving before the
+///    caller gets around to asking for the next event are reported
+///    as a single Reload.
+///  - the program received a signal asking it to report on what it is
+///    doing (see SignalConfig::status)
+///  - an asynchronous child process has exited (noticed either via
+///    SIGCHLD, or via a pidfd registered with watch_pidfd)
+///  - a timer (see add_timer), or the deadline, if one was set, has
+///    expired
+///  - a file descriptor registered with register_fd is ready, or has
+///    been closed at the other end
+///  - something went wrong inside the loop itself, e.g. poll() failed
+///    or the signal pipe stopped working
+///
+/// ChildExit carries the process ID and exit status of a child that
+/// the loop has reaped.  The caller must not try to wait for it again;
+/// if it holds a Child for the process, it should hand that to
+/// subprocess::reap_child.
 pub enum Event {
     StdinClosed,
+    /// Without the newline.
+    StdinLine(String),
     TermSignal(Signal),
-    ChildExit(pid_t),
+    NotifySignal(Signal),
+    Reload,
+    Status(Signal),
+    ChildExit(pid_t, WaitStatus),
+    Timeout(TimerId),
+    /// The descriptor is readable and/or writable, as requested.
+    FdReady(Token, bool, bool),
+    /// The other end of the descriptor has been closed (or it is not a
+    /// valid descriptor), and there is nothing left to read.  It keeps
+    /// being reported until the caller deregisters it.
+    FdHangup(Token),
+    /// The loop could not carry on normally.  The caller should
+    /// report this and start shutting down; the loop can be asked for
+    /// further events, e.g. during teardown, but may not be able to
+    /// produce anything but more errors.
+    Error(HLError),
+}
+
+/// How many times IdleLoop::poll retries when poll() fails for lack
+/// of kernel memory (EAGAIN or ENOMEM), and how long it waits before
+/// the first retry; each wait is longer than the last by the same
+/// amount.  If the shortage lasts longer than that, it is reported as
+/// an Error.
+const POLL_SHORTAGE_RETRIES: u32 = 5;
+const POLL_SHORTAGE_DELAY_MS: u64 = 10;
+
+/// After this many Errors in a row, with nothing else in between, the
+/// Iterator impl for IdleLoop stops yielding events, so that a loop
+/// that is permanently broken doesn't make `for ev in idle` spin.
+const MAX_CONSECUTIVE_ERRORS: u32 = 3;
+
+/// Identifies a file descriptor registered with IdleLoop::register_fd.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Token(u64);
+
+/// What to watch a registered file descriptor for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interest {
+    Readable,
+    Writable,
+    Both,
+}
+
+/// Identifies a timer set with IdleLoop::add_timer or set_deadline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TimerId(u64);
+
+/// Internal: a timer which is due at DEADLINE, and if INTERVAL is
+/// set, again every INTERVAL after that.
+struct Timer {
+    id: TimerId,
+    deadline: Instant,
+    interval: Option<Duration>,
 }
 
 // An IdleLoop is a generator of Events.
 pub struct IdleLoop {
     signal_pipe:  RawFd,
+    stdin_eof_grace: Duration,
     stdin_closed: bool,
     stdin_pending: bool,
     signal_pending: bool,
-    children_pending: bool
+    children_pending: bool,
+    timers: Vec<Timer>,
+    next_timer: u64,
+    deadline_timer: Option<TimerId>,
+    fds: Vec<(Token, RawFd, Interest)>,
+    next_token: u64,
+    fds_pending: VecDeque<Event>,
+    stdin_lines: bool,
+    stdin_buf: Vec<u8>,
+    stdin_overlong: bool,
+    stdin_events: VecDeque<Event>,
+    pidfds: Vec<PidFd>,
+    exited: Vec<(pid_t, WaitStatus)>,
+    notify_signals: Vec<Signal>,
+    reload_signals: Vec<Signal>,
+    status_signals: Vec<Signal>,
+    reload_pending: bool,
+    consecutive_errors: u32
 }
 impl IdleLoop {
     pub fn new (signal_pipe: RawFd) -> IdleLoop {
         IdleLoop {
             signal_pipe: signal_pipe,
+            stdin_eof_grace: Duration::from_secs(0),
             stdin_closed: false,
             stdin_pending: false,
             signal_pending: false,
-            children_pending: false
+            children_pending: false,
+            timers: Vec::new(),
+            next_timer: 0,
+            deadline_timer: None,
+            fds: Vec::new(),
+            next_token: 0,
+            fds_pending: VecDeque::new(),
+            stdin_lines: false,
+            stdin_buf: Vec::new(),
+            stdin_overlong: false,
+            stdin_events: VecDeque::new(),
+            pidfds: Vec::new(),
+            exited: Vec::new(),
+            notify_signals: Vec::new(),
+            reload_signals: Vec::new(),
+            status_signals: Vec::new(),
+            reload_pending: false,
+            consecutive_errors: 0
         }
     }
-    fn poll (&mut self) {
-        use nix::poll::{poll, PollFd, POLLIN, EventFlags};
 
-        if self.stdin_closed {
-            let mut pfds = [PollFd::new(self.signal_pipe, POLLIN,
-                                        EventFlags::empty())];
+    /// Stop picking up signals: see shutdown_signals, which this calls
+    /// with the loop's signal pipe and ORIGINAL_MASK (the second value
+    /// returned by prepare_signals).  The loop can still be used
+    /// afterward, but it will never report a signal again, nor notice
+    /// children exiting except via watched pidfds.
+    pub fn shutdown (&mut self, original_mask: SigSet)
+                     -> Result<(), HLError> {
+        let signal_pipe = mem::replace(&mut self.signal_pipe, -1);
+        self.signal_pending = false;
+        self.reload_pending = false;
+        if signal_pipe < 0 {
+            return Ok(());
+        }
+        shutdown_signals(signal_pipe, original_mask)
+    }
 
-            poll(&mut pfds, -1).unwrap();
-            if !pfds[0].revents().unwrap().is_empty() {
-                self.signal_pending = true;
+    /// Classify received signals according to CONFIG, which should be
+    /// the configuration that set up SIGNAL_PIPE.  Without this, every
+    /// signal but SIGCHLD is reported as TermSignal.
+    pub fn set_signal_config (&mut self, config: &SignalConfig) {
+        self.notify_signals = config.notify.clone();
+        self.reload_signals = config.reload.clone();
+        self.status_signals = config.status.clone();
+    }
+
+    /// Describe the loop's internal state, for debugging.
+    pub fn describe_state (&self) -> String {
+        format!("stdin_closed={} stdin_pending={} signal_pending={} \
+                 children_pending={} reload_pending={} timers={} fds={} \
+                 fds_pending={} stdin_buf={} pidfds={} exited={}",
+                self.stdin_closed, self.stdin_pending, self.signal_pending,
+                self.children_pending, self.reload_pending,
+                self.timers.len(), self.fds.len(), self.fds_pending.len(),
+                self.stdin_buf.len(), self.pidfds.len(), self.exited.len())
+    }
+
+    /// Like new, but the loop will also report Timeout once, when
+    /// DEADLINE has passed.
+    pub fn with_deadline (signal_pipe: RawFd, deadline: Instant) -> IdleLoop {
+        let mut idle = IdleLoop::new(signal_pipe);
+        idle.set_deadline(deadline);
+        idle
+    }
+
+    /// Report Timeout once DEADLINE has passed, whatever else is
+    /// happening.  (If it is already in the past, Timeout is the very
+    /// next event.)  Timeout is reported only once per call to this
+    /// function; afterward the loop carries on as if no deadline had
+    /// been set.  Setting a new deadline cancels the old one.
+    pub fn set_deadline (&mut self, deadline: Instant) -> TimerId {
+        if let Some(old) = self.deadline_timer.take() {
+            self.cancel_timer(old);
+        }
+        let id = self.new_timer(deadline, None);
+        self.deadline_timer = Some(id);
+        id
+    }
+
+    /// Report Timeout for the new timer, which is returned, once AFTER
+    /// has elapsed; if REPEATING, keep reporting it every AFTER from
+    /// then on, until it is cancelled.  If the loop falls behind, a
+    /// repeating timer is reported once, not once per missed interval.
+    /// Timers run on the monotonic clock, so changes to the system
+    /// time don't affect them.
+    pub fn add_timer (&mut self, after: Duration, repeating: bool)
+                      -> TimerId {
+        let interval = if repeating { Some(after) } else { None };
+        self.new_timer(Instant::now() + after, interval)
+    }
+
+    /// Cancel the timer ID.  Returns false if there was no such timer
+    /// (e.g. because it wasn't repeating and has already fired).
+    pub fn cancel_timer (&mut self, id: TimerId) -> bool {
+        let before = self.timers.len();
+        self.timers.retain(|t| t.id != id);
+        self.timers.len() != before
+    }
+
+    /// Make the timer ID next due AFTER from now; if it is repeating,
+    /// it carries on at its usual interval from then.  Returns false
+    /// if there was no such timer.
+    pub fn reschedule_timer (&mut self, id: TimerId, after: Duration)
+                             -> bool {
+        match self.timers.iter_mut().find(|t| t.id == id) {
+            Some(t) => {
+                t.deadline = Instant::now() + after;
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Internal: add a timer due at DEADLINE, repeating every INTERVAL
+    /// if that is set.
+    fn new_timer (&mut self, deadline: Instant, interval: Option<Duration>)
+                  -> TimerId {
+        let id = TimerId(self.next_timer);
+        self.next_timer += 1;
+        self.timers.push(Timer { id: id, deadline: deadline,
+                                 interval: interval });
+        id
+    }
+
+    /// Internal: if any timer is due, return the one that has been
+    /// due longest, and re-arm or forget it.
+    fn expired_timer (&mut self) -> Option<TimerId> {
+        let now = Instant::now();
+        let i = match self.timers.iter().enumerate()
+            .filter(|&(_, t)| t.deadline <= now)
+            .min_by_key(|&(_, t)| t.deadline) {
+                Some((i, _)) => i,
+                None => return None
+            };
+        let id = self.timers[i].id;
+        match self.timers[i].interval {
+            Some(interval) => {
+                let next = self.timers[i].deadline + interval;
+                self.timers[i].deadline =
+                    if next > now { next } else { now + interval };
+            },
+            None => {
+                self.timers.remove(i);
+                if self.deadline_timer == Some(id) {
+                    self.deadline_timer = None;
+                }
             }
+        }
+        Some(id)
+    }
+
+    /// Watch PIDFD, and report ChildExit for its process when it
+    /// becomes readable.  The loop owns the pidfd from now on, and
+    /// closes it once the exit has been reported.  If SIGCHLD reports
+    /// the same child first, the pidfd is closed then instead, so that
+    /// each exit is reported only once.
+    pub fn watch_pidfd (&mut self, pidfd: PidFd) {
+        self.pidfds.push(pidfd);
+    }
+
+    /// Watch FD, and report FdReady, with the returned token, whenever
+    /// it is ready for what INTEREST says.  Readiness is reported for
+    /// as long as it lasts, so the caller must read or write as
+    /// appropriate each time.  The caller still owns FD, and must
+    /// deregister it before closing it.
+    pub fn register_fd (&mut self, fd: RawFd, interest: Interest) -> Token {
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        self.fds.push((token, fd, interest));
+        token
+    }
+
+    /// Stop watching the file descriptor registered as TOKEN.  Any of
+    /// its events not yet reported are discarded.  Returns false if
+    /// there was no such registration.
+    pub fn deregister_fd (&mut self, token: Token) -> bool {
+        let before = self.fds.len();
+        self.fds.retain(|&(t, _, _)| t != token);
+        self.fds_pending.retain(|ev| match ev {
+            &Event::FdReady(t, _, _) | &Event::FdHangup(t) => t != token,
+            _ => true
+        });
+        self.fds.len() != before
+    }
+
+    /// Don't watch stdin at all, and never report StdinClosed.  This
+    /// is for programs whose stdin belongs to a child process; the
+    /// idle loop would otherwise consume and discard the child's input.
+    pub fn ignore_stdin (&mut self) {
+        self.stdin_closed = true;
+    }
+
+    /// Instead of discarding whatever is written to stdin, report each
+    /// line of it as StdinLine.  A partial line at EOF is reported
+    /// just before StdinClosed.  Lines longer than STDIN_LINE_MAX bytes
+    /// are discarded with a warning, and lines that aren't valid UTF-8
+    /// are reported as Error.
+    pub fn read_stdin_lines (&mut self) {
+        self.stdin_lines = true;
+    }
+
+    /// Internal: queue up StdinLine events for each complete line in
+    /// stdin_buf, or, at EOF, for whatever is left.
+    fn split_stdin_lines (&mut self, eof: bool) {
+        loop {
+            let line: Vec<u8> =
+                match self.stdin_buf.iter().position(|&b| b == b'\n') {
+                    Some(n) => {
+                        let line = self.stdin_buf.drain(..n + 1)
+                            .take(n).collect();
+                        if self.stdin_overlong {
+                            // The end of a line already complained about.
+                            self.stdin_overlong = false;
+                            continue;
+                        }
+                        line
+                    },
+                    None if eof && !self.stdin_buf.is_empty() => {
+                        mem::replace(&mut self.stdin_buf, Vec::new())
+                    },
+                    None => {
+                        if self.stdin_buf.len() > STDIN_LINE_MAX {
+                            if !self.stdin_overlong {
+                                writeln!(io::stderr(), "stdin: line longer \
+                                                        than {} bytes \
+                                                        ignored",
+                                         STDIN_LINE_MAX).unwrap();
+                            }
+                            self.stdin_overlong = true;
+                            self.stdin_buf.clear();
+                        }
+                        return;
+                    }
+                };
+            if self.stdin_overlong {
+                // Only reachable at EOF.
+                self.stdin_overlong = false;
+                return;
+            }
+            if line.len() > STDIN_LINE_MAX {
+                writeln!(io::stderr(), "stdin: line longer than {} bytes \
+                                        ignored", STDIN_LINE_MAX).unwrap();
+                continue;
+            }
+            self.stdin_events.push_back(match String::from_utf8(line) {
+                Ok(line) => Event::StdinLine(line),
+                Err(e) => Event::Error(map_utf8_err(
+                    e.utf8_error(), String::from("line read from stdin")))
+            });
+        }
+    }
+
+    /// When EOF is detected on stdin, wait this long and then check
+    /// again before reporting StdinClosed, in case a new writer has
+    /// attached in the meantime (e.g. a FIFO whose writer re-execs).
+    /// The default is zero, meaning EOF is reported immediately.
+    pub fn set_stdin_eof_grace (&mut self, grace: Duration) {
+        self.stdin_eof_grace = grace;
+    }
+
+    /// Internal: having seen EOF on stdin, decide whether it is real.
+    /// Sleeps for the grace period (cutting it short if a signal
+    /// arrives) and then reads stdin again, without blocking: getting
+    /// EOF again confirms it, while data means a new writer has
+    /// attached.  Asking poll() instead wouldn't do, because at EOF
+    /// most kinds of file report themselves readable forever.  If
+    /// LIMIT would pass before the grace period is up, only waits
+    /// until then, and leaves the question to be asked again next
+    /// time.
+    fn stdin_eof_confirmed (&mut self, limit: Option<Instant>) -> bool {
+        use nix::poll::{poll, PollFd, POLLIN, POLLNVAL, EventFlags};
+        use nix::unistd::read;
+        use nix::Errno::{EAGAIN, EINTR};
+
+        if self.stdin_eof_grace == Duration::from_secs(0) {
+            return true;
+        }
 
-        } else {
-            let mut pfds = [PollFd::new(self.signal_pipe, POLLIN,
-                                        EventFlags::empty()),
-                            PollFd::new(0 /* stdin */, POLLIN,
-                                        EventFlags::empty())];
-            poll(&mut pfds, -1).unwrap();
-            if !pfds[0].revents().unwrap().is_empty() {
+        let mut grace = self.stdin_eof_grace;
+        let mut cut_short = false;
+        if let Some(limit) = limit {
+            let now = Instant::now();
+            let left = if limit > now { limit - now }
+                       else { Duration::from_secs(0) };
+            if left < grace {
+                grace = left;
+                cut_short = true;
+            }
+        }
+
+        let mut pfds = [PollFd::new(self.signal_pipe, POLLIN,
+                                    EventFlags::empty())];
+        if let Ok(n) = poll(&mut pfds, duration_to_poll_timeout(grace)) {
+            if n > 0 {
                 self.signal_pending = true;
             }
-            if !pfds[1].revents().unwrap().is_empty() {
+        }
+        if cut_short {
+            // poll() will report the EOF again next time.
+            return false;
+        }
+
+        let mut pfds = [PollFd::new(0 /* stdin */, POLLIN,
+                                    EventFlags::empty())];
+        match poll(&mut pfds, 0) {
+            // Somebody has the write end open, but hasn't written yet.
+            Ok(0) => return false,
+            Ok(_) => if pfds[0].revents().unwrap_or(POLLNVAL)
+                .contains(POLLNVAL) {
+                return true;
+            },
+            Err(_) => return true
+        }
+        let mut scratch = [0u8; 4096];
+        match read(0, &mut scratch) {
+            Ok(0) => true,
+            Ok(n) => {
+                // New data; let consume_stdin carry on from here.
+                if self.stdin_lines {
+                    self.stdin_buf.extend_from_slice(&scratch[..n]);
+                }
                 self.stdin_pending = true;
+                false
+            },
+            Err(nix::Error::Sys(EINTR)) | Err(nix::Error::Sys(EAGAIN)) =>
+                false,
+            Err(_) => true
+        }
+    }
+
+    /// Internal: wait until something happens, or the next timer or
+    /// LIMIT (if set) comes due, and record what happened.  Errors
+    /// identify the descriptor that misbehaved; anything noticed about
+    /// the other descriptors is still recorded.
+    fn poll (&mut self, limit: Option<Instant>) -> Result<(), HLError> {
+        use nix::poll::{poll, PollFd, POLLIN, POLLOUT, POLLHUP, POLLERR,
+                        POLLNVAL, EventFlags};
+        use nix::Errno::{EAGAIN, EINTR, ENOMEM};
+        use std::thread::sleep;
+
+        let mut pfds = vec![PollFd::new(self.signal_pipe, POLLIN,
+                                         EventFlags::empty())];
+        if !self.stdin_closed {
+            pfds.push(PollFd::new(0 /* stdin */, POLLIN,
+                                  EventFlags::empty()));
+        }
+        let first_pidfd = pfds.len();
+        for pidfd in &self.pidfds {
+            pfds.push(PollFd::new(pidfd.as_raw_fd(), POLLIN,
+                                  EventFlags::empty()));
+        }
+        let first_fd = pfds.len();
+        for &(_, fd, interest) in &self.fds {
+            let events = match interest {
+                Interest::Readable => POLLIN,
+                Interest::Writable => POLLOUT,
+                Interest::Both => POLLIN | POLLOUT
+            };
+            pfds.push(PollFd::new(fd, events, EventFlags::empty()));
+        }
+
+        let mut shortages = 0;
+        loop {
+            // Recomputed every time around, so that neither waking up
+            // early nor being interrupted pushes the next timer back.
+            let timeout = match self.timers.iter().map(|t| t.deadline)
+                .chain(limit).min() {
+                // Even if it has already passed, look (without
+                // waiting) at what's ready, so that a zero timeout for
+                // next_event_timeout still gets events.
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        0
+                    } else {
+                        duration_to_poll_timeout(deadline - now)
+                    }
+                },
+                None => -1
+            };
+            match poll(&mut pfds, timeout) {
+                Ok(_) => break,
+                Err(nix::Error::Sys(EINTR)) => (),
+                Err(nix::Error::Sys(errno))
+                    if (errno == EAGAIN || errno == ENOMEM)
+                    && shortages < POLL_SHORTAGE_RETRIES => {
+                        shortages += 1;
+                        sleep(Duration::from_millis(
+                            POLL_SHORTAGE_DELAY_MS * shortages as u64));
+                    },
+                Err(e) => return Err(map_nix_err(e, format!(
+                    "poll (signal pipe fd {}, {} pidfds, {} other fds)",
+                    self.signal_pipe, self.pidfds.len(), self.fds.len())))
+            }
+        }
+
+        let mut result = Ok(());
+        match checked_revents(&pfds[0], &format!("signal pipe (fd {})",
+                                                 self.signal_pipe)) {
+            Ok(revents) => if !revents.is_empty() {
+                self.signal_pending = true;
+            },
+            Err(e) => {
+                // It's never going to get better; stop watching it.
+                self.signal_pipe = -1;
+                result = Err(e);
+            }
+        }
+        // consume_stdin deals with stdin being closed or invalid.
+        if !self.stdin_closed
+            && !pfds[1].revents().unwrap_or(POLLNVAL).is_empty() {
+            self.stdin_pending = true;
+        }
+        // Walk backward so that removing entries doesn't disturb the
+        // correspondence between pfds and self.pidfds.  A pidfd that
+        // misbehaves is forgotten, so it isn't reported again; its
+        // child will still be noticed via SIGCHLD.
+        for i in (0..self.pidfds.len()).rev() {
+            let pfd = &pfds[first_pidfd + i];
+            match checked_revents(pfd, &format!(
+                "pidfd for process {} (fd {})", self.pidfds[i].pid(),
+                self.pidfds[i].as_raw_fd())) {
+                Ok(revents) => if !revents.is_empty() {
+                    let pidfd = self.pidfds.remove(i);
+                    if let Some(exit) = reap_exited_child(pidfd.pid()) {
+                        self.exited.push(exit);
+                    }
+                },
+                Err(e) => {
+                    self.pidfds.remove(i);
+                    result = Err(e);
+                }
             }
         }
+        // Data still waiting to be read is reported before the hangup
+        // behind it.  Registered fds that are invalid are reported as
+        // FdHangup, which is what the caller is expecting.
+        for (i, &(token, _, _)) in self.fds.iter().enumerate() {
+            let revents = pfds[first_fd + i].revents().unwrap_or(POLLNVAL);
+            let readable = revents.contains(POLLIN);
+            let writable = revents.contains(POLLOUT);
+            if revents.contains(POLLNVAL) {
+                self.fds_pending.push_back(Event::FdHangup(token));
+            } else if readable || writable {
+                self.fds_pending.push_back(
+                    Event::FdReady(token, readable, writable));
+            } else if revents.intersects(POLLHUP | POLLERR) {
+                self.fds_pending.push_back(Event::FdHangup(token));
+            }
+        }
+        result
     }
 
+    /// True if the loop has reported so many Errors in a row that it
+    /// is presumably broken for good.  The Iterator impl stops at this
+    /// point.
+    pub fn gave_up (&self) -> bool {
+        self.consecutive_errors >= MAX_CONSECUTIVE_ERRORS
+    }
+
+    /// The file descriptors the loop is currently watching, and what
+    /// for.  Together with next_deadline, this is for driving the loop
+    /// from some other event loop: wait until one of these is ready or
+    /// the deadline passes, then call next_event_timeout with a zero
+    /// timeout until it returns None.  The set changes as the loop
+    /// runs, so ask again each time.
+    pub fn watched_fds (&self) -> Vec<(RawFd, Interest)> {
+        let mut fds = Vec::new();
+        if self.signal_pipe >= 0 {
+            fds.push((self.signal_pipe, Interest::Readable));
+        }
+        if !self.stdin_closed {
+            fds.push((0 /* stdin */, Interest::Readable));
+        }
+        for pidfd in &self.pidfds {
+            fds.push((pidfd.as_raw_fd(), Interest::Readable));
+        }
+        for &(_, fd, interest) in &self.fds {
+            fds.push((fd, interest));
+        }
+        fds
+    }
+
+    /// When the loop next needs attention even if none of its file
+    /// descriptors become ready, i.e. when the next timer is due.
+    pub fn next_deadline (&self) -> Option<Instant> {
+        self.timers.iter().map(|t| t.deadline).min()
+    }
+
+    /// Return the next event, waiting for as long as it takes.
     pub fn next_event (&mut self) -> Event {
+        match self.next_event_before(None) {
+            Some(ev) => ev,
+            None => unreachable!()
+        }
+    }
+
+    /// Return the next event, but give up and return None if nothing
+    /// happens within TIMEOUT.  Wakeups that don't produce an event
+    /// (e.g. a SIGCHLD with no child to reap, or data on stdin) count
+    /// against TIMEOUT; they don't start it over.  Timers still fire
+    /// as usual, and if one is due first, its Timeout is returned.
+    pub fn next_event_timeout (&mut self, timeout: Duration)
+                               -> Option<Event> {
+        self.next_event_before(Some(Instant::now() + timeout))
+    }
+
+    /// Internal: the next event, or None if LIMIT, if set, passes
+    /// first.
+    fn next_event_before (&mut self, limit: Option<Instant>)
+                          -> Option<Event> {
+        let ev = self.next_event_inner(limit);
+        match ev {
+            Some(Event::Error(_)) => self.consecutive_errors += 1,
+            Some(_) => self.consecutive_errors = 0,
+            None => ()
+        }
+        ev
+    }
+
+    fn next_event_inner (&mut self, limit: Option<Instant>)
+                         -> Option<Event> {
+        let mut polled = false;
         loop {
+            if let Some(id) = self.expired_timer() {
+                return Some(Event::Timeout(id));
+            }
             if !self.stdin_pending
                 && !self.signal_pending
-                && !self.children_pending {
-                    self.poll();
+                && !self.children_pending
+                && self.exited.is_empty()
+                && self.fds_pending.is_empty()
+                && self.stdin_events.is_empty() {
+                    // Checked here, rather than only when poll() times
+                    // out, so that time spent on wakeups that came to
+                    // nothing counts against the limit.
+                    if let Some(limit) = limit {
+                        if polled && Instant::now() >= limit {
+                            return None;
+                        }
+                    }
+                    polled = true;
+                    if let Err(e) = self.poll(limit) {
+                        return Some(Event::Error(e));
+                    }
                 }
+            if let Some((pid, status)) = self.exited.pop() {
+                return Some(Event::ChildExit(pid, status));
+            }
+            if let Some(ev) = self.fds_pending.pop_front() {
+                return Some(ev);
+            }
+            if let Some(ev) = self.stdin_events.pop_front() {
+                return Some(ev);
+            }
             if self.stdin_pending {
                 self.stdin_pending = false;
-                match consume_stdin() {
-                    Ok(false) => (),
+                let result = if self.stdin_lines {
+                    consume_stdin(Some(&mut self.stdin_buf))
+                } else {
+                    consume_stdin(None)
+                };
+                match result {
+                    Ok(false) => {
+                        if self.stdin_lines {
+                            self.split_stdin_lines(false);
+                        }
+                    },
                     Ok(true) => {
-                        self.stdin_closed = true;
-                        return Event::StdinClosed;
+                        if self.stdin_eof_confirmed(limit) {
+                            self.stdin_closed = true;
+                            if self.stdin_lines {
+                                self.split_stdin_lines(true);
+                            }
+                            self.stdin_events.push_back(Event::StdinClosed);
+                        } else if self.stdin_lines {
+                            self.split_stdin_lines(false);
+                        }
                     }
                     Err(e) => {
                         writeln!(io::stderr(), "stdin: {}", e).unwrap();
                         // Assume stdin is no good anymore.
                         self.stdin_closed = true;
-                        return Event::StdinClosed;
+                        self.stdin_events.push_back(Event::StdinClosed);
                     }
                 }
             }
             if self.signal_pending {
-                match next_signal(self.signal_pipe) {
-                    None => {
+                match try_next_signal(self.signal_pipe) {
+                    Err(e) => {
+                        // Don't try to read it again until poll() says
+                        // there's something there.
                         self.signal_pending = false;
+                        return Some(Event::Error(e));
                     },
-                    Some(Signal::SIGCHLD) => {
+                    Ok(None) => {
+                        self.signal_pending = false;
+                        // Only now, so that a burst of reload signals
+                        // comes out as one event.
+                        if self.reload_pending {
+                            self.reload_pending = false;
+                            return Some(Event::Reload);
+                        }
+                    },
+                    Ok(Some(Signal::SIGCHLD)) => {
                         self.children_pending = true;
                     },
-                    Some(sig) => {
-                        return Event::TermSignal(sig);
+                    Ok(Some(sig)) if self.reload_signals.contains(&sig) => {
+                        self.reload_pending = true;
+                    },
+                    Ok(Some(sig)) => {
+                        if self.notify_signals.contains(&sig) {
+                            return Some(Event::NotifySignal(sig));
+                        }
+                        if self.status_signals.contains(&sig) {
+                            return Some(Event::Status(sig));
+                        }
+                        return Some(Event::TermSignal(sig));
                     }
                 }
             }
             if self.children_pending {
-                match poll_next_child() {
-                    Some(pid) => {
-                        return Event::ChildExit(pid);
+                match reap_exited_child(-1) {
+                    Some((pid, status)) => {
+                        self.pidfds.retain(|p| p.pid() != pid);
+                        return Some(Event::ChildExit(pid, status));
                     },
                     None => {
                         self.children_pending = false;
@@ -353,6 +1512,742 @@ impl IdleLoop {
 impl Iterator for IdleLoop {
     type Item = Event;
     fn next(&mut self) -> Option<Self::Item> {
+        if self.gave_up() {
+            return None;
+        }
         Some(self.next_event())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::thread;
+    use std::os::unix::io::IntoRawFd;
+    use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+    use nix::sys::signal::Signal::*;
+    use nix::unistd::{close, dup, dup2, pipe};
+    use super::*;
+
+    /// Internal: holds FLAG, which stands for something shared by the
+    /// whole test process, until dropped, so that the tests which use
+    /// it take turns.
+    struct Exclusive(&'static AtomicBool);
+    impl Exclusive {
+        fn take(flag: &'static AtomicBool) -> Exclusive {
+            while flag.compare_and_swap(false, true, Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(10));
+            }
+            Exclusive(flag)
+        }
+    }
+    impl Drop for Exclusive {
+        fn drop(&mut self) {
+            self.0.store(false, Ordering::SeqCst);
+        }
+    }
+
+    /// Taken by tests that replace stdin.
+    static STDIN: AtomicBool = ATOMIC_BOOL_INIT;
+
+    /// Internal: while this exists, the test that made it has stdin to
+    /// itself, and it is FD; the original comes back when it is dropped.
+    struct StdinReplaced {
+        saved: RawFd,
+        _turn: Exclusive,
+    }
+    impl StdinReplaced {
+        fn new(fd: RawFd) -> StdinReplaced {
+            let turn = Exclusive::take(&STDIN);
+            let saved = dup(0).unwrap();
+            dup2(fd, 0).unwrap();
+            close(fd).unwrap();
+            StdinReplaced { saved: saved, _turn: turn }
+        }
+    }
+    impl Drop for StdinReplaced {
+        fn drop(&mut self) {
+            let _ = dup2(self.saved, 0);
+            let _ = close(self.saved);
+        }
+    }
+
+    /// Taken by tests that set up signal handling, which would
+    /// otherwise be thrown by signal_setup_is_retried_when_out_of_fds.
+    static SIGNALS: AtomicBool = ATOMIC_BOOL_INIT;
+
+    /// Internal: send SIG to the calling thread alone, so that it can't
+    /// disturb any other test.
+    fn signal_this_thread(sig: Signal) {
+        use libc::{pthread_kill, pthread_self};
+
+        assert_eq!(unsafe { pthread_kill(pthread_self(), sig as c_int) }, 0);
+    }
+
+    /// Internal: EV, described for comparison.
+    fn describe(ev: Option<Event>) -> String {
+        match ev {
+            None => String::from("nothing"),
+            Some(Event::StdinClosed) => String::from("StdinClosed"),
+            Some(Event::StdinLine(line)) => format!("StdinLine({})", line),
+            Some(Event::TermSignal(sig)) => format!("TermSignal({:?})", sig),
+            Some(Event::NotifySignal(sig)) =>
+                format!("NotifySignal({:?})", sig),
+            Some(Event::Reload) => String::from("Reload"),
+            Some(Event::Status(sig)) => format!("Status({:?})", sig),
+            Some(Event::ChildExit(pid, status)) =>
+                format!("ChildExit({}, {})", pid,
+                        describe_wait_status(&status)),
+            Some(Event::Timeout(id)) => format!("Timeout({:?})", id),
+            Some(Event::FdReady(token, r, w)) =>
+                format!("FdReady({:?}, {}, {})", token, r, w),
+            Some(Event::FdHangup(token)) => format!("FdHangup({:?})", token),
+            Some(Event::Error(e)) => format!("Error({})", e)
+        }
+    }
+
+    #[test]
+    fn signals_survive_the_self_pipe_encoding() {
+        for &sig in &[SIGHUP, SIGINT, SIGTERM, SIGCHLD, SIGUSR1, SIGUSR2,
+                      SIGALRM] {
+            assert_eq!(deserialize_signal(serialize_signal(sig)), Some(sig));
+        }
+        // Numbers that aren't signals are rejected, not misread.
+        let bogus = unsafe {
+            mem::transmute::<c_int, [u8; SIGNAL_RECORD_SIZE]>(1000)
+        };
+        assert_eq!(deserialize_signal(bogus), None::<Signal>);
+    }
+
+    #[test]
+    fn signal_setup_is_retried_when_out_of_fds() {
+        let _signals = Exclusive::take(&SIGNALS);
+        let mut sigs = SigSet::empty();
+        sigs.add(SIGUSR1);
+
+        // One failure is retried.
+        FORCED_SETUP_FAILURES.store(1, Ordering::SeqCst);
+        let (fd, mask) = start_signal_handling(sigs).unwrap();
+        assert_eq!(FORCED_SETUP_FAILURES.load(Ordering::SeqCst), 0);
+        shutdown_signals(fd, mask).unwrap();
+
+        // A persistent shortage isn't.
+        FORCED_SETUP_FAILURES.store(SIGNAL_SETUP_ATTEMPTS as usize + 1,
+                                    Ordering::SeqCst);
+        let err = start_signal_handling(sigs).unwrap_err();
+        FORCED_SETUP_FAILURES.store(0, Ordering::SeqCst);
+        assert!(fd_shortage(&err).is_some());
+        assert!(format!("{}", err).contains("gave up after 3 attempts"));
+    }
+
+    #[test]
+    fn stdin_eof_waits_out_the_grace_period() {
+        use libc::mkfifo;
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::fs::OpenOptionsExt;
+        use std::sync::mpsc::channel;
+
+        let (quiet, quiet_wr) = pipe().unwrap();
+        let patience = Duration::from_secs(5);
+
+        // Without a grace period, EOF is reported at once.
+        let (rd, wr) = pipe().unwrap();
+        close(wr).unwrap();
+        {
+            let _stdin = StdinReplaced::new(rd);
+            let mut idle = IdleLoop::new(quiet);
+            assert_eq!(describe(idle.next_event_timeout(patience)),
+                       "StdinClosed");
+        }
+
+        // With one, a writer that comes back in time is waited for.
+        // That takes a FIFO; a pipe can't get a new writer.
+        let fifo = ::std::env::temp_dir().join(format!(
+            "openvpn-netns-tools-fifo-{}", nix::unistd::getpid()));
+        let c_fifo = CString::new(fifo.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { mkfifo(c_fifo.as_ptr(), 0o600) }, 0);
+        let reader = fs::OpenOptions::new().read(true)
+            .custom_flags(::libc::O_NONBLOCK).open(&fifo).unwrap();
+        let _stdin = StdinReplaced::new(reader.into_raw_fd());
+        drop(fs::OpenOptions::new().write(true).open(&fifo).unwrap());
+
+        let (tx, rx) = channel();
+        let fifo_again = fifo.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            tx.send(fs::OpenOptions::new().write(true).open(&fifo_again)
+                    .unwrap()).unwrap();
+        });
+        let mut idle = IdleLoop::new(quiet);
+        idle.set_stdin_eof_grace(Duration::from_millis(500));
+        assert_eq!(describe(idle.next_event_timeout(
+            Duration::from_millis(1500))), "nothing");
+
+        // Once the new writer goes away for good, EOF is reported.
+        drop(rx.recv().unwrap());
+        let start = Instant::now();
+        assert_eq!(describe(idle.next_event_timeout(patience)),
+                   "StdinClosed");
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        fs::remove_file(&fifo).unwrap();
+        close(quiet).unwrap();
+        close(quiet_wr).unwrap();
+    }
+
+    #[test]
+    fn stdin_eof_grace_ends_for_files_that_are_always_readable() {
+        // /dev/null, like a regular file at its end, polls readable
+        // forever, so only reading it again can confirm the EOF.
+        let (quiet, quiet_wr) = pipe().unwrap();
+        let null = fs::File::open("/dev/null").unwrap();
+        let _stdin = StdinReplaced::new(null.into_raw_fd());
+        let mut idle = IdleLoop::new(quiet);
+        idle.set_stdin_eof_grace(Duration::from_millis(200));
+        let start = Instant::now();
+        assert_eq!(describe(idle.next_event_timeout(
+            Duration::from_secs(5))), "StdinClosed");
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        close(quiet).unwrap();
+        close(quiet_wr).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn sleeps_are_cut_short_by_signals() {
+        let _signals = Exclusive::take(&SIGNALS);
+        let (quiet, quiet_wr) = pipe().unwrap();
+        let start = Instant::now();
+        assert_eq!(interruptible_sleep(Duration::from_millis(100), quiet),
+                   SleepOutcome::Completed);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        close(quiet).unwrap();
+        close(quiet_wr).unwrap();
+
+        let (fd, mask) = SignalConfig::with_set(SigSet::empty())
+            .terminate(SIGUSR1).prepare().unwrap();
+        // SIGCHLD doesn't count.
+        signal_this_thread(SIGCHLD);
+        signal_this_thread(SIGUSR1);
+        let start = Instant::now();
+        assert_eq!(interruptible_sleep(Duration::from_secs(10), fd),
+                   SleepOutcome::Interrupted(SIGUSR1));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        shutdown_signals(fd, mask).unwrap();
+    }
+
+    #[test]
+    fn exited_children_are_reaped_by_pid() {
+        use std::process::Command;
+
+        let mut running = Command::new("sleep").arg("30").spawn().unwrap();
+        assert!(reap_exited_child(running.id() as pid_t).is_none());
+
+        let done = Command::new("sh").args(&["-c", "exit 3"]).spawn()
+            .unwrap();
+        let pid = done.id() as pid_t;
+        let start = Instant::now();
+        let (reaped, status) = loop {
+            if let Some(exit) = reap_exited_child(pid) {
+                break exit;
+            }
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(reaped, pid);
+        assert_eq!(describe_wait_status(&status), "exited with code 3");
+        // Once reaped, it's gone.
+        assert!(reap_exited_child(pid).is_none());
+
+        running.kill().unwrap();
+        running.wait().unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn floods_of_sigchld_do_not_crowd_out_other_signals() {
+        let _signals = Exclusive::take(&SIGNALS);
+        let (fd, mask) = SignalConfig::with_set(SigSet::empty())
+            .terminate(SIGUSR1).prepare().unwrap();
+        for _ in 0..1000 {
+            signal_this_thread(SIGCHLD);
+        }
+        signal_this_thread(SIGUSR1);
+        let mut got = Vec::new();
+        while let Some(sig) = try_next_signal(fd).unwrap() {
+            got.push(sig);
+        }
+        assert_eq!(got.iter().filter(|&&sig| sig == SIGCHLD).count(), 1);
+        assert!(got.contains(&SIGUSR1));
+        shutdown_signals(fd, mask).unwrap();
+    }
+
+    #[test]
+    fn stdin_is_consumed_without_blocking() {
+        use nix::unistd::write;
+
+        let (rd, wr) = pipe().unwrap();
+        let _stdin = StdinReplaced::new(rd);
+        let mut kept = Vec::new();
+        assert!(!consume_stdin(Some(&mut kept)).unwrap());
+        assert!(kept.is_empty());
+
+        write(wr, b"abc").unwrap();
+        assert!(!consume_stdin(Some(&mut kept)).unwrap());
+        assert_eq!(kept, b"abc".to_vec());
+
+        write(wr, b"def").unwrap();
+        close(wr).unwrap();
+        assert!(consume_stdin(None).unwrap());
+        assert_eq!(kept, b"abc".to_vec());
+    }
+
+    #[test]
+    fn timers_fire_repeat_and_can_be_changed() {
+        let (quiet, quiet_wr) = pipe().unwrap();
+        let patience = Duration::from_secs(5);
+        let mut idle = IdleLoop::new(quiet);
+        idle.ignore_stdin();
+        assert!(idle.next_deadline().is_none());
+
+        let fast = idle.add_timer(Duration::from_millis(100), true);
+        let slow = idle.add_timer(Duration::from_millis(450), false);
+        let start = Instant::now();
+        let mut fast_count = 0;
+        loop {
+            match idle.next_event_timeout(patience) {
+                Some(Event::Timeout(id)) if id == fast => fast_count += 1,
+                Some(Event::Timeout(id)) if id == slow => break,
+                ev => panic!("unexpected event: {}", describe(ev))
+            }
+        }
+        assert!(start.elapsed() >= Duration::from_millis(450));
+        // Falling behind costs repeats, but never adds any.
+        assert!(fast_count >= 1 && fast_count <= 4);
+
+        // A one-shot timer is gone once it has fired.
+        assert!(!idle.cancel_timer(slow));
+        assert!(!idle.reschedule_timer(slow, patience));
+        assert!(idle.cancel_timer(fast));
+        assert!(idle.next_deadline().is_none());
+        assert_eq!(describe(idle.next_event_timeout(
+            Duration::from_millis(250))), "nothing");
+
+        // Rescheduling can bring a timer forward.
+        let later = idle.add_timer(Duration::from_secs(60), false);
+        assert!(idle.reschedule_timer(later, Duration::from_millis(50)));
+        let start = Instant::now();
+        assert_eq!(describe(idle.next_event_timeout(patience)),
+                   describe(Some(Event::Timeout(later))));
+        assert!(start.elapsed() < patience);
+
+        // A deadline in the past comes due at once.
+        let deadline = idle.set_deadline(Instant::now());
+        assert_eq!(describe(idle.next_event_timeout(patience)),
+                   describe(Some(Event::Timeout(deadline))));
+        close(quiet).unwrap();
+        close(quiet_wr).unwrap();
+    }
+
+    #[test]
+    fn registered_fds_report_readiness_and_hangups() {
+        use nix::unistd::{read, write};
+
+        let (quiet, quiet_wr) = pipe().unwrap();
+        let patience = Duration::from_secs(5);
+        let (rd, wr) = pipe().unwrap();
+        let mut idle = IdleLoop::new(quiet);
+        idle.ignore_stdin();
+        let reader = idle.register_fd(rd, Interest::Readable);
+        let writer = idle.register_fd(wr, Interest::Writable);
+        assert_eq!(idle.watched_fds(),
+                   vec![(quiet, Interest::Readable),
+                        (rd, Interest::Readable),
+                        (wr, Interest::Writable)]);
+
+        // An empty pipe can be written to, but not read from.
+        assert_eq!(describe(idle.next_event_timeout(patience)),
+                   format!("FdReady({:?}, false, true)", writer));
+        assert!(idle.deregister_fd(writer));
+        assert!(!idle.deregister_fd(writer));
+        assert_eq!(describe(idle.next_event_timeout(
+            Duration::from_millis(100))), "nothing");
+
+        write(wr, b"x").unwrap();
+        assert_eq!(describe(idle.next_event_timeout(patience)),
+                   format!("FdReady({:?}, true, false)", reader));
+        let mut buf = [0u8; 16];
+        assert_eq!(read(rd, &mut buf).unwrap(), 1);
+
+        close(wr).unwrap();
+        assert_eq!(describe(idle.next_event_timeout(patience)),
+                   format!("FdHangup({:?})", reader));
+        assert!(idle.deregister_fd(reader));
+        close(rd).unwrap();
+
+        // A descriptor that was never open counts as hung up, too.
+        let bogus = idle.register_fd(1_000_000, Interest::Both);
+        assert_eq!(describe(idle.next_event_timeout(patience)),
+                   format!("FdHangup({:?})", bogus));
+        idle.deregister_fd(bogus);
+        assert_eq!(idle.watched_fds(), vec![(quiet, Interest::Readable)]);
+        close(quiet).unwrap();
+        close(quiet_wr).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn child_exits_come_with_their_status() {
+        use std::process::Command;
+        use subprocess::reap_child;
+
+        let (quiet, quiet_wr) = pipe().unwrap();
+        let mut child = Command::new("sh").args(&["-c", "exit 7"]).spawn()
+            .unwrap();
+        let pid = child.id() as pid_t;
+        let pidfd = match PidFd::open(pid).unwrap() {
+            Some(pidfd) => pidfd,
+            None => {
+                // Too old a kernel; SIGCHLD can't be tested safely here.
+                child.wait().unwrap();
+                return;
+            }
+        };
+        let mut idle = IdleLoop::new(quiet);
+        idle.ignore_stdin();
+        idle.watch_pidfd(pidfd);
+        let status = match idle.next_event_timeout(Duration::from_secs(10)) {
+            Some(Event::ChildExit(p, status)) if p == pid => status,
+            ev => panic!("unexpected event: {}", describe(ev))
+        };
+        assert_eq!(describe_wait_status(&status), "exited with code 7");
+        // The loop has already reaped the child, and closed the pidfd.
+        assert!(reap_exited_child(pid).is_none());
+        assert_eq!(idle.watched_fds(), vec![(quiet, Interest::Readable)]);
+        assert_eq!(reap_child(child, status).unwrap().code(), Some(7));
+        close(quiet).unwrap();
+        close(quiet_wr).unwrap();
+    }
+
+    #[test]
+    fn signal_configs_classify_signals() {
+        let config = SignalConfig::new()
+            .notify(SIGUSR1)
+            .reload(SIGHUP)
+            .status(SIGUSR2)
+            .pass_through(SIGINT)
+            .pass_through(SIGCHLD);
+        let blocked = config.blocked_set();
+        for &sig in &[SIGUSR1, SIGHUP, SIGUSR2, SIGTERM, SIGCHLD] {
+            assert!(blocked.contains(sig), "{:?} not picked up", sig);
+        }
+        for &sig in &[SIGINT, SIGKILL, SIGSEGV, SIGTSTP] {
+            assert!(!blocked.contains(sig), "{:?} picked up", sig);
+        }
+        assert!(config.is_notify(SIGUSR1));
+        assert!(config.is_reload(SIGHUP));
+        assert!(config.is_status(SIGUSR2));
+        assert!(!config.is_notify(SIGTERM) && !config.is_reload(SIGTERM)
+                && !config.is_status(SIGTERM));
+
+        // The last word on a signal is the one that counts.
+        let config = config.terminate(SIGUSR1).notify(SIGHUP);
+        assert!(!config.is_notify(SIGUSR1));
+        assert!(config.is_notify(SIGHUP) && !config.is_reload(SIGHUP));
+
+        // Only SIGCHLD is picked up when nothing else is asked for.
+        let only_chld = SignalConfig::with_set(SigSet::empty())
+            .blocked_set();
+        assert!(only_chld.contains(SIGCHLD));
+        assert!(!only_chld.contains(SIGTERM));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn signals_are_reported_as_configured() {
+        let _signals = Exclusive::take(&SIGNALS);
+        let patience = Duration::from_secs(5);
+        let config = SignalConfig::with_set(SigSet::empty())
+            .terminate(SIGUSR1).notify(SIGUSR2);
+        let (fd, mask) = config.prepare().unwrap();
+        let mut idle = IdleLoop::new(fd);
+        idle.ignore_stdin();
+        idle.set_signal_config(&config);
+
+        signal_this_thread(SIGUSR2);
+        assert_eq!(describe(idle.next_event_timeout(patience)),
+                   "NotifySignal(SIGUSR2)");
+        signal_this_thread(SIGUSR1);
+        assert_eq!(describe(idle.next_event_timeout(patience)),
+                   "TermSignal(SIGUSR1)");
+        idle.shutdown(mask).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn stdin_reads_are_bounded_and_never_block() {
+        use libc::{fcntl, F_GETFL, F_SETPIPE_SZ, O_NONBLOCK};
+        use nix::unistd::write;
+
+        let chunk = STDIN_READS_PER_CALL * 4096;
+        let (rd, wr) = pipe().unwrap();
+        // Make room for more than one call's worth.
+        assert!(unsafe { fcntl(wr, F_SETPIPE_SZ, (4 * chunk) as c_int) }
+                >= (2 * chunk) as c_int);
+        let data = vec![b'x'; 2 * chunk];
+        assert_eq!(write(wr, &data).unwrap(), data.len());
+
+        let _stdin = StdinReplaced::new(rd);
+        let mut kept = Vec::new();
+        assert!(!consume_stdin(Some(&mut kept)).unwrap());
+        assert_eq!(kept.len(), chunk);
+        assert!(!consume_stdin(Some(&mut kept)).unwrap());
+        assert_eq!(kept, data);
+        // The pipe is empty, but still has a writer: don't wait for it.
+        assert!(!consume_stdin(Some(&mut kept)).unwrap());
+        // Stdin is shared; it has to be left the way it was found.
+        assert_eq!(unsafe { fcntl(0, F_GETFL) } & O_NONBLOCK, 0);
+        close(wr).unwrap();
+        assert!(consume_stdin(None).unwrap());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn bursts_of_reload_signals_are_one_reload() {
+        let _signals = Exclusive::take(&SIGNALS);
+        let patience = Duration::from_secs(5);
+        let config = SignalConfig::with_set(SigSet::empty())
+            .reload(SIGHUP).reload(SIGUSR2);
+        let (fd, mask) = config.prepare().unwrap();
+        let mut idle = IdleLoop::new(fd);
+        idle.ignore_stdin();
+        idle.set_signal_config(&config);
+
+        signal_this_thread(SIGHUP);
+        signal_this_thread(SIGUSR2);
+        assert_eq!(describe(idle.next_event_timeout(patience)), "Reload");
+        assert_eq!(describe(idle.next_event_timeout(
+            Duration::from_millis(100))), "nothing");
+
+        // Without the configuration, it's a request to exit.
+        idle.set_signal_config(&SignalConfig::new());
+        signal_this_thread(SIGHUP);
+        assert_eq!(describe(idle.next_event_timeout(patience)),
+                   "TermSignal(SIGHUP)");
+        idle.shutdown(mask).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn status_signals_are_reported_as_status() {
+        let _signals = Exclusive::take(&SIGNALS);
+        let patience = Duration::from_secs(5);
+        let config = SignalConfig::with_set(SigSet::empty())
+            .status(SIGUSR1).status(SIGUSR2);
+        let (fd, mask) = config.prepare().unwrap();
+        let mut idle = IdleLoop::new(fd);
+        idle.ignore_stdin();
+        idle.set_signal_config(&config);
+
+        signal_this_thread(SIGUSR2);
+        assert_eq!(describe(idle.next_event_timeout(patience)),
+                   "Status(SIGUSR2)");
+        signal_this_thread(SIGUSR1);
+        assert_eq!(describe(idle.next_event_timeout(patience)),
+                   "Status(SIGUSR1)");
+        // The loop carries on afterward.
+        assert!(!idle.gave_up());
+        assert_eq!(describe(idle.next_event_timeout(
+            Duration::from_millis(100))), "nothing");
+        idle.shutdown(mask).unwrap();
+    }
+
+    /// Internal: true if SIG is blocked in the calling thread.
+    fn blocked_here(sig: Signal) -> bool {
+        use libc::{pthread_sigmask, sigismember, sigset_t, SIG_BLOCK};
+        use std::ptr;
+
+        unsafe {
+            let mut set: sigset_t = mem::zeroed();
+            assert_eq!(pthread_sigmask(SIG_BLOCK, ptr::null(), &mut set), 0);
+            sigismember(&set, sig as c_int) == 1
+        }
+    }
+
+    #[test]
+    fn shutdown_gives_signals_back() {
+        let _signals = Exclusive::take(&SIGNALS);
+        let mut sigs = SigSet::empty();
+        sigs.add(SIGWINCH);
+        assert!(!blocked_here(SIGWINCH));
+        let (fd, mask) = SignalConfig::with_set(sigs).prepare().unwrap();
+        assert!(blocked_here(SIGWINCH));
+
+        let mut idle = IdleLoop::new(fd);
+        idle.ignore_stdin();
+        // Picked up, but never collected; shutting down discards it.
+        signal_this_thread(SIGWINCH);
+        idle.shutdown(mask).unwrap();
+        assert!(!blocked_here(SIGWINCH));
+        assert!(!blocked_here(SIGCHLD));
+        assert!(idle.watched_fds().is_empty());
+
+        // Doing it twice is harmless, and the loop still works.
+        idle.shutdown(mask).unwrap();
+        let id = idle.add_timer(Duration::from_millis(10), false);
+        assert_eq!(describe(idle.next_event_timeout(Duration::from_secs(5))),
+                   describe(Some(Event::Timeout(id))));
+    }
+
+    #[test]
+    fn a_broken_signal_pipe_is_an_error_not_a_panic() {
+        let mut idle = IdleLoop::new(1_000_000);
+        idle.ignore_stdin();
+        let ev = describe(idle.next_event_timeout(Duration::from_secs(5)));
+        assert!(ev.starts_with("Error("), "unexpected event: {}", ev);
+        assert!(ev.contains("signal pipe (fd 1000000)"), "{}", ev);
+
+        // It isn't reported again, and the loop carries on without it.
+        assert!(!idle.gave_up());
+        assert!(idle.watched_fds().is_empty());
+        assert_eq!(describe(idle.next_event_timeout(
+            Duration::from_millis(100))), "nothing");
+        idle.shutdown(SigSet::empty()).unwrap();
+    }
+
+    #[test]
+    fn interrupted_waits_carry_on() {
+        use libc::{pthread_kill, pthread_self, sigaction, sigemptyset,
+                   sighandler_t};
+        use std::ptr;
+
+        extern "C" fn ignore(_: c_int) {}
+
+        // A handler without SA_RESTART makes poll() fail with EINTR.
+        // SIGURG is ignored by default, so nobody else will mind.
+        let mut old: sigaction = unsafe { mem::zeroed() };
+        unsafe {
+            let mut act: sigaction = mem::zeroed();
+            act.sa_sigaction = ignore as extern "C" fn(c_int) as sighandler_t;
+            sigemptyset(&mut act.sa_mask);
+            assert_eq!(sigaction(SIGURG as c_int, &act, &mut old), 0);
+        }
+
+        let (quiet, quiet_wr) = pipe().unwrap();
+        let mut idle = IdleLoop::new(quiet);
+        idle.ignore_stdin();
+        let id = idle.add_timer(Duration::from_millis(300), false);
+        let me = unsafe { pthread_self() };
+        let interrupter = thread::spawn(move || {
+            for _ in 0..5 {
+                thread::sleep(Duration::from_millis(20));
+                unsafe { pthread_kill(me, SIGURG as c_int) };
+            }
+        });
+        let start = Instant::now();
+        assert_eq!(describe(idle.next_event_timeout(Duration::from_secs(5))),
+                   describe(Some(Event::Timeout(id))));
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        interrupter.join().unwrap();
+
+        unsafe {
+            assert_eq!(sigaction(SIGURG as c_int, &old, ptr::null_mut()), 0);
+        }
+        close(quiet).unwrap();
+        close(quiet_wr).unwrap();
+    }
+
+    #[test]
+    fn waits_for_events_are_bounded() {
+        use nix::unistd::write;
+
+        let (quiet, quiet_wr) = pipe().unwrap();
+        let mut idle = IdleLoop::new(quiet);
+        idle.ignore_stdin();
+        let start = Instant::now();
+        assert_eq!(describe(idle.next_event_timeout(
+            Duration::from_millis(200))), "nothing");
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        // Wakeups that come to nothing don't start the wait over.
+        let (rd, wr) = pipe().unwrap();
+        let _stdin = StdinReplaced::new(rd);
+        let mut idle = IdleLoop::new(quiet);
+        let chatter = thread::spawn(move || {
+            for _ in 0..20 {
+                write(wr, b"noise\n").unwrap();
+                thread::sleep(Duration::from_millis(50));
+            }
+            close(wr).unwrap();
+        });
+        let start = Instant::now();
+        assert_eq!(describe(idle.next_event_timeout(
+            Duration::from_millis(300))), "nothing");
+        assert!(start.elapsed() < Duration::from_millis(900));
+        assert_eq!(describe(idle.next_event_timeout(
+            Duration::from_secs(5))), "StdinClosed");
+        chatter.join().unwrap();
+        close(quiet).unwrap();
+        close(quiet_wr).unwrap();
+    }
+
+    #[test]
+    fn stdin_lines_are_reported() {
+        use nix::unistd::write;
+
+        let (quiet, quiet_wr) = pipe().unwrap();
+        let patience = Duration::from_secs(5);
+        let (rd, wr) = pipe().unwrap();
+        let mut input = b"one\ntwo\n".to_vec();
+        input.extend(vec![b'x'; STDIN_LINE_MAX + 1]);
+        input.extend_from_slice(b"\n\xff\xfe\nthree\npartial");
+        assert_eq!(write(wr, &input).unwrap(), input.len());
+        close(wr).unwrap();
+
+        let _stdin = StdinReplaced::new(rd);
+        let mut idle = IdleLoop::new(quiet);
+        idle.read_stdin_lines();
+        let mut events = Vec::new();
+        loop {
+            let ev = describe(idle.next_event_timeout(patience));
+            let done = ev == "StdinClosed" || ev == "nothing";
+            events.push(ev);
+            if done {
+                break;
+            }
+        }
+        // The overlong line is dropped, and the invalid one is an error.
+        assert_eq!(events.len(), 6, "{:?}", events);
+        assert_eq!(&events[..2], &["StdinLine(one)", "StdinLine(two)"]);
+        assert!(events[2].starts_with("Error("), "{:?}", events);
+        assert_eq!(&events[3..], &["StdinLine(three)", "StdinLine(partial)",
+                                   "StdinClosed"]);
+        close(quiet).unwrap();
+        close(quiet_wr).unwrap();
+    }
+
+    #[test]
+    fn a_new_deadline_replaces_the_old_one() {
+        let (quiet, quiet_wr) = pipe().unwrap();
+        let far = Instant::now() + Duration::from_secs(60);
+        let mut idle = IdleLoop::with_deadline(quiet, far);
+        idle.ignore_stdin();
+        assert_eq!(idle.next_deadline(), Some(far));
+
+        let soon = idle.set_deadline(Instant::now()
+                                     + Duration::from_millis(100));
+        assert!(idle.next_deadline().unwrap() < far);
+        let start = Instant::now();
+        assert_eq!(describe(idle.next_event_timeout(Duration::from_secs(5))),
+                   describe(Some(Event::Timeout(soon))));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // It fires only once, and the old one is gone for good.
+        assert!(idle.next_deadline().is_none());
+        assert!(!idle.cancel_timer(soon));
+        close(quiet).unwrap();
+        close(quiet_wr).unwrap();
+    }
+}
@@ -6,6 +6,11 @@
 
 extern crate nix;
 extern crate libc;
+extern crate serde_json;
+#[cfg(feature = "async")]
+extern crate tokio;
+#[cfg(feature = "async")]
+extern crate futures_core;
 
 pub use libc::pid_t;
 
@@ -17,3 +22,28 @@ pub use subprocess::*;
 
 mod idle_loop;
 pub use idle_loop::*;
+
+#[cfg(feature = "async")]
+mod async_loop;
+#[cfg(feature = "async")]
+pub use async_loop::*;
+
+mod netns;
+pub use netns::*;
+
+mod daemon;
+pub use daemon::*;
+
+mod metrics;
+pub use metrics::*;
+
+mod envfile;
+pub use envfile::*;
+
+mod rlimit;
+pub use rlimit::*;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod seccomp;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use seccomp::*;
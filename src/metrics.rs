@@ -0,0 +1,133 @@
+//! Coarse performance measurements, for finding out where the time
+//! goes when setting up and tearing down large numbers of namespaces.
+
+use std::time::Duration;
+
+/// Internal: express a duration in (fractional) milliseconds.
+fn millis(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1000.0 + d.subsec_nanos() as f64 / 1_000_000.0
+}
+
+/// Timing information collected over the life of the program.
+/// Phases that haven't happened (yet) are None.
+pub struct Metrics {
+    create_total:   Option<Duration>,
+    create_each:    Vec<Duration>,
+    teardown_total: Option<Duration>,
+    ready:          Option<Duration>,
+}
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            create_total: None,
+            create_each: Vec::new(),
+            teardown_total: None,
+            ready: None,
+        }
+    }
+
+    /// Record the time taken to create one namespace.
+    pub fn record_create(&mut self, d: Duration) {
+        self.create_each.push(d);
+    }
+
+    /// Record the time taken to create all of the namespaces.
+    pub fn set_create_total(&mut self, d: Duration) {
+        self.create_total = Some(d);
+    }
+
+    /// Record the time taken to tear everything down.
+    pub fn set_teardown_total(&mut self, d: Duration) {
+        self.teardown_total = Some(d);
+    }
+
+    /// Record the time from program startup until it reported that
+    /// it was ready for use.
+    pub fn set_ready(&mut self, d: Duration) {
+        self.ready = Some(d);
+    }
+
+    /// The time from startup until ready, if that has happened yet.
+    pub fn ready(&self) -> Option<Duration> {
+        self.ready
+    }
+
+    /// Format D as "ready in Xms".
+    pub fn ready_message(d: Duration) -> String {
+        format!("ready in {:.0}ms", millis(d))
+    }
+
+    /// The time taken to create all of the namespaces, if that has
+    /// happened yet.
+    pub fn create_total(&self) -> Option<Duration> {
+        self.create_total
+    }
+
+    /// The number of per-namespace creation times recorded.
+    pub fn create_count(&self) -> usize {
+        self.create_each.len()
+    }
+
+    /// Summarize the timings in human-readable form, one phase per line.
+    pub fn timing_report(&self) -> String {
+        let mut report = String::new();
+        if let Some(d) = self.create_total {
+            report.push_str(&format!("create: {:.3}ms total\n", millis(d)));
+        }
+        if self.create_each.len() > 0 {
+            let each: Vec<f64> = self.create_each.iter()
+                .map(|&d| millis(d)).collect();
+            let min = each.iter().cloned().fold(::std::f64::INFINITY,
+                                                f64::min);
+            let max = each.iter().cloned().fold(0.0, f64::max);
+            let mean = each.iter().sum::<f64>() / each.len() as f64;
+            report.push_str(&format!(
+                "create: {} namespaces, min {:.3}ms, max {:.3}ms, \
+                 mean {:.3}ms\n", each.len(), min, max, mean));
+        }
+        if let Some(d) = self.ready {
+            report.push_str(&format!("{}\n", Metrics::ready_message(d)));
+        }
+        if let Some(d) = self.teardown_total {
+            report.push_str(&format!("teardown: {:.3}ms total\n",
+                                     millis(d)));
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phases_are_reported_once_they_happen() {
+        let mut metrics = Metrics::new();
+        assert_eq!(metrics.timing_report(), "");
+
+        metrics.record_create(Duration::from_millis(2));
+        metrics.record_create(Duration::from_millis(4));
+        metrics.record_create(Duration::from_millis(9));
+        metrics.set_create_total(Duration::from_millis(15));
+        assert_eq!(metrics.create_count(), 3);
+        assert_eq!(metrics.timing_report(),
+                   "create: 15.000ms total\n\
+                    create: 3 namespaces, min 2.000ms, max 9.000ms, \
+                    mean 5.000ms\n");
+
+        metrics.set_teardown_total(Duration::new(1, 500_000));
+        assert!(metrics.timing_report()
+                .ends_with("teardown: 1000.500ms total\n"));
+    }
+
+    #[test]
+    fn readiness_is_reported_in_whole_milliseconds() {
+        let mut metrics = Metrics::new();
+        assert!(metrics.ready().is_none());
+        metrics.set_ready(Duration::new(2, 345_600_000));
+        assert_eq!(metrics.ready(), Some(Duration::new(2, 345_600_000)));
+        assert_eq!(Metrics::ready_message(Duration::new(2, 345_600_000)),
+                   "ready in 2346ms");
+        assert_eq!(metrics.timing_report(), "ready in 2346ms\n");
+    }
+}
@@ -0,0 +1,162 @@
+//! Network namespace operations that can be done directly, without
+//! running "ip".
+
+use std::path::Path;
+use std::os::unix::io::RawFd;
+
+use nix;
+use nix::Errno;
+use nix::fcntl::{flock, open, FlockArg, O_RDONLY, O_CLOEXEC};
+use nix::sched::{setns, CLONE_NEWNET};
+use nix::sys::stat::Mode;
+use nix::unistd::close;
+
+use err::*;
+
+/// The directory where "ip netns" keeps the handles for named
+/// network namespaces.
+pub const NETNS_RUN_DIR: &'static str = "/run/netns";
+
+/// Check that NAME is acceptable as the name of a network namespace:
+/// not empty, not "." or "..", and not containing whitespace or a
+/// slash.  This means it can't refer to a file outside the directory
+/// of namespace handles, and can't smuggle extra arguments into an
+/// "ip" command line.
+pub fn check_netns_name(name: &str) -> Result<(), HLError> {
+    if name.is_empty() || name == "." || name == ".."
+        || name.chars().any(|c| c.is_whitespace() || c == '/') {
+        return Err(map_config_err(format!("invalid namespace name {:?}",
+                                          name)));
+    }
+    Ok(())
+}
+
+/// Open the handle for the network namespace NAME, which is expected
+/// to be in RUN_DIR (normally NETNS_RUN_DIR).  The file descriptor
+/// is close-on-exec.  NAME is checked with check_netns_name, so this
+/// can't be tricked into opening anything outside RUN_DIR.
+pub fn open_netns(name: &str, run_dir: &Path) -> Result<RawFd, HLError> {
+    try!(check_netns_name(name));
+    let path = run_dir.join(name);
+    open(path.as_path(), O_RDONLY | O_CLOEXEC, Mode::empty())
+        .map_err(|e| map_nix_err(e, format!("open {:?}", &path)))
+}
+
+/// Move the calling thread into the network namespace NAME, the same
+/// way "ip netns exec" would, but without running any other program.
+/// Only the network namespace is changed; unlike "ip netns exec",
+/// this does not arrange for /etc/netns/NAME to be bind-mounted over
+/// /etc.
+///
+/// This allocates memory, so it must not be called from a pre_exec
+/// hook.  To enter a namespace in a child process, call open_netns
+/// in the parent and enter_netns_fd in the hook.
+pub fn enter_netns(name: &str, run_dir: &Path) -> Result<(), HLError> {
+    let fd = try!(open_netns(name, run_dir));
+    let rv = enter_netns_fd(fd)
+        .map_err(|e| map_nix_err(e, format!("setns({})", name)));
+    let _ = close(fd);
+    rv
+}
+
+/// Move the calling thread into the network namespace referred to by
+/// FD, which should have come from open_netns.  This is a thin
+/// wrapper around setns() and is safe to use in a pre_exec hook.
+pub fn enter_netns_fd(fd: RawFd) -> nix::Result<()> {
+    setns(fd, CLONE_NEWNET)
+}
+
+/// A lock on a network namespace handle; see lock_netns.  The lock is
+/// released when this is dropped.
+pub struct NetnsLock(RawFd);
+impl Drop for NetnsLock {
+    fn drop(&mut self) {
+        let _ = close(self.0);
+    }
+}
+
+/// Lock the handle for the network namespace NAME in RUN_DIR: with a
+/// shared lock if EXCLUSIVE is false, an exclusive one if it is true.
+/// Supervisors hold shared locks on the namespaces they manage, so
+/// whoever gets an exclusive lock knows that nobody is managing the
+/// namespace.  Returns None, rather than waiting, if a conflicting
+/// lock is held.
+pub fn lock_netns(name: &str, run_dir: &Path, exclusive: bool)
+                  -> Result<Option<NetnsLock>, HLError> {
+    let lock = NetnsLock(try!(open_netns(name, run_dir)));
+    let arg = if exclusive { FlockArg::LockExclusiveNonblock }
+              else { FlockArg::LockSharedNonblock };
+    match flock(lock.0, arg) {
+        Ok(()) => Ok(Some(lock)),
+        Err(nix::Error::Sys(Errno::EWOULDBLOCK)) => Ok(None),
+        Err(e) => Err(map_nix_err(e, format!("lock namespace {}", name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use nix::unistd::getpid;
+    use super::*;
+
+    #[test]
+    fn namespace_names_are_checked() {
+        assert!(check_netns_name("t_ns0").is_ok());
+        for name in &["", ".", "..", "a/b", "a b", "a\tb"] {
+            assert!(check_netns_name(name).is_err(), "{:?}", name);
+        }
+        // Including when they are used to open a handle.
+        assert!(open_netns("..", Path::new(NETNS_RUN_DIR)).is_err());
+    }
+
+    #[test]
+    fn exclusive_lock_excludes_supervisors() {
+        let run_dir = env::temp_dir().join(format!(
+            "openvpn-netns-tools-lock-{}", getpid()));
+        fs::create_dir_all(&run_dir).unwrap();
+        fs::File::create(run_dir.join("t_ns0")).unwrap();
+
+        {
+            let shared = lock_netns("t_ns0", &run_dir, false).unwrap();
+            assert!(shared.is_some());
+            // Other supervisors can share it, but a reaper can't have it.
+            assert!(lock_netns("t_ns0", &run_dir, false).unwrap()
+                    .is_some());
+            assert!(lock_netns("t_ns0", &run_dir, true).unwrap().is_none());
+        }
+        {
+            let exclusive = lock_netns("t_ns0", &run_dir, true).unwrap();
+            assert!(exclusive.is_some());
+            assert!(lock_netns("t_ns0", &run_dir, false).unwrap()
+                    .is_none());
+        }
+        assert!(lock_netns("../t_ns0", &run_dir, true).is_err());
+        fs::remove_dir_all(&run_dir).unwrap();
+    }
+
+    #[test]
+    fn namespaces_are_entered_directly() {
+        // Entering the namespace we are already in changes nothing,
+        // but it still takes privileges.
+        match enter_netns("net", Path::new("/proc/self/ns")) {
+            Ok(()) => (),
+            Err(e) => assert!(e.to_string().contains("setns(net)"),
+                              "{}", e)
+        }
+
+        let run_dir = env::temp_dir().join(format!(
+            "openvpn-netns-tools-enter-{}", getpid()));
+        fs::create_dir_all(&run_dir).unwrap();
+        // A file that isn't a namespace handle can't be entered...
+        fs::File::create(run_dir.join("t_ns0")).unwrap();
+        let err = enter_netns("t_ns0", &run_dir).unwrap_err();
+        assert!(err.to_string().contains("setns(t_ns0)"), "{}", err);
+        // ...and one that doesn't exist can't even be opened.
+        let err = enter_netns("t_ns1", &run_dir).unwrap_err();
+        assert!(err.to_string().contains("open"), "{}", err);
+        assert!(enter_netns("../t_ns0", &run_dir).is_err());
+        fs::remove_dir_all(&run_dir).unwrap();
+    }
+}
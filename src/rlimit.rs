@@ -0,0 +1,162 @@
+//! Parsing resource limit settings, as given to isolate in its
+//! ISOL_RL_* variables.
+
+use std::io;
+
+use libc::{c_int, rlim_t, RLIM_INFINITY};
+
+use std::ascii::AsciiExt;
+
+use err::*;
+
+/// One of the limits that can be set with an ISOL_RL_* variable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    /// Wall-clock time, in seconds.  There is no rlimit for this; it
+    /// must be enforced by the parent process.
+    Wall,
+    /// RLIMIT_AS, RLIMIT_DATA, and RLIMIT_RSS, which are always set
+    /// to the same value.
+    Mem,
+    /// Any other limit, which corresponds to exactly one RLIMIT_*
+    /// resource.
+    Resource(c_int),
+}
+
+/// Look up the limit set by the variable ISOL_RL_NAME.
+pub fn limit_by_name(name: &str) -> Option<Limit> {
+    use libc::{RLIMIT_CORE, RLIMIT_CPU, RLIMIT_FSIZE, RLIMIT_MEMLOCK,
+               RLIMIT_MSGQUEUE, RLIMIT_NICE, RLIMIT_NOFILE, RLIMIT_NPROC,
+               RLIMIT_SIGPENDING, RLIMIT_STACK};
+
+    Some(match name {
+        "WALL"       => Limit::Wall,
+        "MEM"        => Limit::Mem,
+        "CPU"        => Limit::Resource(RLIMIT_CPU as c_int),
+        "CORE"       => Limit::Resource(RLIMIT_CORE as c_int),
+        "MEMLOCK"    => Limit::Resource(RLIMIT_MEMLOCK as c_int),
+        "MSGQUEUE"   => Limit::Resource(RLIMIT_MSGQUEUE as c_int),
+        "STACK"      => Limit::Resource(RLIMIT_STACK as c_int),
+        "FSIZE"      => Limit::Resource(RLIMIT_FSIZE as c_int),
+        "NICE"       => Limit::Resource(RLIMIT_NICE as c_int),
+        "SIGPENDING" => Limit::Resource(RLIMIT_SIGPENDING as c_int),
+        "NPROC"      => Limit::Resource(RLIMIT_NPROC as c_int),
+        "NOFILE"     => Limit::Resource(RLIMIT_NOFILE as c_int),
+        _ => return None
+    })
+}
+
+/// Set both the soft and the hard limit for LIMIT to VALUE, in the
+/// calling process.  Does nothing for Limit::Wall.  This does not
+/// allocate, so it is safe to call from a pre_exec hook.  Raising a
+/// hard limit requires privilege, so this should be done before
+/// giving up root.
+pub fn apply_limit(limit: Limit, value: rlim_t) -> io::Result<()> {
+    use libc::{rlimit, setrlimit, RLIMIT_AS, RLIMIT_DATA, RLIMIT_RSS};
+
+    fn set(resource: c_int, value: rlim_t) -> io::Result<()> {
+        let rl = rlimit { rlim_cur: value, rlim_max: value };
+        if unsafe { setrlimit(resource as _, &rl) } == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    match limit {
+        Limit::Wall => Ok(()),
+        Limit::Mem => {
+            try!(set(RLIMIT_AS as c_int, value));
+            try!(set(RLIMIT_DATA as c_int, value));
+            set(RLIMIT_RSS as c_int, value)
+        },
+        Limit::Resource(resource) => set(resource, value)
+    }
+}
+
+/// Internal: true if the limit called NAME is measured in bytes, so
+/// that its value may be written with a size suffix.
+fn is_size_limit(name: &str) -> bool {
+    match name {
+        "CORE" | "FSIZE" | "MEM" | "MEMLOCK" | "MSGQUEUE" | "STACK" => true,
+        _ => false
+    }
+}
+
+/// Parse VALUE as a setting for the resource limit called NAME (that
+/// is, the variable ISOL_RL_NAME).  "unlimited" means RLIM_INFINITY.
+/// Otherwise VALUE must be a decimal number; for limits measured in
+/// bytes, it may be followed by one of the suffixes k, M, G, or T (in
+/// either case), which multiply it by 2^10, 2^20, 2^30, or 2^40.
+pub fn parse_rlimit(name: &str, value: &str) -> Result<rlim_t, HLError> {
+    let bad = |why: &str| map_config_err(format!("ISOL_RL_{}={}: {}",
+                                                 name, value, why));
+    if value == "unlimited" {
+        return Ok(RLIM_INFINITY);
+    }
+
+    let (digits, shift) = match value.chars().last() {
+        Some(c) if c.is_ascii() && c.is_alphabetic() => {
+            if !is_size_limit(name) {
+                return Err(bad("size suffixes are only allowed for \
+                                limits measured in bytes"));
+            }
+            let shift = match c.to_ascii_lowercase() {
+                'k' => 10,
+                'm' => 20,
+                'g' => 30,
+                't' => 40,
+                _ => return Err(bad("unrecognized size suffix"))
+            };
+            (&value[..value.len() - 1], shift)
+        },
+        _ => (value, 0)
+    };
+
+    let n = try!(digits.parse::<u64>()
+                 .map_err(|_| bad("invalid rlimit value")));
+    // The value of RLIM_INFINITY is unspecified, as is the width of
+    // rlim_t, so check that the result survives the conversion and
+    // doesn't collide with RLIM_INFINITY.
+    let bytes = try!(n.checked_mul(1u64 << shift)
+                     .ok_or_else(|| bad("rlimit value out of range")));
+    let limit = bytes as rlim_t;
+    if limit as u64 != bytes || limit == RLIM_INFINITY {
+        return Err(bad("rlimit value out of range"));
+    }
+    Ok(limit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_may_have_suffixes() {
+        assert_eq!(parse_rlimit("MEM", "512M").unwrap(), 512 << 20);
+        assert_eq!(parse_rlimit("FSIZE", "1k").unwrap(), 1024);
+        assert_eq!(parse_rlimit("STACK", "2G").unwrap(), 2 << 30);
+        assert_eq!(parse_rlimit("CORE", "0").unwrap(), 0);
+        assert_eq!(parse_rlimit("CPU", "10").unwrap(), 10);
+        assert_eq!(parse_rlimit("NOFILE", "unlimited").unwrap(),
+                   RLIM_INFINITY);
+
+        for &(name, value) in &[("CPU", "10k"), ("MEM", "5X"), ("MEM", "k"),
+                                ("MEM", "-1"), ("MEM", "1.5G"),
+                                ("MEM", "Unlimited"), ("MEM", ""),
+                                ("MEM", "18446744073709551615T")] {
+            let err = parse_rlimit(name, value).unwrap_err();
+            assert!(err.to_string().contains(
+                &format!("ISOL_RL_{}={}", name, value)), "{}", err);
+        }
+    }
+
+    #[test]
+    fn limits_are_known_by_name() {
+        assert_eq!(limit_by_name("WALL"), Some(Limit::Wall));
+        assert_eq!(limit_by_name("MEM"), Some(Limit::Mem));
+        assert_eq!(limit_by_name("NOFILE"),
+                   Some(Limit::Resource(::libc::RLIMIT_NOFILE as c_int)));
+        assert_eq!(limit_by_name("nofile"), None);
+    }
+}
@@ -0,0 +1,275 @@
+//! Seccomp-BPF system call filtering for child processes.
+//!
+//! A filter is built in the parent (which may allocate) and then
+//! installed in the child by a pre_exec hook (which must not).  Once
+//! installed, any system call not on the filter's allowlist kills the
+//! process.  The filter also kills the process if it is running under
+//! a different system call ABI than the one the filter was built for,
+//! since the syscall numbers would mean something else.
+//!
+//! This talks to the kernel directly via prctl(); neither nix nor
+//! libc provides anything higher-level.  It is only supported on
+//! x86-64 and AArch64 Linux.
+
+use std::io;
+
+use std::io::{BufRead, BufReader, ErrorKind};
+use std::path::Path;
+
+use libc::c_long;
+
+use daemon::open_as_real_user;
+use err::*;
+
+// Neither libc nor nix exposes the classic-BPF structures and opcodes
+// in a form we can rely on, so here they are.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SockFilter {
+    code: u16,
+    jt:   u8,
+    jf:   u8,
+    k:    u32,
+}
+
+#[repr(C)]
+struct SockFprog {
+    len:    u16,
+    filter: *const SockFilter,
+}
+
+const BPF_LD:  u16 = 0x00;
+const BPF_W:   u16 = 0x00;
+const BPF_ABS: u16 = 0x20;
+const BPF_JMP: u16 = 0x05;
+const BPF_JEQ: u16 = 0x10;
+const BPF_K:   u16 = 0x00;
+const BPF_RET: u16 = 0x06;
+
+const SECCOMP_MODE_FILTER: c_long = 2;
+const SECCOMP_RET_KILL:    u32 = 0x0000_0000;
+const SECCOMP_RET_ALLOW:   u32 = 0x7fff_0000;
+
+// Offsets into struct seccomp_data.
+const SECCOMP_DATA_NR:   u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH_CURRENT: Option<u32> = Some(0xc000_003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH_CURRENT: Option<u32> = Some(0xc000_00b7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH_CURRENT: Option<u32> = None;
+
+fn bpf_stmt(code: u16, k: u32) -> SockFilter {
+    SockFilter { code: code, jt: 0, jf: 0, k: k }
+}
+fn bpf_jump(code: u16, k: u32, jt: u8, jf: u8) -> SockFilter {
+    SockFilter { code: code, jt: jt, jf: jf, k: k }
+}
+
+/// System calls that may be named in a profile.  Anything else must
+/// be given by number.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn syscall_by_name(name: &str) -> Option<u32> {
+    use libc::*;
+
+    let nr = match name {
+        "read"            => SYS_read,
+        "write"           => SYS_write,
+        "readv"           => SYS_readv,
+        "writev"          => SYS_writev,
+        "pread64"         => SYS_pread64,
+        "pwrite64"        => SYS_pwrite64,
+        "openat"          => SYS_openat,
+        "close"           => SYS_close,
+        "lseek"           => SYS_lseek,
+        "fstat"           => SYS_fstat,
+        "newfstatat"      => SYS_newfstatat,
+        "fcntl"           => SYS_fcntl,
+        "ioctl"           => SYS_ioctl,
+        "dup"             => SYS_dup,
+        "dup3"            => SYS_dup3,
+        "pipe2"           => SYS_pipe2,
+        "getcwd"          => SYS_getcwd,
+        "chdir"           => SYS_chdir,
+        "mmap"            => SYS_mmap,
+        "munmap"          => SYS_munmap,
+        "mprotect"        => SYS_mprotect,
+        "madvise"         => SYS_madvise,
+        "brk"             => SYS_brk,
+        "rt_sigaction"    => SYS_rt_sigaction,
+        "rt_sigprocmask"  => SYS_rt_sigprocmask,
+        "rt_sigreturn"    => SYS_rt_sigreturn,
+        "sigaltstack"     => SYS_sigaltstack,
+        "execve"          => SYS_execve,
+        "exit"            => SYS_exit,
+        "exit_group"      => SYS_exit_group,
+        "wait4"           => SYS_wait4,
+        "kill"            => SYS_kill,
+        "clone"           => SYS_clone,
+        "getpid"          => SYS_getpid,
+        "gettid"          => SYS_gettid,
+        "getuid"          => SYS_getuid,
+        "geteuid"         => SYS_geteuid,
+        "getgid"          => SYS_getgid,
+        "getegid"         => SYS_getegid,
+        "uname"           => SYS_uname,
+        "futex"           => SYS_futex,
+        "set_tid_address" => SYS_set_tid_address,
+        "set_robust_list" => SYS_set_robust_list,
+        "prlimit64"       => SYS_prlimit64,
+        "getrandom"       => SYS_getrandom,
+        "clock_gettime"   => SYS_clock_gettime,
+        "nanosleep"       => SYS_nanosleep,
+        "sched_yield"     => SYS_sched_yield,
+        "socket"          => SYS_socket,
+        "connect"         => SYS_connect,
+        "sendto"          => SYS_sendto,
+        "recvfrom"        => SYS_recvfrom,
+        _ => return None
+    };
+    Some(nr as u32)
+}
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn syscall_by_name(_: &str) -> Option<u32> {
+    None
+}
+
+/// Internal: construct an HLError describing a problem with a
+/// seccomp specification or profile.
+fn seccomp_err(what: String, detail: String) -> HLError {
+    map_io_err(io::Error::new(ErrorKind::InvalidData, what), detail)
+}
+
+/// A compiled seccomp filter, ready to be installed.
+#[derive(Clone)]
+pub struct SeccompFilter {
+    program: Vec<SockFilter>,
+}
+impl SeccompFilter {
+    /// Build a filter that allows exactly the system calls in ALLOWED.
+    fn allowing(allowed: &[u32]) -> Result<SeccompFilter, HLError> {
+        let arch = match AUDIT_ARCH_CURRENT {
+            Some(a) => a,
+            None => return Err(seccomp_err(
+                String::from("not supported on this architecture"),
+                String::from("seccomp")))
+        };
+        // Each jump offset must fit in a u8, and so must the program
+        // length in a u16; profiles long enough to matter are
+        // nowhere near either limit, but check anyway.
+        if allowed.len() > 200 {
+            return Err(seccomp_err(
+                format!("too many system calls ({})", allowed.len()),
+                String::from("seccomp")));
+        }
+
+        let mut program = Vec::with_capacity(allowed.len() * 2 + 5);
+        program.push(bpf_stmt(BPF_LD | BPF_W | BPF_ABS, SECCOMP_DATA_ARCH));
+        program.push(bpf_jump(BPF_JMP | BPF_JEQ | BPF_K, arch, 1, 0));
+        program.push(bpf_stmt(BPF_RET | BPF_K, SECCOMP_RET_KILL));
+        program.push(bpf_stmt(BPF_LD | BPF_W | BPF_ABS, SECCOMP_DATA_NR));
+        for &nr in allowed {
+            program.push(bpf_jump(BPF_JMP | BPF_JEQ | BPF_K, nr, 0, 1));
+            program.push(bpf_stmt(BPF_RET | BPF_K, SECCOMP_RET_ALLOW));
+        }
+        program.push(bpf_stmt(BPF_RET | BPF_K, SECCOMP_RET_KILL));
+
+        Ok(SeccompFilter { program: program })
+    }
+
+    /// The "strict" filter allows only read, write, and exiting (plus
+    /// sigreturn, so signal handlers can return).  execve is also
+    /// allowed, because the filter is installed *before* the program
+    /// to be confined is executed.  In practice this means only
+    /// statically linked programs written with this filter in mind
+    /// will survive; anything else dies at its first mmap.
+    pub fn strict() -> Result<SeccompFilter, HLError> {
+        let names = ["read", "write", "exit", "exit_group",
+                     "rt_sigreturn", "execve"];
+        let mut allowed = Vec::with_capacity(names.len());
+        for name in names.iter() {
+            match syscall_by_name(name) {
+                Some(nr) => allowed.push(nr),
+                None => return Err(seccomp_err(
+                    String::from("not supported on this architecture"),
+                    String::from("seccomp")))
+            }
+        }
+        SeccompFilter::allowing(&allowed)
+    }
+
+    /// Build a filter from a profile: a text file listing the system
+    /// calls to allow, one per line, either by name or by number.
+    /// Blank lines are ignored, as is anything after a '#'.  execve
+    /// is always allowed, as for the strict filter.  The profile is
+    /// opened with the permissions of the real user.
+    pub fn from_profile(path: &Path) -> Result<SeccompFilter, HLError> {
+        let detail = || format!("seccomp profile {:?}", path);
+        let f = try!(open_as_real_user(path)
+                     .map_err(|e| map_io_err(e, detail())));
+
+        let mut allowed = Vec::new();
+        if let Some(nr) = syscall_by_name("execve") {
+            allowed.push(nr);
+        }
+        for (lineno, line) in BufReader::new(f).lines().enumerate() {
+            let line = try!(line.map_err(|e| map_io_err(e, detail())));
+            let entry = line.splitn(2, '#').next().unwrap_or("").trim();
+            if entry.is_empty() {
+                continue;
+            }
+            let nr = match entry.parse::<u32>().ok()
+                                .or_else(|| syscall_by_name(entry)) {
+                Some(nr) => nr,
+                None => return Err(seccomp_err(
+                    format!("line {}: unknown system call {:?}",
+                            lineno + 1, entry),
+                    detail()))
+            };
+            if !allowed.contains(&nr) {
+                allowed.push(nr);
+            }
+        }
+        SeccompFilter::allowing(&allowed)
+    }
+
+    /// Build a filter from a specification string: either "strict",
+    /// or "profile:" followed by the path to a profile.
+    pub fn from_spec(spec: &str) -> Result<SeccompFilter, HLError> {
+        if spec == "strict" {
+            SeccompFilter::strict()
+        } else if spec.starts_with("profile:") {
+            SeccompFilter::from_profile(Path::new(&spec["profile:".len()..]))
+        } else {
+            Err(seccomp_err(format!("unrecognized mode {:?}", spec),
+                            String::from("seccomp")))
+        }
+    }
+
+    /// Install this filter in the calling process.  This does not
+    /// allocate, so it is safe to call from a pre_exec hook.  If the
+    /// kernel does not support seccomp filtering, this fails rather
+    /// than letting the program run unconfined.
+    pub fn install(&self) -> io::Result<()> {
+        use libc::{prctl, PR_SET_NO_NEW_PRIVS, PR_SET_SECCOMP};
+
+        let prog = SockFprog {
+            len: self.program.len() as u16,
+            filter: self.program.as_ptr(),
+        };
+        unsafe {
+            // Unprivileged processes may only install a filter if they
+            // have promised not to gain privileges through exec.
+            if prctl(PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if prctl(PR_SET_SECCOMP, SECCOMP_MODE_FILTER,
+                     &prog as *const SockFprog, 0, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,50 +1,874 @@
 /// Subprocess management.
 
+use std::cmp;
+use std::env;
+use std::fs;
+use std::fmt;
 use std::io;
 use std::num;
 use std::str;
 
-use std::io::Write;
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
 use std::process::{Child,Command,Stdio,ExitStatus};
-use nix::sys::signal::SigSet;
-//use nix::sys::signal::SIG_SETMASK;
-//use std::os::unix::process::CommandExt;
-use libc::pid_t;
+use std::thread::{self, JoinHandle};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering,
+                        ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+use std::time::{Duration, Instant};
+use nix;
+use nix::sys::signal::{SigSet, Signal};
+use nix::sys::wait::WaitStatus;
+use serde_json;
+use serde_json::Value;
+use libc::{c_int, gid_t, pid_t, uid_t, EINVAL};
 
 use err::*;
+use idle_loop::duration_to_poll_timeout;
+#[cfg(target_os = "linux")]
+use netns::{enter_netns_fd, open_netns, NETNS_RUN_DIR};
+use netns::check_netns_name;
+
+/// What a program should do when the idle loop reports that a child
+/// process it wasn't expecting has exited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnexpectedChildPolicy {
+    /// Reap the child and say nothing.
+    Ignore,
+    /// Reap the child and print a warning.
+    Warn,
+    /// Reap the child, print a warning, and shut down, since something
+    /// has probably gone wrong.
+    Fatal,
+}
+impl UnexpectedChildPolicy {
+    /// Parse a policy name: "ignore", "warn", or "fatal".
+    pub fn from_name(name: &str) -> Option<UnexpectedChildPolicy> {
+        match name {
+            "ignore" => Some(UnexpectedChildPolicy::Ignore),
+            "warn"   => Some(UnexpectedChildPolicy::Warn),
+            "fatal"  => Some(UnexpectedChildPolicy::Fatal),
+            _ => None
+        }
+    }
+}
 
-#[allow(dead_code)] // until we turn sigmasks back on
 pub struct ChildEnv {
     pub env:  Vec<(String, String)>,
     pub mask: SigSet,
     pub verbose: bool,
     pub dryrun: bool,
+    pub unexpected_child: UnexpectedChildPolicy,
+    /// Umask for files and directories created on the child's behalf;
+    /// see with_umask.
+    pub umask: u32,
+    /// If set, in dry-run mode, the command line of every child process
+    /// (including read-only queries, which are really executed) is
+    /// appended to this list, so that tests can check what would have
+    /// been run.
+    pub recorder: Option<Mutex<Vec<Vec<String>>>>,
+    /// Absolute paths of helper programs, found once at startup by
+    /// resolve_tools.  A command whose program is one of these names
+    /// runs that file, rather than being looked up in PATH each time;
+    /// messages still show the bare name.
+    pub tools: HashMap<String, PathBuf>,
+    /// The most file descriptors that may be held open at once on
+    /// behalf of child processes: pipes whose output is being tagged
+    /// or forwarded, and pidfds.  Past this, children are started
+    /// without them (see reserve_child_fd).  None means half of the
+    /// RLIMIT_NOFILE soft limit.
+    pub child_fd_cap: Option<usize>,
+}
+impl ChildEnv {
+    /// The command lines recorded so far (see the recorder field).
+    pub fn recorded(&self) -> Vec<Vec<String>> {
+        match self.recorder {
+            Some(ref r) => r.lock().unwrap().clone(),
+            None => Vec::new()
+        }
+    }
+}
+
+/// How much output functions like run_get_output will accept from a
+/// child process, unless told otherwise (see CommandSpec::output_limit).
+pub const DEFAULT_OUTPUT_LIMIT: usize = 1 << 20;
+
+/// How long functions like run_get_output keep reading a child's
+/// output after it exits, unless told otherwise (see
+/// CommandSpec::drain_timeout).
+pub const DEFAULT_DRAIN_TIMEOUT_MS: u64 = 1000;
+
+/// A PATH setting for child processes which includes only the
+/// standard system directories.
+pub const SAFE_PATH: &'static str =
+    "/usr/local/bin:/usr/bin:/bin:/usr/local/sbin:/usr/sbin:/sbin";
+
+/// Find each of the programs in NAMES in the PATH set in ENV (an
+/// environment for child processes, as from allowlisted_env), and
+/// return a map from name to absolute path, suitable for the tools
+/// field of a ChildEnv.  If any of them can't be found, that is an
+/// error, so that a missing program is reported once, at startup,
+/// rather than each time something tries to run it.
+pub fn resolve_tools(env: &[(String, String)], names: &[&str])
+                     -> Result<HashMap<String, PathBuf>, HLError> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = env.iter().find(|&&(ref k, _)| k == "PATH")
+        .map(|&(_, ref v)| v.as_str())
+        .unwrap_or("");
+    let mut tools = HashMap::new();
+    for &name in names {
+        let found = path.split(':')
+            .filter(|dir| dir.starts_with('/'))
+            .map(|dir| Path::new(dir).join(name))
+            .find(|candidate| match fs::metadata(candidate) {
+                Ok(m) => m.is_file() && m.permissions().mode() & 0o111 != 0,
+                Err(_) => false
+            });
+        match found {
+            Some(p) => { tools.insert(String::from(name), p); },
+            None => return Err(map_io_err(
+                io::Error::new(io::ErrorKind::NotFound,
+                               format!("not found in {}", path)),
+                format!("required program '{}'", name)))
+        }
+    }
+    Ok(tools)
+}
+
+/// Build an environment for child processes, starting from our own.
+/// Variables named in NAMES, or whose names begin with one of
+/// PREFIXES, are passed down; those whose names or values aren't valid
+/// UTF-8 are skipped.  The settings in FORCED are then added, replacing
+/// any values that were passed down.  The result is sorted by name.
+pub fn allowlisted_env(names: &[&str], prefixes: &[&str],
+                       forced: &[(&str, &str)]) -> Vec<(String, String)> {
+    let mut child_env: Vec<(String, String)> = env::vars_os()
+        .filter_map(|(k, v)| match (k.into_string(), v.into_string()) {
+            (Ok(k), Ok(v)) => Some((k, v)),
+            _ => None
+        })
+        .filter(|&(ref k, _)| {
+            (names.contains(&k.as_str())
+             || prefixes.iter().any(|p| k.starts_with(p)))
+                && !forced.iter().any(|&(f, _)| f == k)
+        })
+        .collect();
+
+    child_env.extend(forced.iter()
+                     .map(|&(k, v)| (String::from(k), String::from(v))));
+    child_env.sort();
+    child_env
+}
+
+/// What to do with a child process's stdout.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StdoutMode {
+    /// Share our stdout.
+    Inherit,
+    /// Discard it.
+    Null,
+    /// Send it to our stderr, keeping our stdout free for the pipe
+    /// protocol.
+    Stderr,
+    /// Append it to a log file, which is created if necessary.
+    File(PathBuf),
+}
+
+/// Scheduling policy for a child process (see CommandSpec::priority).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SchedPolicy {
+    /// Leave the policy alone.
+    Normal,
+    /// SCHED_BATCH: for CPU-bound work that shouldn't preempt anything.
+    Batch,
+    /// SCHED_IDLE: run only when nothing else wants the CPU.
+    Idle,
+}
+
+/// A command to be run, built up one piece at a time, e.g.
+/// `CommandSpec::new("ip").arg("netns").arg("add").arg(&name)`.
+/// Arguments can be anything that implements Display, so numbers
+/// don't have to be formatted into temporaries first.  By default,
+/// the command runs in our working directory and shares our stdout.
+#[derive(Clone, Debug)]
+pub struct CommandSpec {
+    argv: Vec<OsString>,
+    cwd: Option<PathBuf>,
+    stdout: StdoutMode,
+    user: Option<(uid_t, gid_t)>,
+    process_group: bool,
+    stderr_tag: Option<String>,
+    output_limit: Option<usize>,
+    keep_fds: Vec<RawFd>,
+    priority: Option<(c_int, SchedPolicy)>,
+    netns: Option<String>,
+    pass_fds: Vec<(RawFd, RawFd)>,
+    stderr_file: Option<PathBuf>,
+    extra_env: Vec<(String, String)>,
+    drain_timeout: Duration,
+}
+impl CommandSpec {
+    pub fn new<S: AsRef<OsStr>>(program: S) -> CommandSpec {
+        CommandSpec { argv: vec![program.as_ref().to_os_string()],
+                      cwd: None, stdout: StdoutMode::Inherit, user: None,
+                      process_group: false, stderr_tag: None,
+                      output_limit: Some(DEFAULT_OUTPUT_LIMIT),
+                      keep_fds: Vec::new(), priority: None, netns: None,
+                      pass_fds: Vec::new(), stderr_file: None,
+                      extra_env: Vec::new(),
+                      drain_timeout:
+                          Duration::from_millis(DEFAULT_DRAIN_TIMEOUT_MS) }
+    }
+
+    /// A CommandSpec for ARGV, whose first element is the program.
+    pub fn from_argv<S: AsRef<OsStr>>(argv: &[S]) -> CommandSpec {
+        CommandSpec::new(&argv[0]).args_os(&argv[1..])
+    }
+
+    pub fn arg<T: fmt::Display>(mut self, arg: T) -> CommandSpec {
+        self.argv.push(OsString::from(arg.to_string()));
+        self
+    }
+
+    pub fn args<I>(mut self, args: I) -> CommandSpec
+        where I: IntoIterator, I::Item: fmt::Display
+    {
+        self.argv.extend(args.into_iter()
+                         .map(|a| OsString::from(a.to_string())));
+        self
+    }
+
+    /// Add an argument that need not be valid UTF-8, such as a path,
+    /// exactly as it is.
+    pub fn arg_os<S: AsRef<OsStr>>(mut self, arg: S) -> CommandSpec {
+        self.argv.push(arg.as_ref().to_os_string());
+        self
+    }
+
+    pub fn args_os<I>(mut self, args: I) -> CommandSpec
+        where I: IntoIterator, I::Item: AsRef<OsStr>
+    {
+        self.argv.extend(args.into_iter()
+                         .map(|a| a.as_ref().to_os_string()));
+        self
+    }
+
+    /// Run the command in DIR instead of our working directory.
+    pub fn current_dir<P: AsRef<Path>>(mut self, dir: P) -> CommandSpec {
+        self.cwd = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Set up the command's stdout according to MODE.  This has no
+    /// effect on functions that capture stdout, such as
+    /// run_spec_get_output.
+    pub fn stdout(mut self, mode: StdoutMode) -> CommandSpec {
+        self.stdout = mode;
+        self
+    }
+
+    /// Set the environment variable KEY to VALUE for this command only,
+    /// overriding the ChildEnv.  If the same KEY is set more than
+    /// once, the last setting wins, as with env(1).
+    pub fn env<K: Into<String>, V: Into<String>>(mut self, key: K, value: V)
+                                                 -> CommandSpec {
+        self.extra_env.push((key.into(), value.into()));
+        self
+    }
+
+    /// Like env(), for each of the settings in VARS.
+    pub fn envs(mut self, vars: &[(String, String)]) -> CommandSpec {
+        self.extra_env.extend_from_slice(vars);
+        self
+    }
+
+    /// Append the command's stderr to the log file PATH, which is
+    /// created if necessary, instead of passing it through to ours.
+    /// If the command fails, the error can't then include the last
+    /// part of what it wrote there; look in the file.
+    pub fn stderr_file<P: AsRef<Path>>(mut self, path: P) -> CommandSpec {
+        self.stderr_file = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Run the command with user ID UID and group ID GID (and no
+    /// supplementary groups), instead of with our credentials.  Use
+    /// this for anything that doesn't need our privileges, especially
+    /// programs supplied by the user.
+    pub fn user(mut self, uid: uid_t, gid: gid_t) -> CommandSpec {
+        self.user = Some((uid, gid));
+        self
+    }
+
+    /// Put the command in a new process group, whose ID is the same as
+    /// its process ID, so that it and everything it forks can be
+    /// signaled together with kill_process_group.
+    pub fn new_process_group(mut self) -> CommandSpec {
+        self.process_group = true;
+        self
+    }
+
+    /// Forward each line the command writes to stderr with "[TAG] "
+    /// in front of it, so that it's clear where the line came from.
+    pub fn stderr_tag(mut self, tag: &str) -> CommandSpec {
+        self.stderr_tag = Some(format!("[{}] ", tag));
+        self
+    }
+
+    /// Limit how much the command may write to stdout, when that is
+    /// captured by a function such as run_spec_get_output, to LIMIT
+    /// bytes; if it writes more, it is killed and that is an error.
+    /// The default is DEFAULT_OUTPUT_LIMIT.  None means no limit.
+    pub fn output_limit(mut self, limit: Option<usize>) -> CommandSpec {
+        self.output_limit = limit;
+        self
+    }
+
+    /// After the command exits, keep reading its output for at most
+    /// TIMEOUT.  The pipes only reach EOF when every process holding
+    /// them has closed them, so if the command left a background
+    /// process behind that inherited them, we would otherwise wait for
+    /// that too.  The default is DEFAULT_DRAIN_TIMEOUT_MS.
+    pub fn drain_timeout(mut self, timeout: Duration) -> CommandSpec {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Run the command with nice level NICE and scheduling policy
+    /// POLICY.  If lowering the priority fails, a warning is printed,
+    /// but the command is still run; failing to raise the priority
+    /// (i.e. to a nice level below ours, which takes privilege) is an
+    /// error.  SCHED_BATCH and SCHED_IDLE are only available on Linux.
+    pub fn priority(mut self, nice: c_int, policy: SchedPolicy)
+                    -> CommandSpec {
+        self.priority = Some((nice, policy));
+        self
+    }
+
+    /// Run the command inside the network namespace NAMESPACE, by
+    /// entering it with setns() just before exec.  This is much cheaper
+    /// than "ip netns exec", but unlike that, it does not bind-mount
+    /// the files in /etc/netns/NAMESPACE over /etc, or remount /sys;
+    /// commands that need either should use run_in_netns instead.
+    #[cfg(target_os = "linux")]
+    pub fn netns(mut self, namespace: &str) -> CommandSpec {
+        self.netns = Some(String::from(namespace));
+        self
+    }
+
+    /// Let the command inherit file descriptor FD.  Otherwise, nothing
+    /// but stdin, stdout, and stderr is passed down.
+    pub fn keep_fd(mut self, fd: RawFd) -> CommandSpec {
+        self.keep_fds.push(fd);
+        self
+    }
+
+    /// Hand our file descriptor PARENT_FD to the command as file
+    /// descriptor CHILD_FD, which must be greater than 2 (the standard
+    /// three are set up by stdout() and friends) and not otherwise in
+    /// use by the command, e.g. via keep_fd or another pass_fd.
+    pub fn pass_fd(mut self, parent_fd: RawFd, child_fd: RawFd)
+                   -> CommandSpec {
+        self.pass_fds.push((parent_fd, child_fd));
+        self
+    }
+
+    /// The program name, converted lossily to UTF-8 for display.
+    pub fn program(&self) -> Cow<str> {
+        self.argv[0].to_string_lossy()
+    }
+
+    /// The command line, converted lossily to UTF-8 for display.
+    pub fn argv(&self) -> Vec<String> {
+        self.argv.iter().map(|a| a.to_string_lossy().into_owned()).collect()
+    }
+
+    /// The command line, quoted so that it could be pasted into a shell.
+    pub fn cmdline(&self) -> String {
+        shell_quote_argv(&self.argv())
+    }
+
+    /// Internal: the command line, preceded by any extra environment
+    /// settings, in the form env(1) takes them.
+    fn cmdline_with_env(&self) -> String {
+        let mut words: Vec<String> = self.extra_env.iter()
+            .map(|&(ref k, ref v)| format!("{}={}", k, shell_quote(v)))
+            .collect();
+        words.push(self.cmdline());
+        words.join(" ")
+    }
+
+    /// Internal: the Stdio to use for the command's stdout.
+    fn stdout_stdio(&self, env: &ChildEnv) -> Result<Stdio, HLError> {
+        match self.stdout {
+            StdoutMode::Inherit => Ok(Stdio::inherit()),
+            StdoutMode::Null => Ok(Stdio::null()),
+            StdoutMode::Stderr => self.stderr_as_stdout(),
+            StdoutMode::File(ref path) => open_log_file(path, env)
+        }
+    }
+
+    /// Internal: a copy of our stderr, to be the command's stdout.
+    fn stderr_as_stdout(&self) -> Result<Stdio, HLError> {
+        use libc::{dup, STDERR_FILENO};
+
+        let fd = unsafe { dup(STDERR_FILENO) };
+        if fd < 0 {
+            Err(map_io_err(io::Error::last_os_error(),
+                           format!("spawn {}", self.program())))
+        } else {
+            Ok(unsafe { Stdio::from_raw_fd(fd) })
+        }
+    }
+
+    /// Internal: the Stdio to use for the command's stderr, if it isn't
+    /// going to a file: PIPED if the caller wants to read it, or else
+    /// inherited.
+    fn stderr_stdio(&self, env: &ChildEnv, piped: bool)
+                    -> Result<Stdio, HLError> {
+        match self.stderr_file {
+            Some(ref path) => open_log_file(path, env),
+            None if piped => Ok(Stdio::piped()),
+            None => Ok(Stdio::inherit())
+        }
+    }
+}
+
+/// Internal: open the log file PATH, for appending, to receive a
+/// child's output.  In dry-run mode, the file is not touched, and the
+/// output is discarded.  (The command itself isn't really run then,
+/// but the file still shouldn't be created.)
+fn open_log_file(path: &Path, env: &ChildEnv) -> Result<Stdio, HLError> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    if env.dryrun {
+        return Ok(Stdio::null());
+    }
+    let file = try!(fs::OpenOptions::new()
+                    .append(true).create(true).mode(0o640)
+                    .open(path)
+                    .map_err(|e| map_io_err(e, format!("log file {:?}",
+                                                       path))));
+    // The file is opened close-on-exec, but the child gets a copy made
+    // with dup2(), which doesn't have that flag.
+    Ok(unsafe { Stdio::from_raw_fd(file.into_raw_fd()) })
+}
+
+/// Internal: convert a nix error to an io::Error, without allocating,
+/// for reporting failures from a pre_exec hook.
+fn hook_error(e: nix::Error) -> io::Error {
+    io::Error::from_raw_os_error(match e {
+        nix::Error::Sys(errno) => errno as i32,
+        _ => EINVAL
+    })
+}
+
+/// Internal: set the close-on-exec flag on file descriptors LO through
+/// HI inclusive, or as many of them as could be open.  Called from a
+/// pre_exec hook, so must not allocate.
+fn set_cloexec_range(lo: RawFd, hi: RawFd) {
+    use libc::{fcntl, getrlimit, rlimit, F_SETFD, FD_CLOEXEC, RLIMIT_NOFILE};
+
+    // close_range (Linux 5.11 and later, for this flag) does the whole
+    // job in one go.  It is too new for libc to know about; its number
+    // is the same on every architecture, except Alpha.
+    #[cfg(target_os = "linux")]
+    {
+        use libc::{c_long, c_uint, syscall};
+        const SYS_CLOSE_RANGE: c_long = 436;
+        const CLOSE_RANGE_CLOEXEC: c_uint = 1 << 2;
+        if unsafe { syscall(SYS_CLOSE_RANGE, lo as c_uint, hi as c_uint,
+                            CLOSE_RANGE_CLOEXEC) } == 0 {
+            return;
+        }
+    }
+
+    // Otherwise, do them one at a time, stopping at the limit on open
+    // files.  fcntl fails harmlessly on descriptors that aren't open.
+    let mut lim = rlimit { rlim_cur: 0, rlim_max: 0 };
+    let mut end = hi as u64 + 1;
+    if unsafe { getrlimit(RLIMIT_NOFILE, &mut lim) } == 0
+        && lim.rlim_cur < end {
+        end = lim.rlim_cur;
+    }
+    for fd in lo..(end as RawFd) {
+        unsafe { fcntl(fd, F_SETFD, FD_CLOEXEC); }
+    }
+}
+
+/// Internal: make sure that the only file descriptors which survive
+/// exec are 0, 1, 2, and those in KEEP, which must be sorted.  The
+/// others are marked close-on-exec rather than closed outright,
+/// because the standard library (and spawn_with_setup) need to keep a
+/// pipe open right up to the exec to report errors.  Called from a
+/// pre_exec hook, so must not allocate.
+fn restrict_inherited_fds(keep: &[RawFd]) -> io::Result<()> {
+    use libc::{fcntl, F_SETFD};
+    use std::i32;
+
+    let mut next = 3;
+    for &fd in keep.iter().filter(|&&fd| fd > 2) {
+        if fd > next {
+            set_cloexec_range(next, fd - 1);
+        }
+        // Descriptors opened by Rust are close-on-exec by default, so
+        // the ones to keep must have that flag cleared.
+        if unsafe { fcntl(fd, F_SETFD, 0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        next = fd + 1;
+    }
+    set_cloexec_range(next, i32::MAX);
+    Ok(())
 }
 
-fn internal_spawn(argv: &[&str], env: &ChildEnv, stdout: Stdio)
-                  -> io::Result<Child> {
+/// Internal: set up a Command for a child process.  In dry-run mode,
+/// the command is replaced with "true", unless READ_ONLY is set, which
+/// means the command is a query that doesn't change anything and is
+/// therefore safe to execute anyway (so that dry runs reflect the real
+/// state of the system).
+fn internal_command(spec: &CommandSpec, env: &ChildEnv, stdout: Stdio,
+                    stderr: Stdio, read_only: bool) -> Command {
 
     if env.verbose {
-        writeln!(io::stderr(), "{}", argv.join(" ")).unwrap();
+        let mut notes = Vec::new();
+        if let Some(ref namespace) = spec.netns {
+            notes.push(format!("in netns {}", namespace));
+        }
+        if let StdoutMode::File(ref path) = spec.stdout {
+            notes.push(format!("stdout >> {}", path.display()));
+        }
+        if let Some(ref path) = spec.stderr_file {
+            notes.push(format!("stderr >> {}", path.display()));
+        }
+        match spec.priority {
+            Some((nice, SchedPolicy::Normal)) =>
+                notes.push(format!("nice {}", nice)),
+            Some((nice, policy)) =>
+                notes.push(format!("nice {}, {:?}", nice, policy)),
+            None => ()
+        }
+        if notes.is_empty() {
+            writeln!(io::stderr(), "{}", spec.cmdline_with_env()).unwrap();
+        } else {
+            writeln!(io::stderr(), "{}  # {}",
+                     spec.cmdline_with_env(), notes.join("; ")).unwrap();
+        }
+    }
+    if env.dryrun {
+        if let Some(ref recorder) = env.recorder {
+            recorder.lock().unwrap().push(spec.argv());
+        }
     }
 
-    let exe = if env.dryrun { "true" } else { argv[0] };
+    let exe = if env.dryrun && !read_only {
+        OsStr::new("true")
+    } else {
+        match spec.argv[0].to_str().and_then(|p| env.tools.get(p)) {
+            Some(path) => path.as_os_str(),
+            None => spec.argv[0].as_os_str()
+        }
+    };
 
     let mut cmd = Command::new(exe);
     cmd.stdin(Stdio::null());
     cmd.stdout(stdout);
-    cmd.args(&argv[1..]);
+    cmd.stderr(stderr);
+    cmd.args(&spec.argv[1..]);
     cmd.env_clear();
+    if let Some(ref dir) = spec.cwd {
+        cmd.current_dir(dir);
+    }
 
-    for &(ref k, ref v) in env.env.iter() {
+    // Later settings replace earlier ones.
+    for &(ref k, ref v) in env.env.iter().chain(spec.extra_env.iter()) {
         cmd.env(k, v);
     }
-/*
-    cmd.before_exec(|| {
-        pthread_sigmask(SIG_SETMASK, Some(env.mask), None)
+
+    // prepare_signals blocked nearly every signal; put back the mask
+    // we started with, or the child couldn't be killed normally.  The
+    // hook runs after fork, so it must not allocate.
+    // Likewise, no file descriptors but the standard three (and any
+    // the caller asked for) should leak into the child; we run setuid.
+    let mask = env.mask;
+    let mut keep = spec.keep_fds.clone();
+    keep.sort();
+    keep.dedup();
+    unsafe {
+        cmd.pre_exec(move || {
+            try!(mask.thread_set_mask().map_err(hook_error));
+            restrict_inherited_fds(&keep)
+        });
+    }
+    cmd
+}
+
+/// Internal: if SPEC calls for it, arrange for CMD to enter a network
+/// namespace before it execs.  (In dry-run mode, commands that aren't
+/// going to be run for real stay where they are.)  Returns the open
+/// namespace file, which must be kept open until CMD has been spawned.
+#[cfg(target_os = "linux")]
+fn enter_netns(cmd: &mut Command, spec: &CommandSpec, env: &ChildEnv,
+               read_only: bool) -> Result<Option<fs::File>, HLError> {
+    let namespace = match spec.netns {
+        Some(ref namespace) if read_only || !env.dryrun => namespace,
+        _ => return Ok(None)
+    };
+    let fd = try!(open_netns(namespace, Path::new(NETNS_RUN_DIR)));
+    // The file is opened close-on-exec, but stays open up to the exec.
+    let file = unsafe { fs::File::from_raw_fd(fd) };
+    unsafe {
+        cmd.pre_exec(move || enter_netns_fd(fd).map_err(hook_error));
+    }
+    Ok(Some(file))
+}
+#[cfg(not(target_os = "linux"))]
+fn enter_netns(_: &mut Command, _: &CommandSpec, _: &ChildEnv, _: bool)
+               -> Result<Option<fs::File>, HLError> {
+    Ok(None)
+}
+
+/// Internal: if SPEC calls for it, arrange for CMD to receive file
+/// descriptors from us under different numbers.  The requests are
+/// checked for collisions here, since after the fork it's too late.
+///
+/// The pipes that the standard library (and spawn_with_setup) create
+/// while spawning must not end up with any of the target numbers, or
+/// dup2() would clobber them in the child; so any target that isn't
+/// open in this process is occupied by a placeholder, which is
+/// returned and must be kept open until CMD has been spawned.
+fn pass_fds(cmd: &mut Command, spec: &CommandSpec)
+            -> Result<Vec<fs::File>, HLError> {
+    use libc::{dup2, fcntl, F_DUPFD_CLOEXEC, F_GETFD};
+
+    let mut placeholders = Vec::new();
+    if spec.pass_fds.is_empty() {
+        return Ok(placeholders);
+    }
+    let mut targets = Vec::new();
+    for &(parent_fd, child_fd) in spec.pass_fds.iter() {
+        if child_fd <= 2 || targets.contains(&child_fd)
+            || spec.keep_fds.contains(&child_fd) {
+            return Err(map_config_err(format!(
+                "{}: cannot pass fd {} as fd {}, which is already in use",
+                spec.program(), parent_fd, child_fd)));
+        }
+        targets.push(child_fd);
+    }
+    for &child_fd in targets.iter() {
+        if unsafe { fcntl(child_fd, F_GETFD) } < 0 {
+            placeholders.push(try!(reserve_fd(child_fd)));
+        }
+    }
+
+    // Each source is first copied above every fd number involved, so
+    // that no dup2() can clobber a source that hasn't been moved yet.
+    // The copies are close-on-exec; dup2() clears that flag on the
+    // final descriptors.  The scratch vector is allocated here because
+    // the hook must not allocate.
+    let pairs = spec.pass_fds.clone();
+    let floor = pairs.iter().map(|&(p, c)| cmp::max(p, c)).max().unwrap() + 1;
+    let mut scratch: Vec<RawFd> = vec![-1; pairs.len()];
+    unsafe {
+        cmd.pre_exec(move || {
+            for (i, &(parent_fd, _)) in pairs.iter().enumerate() {
+                scratch[i] = fcntl(parent_fd, F_DUPFD_CLOEXEC, floor);
+                if scratch[i] < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            for (i, &(_, child_fd)) in pairs.iter().enumerate() {
+                if dup2(scratch[i], child_fd) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    Ok(placeholders)
+}
+
+/// Internal: open /dev/null, close-on-exec, as file descriptor FD.
+fn reserve_fd(fd: RawFd) -> Result<fs::File, HLError> {
+    use libc::{dup2, fcntl, FD_CLOEXEC, F_SETFD};
+
+    let null = try!(fs::File::open("/dev/null")
+                    .map_err(|e| map_io_err(e, String::from("/dev/null"))));
+    if unsafe { dup2(null.as_raw_fd(), fd) } < 0 {
+        return Err(map_io_err(io::Error::last_os_error(),
+                              format!("reserve fd {}", fd)));
+    }
+    let reserved = unsafe { fs::File::from_raw_fd(fd) };
+    unsafe { fcntl(fd, F_SETFD, FD_CLOEXEC); }
+    Ok(reserved)
+}
+
+/// Internal: spawn a child process, as set up by internal_command.
+/// If SPEC calls for a network namespace, a different priority, or
+/// different credentials, they are applied here.
+fn internal_spawn(spec: &CommandSpec, env: &ChildEnv, stdout: Stdio,
+                  stderr: Stdio, read_only: bool) -> Result<Child, HLError> {
+    internal_spawn_with_stdin(spec, env, Stdio::null(), stdout, stderr,
+                              read_only)
+}
+
+/// Internal: like internal_spawn, but the child's stdin is STDIN
+/// rather than /dev/null.
+fn internal_spawn_with_stdin(spec: &CommandSpec, env: &ChildEnv,
+                             stdin: Stdio, stdout: Stdio, stderr: Stdio,
+                             read_only: bool) -> Result<Child, HLError> {
+    use libc::setpgid;
+
+    let mut cmd = internal_command(spec, env, stdout, stderr, read_only);
+    cmd.stdin(stdin);
+    // This runs after internal_command's hook has dealt with the fds
+    // we aren't passing down.
+    let _placeholders = try!(pass_fds(&mut cmd, spec));
+    // This must come before spawn_with_setup gives up our privileges.
+    let _netns = try!(enter_netns(&mut cmd, spec, env, read_only));
+    if spec.process_group {
+        unsafe {
+            cmd.pre_exec(|| {
+                if setpgid(0, 0) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+    let child = try!(if spec.user.is_some() || spec.priority.is_some() {
+        spawn_with_setup(cmd, spec)
+    } else {
+        cmd.spawn()
+            .map_err(|e| map_io_err(e, format!("spawn {}", spec.program())))
     });
-*/
-    cmd.spawn()
+    if spec.process_group {
+        // Also set the group from this side, so that it is certain to
+        // exist by the time the caller tries to signal it, whichever
+        // process got to run first.  If the child has already exec'd,
+        // this fails with EACCES, but then the child's own call has
+        // taken effect, so the error is ignored.
+        let pid = child.id() as pid_t;
+        unsafe { setpgid(pid, pid); }
+    }
+    Ok(child)
+}
+
+/// Internal: set the scheduling policy of the calling process to
+/// POLICY, and return true if that worked.  Called from a pre_exec
+/// hook, so must not allocate.
+#[cfg(target_os = "linux")]
+fn set_sched_policy(policy: SchedPolicy) -> bool {
+    use libc::{sched_param, sched_setscheduler, SCHED_BATCH, SCHED_IDLE};
+
+    let policy = match policy {
+        SchedPolicy::Normal => return true,
+        SchedPolicy::Batch => SCHED_BATCH,
+        SchedPolicy::Idle => SCHED_IDLE
+    };
+    let param = sched_param { sched_priority: 0 };
+    unsafe { sched_setscheduler(0, policy, &param) == 0 }
+}
+#[cfg(not(target_os = "linux"))]
+fn set_sched_policy(policy: SchedPolicy) -> bool {
+    policy == SchedPolicy::Normal
+}
+
+/// Internal: spawn CMD, set up from SPEC, with the priority and
+/// credentials SPEC calls for.  If a necessary step fails, the error
+/// says which step it was; the pre_exec hook reports this over a
+/// close-on-exec pipe, since all that spawn() passes back from the
+/// child is an errno code.
+fn spawn_with_setup(mut cmd: Command, spec: &CommandSpec)
+                    -> Result<Child, HLError> {
+    use libc::{c_void, getpriority, setgid, setgroups, setpriority, setuid,
+               write, PRIO_PROCESS};
+    use nix::fcntl::O_CLOEXEC;
+    use nix::unistd::{close, pipe2, read};
+
+    let user = spec.user;
+    let priority = spec.priority;
+    // Raising priority takes privilege, and lowering it doesn't; we
+    // need to know which one we're doing to decide what's fatal.
+    let raising = match priority {
+        Some((nice, _)) => nice < unsafe { getpriority(PRIO_PROCESS as _, 0) },
+        None => false
+    };
+
+    let (rd, wr) = try!(pipe2(O_CLOEXEC)
+                        .map_err(|e| map_nix_err(e, String::from("pipe"))));
+    // The hook must not allocate, which is why the warnings are written
+    // with write().  Priority must be set, supplementary groups dropped,
+    // and setgid done, while we still have the privilege.
+    unsafe {
+        cmd.pre_exec(move || {
+            let warn = |msg: &[u8]| {
+                write(2, msg.as_ptr() as *const c_void, msg.len());
+            };
+            let setup = || -> Result<(), u8> {
+                if let Some((nice, policy)) = priority {
+                    if setpriority(PRIO_PROCESS as _, 0, nice) != 0 {
+                        if raising {
+                            return Err(4);
+                        }
+                        warn(b"warning: could not lower priority\n");
+                    }
+                    if !set_sched_policy(policy) {
+                        warn(b"warning: could not set scheduling policy\n");
+                    }
+                }
+                if let Some((uid, gid)) = user {
+                    if setgroups(1, &gid) != 0 {
+                        return Err(1);
+                    } else if setgid(gid) != 0 {
+                        return Err(2);
+                    } else if setuid(uid) != 0 {
+                        return Err(3);
+                    }
+                }
+                Ok(())
+            };
+            match setup() {
+                Ok(()) => Ok(()),
+                Err(step) => {
+                    let err = io::Error::last_os_error();
+                    write(wr, &step as *const u8 as *const c_void, 1);
+                    Err(err)
+                }
+            }
+        });
+    }
+    let result = cmd.spawn();
+    let _ = close(wr);
+    let mut step = [0u8; 1];
+    let got_step = match read(rd, &mut step) { Ok(1) => true, _ => false };
+    let _ = close(rd);
+
+    match result {
+        Ok(child) => Ok(child),
+        Err(e) => match (e.raw_os_error(), user, priority) {
+            (Some(errno), Some((uid, gid)), _) if got_step && step[0] <= 3 =>
+                Err(map_nix_err(
+                    nix::Error::Sys(nix::Errno::from_i32(errno)),
+                    match step[0] {
+                        1 => format!("setgroups({})", gid),
+                        2 => format!("setgid({})", gid),
+                        _ => format!("setuid({})", uid)
+                    })),
+            (Some(errno), _, Some((nice, _))) if got_step =>
+                Err(map_nix_err(
+                    nix::Error::Sys(nix::Errno::from_i32(errno)),
+                    format!("raise priority of {} to nice {}",
+                            spec.program(), nice))),
+            _ => Err(map_io_err(e, format!("spawn {}", spec.program())))
+        }
+    }
 }
 
 fn check_child_status(argv: &[&str], status: &ExitStatus)
@@ -56,53 +880,2184 @@ fn check_child_status(argv: &[&str], status: &ExitStatus)
     }
 }
 
-pub fn spawn(argv: &[&str], env: &ChildEnv) -> Result<Child, HLError> {
-    internal_spawn(argv, env, Stdio::inherit())
-        .map_err(|e| map_io_err(e, format!("spawn {}", argv[0])))
+/// Set up, but don't start, a Command that runs ARGV the same way
+/// spawn would, so that the caller can adjust it further (e.g. with
+/// additional pre_exec hooks, which run after ours).
+pub fn command(argv: &[&str], env: &ChildEnv) -> Command {
+    internal_command(&CommandSpec::from_argv(argv), env,
+                     Stdio::inherit(), Stdio::inherit(), false)
 }
 
-pub fn run(argv: &[&str], env: &ChildEnv) -> Result<(), HLError> {
+/// How many file descriptors are currently held open on behalf of
+/// child processes; see ChildEnv::child_fd_cap.
+static CHILD_FDS: AtomicUsize = ATOMIC_USIZE_INIT;
 
-    let mut child = try!(spawn(argv, env));
-    let status = try!(child.wait()
-                      .map_err(|e| map_io_err(e, format!("wait for {}",
-                                                         argv[0]))));
+/// Set once the cap on CHILD_FDS has been reached and that has been
+/// reported, so that it is only reported once.
+static CHILD_FD_CAP_REPORTED: AtomicBool = ATOMIC_BOOL_INIT;
 
-    check_child_status(argv, &status)
+/// Internal: one file descriptor's worth of CHILD_FDS, given back when
+/// this is dropped.  It goes wherever the descriptor goes.
+struct ChildFdSlot;
+impl Drop for ChildFdSlot {
+    fn drop(&mut self) {
+        CHILD_FDS.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
-pub fn run_ignore_failure(argv: &[&str], env: &ChildEnv) {
-    match run(argv, env) {
-        Ok(_) => (),
+/// Internal: the default for ChildEnv::child_fd_cap.
+fn default_child_fd_cap() -> usize {
+    use libc::{getrlimit, rlimit, RLIMIT_NOFILE, RLIM_INFINITY};
+
+    let mut rlim = rlimit { rlim_cur: 0, rlim_max: 0 };
+    if unsafe { getrlimit(RLIMIT_NOFILE, &mut rlim) } != 0
+        || rlim.rlim_cur == RLIM_INFINITY {
+        // Can't tell, so go by the traditional limit.
+        return 512;
+    }
+    cmp::min(rlim.rlim_cur / 2, usize::max_value() as u64) as usize
+}
+
+/// Internal: reserve room for one more file descriptor to be held
+/// open on behalf of a child process, if that wouldn't go over ENV's
+/// cap.  If it would, returns None, and the caller must do without:
+/// children's output is then passed through untagged, and they are
+/// reaped via SIGCHLD instead of pidfds.  The first time that happens,
+/// it is reported.
+fn reserve_child_fd(env: &ChildEnv) -> Option<ChildFdSlot> {
+    let cap = env.child_fd_cap.unwrap_or_else(default_child_fd_cap);
+    if CHILD_FDS.fetch_add(1, Ordering::SeqCst) < cap {
+        return Some(ChildFdSlot);
+    }
+    CHILD_FDS.fetch_sub(1, Ordering::SeqCst);
+    if !CHILD_FD_CAP_REPORTED.swap(true, Ordering::SeqCst) {
+        writeln!(io::stderr(), "warning: {} file descriptors are already \
+                                open for child processes; output from \
+                                further children will not be tagged, and \
+                                they will be reaped without pidfds",
+                 cap).unwrap();
+    }
+    None
+}
+
+pub fn spawn_spec(spec: &CommandSpec, env: &ChildEnv)
+                  -> Result<Child, HLError> {
+    let stdout = try!(spec.stdout_stdio(env));
+    let (prefix, slot) = match spec.stderr_tag {
+        Some(ref tag) if spec.stderr_file.is_none() => {
+            match reserve_child_fd(env) {
+                Some(slot) => (tag.clone(), slot),
+                None => return internal_spawn(spec, env, stdout,
+                                              Stdio::inherit(), false)
+            }
+        },
+        _ => return internal_spawn(spec, env, stdout,
+                                   try!(spec.stderr_stdio(env, false)), false)
+    };
+    let mut child = try!(internal_spawn(spec, env, stdout, Stdio::piped(),
+                                        false));
+    // This unwrap is safe because we just asked for stderr to be piped.
+    // The thread exits by itself when the child closes its stderr, so
+    // it doesn't need to be joined, and it doesn't get in the way of
+    // reaping the child.
+    let stderr = child.stderr.take().unwrap();
+    thread::spawn(move || {
+        let _slot = slot;
+        tee_stderr_tail(stderr, Some(prefix))
+    });
+    Ok(child)
+}
+
+pub fn spawn<S: AsRef<OsStr>>(argv: &[S], env: &ChildEnv)
+                              -> Result<Child, HLError> {
+    spawn_spec(&CommandSpec::from_argv(argv), env)
+}
+
+/// Spawn ARGV with the settings in EXTRA added to its environment (see
+/// CommandSpec::env).  Otherwise the same as spawn().
+pub fn spawn_with_env<S: AsRef<OsStr>>(argv: &[S], env: &ChildEnv,
+                                       extra: &[(String, String)])
+                                       -> Result<Child, HLError> {
+    spawn_spec(&CommandSpec::from_argv(argv).envs(extra), env)
+}
+
+/// A "pidfd": a file descriptor referring to a child process, which
+/// becomes readable when the child exits (see IdleLoop::watch_pidfd).
+/// This only exists on Linux.  The descriptor is closed when dropped.
+pub struct PidFd {
+    fd: RawFd,
+    pid: pid_t,
+    slot: Option<ChildFdSlot>,
+}
+impl PidFd {
+    /// Open a pidfd for the process PID.  Returns None if the kernel
+    /// doesn't support pidfds (they were added in Linux 5.3), in which
+    /// case the caller must rely on SIGCHLD instead.
+    #[cfg(target_os = "linux")]
+    pub fn open(pid: pid_t) -> Result<Option<PidFd>, HLError> {
+        use libc::{c_long, syscall, ENOSYS};
+
+        // pidfd_open is too new for libc to know about.  System calls
+        // added since Linux 5.1 have the same number on every
+        // architecture, except Alpha.
+        const SYS_PIDFD_OPEN: c_long = 434;
+
+        let fd = unsafe { syscall(SYS_PIDFD_OPEN, pid, 0) };
+        if fd >= 0 {
+            return Ok(Some(PidFd { fd: fd as RawFd, pid: pid, slot: None }));
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() == Some(ENOSYS) {
+            Ok(None)
+        } else {
+            Err(map_io_err(err, format!("pidfd_open({})", pid)))
+        }
+    }
+
+    /// The process ID this pidfd refers to.
+    pub fn pid(&self) -> pid_t {
+        self.pid
+    }
+}
+impl AsRawFd for PidFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+impl Drop for PidFd {
+    fn drop (&mut self) {
+        let _ = nix::unistd::close(self.fd);
+    }
+}
+
+/// Like spawn, but also open a pidfd for the child, if the kernel
+/// supports them.  If it does, the idle loop can watch the pidfd, and
+/// report exactly when this child exits, instead of relying on SIGCHLD.
+/// The pidfd counts against ENV's cap on descriptors held for children;
+/// if there is no room, there is no pidfd either.
+#[cfg(target_os = "linux")]
+pub fn spawn_with_pidfd<S: AsRef<OsStr>>(argv: &[S], env: &ChildEnv)
+                                         -> Result<(Child, Option<PidFd>),
+                                                   HLError> {
+    let mut child = try!(spawn(argv, env));
+    let slot = match reserve_child_fd(env) {
+        Some(slot) => slot,
+        None => return Ok((child, None))
+    };
+    match PidFd::open(child.id() as pid_t) {
+        Ok(pidfd) => Ok((child, pidfd.map(|mut p| {
+            p.slot = Some(slot);
+            p
+        }))),
         Err(e) => {
-            writeln!(io::stderr(), "{}", e).unwrap();
+            let _ = child.kill();
+            let _ = child.wait();
+            Err(e)
         }
     }
 }
 
-pub fn run_get_output(argv: &[&str], env: &ChildEnv)
-                      -> Result<Vec<u8>, HLError> {
-    let child = try!(internal_spawn(argv, env, Stdio::piped())
-                     .map_err(|e| map_io_err(e, format!("spawn {}",
-                                                        argv[0]))));
-    let output = try!(child.wait_with_output()
-                      .map_err(|e| map_io_err(e, format!("reading from {}",
-                                                         argv[0]))));
+/// Start a command and don't wait for it to finish.  The child is
+/// "double forked": an intermediate process forks it, puts it in a new
+/// session, and exits immediately, so that it is reparented to init.
+/// The intermediate process is reaped before this function returns,
+/// so no zombie is left behind, and the command itself is never one
+/// of our children (in particular, the idle loop never sees it exit).
+pub fn spawn_detached(argv: &[&str], env: &ChildEnv) -> Result<(), HLError> {
+    use libc::{_exit, fork, setsid};
+
+    let mut cmd = internal_command(&CommandSpec::from_argv(argv), env,
+                                   Stdio::inherit(), Stdio::inherit(), false);
+    // The hook must not allocate.  The intermediate process exits from
+    // inside it, and the grandchild carries on to exec.  If the exec
+    // fails, spawn() still finds out, because the grandchild inherits
+    // the pipe it uses for that.
+    unsafe {
+        cmd.pre_exec(|| {
+            match fork() {
+                -1 => Err(io::Error::last_os_error()),
+                0 => {
+                    setsid();
+                    Ok(())
+                },
+                _ => _exit(0)
+            }
+        });
+    }
+    let mut child = try!(cmd.spawn()
+                         .map_err(|e| map_io_err(e, format!("spawn {}",
+                                                            argv[0]))));
+    try!(child.wait()
+         .map_err(|e| map_io_err(e, format!("wait for {}", argv[0]))));
+    Ok(())
+}
 
-    try!(check_child_status(argv, &output.status));
-    Ok(output.stdout)
+/// Internal: copy SRC to our stderr, one line at a time, passing each
+/// line (without its terminating newline) through FORMAT first.  A
+/// partial line at EOF is still copied.  Bytes that aren't valid UTF-8
+/// are replaced with U+FFFD.  Runs until EOF or a read error.
+fn pump_lines<R, F>(src: R, format: F)
+    where R: Read, F: Fn(&str) -> String
+{
+    let mut src = BufReader::new(src);
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        match src.read_until(b'\n', &mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {
+                if buf.last() == Some(&b'\n') {
+                    buf.pop();
+                }
+                let line = format(&String::from_utf8_lossy(&buf));
+                // Lock stderr so that lines from concurrent pumps
+                // don't get mixed together.
+                let stderr = io::stderr();
+                let _ = writeln!(stderr.lock(), "{}", line);
+            }
+        }
+    }
 }
 
-pub fn run_get_output_pids(argv: &[&str], env: &ChildEnv)
-                           -> Result<Vec<pid_t>, HLError> {
+/// Spawn a child process whose stdout is forwarded, line by line, to
+/// our stderr, after passing through FORMAT (which might, for instance,
+/// add a prefix identifying the child).  This keeps our own stdout
+/// free for the pipe protocol.  Returns the child and the handle of
+/// the thread doing the forwarding; the thread exits when the child
+/// closes its stdout, so after waiting for the child, join the thread
+/// to be sure all of its output has been forwarded.  If ENV's cap on
+/// descriptors held for children has been reached, the child's stdout
+/// goes straight to our stderr, unformatted, and the thread does
+/// nothing.
+pub fn spawn_stdout_to_stderr<S, F>(argv: &[S], env: &ChildEnv, format: F)
+                                    -> Result<(Child, JoinHandle<()>), HLError>
+    where S: AsRef<OsStr>, F: Fn(&str) -> String + Send + 'static
+{
+    spawn_spec_stdout_to_stderr(&CommandSpec::from_argv(argv), env, format)
+}
 
-    let raw_output = try!(run_get_output(argv, env));
-    let output = try!(str::from_utf8(&raw_output)
-                      .map_err(|e| map_utf8_err(e, format!("{:?}",
-                                                           raw_output))));
-
-    output
-        .split_whitespace().map(|s| s.parse::<pid_t>())
-        .collect::<Result<Vec<pid_t>, num::ParseIntError>>()
-        .map_err(|e| map_pi_err(e, String::from("expected process id")))
+/// Like spawn_stdout_to_stderr, for the command described by SPEC,
+/// whose own stdout setting is ignored.
+pub fn spawn_spec_stdout_to_stderr<F>(spec: &CommandSpec, env: &ChildEnv,
+                                      format: F)
+                                      -> Result<(Child, JoinHandle<()>),
+                                                HLError>
+    where F: Fn(&str) -> String + Send + 'static
+{
+    let stderr = try!(spec.stderr_stdio(env, false));
+    let slot = match reserve_child_fd(env) {
+        Some(slot) => slot,
+        None => {
+            let stdout = try!(spec.stderr_as_stdout());
+            let child = try!(internal_spawn(spec, env, stdout, stderr,
+                                            false));
+            return Ok((child, thread::spawn(|| ())));
+        }
+    };
+    let mut child = try!(internal_spawn(spec, env, Stdio::piped(), stderr,
+                                        false));
+    // This unwrap is safe because we just asked for stdout to be piped.
+    let stdout = child.stdout.take().unwrap();
+    let pump = thread::spawn(move || {
+        let _slot = slot;
+        pump_lines(stdout, format)
+    });
+
+    Ok((child, pump))
+}
+
+/// How much of a child's stderr output to keep, for inclusion in the
+/// error reported if the child fails.
+const STDERR_TAIL_LIMIT: usize = 4096;
+
+/// Internal: write CHUNK, which is part of a child's stderr output,
+/// to OUT, with PREFIX at the beginning of each line.  AT_LINE_START
+/// says whether the previous chunk ended with a newline, and is
+/// updated accordingly.
+fn write_prefixed<W: Write>(out: &mut W, chunk: &[u8], prefix: &str,
+                            at_line_start: &mut bool) {
+    let mut rest = chunk;
+    while !rest.is_empty() {
+        if *at_line_start {
+            let _ = out.write_all(prefix.as_bytes());
+        }
+        let end = match rest.iter().position(|&b| b == b'\n') {
+            Some(i) => i + 1,
+            None => rest.len()
+        };
+        let _ = out.write_all(&rest[..end]);
+        *at_line_start = rest[end - 1] == b'\n';
+        rest = &rest[end..];
+    }
+}
+
+/// Internal: copy SRC to our stderr as it arrives, and also keep the
+/// last STDERR_TAIL_LIMIT bytes of it.  Runs until EOF or a read error,
+/// then returns the saved text.  If anything had to be discarded, the
+/// (probably partial) first line of what's left is dropped as well.
+/// If PREFIX is supplied, each line copied to our stderr begins with
+/// it, and a partial line at EOF is terminated.
+fn tee_stderr_tail<R: Read>(mut src: R, prefix: Option<String>) -> String {
+    let mut tail = Vec::with_capacity(2 * STDERR_TAIL_LIMIT);
+    let mut truncated = false;
+    let mut at_line_start = true;
+    let mut buf = [0u8; 4096];
+    loop {
+        match src.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                match prefix {
+                    Some(ref p) => {
+                        // Lock stderr so that lines from concurrent
+                        // pumps don't get mixed together.
+                        let stderr = io::stderr();
+                        write_prefixed(&mut stderr.lock(), &buf[..n], p,
+                                       &mut at_line_start);
+                    },
+                    None => { let _ = io::stderr().write_all(&buf[..n]); }
+                }
+                tail.extend_from_slice(&buf[..n]);
+                if tail.len() > STDERR_TAIL_LIMIT {
+                    let excess = tail.len() - STDERR_TAIL_LIMIT;
+                    tail.drain(..excess);
+                    truncated = true;
+                }
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(_) => break
+        }
+    }
+    if prefix.is_some() && !at_line_start {
+        let _ = io::stderr().write_all(b"\n");
+    }
+
+    let mut start = 0;
+    if truncated {
+        if let Some(i) = tail.iter().position(|&b| b == b'\n') {
+            start = i + 1;
+        }
+    }
+    let text = String::from_utf8_lossy(&tail[start..]);
+    if truncated {
+        format!("[...]\n{}", text.trim_right())
+    } else {
+        String::from(text.trim_right())
+    }
+}
+
+/// Internal: read SRC until EOF or a read error, and return the first
+/// LIMIT bytes of it (all of it, if LIMIT is None) as text, along with
+/// the total number of bytes read.
+fn capture_stderr<R: Read>(mut src: R, limit: Option<usize>)
+                           -> (String, u64) {
+    let mut captured = Vec::new();
+    let mut total: u64 = 0;
+    let mut buf = [0u8; 4096];
+    loop {
+        match src.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                total += n as u64;
+                let keep = match limit {
+                    Some(limit) => cmp::min(n, limit - cmp::min(
+                        limit, captured.len())),
+                    None => n
+                };
+                captured.extend_from_slice(&buf[..keep]);
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(_) => break
+        }
+    }
+    (String::from(String::from_utf8_lossy(&captured)), total)
+}
+
+/// Internal: how wait_with_stderr_tail should deal with a child's
+/// output.
+struct OutputOpts {
+    /// As for tee_stderr_tail.
+    prefix: Option<String>,
+    /// The most the child may write to stdout (or to stderr, when
+    /// that is captured).
+    limit: Option<usize>,
+    /// How long to keep reading after the child exits.
+    drain: Duration,
+    /// If true, the child's stderr is not forwarded to ours; all of
+    /// it is collected instead, and returned in place of the tail.
+    capture_stderr: bool,
+    verbose: bool,
+}
+impl OutputOpts {
+    fn new(spec: &CommandSpec, env: &ChildEnv) -> OutputOpts {
+        OutputOpts { prefix: spec.stderr_tag.clone(),
+                     limit: spec.output_limit,
+                     drain: spec.drain_timeout,
+                     capture_stderr: false,
+                     verbose: env.verbose }
+    }
+}
+
+/// Internal: wait for CHILD to exit.  Meanwhile, if its stderr is
+/// piped, it is forwarded to ours, and if the child fails, the last
+/// few kilobytes of that are included in the error.  Returns
+/// whatever the child wrote to stdout, if that was piped too.  If the
+/// child writes more than OPTS.limit bytes to stdout, it is killed,
+/// the rest of its output is discarded, and an OutputTooLong error is
+/// returned.
+fn wait_with_stderr_tail<S: AsRef<str>>(child: Child, argv: &[S],
+                                        opts: OutputOpts)
+                                        -> Result<Vec<u8>, HLError> {
+    let (status, output, tail) = try!(wait_collecting_output(child, argv,
+                                                             opts));
+    if status.success() {
+        Ok(output)
+    } else {
+        Err(map_unsuc_child_with_stderr(&status, argv, &tail))
+    }
+}
+
+/// Internal: like wait_with_stderr_tail, but the child's exit status
+/// is returned, along with its output and the tail of its stderr,
+/// rather than being checked.
+///
+/// Reading stops OPTS.drain after the child exits, even if its pipes
+/// are still open, because a background process it started may have
+/// inherited them and could hold them open indefinitely.  If reading
+/// or waiting fails, the child is killed and reaped before the error
+/// is returned.
+fn wait_collecting_output<S: AsRef<str>>(mut child: Child, argv: &[S],
+                                         opts: OutputOpts)
+                                         -> Result<(ExitStatus, Vec<u8>,
+                                                    String), HLError> {
+    use nix::poll::{poll, PollFd, POLLIN, EventFlags};
+    use std::sync::mpsc::{channel, RecvTimeoutError};
+
+    let name = String::from(argv[0].as_ref());
+
+    // Stderr isn't piped if it was sent to a log file.  The tail (or
+    // all of it, if it is being captured) is sent back over a channel,
+    // so that we needn't wait indefinitely for the thread to finish.
+    let prefix = opts.prefix;
+    let capture_limit = if opts.capture_stderr { Some(opts.limit) }
+                        else { None };
+    let tee = child.stderr.take().map(|stderr| {
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let _ = tx.send(match capture_limit {
+                Some(limit) => capture_stderr(stderr, limit),
+                None => (tee_stderr_tail(stderr, prefix), 0)
+            });
+        });
+        rx
+    });
+
+    let mut output = Vec::new();
+    let mut total: u64 = 0;
+    let mut status = None;
+    let mut exited_at = None;
+    let mut abandoned = false;
+    if let Some(mut stdout) = child.stdout.take() {
+        let fd = stdout.as_raw_fd();
+        let mut buf = [0u8; 4096];
+        loop {
+            // While the child is running, check on it now and then;
+            // once it has exited, read only until the drain deadline.
+            let timeout = match exited_at {
+                None => Duration::from_millis(50),
+                Some(t) => {
+                    let deadline = t + opts.drain;
+                    let now = Instant::now();
+                    if now >= deadline {
+                        abandoned = true;
+                        break;
+                    }
+                    deadline - now
+                }
+            };
+            let mut pfds = [PollFd::new(fd, POLLIN, EventFlags::empty())];
+            match poll(&mut pfds, duration_to_poll_timeout(timeout)) {
+                Ok(0) | Err(nix::Error::Sys(nix::Errno::EINTR)) => (),
+                Ok(_) => {
+                    let n = match stdout.read(&mut buf) {
+                        Ok(0) => break,
+                        Ok(n) => n,
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted
+                            => 0,
+                        Err(e) => return Err(abandon_child(
+                            &mut child, map_io_err(
+                                e, format!("reading from {}", name))))
+                    };
+                    total += n as u64;
+                    // Anything past the limit is discarded, after
+                    // killing the child.
+                    match opts.limit {
+                        Some(limit) if total > limit as u64 => {
+                            let keep = limit - output.len();
+                            output.extend_from_slice(&buf[..keep]);
+                            if status.is_none() {
+                                let _ = child.kill();
+                            }
+                        },
+                        _ => output.extend_from_slice(&buf[..n])
+                    }
+                },
+                Err(e) => return Err(abandon_child(
+                    &mut child, map_nix_err(
+                        e, format!("reading from {}", name))))
+            }
+            if status.is_none() {
+                status = match child.try_wait() {
+                    Ok(status) => status,
+                    Err(e) => return Err(abandon_child(
+                        &mut child, map_io_err(
+                            e, format!("wait for {}", name))))
+                };
+                if status.is_some() {
+                    exited_at = Some(Instant::now());
+                }
+            }
+        }
+    }
+
+    let status = match status {
+        Some(status) => status,
+        None => {
+            let status = match child.wait() {
+                Ok(status) => status,
+                Err(e) => return Err(abandon_child(
+                    &mut child, map_io_err(
+                        e, format!("wait for {}", name))))
+            };
+            exited_at = Some(Instant::now());
+            status
+        }
+    };
+    let (tail, stderr_total) = match tee {
+        None => (String::new(), 0),
+        Some(rx) => {
+            let deadline = exited_at.unwrap() + opts.drain;
+            let now = Instant::now();
+            let wait = if now < deadline { deadline - now }
+                       else { Duration::from_millis(0) };
+            match rx.recv_timeout(wait) {
+                Ok(tail) => tail,
+                Err(RecvTimeoutError::Timeout) => {
+                    abandoned = true;
+                    (String::new(), 0)
+                },
+                Err(RecvTimeoutError::Disconnected) => (String::new(), 0)
+            }
+        }
+    };
+    if abandoned && opts.verbose {
+        writeln!(io::stderr(), "# {}: output still held open after it \
+                                exited; not waiting for more", name)
+            .unwrap();
+    }
+
+    match opts.limit {
+        Some(limit) if total > limit as u64 =>
+            Err(map_output_too_long(argv, limit, total)),
+        Some(limit) if stderr_total > limit as u64 =>
+            Err(map_output_too_long(argv, limit, stderr_total)),
+        _ => Ok((status, output, tail))
+    }
+}
+
+/// Internal: having given up on collecting CHILD's output because of
+/// ERR, make sure it isn't left running, or left unreaped, before ERR
+/// is passed on.
+fn abandon_child(child: &mut Child, err: HLError) -> HLError {
+    let _ = child.kill();
+    let _ = child.wait();
+    err
+}
+
+/// Run a command and wait for it to finish.  Its stderr is passed
+/// through to ours, but if it fails, the last part of that output is
+/// also included in the error, so that the reason for the failure
+/// isn't lost among everything else we print.
+pub fn run_spec(spec: &CommandSpec, env: &ChildEnv) -> Result<(), HLError> {
+    let stdout = try!(spec.stdout_stdio(env));
+    let stderr = try!(spec.stderr_stdio(env, true));
+    let child = try!(internal_spawn(spec, env, stdout, stderr, false));
+    wait_with_stderr_tail(child, &spec.argv(),
+                          OutputOpts { limit: None,
+                                       ..OutputOpts::new(spec, env) })
+        .map(|_| ())
+}
+
+pub fn run<S: AsRef<OsStr>>(argv: &[S], env: &ChildEnv)
+                            -> Result<(), HLError> {
+    run_spec(&CommandSpec::from_argv(argv), env)
+}
+
+/// Get the exit status of CHILD, which the idle loop has reported, via
+/// Event::ChildExit, to have exited with STATUS.  The idle loop has
+/// already reaped the child, so this just converts STATUS; CHILD is
+/// consumed, so that it can't be waited for a second time (which would
+/// fail, or worse, reap some other process that has been given the
+/// same ID).
+///
+/// The intended pattern is for each long-lived child to be kept in a
+/// ChildRegistry; when ChildExit arrives, the registry is asked to
+/// reap that process ID (see ChildRegistry::reap), and if it doesn't
+/// know of the child, it was unexpected.
+pub fn reap_child(mut child: Child, status: WaitStatus)
+                  -> Result<ExitStatus, HLError> {
+    // This is the traditional encoding of wait statuses, which is
+    // what ExitStatusExt::from_raw expects.
+    match status {
+        WaitStatus::Exited(_, code) =>
+            return Ok(ExitStatus::from_raw((code as u8 as i32) << 8)),
+        WaitStatus::Signaled(_, sig, core) =>
+            return Ok(ExitStatus::from_raw(
+                sig as i32 | if core { 0x80 } else { 0 })),
+        _ => ()
+    }
+    // The child hasn't actually exited, so it hasn't been reaped.
+    let pid = child.id();
+    child.wait()
+        .map_err(|e| map_io_err(e, format!("wait for process {}", pid)))
+}
+
+/// Run ARGV with the settings in EXTRA added to its environment (see
+/// CommandSpec::env).  Otherwise the same as run().
+pub fn run_with_env<S: AsRef<OsStr>>(argv: &[S], env: &ChildEnv,
+                                     extra: &[(String, String)])
+                                     -> Result<(), HLError> {
+    run_spec(&CommandSpec::from_argv(argv).envs(extra), env)
+}
+
+/// A child process recorded in a ChildRegistry.
+pub struct RegisteredChild {
+    pub child: Child,
+    /// What the child is for, e.g. "openvpn" or "health check".
+    pub label: String,
+    /// Whether the caller should start a replacement if it exits.
+    pub restart: bool,
+}
+
+/// What's left of a RegisteredChild once it has been reaped.
+pub struct ExitedChild {
+    pub label: String,
+    pub restart: bool,
+    pub status: ExitStatus,
+}
+
+/// Keeps track of long-lived child processes by process ID, so that
+/// when the idle loop reports a ChildExit, the program can find out
+/// which child it was and get back the Child object to wait() on.
+///
+/// The registry owns each Child until it is removed, so nothing else
+/// can reap it in the meantime.  That means a registered process ID
+/// can't be reused by another process; once the child has been removed
+/// and reaped, its ID may be reused, and may be registered again.
+pub struct ChildRegistry {
+    children: HashMap<pid_t, RegisteredChild>,
+}
+impl ChildRegistry {
+    pub fn new() -> ChildRegistry {
+        ChildRegistry { children: HashMap::new() }
+    }
+
+    /// Add CHILD to the registry under LABEL, and return its process ID.
+    pub fn register(&mut self, child: Child, label: &str, restart: bool)
+                    -> pid_t {
+        let pid = child.id() as pid_t;
+        self.children.insert(pid, RegisteredChild {
+            child: child, label: String::from(label), restart: restart
+        });
+        pid
+    }
+
+    /// The label of the registered child with process ID PID, if any.
+    pub fn label(&self, pid: pid_t) -> Option<&str> {
+        self.children.get(&pid).map(|c| c.label.as_str())
+    }
+
+    pub fn contains(&self, pid: pid_t) -> bool {
+        self.children.contains_key(&pid)
+    }
+
+    /// Take the child with process ID PID out of the registry and
+    /// return it, so that the caller can wait() for it.
+    pub fn remove(&mut self, pid: pid_t) -> Option<RegisteredChild> {
+        self.children.remove(&pid)
+    }
+
+    /// Take the child with process ID PID, which the idle loop has
+    /// reported to have exited with STATUS, out of the registry, and
+    /// reap it with reap_child.  Returns None if PID isn't registered.
+    pub fn reap(&mut self, pid: pid_t, status: WaitStatus)
+                -> Option<Result<ExitedChild, HLError>> {
+        self.children.remove(&pid).map(|c| {
+            reap_child(c.child, status).map(|status| ExitedChild {
+                label: c.label, restart: c.restart, status: status
+            })
+        })
+    }
+
+    /// The process IDs of all the registered children.
+    pub fn pids(&self) -> Vec<pid_t> {
+        self.children.keys().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.children.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.children.is_empty()
+    }
+}
+
+/// Send SIG to every process in the process group PGID (see
+/// CommandSpec::new_process_group).  It is not an error if they have
+/// all exited already.
+pub fn kill_process_group(pgid: pid_t, sig: Signal) -> Result<(), HLError> {
+    use nix::sys::signal::kill;
+
+    match kill(-pgid, sig) {
+        Ok(()) | Err(nix::Error::Sys(nix::Errno::ESRCH)) => Ok(()),
+        Err(e) => Err(map_nix_err(e, format!("kill process group {}", pgid)))
+    }
+}
+
+/// Send SIG to process PID, reporting this first if ENV is verbose.
+/// In dry-run mode, nothing is sent.  It is not an error if the
+/// process has already exited.
+pub fn kill_process(pid: pid_t, sig: Signal, env: &ChildEnv)
+                    -> Result<(), HLError> {
+    use nix::sys::signal::kill;
+
+    if env.verbose {
+        writeln!(io::stderr(), "kill -s {:?} {}", sig, pid).unwrap();
+    }
+    if env.dryrun {
+        return Ok(());
+    }
+    match kill(pid, sig) {
+        Ok(()) | Err(nix::Error::Sys(nix::Errno::ESRCH)) => Ok(()),
+        Err(e) => Err(map_nix_err(e, format!("kill process {}", pid)))
+    }
+}
+
+/// Internal: common code for kill_gracefully and kill_group_gracefully.
+/// TARGET is passed directly to kill(); WHAT describes it for errors.
+fn kill_target_gracefully(target: pid_t, what: String, grace: Duration)
+                          -> Result<bool, HLError> {
+    use libc::{kill, SIGKILL, SIGTERM};
+    use nix::Errno;
+
+    // Returns Ok(true) if the target is gone.  Signal 0 only probes.
+    let send = |sig| {
+        if unsafe { kill(target, sig) } == 0 {
+            Ok(false)
+        } else {
+            match Errno::last() {
+                Errno::ESRCH => Ok(true),
+                e => Err(map_nix_err(nix::Error::Sys(e),
+                                     format!("kill {}", what)))
+            }
+        }
+    };
+
+    if try!(send(SIGTERM)) { return Ok(false); }
+    let deadline = Instant::now() + grace;
+    loop {
+        if try!(send(0)) { return Ok(false); }
+        let now = Instant::now();
+        if now >= deadline { break; }
+        thread::sleep(cmp::min(deadline - now, Duration::from_millis(20)));
+    }
+    try!(send(SIGKILL));
+    Ok(true)
+}
+
+/// Send SIGTERM to process PID, wait up to GRACE for it to go away,
+/// and if it hasn't, send SIGKILL.  Returns true if SIGKILL was needed.
+/// It is not an error if the process has already exited.  Note that a
+/// child of ours that has exited but not been waited for still exists
+/// as far as this function is concerned, so for those, prefer
+/// wait_with_timeout.
+pub fn kill_gracefully(pid: pid_t, grace: Duration) -> Result<bool, HLError> {
+    kill_target_gracefully(pid, format!("process {}", pid), grace)
+}
+
+/// Like kill_gracefully, but for every process in the process group
+/// PGID, which may be given with either sign.  The group is considered
+/// gone once none of its members remain.
+pub fn kill_group_gracefully(pgid: pid_t, grace: Duration)
+                             -> Result<bool, HLError> {
+    let pgid = if pgid < 0 { -pgid } else { pgid };
+    kill_target_gracefully(-pgid, format!("process group {}", pgid), grace)
+}
+
+/// Run a command, retrying if it exits unsuccessfully; see
+/// Runner::run_with_retry.
+pub fn run_with_retry(argv: &[&str], env: &ChildEnv, attempts: u32,
+                      backoff: Duration) -> Result<(), HLError> {
+    Runner::run_with_retry(env, argv, attempts, backoff)
+}
+
+/// Run a command and report whether it succeeded.  Unlike run(), an
+/// unsuccessful exit is not an error; only failing to run the command
+/// at all is.
+pub fn run_status(argv: &[&str], env: &ChildEnv) -> Result<bool, HLError> {
+    let mut child = try!(spawn(argv, env));
+    let status = try!(child.wait()
+                      .map_err(|e| map_io_err(e, format!("wait for {}",
+                                                         argv[0]))));
+    Ok(status.success())
+}
+
+/// Like run_status, but the command runs inside the network namespace
+/// whose handle is NETNS_FD (see open_netns), and with the *real* user
+/// and group IDs of this process, not the effective ones.  This is for
+/// running a command chosen by whoever invoked a setuid program, in a
+/// namespace, without giving it our privileges.
+pub fn run_status_in_netns_as_invoker(netns_fd: RawFd, argv: &[&str],
+                                      env: &ChildEnv)
+                                      -> Result<bool, HLError> {
+    use libc::{getgid, getuid, setgid, setuid};
+    use netns::enter_netns_fd;
+
+    let mut cmd = internal_command(&CommandSpec::from_argv(argv), env,
+                                   Stdio::inherit(), Stdio::inherit(), false);
+    // The hook must not allocate, so errors are converted to io::Error
+    // by hand rather than via HLError.
+    unsafe {
+        cmd.pre_exec(move || {
+            try!(enter_netns_fd(netns_fd).map_err(hook_error));
+            // setgid first, while we still have the privilege to do it.
+            if setgid(getgid()) != 0 || setuid(getuid()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = try!(cmd.spawn()
+                         .map_err(|e| map_io_err(e, format!("spawn {}",
+                                                            argv[0]))));
+    let status = try!(child.wait()
+                      .map_err(|e| map_io_err(e, format!("wait for {}",
+                                                         argv[0]))));
+    Ok(status.success())
+}
+
+/// Internal: wait up to DUR for CHILD (which is running ARGV) to exit,
+/// and return its status if it does.
+fn wait_with_timeout(child: &mut Child, dur: Duration, argv: &[&str])
+                     -> Result<Option<ExitStatus>, HLError> {
+    use std::cmp::min;
+
+    let deadline = Instant::now() + dur;
+    loop {
+        if let Some(status) = try!(child.try_wait().map_err(
+            |e| map_io_err(e, format!("wait for {}", argv[0])))) {
+            return Ok(Some(status));
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(None);
+        }
+        thread::sleep(min(deadline - now, Duration::from_millis(10)));
+    }
+}
+
+/// Like run, but if the command hasn't finished after TIMEOUT, it is
+/// sent SIGTERM, and if it still hasn't exited GRACE after that, it is
+/// sent SIGKILL.  In either of those cases, the child is reaped and
+/// the result is a ChildTimedOut error.  A command that fails within
+/// the time limit produces the same error as with run.
+pub fn run_with_timeout(argv: &[&str], env: &ChildEnv, timeout: Duration,
+                        grace: Duration) -> Result<(), HLError> {
+    use nix::Errno::ESRCH;
+    use nix::sys::signal::{kill, SIGKILL, SIGTERM};
+
+    let mut child = try!(spawn(argv, env));
+    if let Some(status) = try!(wait_with_timeout(&mut child, timeout,
+                                                 argv)) {
+        return check_child_status(argv, &status);
+    }
+
+    // The child can't be reaped until we wait for it, so its process
+    // ID can't have been reused, but it may have exited on its own
+    // in the meantime; that is not an error.
+    let pid = child.id() as pid_t;
+    let signal = |sig: Signal| match kill(pid, sig) {
+        Ok(()) | Err(nix::Error::Sys(ESRCH)) => Ok(()),
+        Err(e) => Err(map_nix_err(e, format!("kill {}", argv[0])))
+    };
+
+    try!(signal(SIGTERM));
+    if try!(wait_with_timeout(&mut child, grace, argv)).is_some() {
+        return Err(map_child_timed_out(argv, timeout, false));
+    }
+    try!(signal(SIGKILL));
+    try!(child.wait()
+         .map_err(|e| map_io_err(e, format!("wait for {}", argv[0]))));
+    Err(map_child_timed_out(argv, timeout, true))
+}
+
+pub fn run_ignore_failure(argv: &[&str], env: &ChildEnv) {
+    Runner::run_ignore_failure(env, argv)
+}
+
+/// Internal: if STDERR, as captured from "ip netns exec", contains the
+/// message "ip" prints when it cannot execute the inner command,
+/// return the reason it gave.
+fn netns_exec_failure(stderr: &str) -> Option<&str> {
+    for line in stderr.lines() {
+        if line.starts_with("exec of \"") {
+            if let Some(i) = line.find("\" failed: ") {
+                return Some(&line[i + "\" failed: ".len()..]);
+            }
+        }
+    }
+    None
+}
+
+/// Run a command with its stderr captured, instead of passed through
+/// to ours, so that the caller can examine it.  Returns the usual
+/// result of running the command, and whatever it wrote to stderr
+/// (lossily converted to UTF-8).  The caller is responsible for
+/// reporting the stderr text, if appropriate.  As with
+/// run_get_output, writing too much is an error, and reading stops
+/// shortly after the command exits, even if something it started
+/// still holds its stderr open.
+pub fn run_capture_stderr(argv: &[&str], env: &ChildEnv)
+                          -> (Result<(), HLError>, String) {
+    let spec = CommandSpec::from_argv(argv);
+    let child = match internal_spawn(&spec, env, Stdio::inherit(),
+                                     Stdio::piped(), false) {
+        Ok(child) => child,
+        Err(e) => return (Err(e), String::new())
+    };
+    let opts = OutputOpts { capture_stderr: true,
+                            ..OutputOpts::new(&spec, env) };
+    match wait_collecting_output(child, argv, opts) {
+        Ok((status, _, stderr)) => (check_child_status(argv, &status),
+                                    stderr),
+        Err(e) => (Err(e), String::new())
+    }
+}
+
+/// Construct the command line that runs ARGV inside the network
+/// namespace NAMESPACE, using "ip netns exec".  NAMESPACE is checked
+/// as described for check_netns_name.
+pub fn netns_argv<'a>(namespace: &'a str, argv: &[&'a str])
+                      -> Result<Vec<&'a str>, HLError> {
+    try!(check_netns_name(namespace));
+    let mut full_argv = vec!["ip", "netns", "exec", namespace];
+    full_argv.extend_from_slice(argv);
+    Ok(full_argv)
+}
+
+/// Spawn ARGV inside the network namespace NAMESPACE, using "ip netns
+/// exec".  Otherwise the same as spawn().
+pub fn spawn_in_netns(namespace: &str, argv: &[&str], env: &ChildEnv)
+                      -> Result<Child, HLError> {
+    let full_argv = try!(netns_argv(namespace, argv));
+    spawn(&full_argv, env)
+}
+
+/// Run ARGV inside the network namespace NAMESPACE, using "ip netns
+/// exec".  If the command itself could not be executed (most often
+/// because it isn't installed), this is reported as a NetnsExecFailed
+/// error naming the command, rather than as a failure of "ip".  The
+/// child's stderr is captured in order to detect this, and is copied
+/// to our stderr afterward.
+pub fn run_in_netns(namespace: &str, argv: &[&str], env: &ChildEnv)
+                    -> Result<(), HLError> {
+    let full_argv = try!(netns_argv(namespace, argv));
+    let (result, stderr_text) = run_capture_stderr(&full_argv, env);
+    if result.is_err() {
+        if let Some(reason) = netns_exec_failure(&stderr_text) {
+            return Err(map_netns_exec_failed(namespace, argv[0], reason));
+        }
+    }
+    if !stderr_text.is_empty() {
+        let _ = write!(io::stderr(), "{}", stderr_text);
+    }
+    result
+}
+
+/// Run ARGV inside the network namespace NAMESPACE, entering it
+/// directly rather than via "ip netns exec"; see CommandSpec::netns
+/// for what that doesn't do.  Otherwise the same as run().
+#[cfg(target_os = "linux")]
+pub fn run_in_netns_direct(namespace: &str, argv: &[&str], env: &ChildEnv)
+                           -> Result<(), HLError> {
+    run_spec(&CommandSpec::from_argv(argv).netns(namespace), env)
+}
+
+/// Run each of the commands in ARGVS, one after another.  Unlike
+/// calling run() in a loop, this does not stop at the first failure;
+/// every command is run, and if any of them failed, the error is a
+/// CommandFailures that lists the index and full command line of
+/// each one that did, along with its own error.
+pub fn run_each(argvs: &[&[&str]], env: &ChildEnv) -> Result<(), HLError> {
+    let failures: Vec<(usize, String, HLError)> = argvs.iter()
+        .enumerate()
+        .filter_map(|(i, argv)| run(argv, env).err()
+                    .map(|e| (i, shell_quote_argv(argv), e)))
+        .collect();
+
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(map_command_failures(argvs.len(), failures))
+    }
+}
+
+/// Run all of the commands in ARGVS, like run_each, but with up to
+/// MAX_PARALLEL of them running at once, to overlap their startup
+/// costs.  Commands are started in order, and each is logged (in
+/// verbose or dry-run mode) as it is started; if one can't be started,
+/// that is recorded as its failure, and the rest still run.  Errors
+/// are reported as for run_each, in the order of ARGVS.
+pub fn run_all(argvs: &[&[&str]], env: &ChildEnv, max_parallel: usize)
+               -> Result<(), HLError> {
+    let max_parallel = cmp::max(max_parallel, 1);
+    let mut failures: Vec<(usize, String, HLError)> = Vec::new();
+    let mut running: VecDeque<(usize, Child)> = VecDeque::new();
+
+    // Children are waited for oldest first.  Until its turn comes, a
+    // child's stderr stays in its pipe (so a very chatty one may
+    // stall).  Only one child's stderr is forwarded at a time, and
+    // nothing else is printed meanwhile, so nothing is mixed up.
+    let wait = |i: usize, child: Child| {
+        let opts = OutputOpts {
+            limit: None,
+            ..OutputOpts::new(&CommandSpec::from_argv(argvs[i]), env)
+        };
+        wait_with_stderr_tail(child, argvs[i], opts).err()
+            .map(|e| (i, shell_quote_argv(argvs[i]), e))
+    };
+    for (i, argv) in argvs.iter().enumerate() {
+        if running.len() >= max_parallel {
+            let (j, child) = running.pop_front().unwrap();
+            failures.extend(wait(j, child));
+        }
+        match internal_spawn(&CommandSpec::from_argv(argv), env,
+                             Stdio::inherit(), Stdio::piped(), false) {
+            Ok(child) => running.push_back((i, child)),
+            Err(e) => failures.push((i, shell_quote_argv(argv), e))
+        }
+    }
+    while let Some((j, child)) = running.pop_front() {
+        failures.extend(wait(j, child));
+    }
+
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        failures.sort_by_key(|f| f.0);
+        Err(map_command_failures(argvs.len(), failures))
+    }
+}
+
+fn internal_run_get_output(spec: &CommandSpec, env: &ChildEnv,
+                           read_only: bool) -> Result<Vec<u8>, HLError> {
+    let stderr = try!(spec.stderr_stdio(env, true));
+    let child = try!(internal_spawn(spec, env, Stdio::piped(), stderr,
+                                    read_only));
+    wait_with_stderr_tail(child, &spec.argv(), OutputOpts::new(spec, env))
+}
+
+/// Run a command and return everything it writes to stdout.
+/// In dry-run mode, the command is not executed and the output
+/// is empty.
+pub fn run_spec_get_output(spec: &CommandSpec, env: &ChildEnv)
+                           -> Result<Vec<u8>, HLError> {
+    internal_run_get_output(spec, env, false)
+}
+
+pub fn run_get_output<S: AsRef<OsStr>>(argv: &[S], env: &ChildEnv)
+                                       -> Result<Vec<u8>, HLError> {
+    run_spec_get_output(&CommandSpec::from_argv(argv), env)
+}
+
+/// Run a command and return its exit status along with everything it
+/// writes to stdout.  Unlike run_get_output, an unsuccessful exit is
+/// not an error; that is for the caller to interpret.  (Failure to
+/// run the command at all, or too much output, still is.)  In dry-run
+/// mode, the command is not executed, the status is success, and the
+/// output is empty.
+pub fn run_get_output_status<S: AsRef<OsStr>>(argv: &[S], env: &ChildEnv)
+                                              -> Result<(ExitStatus, Vec<u8>),
+                                                        HLError> {
+    let spec = CommandSpec::from_argv(argv);
+    let child = try!(internal_spawn(&spec, env, Stdio::piped(),
+                                    Stdio::piped(), false));
+    let (status, output, _) = try!(wait_collecting_output(
+        child, &spec.argv(), OutputOpts::new(&spec, env)));
+    Ok((status, output))
+}
+
+/// Internal: run a command with INPUT fed to its stdin, and its
+/// stdout set up as STDOUT.  The input is written from a separate
+/// thread, so that a child which produces output before it has read
+/// all of its input can't deadlock against us.  If the child fails,
+/// that is what's reported; otherwise, failure to write all of the
+/// input (e.g. EPIPE, because the child exited without reading it)
+/// is an error.
+fn internal_run_with_input(argv: &[&str], env: &ChildEnv, input: &[u8],
+                           stdout: Stdio) -> Result<Vec<u8>, HLError> {
+    let spec = CommandSpec::from_argv(argv);
+    let mut child = try!(internal_spawn_with_stdin(&spec, env,
+                                                   Stdio::piped(), stdout,
+                                                   Stdio::piped(), false));
+
+    // This unwrap is safe because stdin was set to piped() above.
+    // The pipe is closed when the writer thread drops it.
+    let mut stdin = child.stdin.take().unwrap();
+    let input = input.to_vec();
+    let writer = thread::spawn(move || stdin.write_all(&input));
+
+    let output = try!(wait_with_stderr_tail(child, argv,
+                                            OutputOpts::new(&spec, env)));
+    match writer.join() {
+        Ok(Ok(())) => Ok(output),
+        // In dry-run mode the child is "true", which reads nothing.
+        Ok(Err(_)) if env.dryrun => Ok(output),
+        Ok(Err(e)) => Err(map_io_err(e, format!("writing to {}",
+                                                shell_quote_argv(argv)))),
+        Err(_) => panic!("run_with_input: writer thread panicked")
+    }
+}
+
+/// Run a command and feed it INPUT on its stdin.  Its stdout and
+/// stderr are passed through to ours, as for run().
+pub fn run_with_input(argv: &[&str], env: &ChildEnv, input: &[u8])
+                      -> Result<(), HLError> {
+    internal_run_with_input(argv, env, input, Stdio::inherit()).map(|_| ())
+}
+
+/// Run a command, feed it INPUT on its stdin, and return everything it
+/// writes to stdout.
+pub fn run_with_input_get_output(argv: &[&str], env: &ChildEnv,
+                                 input: &[u8]) -> Result<Vec<u8>, HLError> {
+    internal_run_with_input(argv, env, input, Stdio::piped())
+}
+
+/// Run a *read-only* command and return everything it writes to
+/// stdout.  Because the command is promised not to change anything,
+/// it is executed even in dry-run mode.  Do not use this for commands
+/// with side effects.
+pub fn run_query_get_output(argv: &[&str], env: &ChildEnv)
+                            -> Result<Vec<u8>, HLError> {
+    internal_run_get_output(&CommandSpec::from_argv(argv), env, true)
+}
+
+/// Internal: ARGV, an "ip" command, with "-j" (for JSON output)
+/// inserted after the program name.
+fn ip_json_argv<'a>(argv: &[&'a str]) -> Vec<&'a str> {
+    let mut json_argv = vec![argv[0], "-j"];
+    json_argv.extend_from_slice(&argv[1..]);
+    json_argv
+}
+
+/// Internal: parse RESULT, the outcome of running ARGV, an "ip"
+/// command with -j, as JSON.  See run_get_json.
+fn parse_ip_json(argv: &[&str], result: Result<Vec<u8>, HLError>)
+                 -> Result<Value, HLError> {
+    let output = match result {
+        Ok(output) => output,
+        Err(HLError::UnsuccessfulChild { ref cmdline, ref stderr, .. })
+            if stderr.contains("\"-j\" is unknown") => {
+                return Err(HLError::UnsuccessfulChild {
+                    status: String::from("failed: this version of iproute2 \
+                                          does not support JSON output"),
+                    cmdline: cmdline.clone(),
+                    stderr: stderr.clone()
+                });
+            },
+        Err(e) => return Err(e)
+    };
+    // "ip" prints nothing at all, rather than "[]", when there is
+    // nothing to list.
+    if String::from_utf8_lossy(&output).trim().is_empty() {
+        return Ok(Value::Array(Vec::new()));
+    }
+    serde_json::from_slice(&output)
+        .map_err(|e| map_json_err(e, argv, &output))
+}
+
+/// Internal: run the read-only command ARGV, which should print JSON,
+/// and parse its output.  See run_get_json.
+fn internal_run_get_json(argv: &[&str], env: &ChildEnv)
+                         -> Result<Value, HLError> {
+    parse_ip_json(argv, internal_run_get_output(
+        &CommandSpec::from_argv(argv), env, true))
+}
+
+/// Run the *read-only* "ip" command ARGV with the -j option, which
+/// makes it print JSON instead of its usual human-readable output, and
+/// return the parsed output.  Like run_query_get_output, the command
+/// is executed even in dry-run mode.  If "ip" is too old to support
+/// -j, the error says so.
+pub fn run_get_json(argv: &[&str], env: &ChildEnv) -> Result<Value, HLError> {
+    internal_run_get_json(&ip_json_argv(argv), env)
+}
+
+/// Like run_get_json, but the command runs inside the network
+/// namespace NAMESPACE (see netns_argv).
+pub fn run_in_netns_get_json(namespace: &str, argv: &[&str], env: &ChildEnv)
+                             -> Result<Value, HLError> {
+    let json_argv = ip_json_argv(argv);
+    let full_argv = try!(netns_argv(namespace, &json_argv));
+    internal_run_get_json(&full_argv, env)
+}
+
+/// Internal: split RAW_OUTPUT, the output of ARGV, into lines; see
+/// run_get_output_lines.
+fn output_lines(argv: &[&str], raw_output: &[u8])
+                -> Result<Vec<String>, HLError> {
+    let output = try!(str::from_utf8(raw_output)
+                      .map_err(|e| map_utf8_err(e, format!("output of {}",
+                                                           argv[0]))));
+    Ok(output
+       .lines()
+       .filter(|l| !l.is_empty())
+       .map(String::from)
+       .collect())
+}
+
+/// Internal: split RAW_OUTPUT into lines, replacing anything that
+/// isn't valid UTF-8; see run_get_output_lines_lossy.
+fn output_lines_lossy(raw_output: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(raw_output)
+        .lines()
+        .filter(|l| !l.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Run a command and return its output as a vector of lines, with
+/// line terminators (either LF or CRLF) and empty lines removed.  The
+/// last line need not be terminated.  Output that is not valid UTF-8
+/// is an error.  Like run_get_output, in dry-run mode the command is
+/// not executed and there are no lines.
+pub fn run_get_output_lines(argv: &[&str], env: &ChildEnv)
+                            -> Result<Vec<String>, HLError> {
+    let raw_output = try!(run_get_output(argv, env));
+    output_lines(argv, &raw_output)
+}
+
+/// Like run_get_output_lines, but for a *read-only* command, which is
+/// executed even in dry-run mode (see run_query_get_output).
+pub fn run_query_get_output_lines(argv: &[&str], env: &ChildEnv)
+                                  -> Result<Vec<String>, HLError> {
+    let raw_output = try!(run_query_get_output(argv, env));
+    output_lines(argv, &raw_output)
+}
+
+/// Run a command and return its output as a vector of lines, with
+/// empty lines removed.  Any bytes that are not valid UTF-8 are
+/// replaced with U+FFFD, so this never fails on account of the child's
+/// output encoding; use it for diagnostics and other text that only
+/// needs to be approximately right.  Like run_get_output, in dry-run
+/// mode the command is not executed and there are no lines.
+pub fn run_get_output_lines_lossy(argv: &[&str], env: &ChildEnv)
+                                  -> Result<Vec<String>, HLError> {
+    let raw_output = try!(run_get_output(argv, env));
+    Ok(output_lines_lossy(&raw_output))
+}
+
+/// Like run_get_output_lines_lossy, but for a *read-only* command,
+/// which is executed even in dry-run mode (see run_query_get_output).
+pub fn run_query_get_output_lines_lossy(argv: &[&str], env: &ChildEnv)
+                                        -> Result<Vec<String>, HLError> {
+    let raw_output = try!(run_query_get_output(argv, env));
+    Ok(output_lines_lossy(&raw_output))
+}
+
+/// Parse OUTPUT, which is expected to be a whitespace-separated list
+/// of process IDs, as printed by "ip netns pids".  Some versions of
+/// iproute2 mix warnings in with the list, so lines containing
+/// anything but digits are skipped (and mentioned, if VERBOSE).  If
+/// there were nonblank lines, but none of them held any process IDs,
+/// the output is assumed to be garbage, and that is an error.  Blank
+/// output is just an empty list.
+pub fn parse_pid_list(output: &[u8], verbose: bool)
+                      -> Result<Vec<pid_t>, HLError> {
+    let output = String::from_utf8_lossy(output);
+    let mut pids = Vec::new();
+    let mut first_bad = None;
+    for line in output.lines() {
+        let parsed = line.split_whitespace()
+            .map(|s| s.parse::<pid_t>())
+            .collect::<Result<Vec<pid_t>, num::ParseIntError>>();
+        match parsed {
+            Ok(mut p) => pids.append(&mut p),
+            Err(e) => {
+                if verbose {
+                    writeln!(io::stderr(), "# ignoring {:?} in pid list",
+                             line).unwrap();
+                }
+                if first_bad.is_none() {
+                    first_bad = Some((e, String::from(line)));
+                }
+            }
+        }
+    }
+    match first_bad {
+        Some((e, line)) if pids.is_empty() =>
+            Err(map_pi_err(e, format!("expected process id: {:?}", line))),
+        _ => Ok(pids)
+    }
+}
+
+/// Run a command whose output is expected to be a whitespace-separated
+/// list of process IDs, and return them, as parsed by parse_pid_list.
+/// Like run_get_output, in dry-run mode the command is not executed
+/// and the list is empty.
+pub fn run_get_output_pids(argv: &[&str], env: &ChildEnv)
+                           -> Result<Vec<pid_t>, HLError> {
+    let raw_output = try!(run_get_output(argv, env));
+    parse_pid_list(&raw_output, env.verbose)
+}
+
+/// Like run_get_output_pids, but for a *read-only* command, which is
+/// executed even in dry-run mode (see run_query_get_output).
+pub fn run_query_get_output_pids(argv: &[&str], env: &ChildEnv)
+                                 -> Result<Vec<pid_t>, HLError> {
+    Runner::run_query_get_output_pids(env, argv)
+}
+
+/// Something that runs commands.  ChildEnv implements this by really
+/// running them, with the functions of the same names; code written
+/// against a Runner (rather than calling those functions directly) can
+/// be exercised with a substitute that merely records what it was
+/// asked to do and hands back scripted results.
+///
+/// The provided methods are written in terms of the others, so they
+/// behave the same way whichever Runner they are used with.
+pub trait Runner {
+    /// The environment commands are run in.  Besides being used to run
+    /// them, this tells callers whether this is a dry run, etc.
+    fn child_env(&self) -> &ChildEnv;
+
+    fn run(&self, argv: &[&str]) -> Result<(), HLError>;
+    fn run_get_output(&self, argv: &[&str]) -> Result<Vec<u8>, HLError>;
+    fn run_query_get_output(&self, argv: &[&str])
+                            -> Result<Vec<u8>, HLError>;
+    fn spawn(&self, argv: &[&str]) -> Result<Child, HLError>;
+    fn run_in_netns(&self, namespace: &str, argv: &[&str])
+                    -> Result<(), HLError>;
+    #[cfg(target_os = "linux")]
+    fn run_in_netns_direct(&self, namespace: &str, argv: &[&str])
+                           -> Result<(), HLError>;
+    /// Send SIG to process PID; see kill_process.
+    fn kill(&self, pid: pid_t, sig: Signal) -> Result<(), HLError>;
+
+    /// Run a command, and report but otherwise ignore any failure.
+    fn run_ignore_failure(&self, argv: &[&str]) {
+        match self.run(argv) {
+            Ok(_) => (),
+            Err(e) => {
+                writeln!(io::stderr(), "{}", e).unwrap();
+            }
+        }
+    }
+
+    /// Run the read-only "ip" command ARGV with -j, and return its
+    /// output, parsed as JSON; see run_get_json.
+    fn run_get_json(&self, argv: &[&str]) -> Result<Value, HLError> {
+        let json_argv = ip_json_argv(argv);
+        parse_ip_json(&json_argv, self.run_query_get_output(&json_argv))
+    }
+
+    /// Run a read-only command which prints a list of process IDs,
+    /// and return them; see parse_pid_list.
+    fn run_query_get_output_pids(&self, argv: &[&str])
+                                 -> Result<Vec<pid_t>, HLError> {
+        let raw_output = try!(self.run_query_get_output(argv));
+        parse_pid_list(&raw_output, self.child_env().verbose)
+    }
+
+    /// Run a command, and if it exits unsuccessfully, try again, up to
+    /// ATTEMPTS times in all.  The first retry happens after BACKOFF,
+    /// and each one after that waits twice as long as the last.  If
+    /// the command can't be run at all, that is not retried.  If every
+    /// attempt fails, the last error is returned, noting how many were
+    /// made.
+    fn run_with_retry(&self, argv: &[&str], attempts: u32,
+                      backoff: Duration) -> Result<(), HLError> {
+        let mut delay = backoff;
+        let mut attempt = 1;
+        loop {
+            match self.run(argv) {
+                Err(HLError::UnsuccessfulChild { status, cmdline,
+                                                 stderr }) => {
+                    if attempt >= attempts {
+                        let status = if attempt > 1 {
+                            format!("{}, after {} attempts", status, attempt)
+                        } else {
+                            status
+                        };
+                        return Err(HLError::UnsuccessfulChild {
+                            status: status, cmdline: cmdline, stderr: stderr
+                        });
+                    }
+                    if self.child_env().verbose {
+                        let ms = delay.as_secs() * 1000
+                            + (delay.subsec_nanos() / 1_000_000) as u64;
+                        writeln!(io::stderr(), "# {} {}; retrying in {} ms",
+                                 cmdline, status, ms).unwrap();
+                    }
+                    thread::sleep(delay);
+                    delay = delay * 2;
+                    attempt += 1;
+                },
+                result => return result
+            }
+        }
+    }
+}
+
+impl Runner for ChildEnv {
+    fn child_env(&self) -> &ChildEnv {
+        self
+    }
+    fn run(&self, argv: &[&str]) -> Result<(), HLError> {
+        run(argv, self)
+    }
+    fn run_get_output(&self, argv: &[&str]) -> Result<Vec<u8>, HLError> {
+        run_get_output(argv, self)
+    }
+    fn run_query_get_output(&self, argv: &[&str])
+                            -> Result<Vec<u8>, HLError> {
+        run_query_get_output(argv, self)
+    }
+    fn spawn(&self, argv: &[&str]) -> Result<Child, HLError> {
+        spawn(argv, self)
+    }
+    fn run_in_netns(&self, namespace: &str, argv: &[&str])
+                    -> Result<(), HLError> {
+        run_in_netns(namespace, argv, self)
+    }
+    #[cfg(target_os = "linux")]
+    fn run_in_netns_direct(&self, namespace: &str, argv: &[&str])
+                           -> Result<(), HLError> {
+        run_in_netns_direct(namespace, argv, self)
+    }
+    fn kill(&self, pid: pid_t, sig: Signal) -> Result<(), HLError> {
+        kill_process(pid, sig, self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_env(dryrun: bool) -> ChildEnv {
+        ChildEnv {
+            env: vec![(String::from("PATH"), String::from(SAFE_PATH))],
+            mask: SigSet::empty(),
+            verbose: false,
+            dryrun: dryrun,
+            unexpected_child: UnexpectedChildPolicy::Ignore,
+            umask: 0o022,
+            recorder: Some(Mutex::new(Vec::new())),
+            tools: HashMap::new(),
+            child_fd_cap: None
+        }
+    }
+
+    #[test]
+    fn output_helpers_honor_dry_run() {
+        let env = test_env(true);
+        assert!(run_get_output_lines(&["echo", "a"], &env).unwrap()
+                .is_empty());
+        assert!(run_get_output_lines_lossy(&["echo", "b"], &env).unwrap()
+                .is_empty());
+        assert!(run_get_output_pids(&["echo", "1"], &env).unwrap()
+                .is_empty());
+        // They were only recorded, not run.
+        assert_eq!(env.recorded().len(), 3);
+    }
+
+    #[test]
+    fn query_helpers_run_even_in_dry_run() {
+        let env = test_env(true);
+        assert_eq!(run_query_get_output_lines(&["printf", "a\n\nb\r\n"],
+                                              &env).unwrap(),
+                   vec![String::from("a"), String::from("b")]);
+        assert_eq!(run_query_get_output_lines_lossy(&["printf", "\\377c"],
+                                                    &env).unwrap(),
+                   vec![String::from("\u{fffd}c")]);
+        assert_eq!(run_query_get_output_pids(&["echo", "12 34"], &env)
+                   .unwrap(),
+                   vec![12, 34]);
+    }
+
+    #[test]
+    fn captured_stderr_is_returned() {
+        let env = test_env(false);
+        let (result, stderr) = run_capture_stderr(
+            &["sh", "-c", "echo oops >&2; exit 3"], &env);
+        assert!(result.is_err());
+        assert_eq!(stderr, "oops\n");
+    }
+
+    #[test]
+    fn captured_stderr_held_open_by_a_grandchild() {
+        let env = test_env(false);
+        let start = Instant::now();
+        let (result, _) = run_capture_stderr(
+            &["sh", "-c", "sleep 30 &"], &env);
+        assert!(result.is_ok());
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    /// Internal: what a child spawned with a stderr tag has as its
+    /// stderr, when CAP descriptors may be held open for children.
+    fn tagged_child_stderr(cap: Option<usize>) -> String {
+        let out = env::temp_dir().join(format!(
+            "openvpn-netns-tools-fdcap-{}", nix::unistd::getpid()));
+        let env = ChildEnv { child_fd_cap: cap, ..test_env(false) };
+        let spec = CommandSpec::from_argv(&["readlink", "/proc/self/fd/2"])
+            .stdout(StdoutMode::File(out.clone()))
+            .stderr_tag("t");
+        let status = spawn_spec(&spec, &env).unwrap().wait().unwrap();
+        assert!(status.success());
+        let mut target = String::new();
+        fs::File::open(&out).unwrap().read_to_string(&mut target).unwrap();
+        fs::remove_file(&out).unwrap();
+        String::from(target.trim_right())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn children_do_without_fds_past_the_cap() {
+        assert!(tagged_child_stderr(None).starts_with("pipe:"));
+        // With no room, the child's stderr is simply ours.
+        assert_eq!(tagged_child_stderr(Some(0)),
+                   fs::read_link("/proc/self/fd/2").unwrap()
+                   .to_string_lossy());
+
+        let env = ChildEnv { child_fd_cap: Some(0), ..test_env(false) };
+        let (mut child, pidfd) = spawn_with_pidfd(&["true"], &env).unwrap();
+        assert!(pidfd.is_none());
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn output_that_is_not_utf8_can_be_read_lossily() {
+        let env = test_env(false);
+        assert!(run_get_output_lines(&["printf", "ok\\n\\377\\n"], &env)
+                .is_err());
+        assert_eq!(run_get_output_lines_lossy(&["printf", "ok\\n\\377\\n"],
+                                              &env).unwrap(),
+                   vec![String::from("ok"), String::from("\u{fffd}")]);
+    }
+
+    #[test]
+    fn slow_commands_are_timed_out() {
+        let env = test_env(false);
+        assert!(run_with_timeout(&["true"], &env, Duration::from_secs(10),
+                                 Duration::from_millis(100)).is_ok());
+
+        let start = Instant::now();
+        match run_with_timeout(&["sleep", "30"], &env,
+                               Duration::from_millis(100),
+                               Duration::from_secs(5)) {
+            Err(HLError::ChildTimedOut { killed: false, .. }) => (),
+            r => panic!("unexpected result: {:?}", r)
+        }
+        // This one ignores SIGTERM, so it has to be killed.
+        match run_with_timeout(&["sh", "-c", "trap '' TERM; sleep 30"], &env,
+                               Duration::from_millis(200),
+                               Duration::from_millis(100)) {
+            Err(HLError::ChildTimedOut { killed: true, .. }) => (),
+            r => panic!("unexpected result: {:?}", r)
+        }
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn children_get_the_original_signal_mask() {
+        let mut mask = SigSet::empty();
+        mask.add(Signal::SIGUSR1);
+        let env = ChildEnv { mask: mask, ..test_env(false) };
+        assert_eq!(run_get_output_lines(&["grep", "^SigBlk:",
+                                          "/proc/self/status"], &env)
+                   .unwrap(),
+                   vec![String::from("SigBlk:\t0000000000000200")]);
+    }
+
+    #[test]
+    fn output_lines_are_split_and_strictly_decoded() {
+        let env = test_env(false);
+        assert_eq!(run_get_output_lines(&["printf", "a\\r\\nb\\n\\nc"], &env)
+                   .unwrap(),
+                   vec![String::from("a"), String::from("b"),
+                        String::from("c")]);
+        match run_get_output_lines(&["printf", "\\377"], &env) {
+            Err(HLError::UTF8Error { .. }) => (),
+            r => panic!("unexpected result: {:?}", r)
+        }
+    }
+
+    #[test]
+    fn failures_include_the_end_of_stderr() {
+        let env = test_env(false);
+        match run(&["sh", "-c", "echo first >&2; echo why >&2; exit 1"],
+                  &env) {
+            Err(HLError::UnsuccessfulChild { ref stderr, .. }) =>
+                assert_eq!(stderr, "first\nwhy"),
+            r => panic!("unexpected result: {:?}", r)
+        }
+        // Only the last few kilobytes are kept, starting at a line.
+        match run(&["sh", "-c", "yes filler | head -n 2000 >&2; \
+                                 echo last >&2; exit 1"], &env) {
+            Err(HLError::UnsuccessfulChild { ref stderr, .. }) => {
+                assert!(stderr.starts_with("[...]\nfiller\n"));
+                assert!(stderr.ends_with("\nlast"));
+                assert!(stderr.len() <= STDERR_TAIL_LIMIT + 6);
+            },
+            r => panic!("unexpected result: {:?}", r)
+        }
+    }
+
+    #[test]
+    fn input_is_fed_to_children() {
+        let env = test_env(false);
+        assert_eq!(run_with_input_get_output(&["tr", "a-z", "A-Z"], &env,
+                                             b"hello\n").unwrap(),
+                   b"HELLO\n".to_vec());
+        let big = vec![b'x'; 1 << 20];
+        assert!(run_with_input(&["sh", "-c", "cat >/dev/null"], &env, &big)
+                .is_ok());
+        // A child that doesn't read its input is an error ...
+        assert!(run_with_input(&["true"], &env, &big).is_err());
+        // ... except in a dry run, where every child is like that.
+        assert!(run_with_input(&["cat"], &test_env(true), &big).is_ok());
+    }
+
+    #[test]
+    fn namespace_names_are_checked_before_running_ip() {
+        assert_eq!(netns_argv("ns0", &["ip", "link"]).unwrap(),
+                   vec!["ip", "netns", "exec", "ns0", "ip", "link"]);
+        let env = test_env(true);
+        for &name in &["", ".", "..", "a/b", "a b"] {
+            assert!(netns_argv(name, &["true"]).is_err());
+            assert!(spawn_in_netns(name, &["true"], &env).is_err());
+        }
+        assert!(env.recorded().is_empty());
+    }
+
+    #[test]
+    fn command_specs_are_built_up_in_order() {
+        let spec = CommandSpec::new("ip").arg("link").args(&[1, 2])
+            .arg_os(OsStr::new("it's"));
+        assert_eq!(spec.program(), "ip");
+        assert_eq!(spec.argv(), vec!["ip", "link", "1", "2", "it's"]);
+        assert_eq!(spec.cmdline(), "ip link 1 2 'it'\\''s'");
+
+        let env = test_env(false);
+        let pwd = CommandSpec::new("pwd").current_dir("/");
+        assert_eq!(run_spec_get_output(&pwd, &env).unwrap(), b"/\n".to_vec());
+
+        let env = test_env(true);
+        run_spec(&spec, &env).unwrap();
+        assert_eq!(env.recorded(), vec![spec.argv()]);
+    }
+
+    /// Internal: a scratch file name for the test WHAT, unique to this
+    /// process.
+    fn scratch_path(what: &str) -> PathBuf {
+        env::temp_dir().join(format!("openvpn-netns-tools-{}-{}",
+                                     what, nix::unistd::getpid()))
+    }
+
+    #[test]
+    fn failed_commands_are_retried() {
+        let env = test_env(false);
+        let count = scratch_path("retry");
+        let _ = fs::remove_file(&count);
+        // This fails until it has been run three times.
+        let script = format!("echo x >> {0}; [ $(wc -l < {0}) -ge 3 ]",
+                             count.display());
+        let argv = ["sh", "-c", &script[..]];
+
+        match run_with_retry(&argv, &env, 2, Duration::from_millis(1)) {
+            Err(HLError::UnsuccessfulChild { ref status, .. }) =>
+                assert!(status.ends_with(", after 2 attempts")),
+            r => panic!("unexpected result: {:?}", r)
+        }
+        assert!(run_with_retry(&argv, &env, 2, Duration::from_millis(1))
+                .is_ok());
+        fs::remove_file(&count).unwrap();
+
+        // Failing to run the command at all is not retried.
+        let start = Instant::now();
+        assert!(run_with_retry(&["/nonexistent"], &env, 5,
+                               Duration::from_secs(5)).is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn children_can_run_as_another_user() {
+        let env = test_env(false);
+        let spec = CommandSpec::from_argv(&["id", "-u"]).user(65534, 65534);
+        let result = run_spec_get_output(&spec, &env);
+        if unsafe { ::libc::geteuid() } == 0 {
+            assert_eq!(result.unwrap(), b"65534\n".to_vec());
+        } else {
+            // Without privilege, the error says which step failed.
+            match result {
+                Err(HLError::NixError { ref detail, .. }) =>
+                    assert_eq!(detail, "setgroups(65534)"),
+                r => panic!("unexpected result: {:?}", r)
+            }
+        }
+    }
+
+    #[test]
+    fn process_groups_are_signaled_together() {
+        let env = test_env(false);
+        let spec = CommandSpec::from_argv(&["sh", "-c", "sleep 30 & wait"])
+            .new_process_group();
+        let mut child = spawn_spec(&spec, &env).unwrap();
+        let pgid = child.id() as pid_t;
+        assert_eq!(unsafe { ::libc::getpgid(pgid) }, pgid);
+
+        kill_process_group(pgid, Signal::SIGTERM).unwrap();
+        assert_eq!(child.wait().unwrap().signal(),
+                   Some(Signal::SIGTERM as c_int));
+        // A group that has gone away is not an error.
+        kill_process_group(pgid, Signal::SIGTERM).unwrap();
+    }
+
+    #[test]
+    fn detached_children_are_not_ours() {
+        use libc::{getsid, waitpid, ECHILD, WNOHANG};
+        use std::ptr;
+
+        let env = test_env(false);
+        let pidfile = scratch_path("detached");
+        let script = format!("echo $$ > {0}.tmp; mv {0}.tmp {0}; \
+                              exec sleep 30", pidfile.display());
+        spawn_detached(&["sh", "-c", &script[..]], &env).unwrap();
+
+        let start = Instant::now();
+        let mut text = String::new();
+        while let Err(_) = fs::File::open(&pidfile)
+            .and_then(|mut f| f.read_to_string(&mut text)) {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        }
+        fs::remove_file(&pidfile).unwrap();
+        let pid: pid_t = text.trim().parse().unwrap();
+
+        // It can't be waited for, and it leads its own session.
+        assert_eq!(unsafe { waitpid(pid, ptr::null_mut(), WNOHANG) }, -1);
+        assert_eq!(io::Error::last_os_error().raw_os_error(), Some(ECHILD));
+        assert_eq!(unsafe { getsid(pid) }, pid);
+        let _ = nix::sys::signal::kill(pid, Signal::SIGKILL);
+    }
+
+    #[test]
+    fn tagged_lines_are_prefixed_across_chunks() {
+        let mut out = Vec::new();
+        let mut at_line_start = true;
+        for chunk in &[&b"one\ntw"[..], &b"o\n"[..], &b"\nthree"[..]] {
+            write_prefixed(&mut out, chunk, "[t] ", &mut at_line_start);
+        }
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "[t] one\n[t] two\n[t] \n[t] three");
+        assert!(!at_line_start);
+    }
+
+    #[test]
+    fn registered_children_are_found_and_reaped() {
+        use nix::sys::wait::waitpid;
+
+        let env = test_env(false);
+        let mut registry = ChildRegistry::new();
+        let sleeper = registry.register(spawn(&["sleep", "30"], &env)
+                                        .unwrap(), "sleeper", true);
+        let quick = registry.register(spawn(&["true"], &env).unwrap(),
+                                      "quick", false);
+        assert_eq!(registry.len(), 2);
+        assert_eq!(registry.label(quick), Some("quick"));
+        assert!(!registry.contains(0));
+        assert!(registry.reap(0, WaitStatus::Exited(0, 0)).is_none());
+
+        // As if the idle loop had reported it.
+        let status = waitpid(quick, None).unwrap();
+        let exited = registry.reap(quick, status).unwrap().unwrap();
+        assert_eq!(exited.label, "quick");
+        assert!(!exited.restart && exited.status.success());
+        assert_eq!(registry.pids(), vec![sleeper]);
+
+        let mut child = registry.remove(sleeper).unwrap().child;
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(registry.is_empty());
+    }
+
+    #[test]
+    fn child_stdout_is_forwarded_line_by_line() {
+        use std::sync::mpsc::channel;
+
+        let env = test_env(false);
+        let (tx, rx) = channel();
+        let (mut child, pump) = spawn_stdout_to_stderr(
+            &["printf", "one\\n\\377\\nthree"], &env, move |line| {
+                tx.send(String::from(line)).unwrap();
+                format!("[t] {}", line)
+            }).unwrap();
+        assert!(child.wait().unwrap().success());
+        pump.join().unwrap();
+        assert_eq!(rx.iter().collect::<Vec<String>>(),
+                   vec!["one", "\u{fffd}", "three"]);
+    }
+
+    #[test]
+    fn exec_failures_in_namespaces_are_recognized() {
+        assert_eq!(netns_exec_failure("exec of \"openvpn\" failed: \
+                                       No such file or directory\n"),
+                   Some("No such file or directory"));
+        assert_eq!(netns_exec_failure("openvpn: exec of \"x\" failed: y\n"),
+                   None);
+        assert_eq!(netns_exec_failure("Cannot open network namespace \
+                                       \"t\": No such file or directory\n"),
+                   None);
+    }
+
+    #[test]
+    fn every_failure_in_a_sequence_is_reported() {
+        let env = test_env(false);
+        assert!(run_each(&[&["true"], &["true"]], &env).is_ok());
+        match run_each(&[&["false"], &["true"], &["sh", "-c", "exit 3"]],
+                       &env) {
+            Err(HLError::CommandFailures { total: 3, ref failures }) => {
+                let which: Vec<(usize, &str)> = failures.iter()
+                    .map(|&(i, ref cmdline, _)| (i, cmdline.as_str()))
+                    .collect();
+                assert_eq!(which, vec![(0, "false"), (2, "sh -c 'exit 3'")]);
+            },
+            r => panic!("unexpected result: {:?}", r)
+        }
+    }
+
+    #[test]
+    fn arguments_need_not_be_utf8() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let env = test_env(false);
+        let argv = [OsStr::new("printf"), OsStr::from_bytes(b"a\xff")];
+        assert_eq!(run_get_output(&argv, &env).unwrap(), b"a\xff".to_vec());
+        // Messages show them lossily.
+        assert_eq!(CommandSpec::from_argv(&argv).cmdline(),
+                   "printf 'a\u{fffd}'");
+    }
+
+    /// Internal: convert PAIRS to owned strings.
+    fn string_pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|&(k, v)| (String::from(k), String::from(v)))
+            .collect()
+    }
+
+    #[test]
+    fn only_allowlisted_variables_are_passed_down() {
+        env::set_var("OVPN_NETNS_TEST_KEEP", "1");
+        env::set_var("OVPN_NETNS_TEST_DROP", "2");
+        env::set_var("OVPN_NETNS_TEST_X_PREFIXED", "3");
+        env::set_var("OVPN_NETNS_TEST_FORCED", "4");
+        let child_env = allowlisted_env(
+            &["OVPN_NETNS_TEST_KEEP", "OVPN_NETNS_TEST_FORCED"],
+            &["OVPN_NETNS_TEST_X_"],
+            &[("PATH", SAFE_PATH), ("OVPN_NETNS_TEST_FORCED", "forced")]);
+        let ours: Vec<(String, String)> = child_env.into_iter()
+            .filter(|&(ref k, _)| k.starts_with("OVPN_NETNS_TEST_")
+                    || k == "PATH")
+            .collect();
+        assert_eq!(ours, string_pairs(&[
+            ("OVPN_NETNS_TEST_FORCED", "forced"),
+            ("OVPN_NETNS_TEST_KEEP", "1"),
+            ("OVPN_NETNS_TEST_X_PREFIXED", "3"),
+            ("PATH", SAFE_PATH)]));
+    }
+
+    #[test]
+    fn too_much_output_is_an_error() {
+        let env = test_env(false);
+        let spec = CommandSpec::from_argv(&["yes"]).output_limit(Some(1000));
+        match run_spec_get_output(&spec, &env) {
+            Err(HLError::OutputTooLong { limit: 1000, read, .. }) =>
+                assert!(read > 1000),
+            r => panic!("unexpected result: {:?}", r)
+        }
+        let spec = CommandSpec::from_argv(&["head", "-c", "5000",
+                                            "/dev/zero"])
+            .output_limit(None);
+        assert_eq!(run_spec_get_output(&spec, &env).unwrap().len(), 5000);
+    }
+
+    /// Internal: convert WORDS to owned strings.
+    fn strings(words: &[&str]) -> Vec<String> {
+        words.iter().map(|&w| String::from(w)).collect()
+    }
+
+    #[test]
+    fn dry_runs_record_every_command() {
+        let env = test_env(true);
+        run(&["ip", "link", "del", "veth0"], &env).unwrap();
+        assert_eq!(run_query_get_output(&["echo", "hi"], &env).unwrap(),
+                   b"hi\n".to_vec());
+        assert!(spawn(&["ip", "netns", "add", "t"], &env).unwrap().wait()
+                .unwrap().success());
+        assert_eq!(env.recorded(),
+                   vec![strings(&["ip", "link", "del", "veth0"]),
+                        strings(&["echo", "hi"]),
+                        strings(&["ip", "netns", "add", "t"])]);
+
+        // Real runs aren't recorded.
+        let env = test_env(false);
+        run(&["true"], &env).unwrap();
+        assert!(env.recorded().is_empty());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn pidfds_become_readable_when_children_exit() {
+        use nix::poll::{poll, PollFd, POLLIN, EventFlags};
+
+        let env = test_env(false);
+        let (mut child, pidfd) = spawn_with_pidfd(&["sleep", "0.2"], &env)
+            .unwrap();
+        let pidfd = match pidfd {
+            Some(pidfd) => pidfd,
+            // The kernel is too old to have them.
+            None => return
+        };
+        assert_eq!(pidfd.pid(), child.id() as pid_t);
+        let mut pfds = [PollFd::new(pidfd.as_raw_fd(), POLLIN,
+                                    EventFlags::empty())];
+        assert_eq!(poll(&mut pfds, 0).unwrap(), 0);
+        assert_eq!(poll(&mut pfds, 10000).unwrap(), 1);
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn stray_descriptors_are_not_inherited() {
+        use libc::{fcntl, F_SETFD};
+
+        let env = test_env(false);
+        let file = fs::File::open("/dev/null").unwrap();
+        let fd = file.as_raw_fd();
+        // As if something had opened it without O_CLOEXEC.
+        assert_eq!(unsafe { fcntl(fd, F_SETFD, 0) }, 0);
+        let path = format!("/proc/self/fd/{}", fd);
+        let argv = ["test", "-e", &path[..]];
+        assert!(!run_status(&argv, &env).unwrap());
+        assert!(run_spec(&CommandSpec::from_argv(&argv).keep_fd(fd), &env)
+                .is_ok());
+    }
+
+    #[test]
+    fn ip_json_output_is_parsed() {
+        let argv = ip_json_argv(&["ip", "link", "show"]);
+        assert_eq!(argv, vec!["ip", "-j", "link", "show"]);
+
+        let links = parse_ip_json(&argv, Ok(b"[{\"ifname\":\"lo\"}]\n"
+                                            .to_vec())).unwrap();
+        assert_eq!(links[0]["ifname"], "lo");
+        // Nothing at all means nothing to list.
+        assert_eq!(parse_ip_json(&argv, Ok(b"\n".to_vec())).unwrap(),
+                   Value::Array(Vec::new()));
+        match parse_ip_json(&argv, Ok(b"lo: <LOOPBACK>".to_vec())) {
+            Err(HLError::JSONError { ref cmdline, .. }) =>
+                assert_eq!(cmdline, "ip -j link show"),
+            r => panic!("unexpected result: {:?}", r)
+        }
+        let too_old = HLError::UnsuccessfulChild {
+            status: String::from("exited unsuccessfully (code 255)"),
+            cmdline: String::from("ip -j link show"),
+            stderr: String::from("Option \"-j\" is unknown, try \
+                                  \"ip -help\".")
+        };
+        match parse_ip_json(&argv, Err(too_old)) {
+            Err(HLError::UnsuccessfulChild { ref status, .. }) =>
+                assert!(status.contains("does not support JSON")),
+            r => panic!("unexpected result: {:?}", r)
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn children_can_run_at_lower_priority() {
+        use libc::{getpriority, PRIO_PROCESS, SCHED_BATCH};
+
+        let env = test_env(false);
+        let nice = cmp::min(unsafe { getpriority(PRIO_PROCESS as _, 0) } + 5,
+                            19);
+        let spec = CommandSpec::from_argv(&["cat", "/proc/self/stat"])
+            .priority(nice, SchedPolicy::Batch);
+        let stat = run_spec_get_output(&spec, &env).unwrap();
+        let stat = String::from_utf8(stat).unwrap();
+        // The fields after the command name, starting with the third.
+        let fields: Vec<&str> = stat[stat.rfind(')').unwrap() + 1..]
+            .split_whitespace().collect();
+        assert_eq!(fields[16], nice.to_string());
+        assert_eq!(fields[38], SCHED_BATCH.to_string());
+    }
+
+    #[test]
+    fn stubborn_processes_are_killed() {
+        let env = test_env(false);
+        let mut child = spawn(&["sh", "-c", "trap '' TERM; sleep 30"], &env)
+            .unwrap();
+        let pid = child.id() as pid_t;
+        // Give it time to ignore SIGTERM.
+        thread::sleep(Duration::from_millis(200));
+        assert!(kill_gracefully(pid, Duration::from_millis(100)).unwrap());
+        assert_eq!(child.wait().unwrap().signal(),
+                   Some(Signal::SIGKILL as c_int));
+        // Once it's gone, there's nothing to do.
+        assert!(!kill_gracefully(pid, Duration::from_secs(5)).unwrap());
+    }
+
+    #[test]
+    fn exit_status_is_left_to_the_caller() {
+        let (status, output) = run_get_output_status(
+            &["sh", "-c", "echo out; exit 3"], &test_env(false)).unwrap();
+        assert_eq!(status.code(), Some(3));
+        assert_eq!(output, b"out\n".to_vec());
+
+        let (status, output) = run_get_output_status(
+            &["sh", "-c", "echo out; exit 3"], &test_env(true)).unwrap();
+        assert!(status.success() && output.is_empty());
+    }
+
+    #[test]
+    fn reported_statuses_are_converted() {
+        use nix::sys::wait::waitpid;
+
+        let env = test_env(false);
+        let child = spawn(&["sh", "-c", "exit 7"], &env).unwrap();
+        let status = waitpid(child.id() as pid_t, None).unwrap();
+        assert_eq!(reap_child(child, status).unwrap().code(), Some(7));
+
+        let mut child = spawn(&["sleep", "30"], &env).unwrap();
+        child.kill().unwrap();
+        let status = waitpid(child.id() as pid_t, None).unwrap();
+        assert_eq!(reap_child(child, status).unwrap().signal(),
+                   Some(Signal::SIGKILL as c_int));
+    }
+
+    #[test]
+    fn batches_run_in_parallel_and_report_every_failure() {
+        let env = test_env(false);
+        let start = Instant::now();
+        run_all(&[&["sleep", "0.5"], &["sleep", "0.5"], &["sleep", "0.5"]],
+                &env, 3).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(1400));
+
+        match run_all(&[&["true"], &["sh", "-c", "exit 2"],
+                        &["/nonexistent"], &["true"]], &env, 2) {
+            Err(HLError::CommandFailures { total: 4, ref failures }) => {
+                let which: Vec<(usize, &str)> = failures.iter()
+                    .map(|&(i, ref cmdline, _)| (i, cmdline.as_str()))
+                    .collect();
+                assert_eq!(which, vec![(1, "sh -c 'exit 2'"),
+                                       (2, "/nonexistent")]);
+            },
+            r => panic!("unexpected result: {:?}", r)
+        }
+    }
+
+    #[test]
+    fn descriptors_are_passed_under_new_numbers() {
+        let env = test_env(false);
+        let path = scratch_path("pass-fd");
+        let file = fs::File::create(&path).unwrap();
+        let spec = CommandSpec::from_argv(&["sh", "-c", "echo hi >&7"])
+            .pass_fd(file.as_raw_fd(), 7);
+        run_spec(&spec, &env).unwrap();
+        let mut text = String::new();
+        fs::File::open(&path).unwrap().read_to_string(&mut text).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(text, "hi\n");
+
+        // Numbers that are taken are refused before anything runs.
+        let fd = file.as_raw_fd();
+        for spec in &[CommandSpec::new("true").pass_fd(fd, 2),
+                      CommandSpec::new("true").pass_fd(fd, 5)
+                          .pass_fd(fd, 5),
+                      CommandSpec::new("true").keep_fd(5).pass_fd(fd, 5)] {
+            match run_spec(spec, &env) {
+                Err(HLError::ConfigError { .. }) => (),
+                r => panic!("unexpected result: {:?}", r)
+            }
+        }
+    }
+
+    #[test]
+    fn output_can_be_appended_to_log_files() {
+        let out = scratch_path("log-out");
+        let err = scratch_path("log-err");
+        let spec = CommandSpec::from_argv(&["sh", "-c",
+                                            "echo out; echo err >&2"])
+            .stdout(StdoutMode::File(out.clone()))
+            .stderr_file(&err);
+
+        // A dry run doesn't touch them.
+        run_spec(&spec, &test_env(true)).unwrap();
+        assert!(!out.exists() && !err.exists());
+
+        let env = test_env(false);
+        run_spec(&spec, &env).unwrap();
+        run_spec(&spec, &env).unwrap();
+        for &(ref path, text) in &[(&out, "out\nout\n"),
+                                   (&err, "err\nerr\n")] {
+            let mut contents = String::new();
+            fs::File::open(path).unwrap().read_to_string(&mut contents)
+                .unwrap();
+            fs::remove_file(path).unwrap();
+            assert_eq!(contents, text);
+        }
+    }
+
+    #[test]
+    fn pid_lists_tolerate_blanks_and_warnings() {
+        assert_eq!(parse_pid_list(b"", false).unwrap(), Vec::<pid_t>::new());
+        assert_eq!(parse_pid_list(b"12 34\n\n56\n", false).unwrap(),
+                   vec![12, 34, 56]);
+        assert_eq!(parse_pid_list(b"Warning: something odd\n78\n", false)
+                   .unwrap(),
+                   vec![78]);
+        match parse_pid_list(b"this is not a list\n", false) {
+            Err(HLError::PIError { .. }) => (),
+            r => panic!("unexpected result: {:?}", r)
+        }
+    }
+
+    #[test]
+    fn commands_can_have_their_own_settings() {
+        let env = test_env(false);
+        let spec = CommandSpec::from_argv(&["sh", "-c", "echo $A $PATH"])
+            .env("A", "1").env("A", "2").env("PATH", "/bin");
+        assert_eq!(run_spec_get_output(&spec, &env).unwrap(),
+                   b"2 /bin\n".to_vec());
+        assert_eq!(spec.cmdline_with_env(),
+                   "A=1 A=2 PATH=/bin sh -c 'echo $A $PATH'");
+
+        let extra = string_pairs(&[("B", "3")]);
+        assert!(run_with_env(&["sh", "-c", "[ \"$B\" = 3 ]"], &env, &extra)
+                .is_ok());
+        assert!(spawn_with_env(&["sh", "-c", "[ \"$B\" = 3 ]"], &env,
+                               &extra).unwrap().wait().unwrap().success());
+        // The settings are for that command only.
+        assert!(run(&["sh", "-c", "[ -z \"$B\" ]"], &env).is_ok());
+    }
 }